@@ -1,14 +1,33 @@
-use reqwest;
-use std::collections::{HashMap};
-use serde_json::{Value, Map};
+use serde_json::{json, Value, Map};
+use ::airtable::client::{AirtableClient as TableClient, AirtableConfig as TableConfig};
+use crate::errors::*;
 
-const AIRTABLE_BASE_URL: &str = "https://api.airtable.com/v0";
+// Record fields and the column each is written to unless the column map renames it.
+const DEFAULT_COLUMNS: [(&str, &str); 5] = [
+  ("total_btc", "Value (BTC)"),
+  ("total_usd", "Value (USD)"),
+  ("details", "Details"),
+  ("timestamp", "Timestamp"),
+  ("name", "Name"),
+];
+// The same for the rows of the detail table.
+const DEFAULT_DETAIL_COLUMNS: [(&str, &str); 6] = [
+  ("snapshot", "Snapshot"),
+  ("symbol", "Symbol"),
+  ("quantity", "Quantity"),
+  ("value_usd", "Value (USD)"),
+  ("value_btc", "Value (BTC)"),
+  ("weight", "Weight"),
+];
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AirtableConfig {
     pub key: String,
     pub app: String,
     pub table: String,
+    // A table in the same base that gets a row per asset of every snapshot, linked to the
+    // snapshot's row. The details column is no longer written when it is set.
+    pub detail_table: Option<String>,
     pub column_map: Option<ColumnMap>,
 }
 
@@ -19,87 +38,135 @@ pub struct ColumnMap {
     pub total_usd: Option<String>,
     pub timestamp: Option<String>,
     pub details: Option<String>,
+    // Defaults to "Value (<currency>)" for the configured reporting currency.
+    pub total_value: Option<String>,
+    // Only written by the file sinks, airtable puts the currency in the value column's name.
+    pub currency: Option<String>,
+    // Columns of the detail table. `snapshot` is the link to the snapshot table.
+    pub snapshot: Option<String>,
+    pub symbol: Option<String>,
+    pub quantity: Option<String>,
+    pub value_usd: Option<String>,
+    pub value_btc: Option<String>,
+    pub weight: Option<String>,
+}
+
+impl ColumnMap {
+  /**
+   * The column a snapshot field was renamed to, if any.
+   */
+  pub fn column(&self, field: &str) -> Option<&str> {
+    let column = match field {
+      "name" => &self.name,
+      "total_btc" => &self.total_btc,
+      "total_usd" => &self.total_usd,
+      "timestamp" => &self.timestamp,
+      "details" => &self.details,
+      "total_value" => &self.total_value,
+      "currency" => &self.currency,
+      "snapshot" => &self.snapshot,
+      "symbol" => &self.symbol,
+      "quantity" => &self.quantity,
+      "value_usd" => &self.value_usd,
+      "value_btc" => &self.value_btc,
+      "weight" => &self.weight,
+      _ => &None,
+    };
+    column.as_ref().map(|c| c.as_str())
+  }
+}
+
+/**
+ * An asset's row in the detail table. The weight is its share of the snapshot's USD value.
+ */
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DetailRecord {
+  pub symbol: String,
+  pub quantity: f64,
+  pub value_usd: f64,
+  pub value_btc: f64,
+  pub weight: f64,
 }
 
 pub struct AirtableClient<'a> {
   config: &'a AirtableConfig,
-  client: reqwest::Client,
+  snapshots: TableClient,
+  details: Option<TableClient>,
 }
 impl<'a> AirtableClient<'a> {
   pub fn new(config: &'a AirtableConfig) -> Self {
-    let client = reqwest::Client::new();
+    let snapshots = TableClient::new(&TableConfig {
+      key: config.key.to_string(),
+      app: config.app.to_string(),
+      table: config.table.to_string(),
+    });
+    let details = config.detail_table.as_ref().map(|table| snapshots.for_table(table));
     AirtableClient {
       config: config,
-      client: client,
+      snapshots: snapshots,
+      details: details,
     }
   }
-  fn replace_record(&self, record: Value) -> Value {
-    let formatted = if let Some(cm) = &self.config.column_map {
-      let mut value = Map::new();
-      let btc_key = match &cm.total_btc {
-        Some(s) => s.to_string(),
-        None => "Value (BTC)".to_string()
-      };
-      let usd_key = match &cm.total_usd {
-        Some(s) => s.to_string(),
-        None => "Value (USD)".to_string()
-      };
-      let details_key = match &cm.details {
-        Some(s) => s.to_string(),
-        None => "Details".to_string()
-      };
-      let ts_key = match &cm.timestamp {
-        Some(s) => s.to_string(),
-        None => "Timestamp".to_string()
-      };
-      let name_key = match &cm.name {
+
+  /**
+   * Whether snapshots write their balances to the detail table rather than the details column.
+   */
+  pub fn has_details(&self) -> bool {
+    self.details.is_some()
+  }
+
+  /**
+   * Renames the fields of a record to the configured columns. Fields the record does not have are left out.
+   * Without a column map the record is sent as is, with the field names as columns.
+   */
+  fn replace_record(&self, record: &Value, columns: &[(&str, &str)]) -> Value {
+    let cm = match &self.config.column_map {
+      Some(cm) => cm,
+      None => return record.clone(),
+    };
+    let mut value = Map::new();
+    for (field, default) in columns {
+      if let Some(v) = record.get(*field) {
+        let key = cm.column(field).unwrap_or(default);
+        value.insert(key.to_string(), v.clone());
+      }
+    }
+    if let (Some(total), Some(currency)) = (record.get("total_value"), record.get("currency")) {
+      let value_key = match &cm.total_value {
         Some(s) => s.to_string(),
-        None => "Name".to_string()
+        None => format!("Value ({})", currency.as_str().unwrap_or("")),
       };
-      value.insert(
-        btc_key,
-        serde_json::to_value(record.get("total_btc").unwrap().as_f64().unwrap()).unwrap()
-      );
-      value.insert(
-        usd_key,
-        serde_json::to_value(record.get("total_usd").unwrap().as_f64().unwrap()).unwrap()
-      );
-      value.insert(
-        details_key,
-        serde_json::to_value(record.get("details").unwrap().as_str().unwrap()).unwrap()
-      );
-      value.insert(
-        ts_key,
-        serde_json::to_value(record.get("timestamp").unwrap().as_str().unwrap()).unwrap()
-      );
-      value.insert(
-        name_key,
-        serde_json::to_value(record.get("name").unwrap().as_str().unwrap()).unwrap()
-      );
-      serde_json::to_value(value).unwrap()
-    } else {
-      record
-    };
-    formatted
+      value.insert(value_key, total.clone());
+    }
+    Value::Object(value)
   }
-  pub fn create_record(&self, record: Value) -> reqwest::Result<String> {
-    let url = format!("{}/{}/{}", AIRTABLE_BASE_URL, self.config.app, escape_spaces(self.config.table.to_string()));
-    let formatted = self.replace_record(record);
-    let mut data = HashMap::new();
-    data.insert("fields", formatted);
-    // TODO: Handle error gracefully.
-    let response = match self.client.post(&url)
-      .header("Content-Type", "application/json")
-      .header("Authorization", format!("Bearer {}", self.config.key))
-      .json(&data)
-      .send() {
-        Ok(mut d) => d.text(),
-        Err(e) => Err(e)
-      };
-    response
+
+  /**
+   * Creates a row in the snapshot table and returns its record id.
+   */
+  pub fn create_record(&self, record: Value) -> Result<String> {
+    let formatted = self.replace_record(&record, &DEFAULT_COLUMNS);
+    match self.snapshots.create_records(vec![formatted])?.into_iter().next() {
+      Some(created) => Ok(created.id),
+      None => bail!("Airtable did not return the record it created"),
+    }
   }
-}
 
-fn escape_spaces(s: String) -> String {
-  s.split(' ').collect::<Vec<&str>>().join("%20")
-}
\ No newline at end of file
+  /**
+   * Creates a row in the detail table for every asset, linked to the snapshot row, ten per request.
+   * Returns the number of rows created.
+   */
+  pub fn create_details(&self, snapshot_id: &str, rows: &[DetailRecord]) -> Result<usize> {
+    let details = match &self.details {
+      Some(d) => d,
+      None => bail!("No detail_table is configured"),
+    };
+    let mut records = vec![];
+    for row in rows {
+      let mut record = serde_json::to_value(row).chain_err(|| "Could not serialize an asset row")?;
+      record["snapshot"] = json!([snapshot_id]);
+      records.push(self.replace_record(&record, &DEFAULT_DETAIL_COLUMNS));
+    }
+    Ok(details.create_records(records)?.len())
+  }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use chrono::prelude::*;
+use native_tls::{TlsConnector, TlsStream};
+use coin_sdk::alerts::{Alert, AlertRule};
+use crate::errors::*;
+
+// How long to wait on the mail server before giving up.
+const SMTP_TIMEOUT_SECS: u64 = 30;
+
+/**
+ * Alert rules and where alerts go, configured in the [alerts] table of ~/.coin.toml.
+ */
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct AlertsConfig {
+  // Minutes after alerting before a condition may alert again. Defaults to 60.
+  pub rearm_minutes: Option<u64>,
+  pub rules: Option<Vec<AlertRule>>,
+  // Print alerts, which puts them in the log when the watcher runs the check. Defaults to true.
+  pub log: Option<bool>,
+  // A shell command run for every alert. It gets the alert as JSON on stdin and in COIN_ALERT_ variables.
+  pub command: Option<String>,
+  pub smtp: Option<SmtpConfig>,
+  pub webhook: Option<WebhookConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SmtpConfig {
+  pub host: String,
+  // Defaults to 465 with tls, 587 with starttls and 25 with none.
+  pub port: Option<u16>,
+  // tls, starttls or none. Defaults to starttls.
+  pub security: Option<String>,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  pub from: String,
+  pub to: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WebhookConfig {
+  pub url: String,
+  // Sent with every request, e.g. an Authorization header.
+  pub headers: Option<HashMap<String, String>>,
+}
+
+/**
+ * Somewhere alerts are delivered.
+ */
+pub trait AlertChannel {
+  fn name(&self) -> &str;
+  fn send(&self, alert: &Alert) -> Result<()>;
+}
+
+/**
+ * Sends the alert through every channel. A channel that fails does not stop the others, the
+ * outcome of each is returned with the channel's name.
+ */
+pub fn deliver(channels: &[Box<dyn AlertChannel + '_>], alert: &Alert) -> Vec<(String, Result<()>)> {
+  channels.iter().map(|channel| (channel.name().to_string(), channel.send(alert))).collect()
+}
+
+fn local_time(timestamp: u64) -> String {
+  match Local.timestamp_opt(timestamp as i64, 0).single() {
+    Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+    None => timestamp.to_string(),
+  }
+}
+
+/**
+ * Prints alerts. The watcher keeps the output of its jobs in its log.
+ */
+pub struct LogChannel;
+impl AlertChannel for LogChannel {
+  fn name(&self) -> &str {
+    "log"
+  }
+  fn send(&self, alert: &Alert) -> Result<()> {
+    println!("[{}] Alert: {}. {}", local_time(alert.timestamp), alert.rule, alert.message);
+    Ok(())
+  }
+}
+
+/**
+ * Runs a shell command for every alert with the alert as JSON on stdin and its fields in the
+ * COIN_ALERT_KEY, COIN_ALERT_RULE, COIN_ALERT_MESSAGE and COIN_ALERT_TIMESTAMP variables.
+ */
+pub struct CommandChannel<'a> {
+  command: &'a str,
+}
+impl<'a> CommandChannel<'a> {
+  pub fn new(command: &'a str) -> Self {
+    CommandChannel { command }
+  }
+}
+impl<'a> AlertChannel for CommandChannel<'a> {
+  fn name(&self) -> &str {
+    "command"
+  }
+  fn send(&self, alert: &Alert) -> Result<()> {
+    let json = serde_json::to_string(alert).chain_err(|| "Could not serialize the alert")?;
+    let mut child = Command::new("sh")
+      .args(&["-c", self.command])
+      .env("COIN_ALERT_KEY", &alert.key)
+      .env("COIN_ALERT_RULE", &alert.rule)
+      .env("COIN_ALERT_MESSAGE", &alert.message)
+      .env("COIN_ALERT_TIMESTAMP", alert.timestamp.to_string())
+      .stdin(Stdio::piped())
+      .spawn()
+      .chain_err(|| format!("Could not run {}", self.command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+      // A command that does not read its input may close it early, that is fine.
+      let _ = stdin.write_all(json.as_bytes());
+    }
+    let status = child.wait().chain_err(|| format!("Could not run {}", self.command))?;
+    if !status.success() {
+      bail!("{} exited with {}", self.command, status);
+    }
+    Ok(())
+  }
+}
+
+/**
+ * POSTs every alert as JSON to a URL.
+ */
+pub struct WebhookChannel<'a> {
+  config: &'a WebhookConfig,
+  client: reqwest::Client,
+}
+impl<'a> WebhookChannel<'a> {
+  pub fn new(config: &'a WebhookConfig) -> Self {
+    WebhookChannel { config, client: reqwest::Client::new() }
+  }
+}
+impl<'a> AlertChannel for WebhookChannel<'a> {
+  fn name(&self) -> &str {
+    "webhook"
+  }
+  fn send(&self, alert: &Alert) -> Result<()> {
+    let mut request = self.client.post(&self.config.url).json(alert);
+    for (name, value) in self.config.headers.iter().flatten() {
+      request = request.header(&name[..], &value[..]);
+    }
+    let response = request.send().chain_err(|| format!("Could not reach {}", self.config.url))?;
+    if !response.status().is_success() {
+      bail!("{} answered {}", self.config.url, response.status());
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Security {
+  Tls,
+  StartTls,
+  None,
+}
+
+/**
+ * Emails every alert through an SMTP server, over TLS unless the security is none.
+ */
+pub struct SmtpChannel<'a> {
+  config: &'a SmtpConfig,
+}
+impl<'a> SmtpChannel<'a> {
+  pub fn new(config: &'a SmtpConfig) -> Self {
+    SmtpChannel { config }
+  }
+
+  fn security(&self) -> Result<Security> {
+    match self.config.security.as_ref().map(|s| s.to_lowercase()).as_ref().map(|s| &s[..]) {
+      Some("tls") => Ok(Security::Tls),
+      Some("starttls") | None => Ok(Security::StartTls),
+      Some("none") => Ok(Security::None),
+      Some(other) => bail!("Unknown SMTP security {}. Use tls, starttls or none.", other),
+    }
+  }
+
+  fn message(&self, alert: &Alert) -> String {
+    let headers = vec![
+      format!("From: {}", self.config.from),
+      format!("To: {}", self.config.to.join(", ")),
+      format!("Subject: coin alert: {}", alert.rule),
+      format!("Date: {}", Local::now().to_rfc2822()),
+      "MIME-Version: 1.0".to_string(),
+      "Content-Type: text/plain; charset=utf-8".to_string(),
+    ];
+    let body = format!("{}\n\n{}\n", alert.message, local_time(alert.timestamp));
+    // Lines starting with a dot get another one so the server does not read them as the end.
+    let body: Vec<String> = body.lines()
+      .map(|l| if l.starts_with('.') { format!(".{}", l) } else { l.to_string() })
+      .collect();
+    format!("{}\r\n\r\n{}\r\n", headers.join("\r\n"), body.join("\r\n"))
+  }
+}
+impl<'a> AlertChannel for SmtpChannel<'a> {
+  fn name(&self) -> &str {
+    "smtp"
+  }
+  fn send(&self, alert: &Alert) -> Result<()> {
+    let config = self.config;
+    let security = self.security()?;
+    let port = config.port.unwrap_or(match security {
+      Security::Tls => 465,
+      Security::StartTls => 587,
+      Security::None => 25,
+    });
+    let tcp = TcpStream::connect((&config.host[..], port))
+      .chain_err(|| format!("Could not connect to {}:{}", config.host, port))?;
+    let timeout = Some(Duration::from_secs(SMTP_TIMEOUT_SECS));
+    tcp.set_read_timeout(timeout).and_then(|_| tcp.set_write_timeout(timeout))
+      .chain_err(|| "Could not set the mail server timeout")?;
+    let connector = TlsConnector::new().chain_err(|| "Could not set up TLS")?;
+    let mut session = match security {
+      Security::Tls => SmtpSession::Tls(tls(&connector, &config.host, tcp)?),
+      _ => SmtpSession::Plain(tcp),
+    };
+    session.reply(&[220])?;
+    session.command("EHLO localhost", &[250])?;
+    if security == Security::StartTls {
+      session.command("STARTTLS", &[220])?;
+      session = match session {
+        SmtpSession::Plain(tcp) => SmtpSession::Tls(tls(&connector, &config.host, tcp)?),
+        tls => tls,
+      };
+      session.command("EHLO localhost", &[250])?;
+    }
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+      let credentials = base64::encode(&format!("\0{}\0{}", username, password));
+      session.command(&format!("AUTH PLAIN {}", credentials), &[235])?;
+    }
+    session.command(&format!("MAIL FROM:<{}>", config.from), &[250])?;
+    for to in &config.to {
+      session.command(&format!("RCPT TO:<{}>", to), &[250, 251])?;
+    }
+    session.command("DATA", &[354])?;
+    session.write(&format!("{}.", self.message(alert)))?;
+    session.reply(&[250]).chain_err(|| "The mail server refused the message")?;
+    let _ = session.command("QUIT", &[221]);
+    Ok(())
+  }
+}
+
+fn tls(connector: &TlsConnector, host: &str, tcp: TcpStream) -> Result<TlsStream<TcpStream>> {
+  connector.connect(host, tcp).map_err(|e| format!("Could not start TLS with {}: {}", host, e).into())
+}
+
+enum SmtpSession {
+  Plain(TcpStream),
+  Tls(TlsStream<TcpStream>),
+}
+impl SmtpSession {
+  fn stream(&mut self) -> &mut dyn ReadWrite {
+    match self {
+      SmtpSession::Plain(s) => s,
+      SmtpSession::Tls(s) => s,
+    }
+  }
+
+  fn write(&mut self, line: &str) -> Result<()> {
+    self.stream().write_all(format!("{}\r\n", line).as_bytes())
+      .chain_err(|| "Could not write to the mail server")
+  }
+
+  /**
+   * Sends a line and reads the reply. Fails unless the reply has one of the expected codes.
+   */
+  fn command(&mut self, line: &str, expected: &[u16]) -> Result<String> {
+    self.write(line)?;
+    // Only name the verb, the line may hold credentials.
+    let verb = line.split(|c| c == ' ' || c == ':' || c == '\r').next().unwrap_or("");
+    self.reply(expected).chain_err(|| format!("The mail server refused {}", verb))
+  }
+
+  /**
+   * Reads a reply, which may span lines written as `250-...` up to a last one written as `250 ...`.
+   */
+  fn reply(&mut self, expected: &[u16]) -> Result<String> {
+    let mut reply = String::new();
+    loop {
+      let line = self.read_line()?;
+      reply.push_str(&line);
+      reply.push('\n');
+      if line.len() < 4 || line.as_bytes()[3] != b'-' {
+        break;
+      }
+    }
+    let code: u16 = reply.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+    if !expected.contains(&code) {
+      bail!("{}", reply.trim());
+    }
+    Ok(reply)
+  }
+
+  // Reads byte by byte so nothing is buffered past the reply when the connection switches to TLS.
+  fn read_line(&mut self) -> Result<String> {
+    let mut line = vec![];
+    let mut byte = [0u8; 1];
+    loop {
+      let read = self.stream().read(&mut byte).chain_err(|| "Could not read from the mail server")?;
+      if read == 0 {
+        bail!("The mail server closed the connection");
+      }
+      if byte[0] == b'\n' {
+        break;
+      }
+      line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+  }
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::thread;
+  use coin_sdk::alerts::Alert;
+  use super::*;
+
+  /**
+   * Answers one SMTP session the way a mail server would, replying `rcpt` to every recipient, and
+   * hands back every line it received.
+   */
+  fn mock_server(rcpt: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut seen = vec![];
+      let mut in_data = false;
+      write!(stream, "220 mock ESMTP\r\n").unwrap();
+      loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+          break;
+        }
+        let line = line.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
+        seen.push(line.to_string());
+        let reply = if in_data {
+          if line != "." {
+            continue;
+          }
+          in_data = false;
+          "250 queued"
+        } else if line.starts_with("EHLO") {
+          "250-mock\r\n250 AUTH PLAIN"
+        } else if line.starts_with("AUTH") {
+          "235 authenticated"
+        } else if line.starts_with("MAIL") {
+          "250 ok"
+        } else if line.starts_with("RCPT") {
+          rcpt
+        } else if line == "DATA" {
+          in_data = true;
+          "354 go ahead"
+        } else if line == "QUIT" {
+          write!(stream, "221 bye\r\n").unwrap();
+          break;
+        } else {
+          "500 unknown"
+        };
+        write!(stream, "{}\r\n", reply).unwrap();
+      }
+      seen
+    });
+    (port, handle)
+  }
+
+  fn config(port: u16) -> SmtpConfig {
+    SmtpConfig {
+      host: "127.0.0.1".to_string(),
+      port: Some(port),
+      security: Some("none".to_string()),
+      username: Some("user".to_string()),
+      password: Some("secret".to_string()),
+      from: "coin@example.com".to_string(),
+      to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+    }
+  }
+
+  fn alert() -> Alert {
+    Alert {
+      key: "test".to_string(),
+      rule: "BTC above $10000".to_string(),
+      message: "BTC is at $11000.00\n.and climbing".to_string(),
+      timestamp: 0,
+    }
+  }
+
+  #[test]
+  fn test_smtp_send() {
+    let (port, server) = mock_server("250 ok");
+    let config = config(port);
+    SmtpChannel::new(&config).send(&alert()).unwrap();
+    let seen = server.join().unwrap();
+    assert_eq!(seen[0], "EHLO localhost");
+    assert_eq!(seen[1], format!("AUTH PLAIN {}", base64::encode("\0user\0secret")));
+    assert_eq!(&seen[2..6], &[
+      "MAIL FROM:<coin@example.com>",
+      "RCPT TO:<a@example.com>",
+      "RCPT TO:<b@example.com>",
+      "DATA",
+    ]);
+    assert!(seen.contains(&"Subject: coin alert: BTC above $10000".to_string()));
+    assert!(seen.contains(&"To: a@example.com, b@example.com".to_string()));
+    // The line starting with a dot is stuffed so it does not end the message.
+    assert!(seen.contains(&"..and climbing".to_string()));
+    assert_eq!(&seen[seen.len() - 2..], &[".", "QUIT"]);
+  }
+
+  #[test]
+  fn test_smtp_refused() {
+    let (port, server) = mock_server("550 no such user");
+    let config = config(port);
+    let error = SmtpChannel::new(&config).send(&alert()).unwrap_err();
+    let messages: Vec<String> = error.iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages, vec!["The mail server refused RCPT", "550 no such user"]);
+    // The channel hangs up after the refusal without sending the message.
+    let seen = server.join().unwrap();
+    assert!(!seen.contains(&"DATA".to_string()));
+  }
+}
@@ -7,6 +7,15 @@ use crate::errors::*;
 
 use crate::model::{ExchangeOps,Account as CommonAccount,Price,Order};
 
+const BINANCE_TICKER_URL: &str = "https://api.binance.com/api/v1/ticker/24hr";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ticker24h {
+  symbol: String,
+  quote_volume: String,
+}
+
 pub struct BinanceClient {
   pub name: String,
   pub key: String,
@@ -81,6 +90,19 @@ impl ExchangeOps for BinanceClient {
     self.market.get_price(symbol).chain_err(|| format!("Could not find symbol {}", symbol))
   }
 
+  /**
+   * Return the 24 hour volume of every ticker in its quote currency.
+   */
+  fn volumes_24h(&self) -> Result<HashMap<String, f64>> {
+    let mut response = reqwest::get(BINANCE_TICKER_URL)?;
+    let tickers: Vec<Ticker24h> = response.json()?;
+    let mut volumes = HashMap::new();
+    for ticker in tickers {
+      volumes.insert(ticker.symbol, ticker.quote_volume.parse()?);
+    }
+    Ok(volumes)
+  }
+
   /**
    * Buy one currency using some other base currency. You specify how much of the base
    * currency you would like to sell. This method will look up the current spread and
@@ -0,0 +1,120 @@
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Bits of the dots in a braille character, by row from the top, for the left and right column.
+const BRAILLE_LEFT: [u32; 4] = [0x01, 0x02, 0x04, 0x40];
+const BRAILLE_RIGHT: [u32; 4] = [0x08, 0x10, 0x20, 0x80];
+const BRAILLE_BLANK: u32 = 0x2800;
+
+/**
+ * Spreads timestamped values over `width` equal slices of time, keeping the last value of each
+ * slice. Slices without a value repeat the one before, so gaps between snapshots read as flat.
+ */
+pub fn resample(points: &[(u64, f64)], width: usize) -> Vec<f64> {
+  if points.is_empty() || width == 0 {
+    return vec![];
+  }
+  if points.len() <= width {
+    return points.iter().map(|(_, v)| *v).collect();
+  }
+  let first = points[0].0;
+  let span = (points[points.len() - 1].0 - first).max(1) as f64;
+  let mut slices: Vec<Option<f64>> = vec![None; width];
+  for (timestamp, value) in points {
+    let slice = (((timestamp - first) as f64 / span) * (width - 1) as f64).round() as usize;
+    slices[slice.min(width - 1)] = Some(*value);
+  }
+  let mut last = points[0].1;
+  slices.into_iter().map(|s| {
+    if let Some(v) = s {
+      last = v;
+    }
+    last
+  }).collect()
+}
+
+/**
+ * A one line chart of the values using block characters.
+ */
+pub fn sparkline(values: &[f64]) -> String {
+  let (min, max) = bounds(values);
+  values.iter().map(|v| SPARKS[level(*v, min, max, SPARKS.len())]).collect()
+}
+
+/**
+ * A line chart `height` characters tall drawn with braille dots. Every character holds two values
+ * side by side, so `values` should have twice as many points as the chart is wide.
+ */
+pub fn braille(values: &[f64], height: usize) -> Vec<String> {
+  let rows = height.max(1) * 4;
+  let columns = (values.len() + 1) / 2;
+  let mut cells = vec![vec![0u32; columns]; height.max(1)];
+  let (min, max) = bounds(values);
+  let mut previous: Option<usize> = None;
+  for (x, v) in values.iter().enumerate() {
+    // Rows count down from the top.
+    let y = rows - 1 - level(*v, min, max, rows);
+    // Fill the gap to the previous point so steep moves stay connected.
+    let (from, to) = match previous {
+      Some(p) if p < y => (p + 1, y),
+      Some(p) if p > y => (y, p - 1),
+      _ => (y, y),
+    };
+    for row in from..=to {
+      let dots = if x % 2 == 0 { &BRAILLE_LEFT } else { &BRAILLE_RIGHT };
+      cells[row / 4][x / 2] |= dots[row % 4];
+    }
+    previous = Some(y);
+  }
+  cells.into_iter()
+    .map(|line| line.into_iter().map(|c| std::char::from_u32(BRAILLE_BLANK + c).unwrap_or(' ')).collect())
+    .collect()
+}
+
+fn bounds(values: &[f64]) -> (f64, f64) {
+  let min = values.iter().cloned().fold(std::f64::INFINITY, f64::min);
+  let max = values.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+  (min, max)
+}
+
+// Which of `levels` steps between min and max a value falls on.
+fn level(value: f64, min: f64, max: f64, levels: usize) -> usize {
+  if !(max > min) {
+    return levels / 2;
+  }
+  let step = ((value - min) / (max - min) * (levels - 1) as f64).round() as usize;
+  step.min(levels - 1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resample() {
+    assert!(resample(&[], 10).is_empty());
+    assert!(resample(&[(0, 1.0)], 0).is_empty());
+    // Fewer points than columns are kept as they are.
+    assert_eq!(resample(&[(0, 1.0), (100, 2.0)], 10), vec![1.0, 2.0]);
+    // The last value of a slice wins and empty slices repeat the one before.
+    let points = [(0, 1.0), (10, 2.0), (20, 3.0), (90, 4.0), (100, 5.0)];
+    assert_eq!(resample(&points, 3), vec![3.0, 3.0, 5.0]);
+  }
+
+  #[test]
+  fn test_sparkline() {
+    assert_eq!(sparkline(&[1.0, 2.0, 3.0, 8.0]), "▁▂▃█");
+    // A flat line sits in the middle.
+    assert_eq!(sparkline(&[5.0, 5.0]), "▅▅");
+    assert_eq!(sparkline(&[]), "");
+  }
+
+  #[test]
+  fn test_braille() {
+    // A rising line starts at the bottom left dot and climbs the right column to the top.
+    assert_eq!(braille(&[0.0, 1.0], 1), vec!["⡸"]);
+    // Steep moves are connected through the rows in between, across lines too.
+    assert_eq!(braille(&[0.0, 1.0, 0.0], 2), vec!["⢸⡆", "⡸⡇"]);
+    // An odd number of values leaves the right column of the last character empty.
+    assert_eq!(braille(&[1.0, 1.0, 1.0], 1), vec!["⠒⠂"]);
+  }
+}
@@ -0,0 +1,92 @@
+/**
+ * Categories of assets that track something else and make poor index constituents.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AssetCategory {
+  Stablecoin,
+  Wrapped,
+  ExchangeToken,
+  Pegged,
+}
+
+impl AssetCategory {
+  pub fn name(&self) -> &str {
+    match self {
+      AssetCategory::Stablecoin => "stablecoin",
+      AssetCategory::Wrapped => "wrapped asset",
+      AssetCategory::ExchangeToken => "exchange token",
+      AssetCategory::Pegged => "pegged asset",
+    }
+  }
+}
+
+// CoinMarketCap tags that identify each category.
+const STABLECOIN_TAGS: [&str; 4] = ["stablecoin", "stablecoins", "asset-backed-stablecoin", "fiat-stablecoin"];
+const WRAPPED_TAGS: [&str; 3] = ["wrapped-tokens", "tokenized-btc", "liquid-staking-derivatives"];
+const EXCHANGE_TOKEN_TAGS: [&str; 3] = ["centralized-exchange", "centralized-exchange-token-cex", "exchange-based-tokens"];
+const PEGGED_TAGS: [&str; 4] = ["tokenized-gold", "asset-backed-coin", "commodities", "tokenized-commodities"];
+
+// Bundled classification for assets without useful tags.
+const STABLECOINS: [&str; 20] = [
+  "USDT", "USDC", "TUSD", "PAX", "USDP", "BUSD", "DAI", "GUSD", "USDS", "SUSD",
+  "HUSD", "EURS", "USDK", "UST", "FDUSD", "PYUSD", "USDD", "FRAX", "LUSD", "USDE",
+];
+const WRAPPED: [&str; 12] = [
+  "WBTC", "WETH", "WBNB", "RENBTC", "HBTC", "BTCB", "TBTC", "STETH", "WSTETH", "CBETH", "RETH", "WEETH",
+];
+const EXCHANGE_TOKENS: [&str; 10] = ["BNB", "HT", "OKB", "LEO", "KCS", "CRO", "FTT", "GT", "MX", "BGB"];
+const PEGGED: [&str; 3] = ["PAXG", "XAUT", "DGX"];
+
+/**
+ * Classifies an asset using its CoinMarketCap tags and falls back to the bundled
+ * classification when none of the tags are recognized.
+ */
+pub fn classify(symbol: &str, tags: &[String]) -> Option<AssetCategory> {
+  let has_tag = |known: &[&str]| tags.iter().any(|t| known.contains(&&t.to_lowercase()[..]));
+  if has_tag(&STABLECOIN_TAGS) {
+    return Some(AssetCategory::Stablecoin);
+  }
+  if has_tag(&WRAPPED_TAGS) {
+    return Some(AssetCategory::Wrapped);
+  }
+  if has_tag(&PEGGED_TAGS) {
+    return Some(AssetCategory::Pegged);
+  }
+  if has_tag(&EXCHANGE_TOKEN_TAGS) {
+    return Some(AssetCategory::ExchangeToken);
+  }
+  let symbol = &symbol.to_uppercase()[..];
+  if STABLECOINS.contains(&symbol) {
+    Some(AssetCategory::Stablecoin)
+  } else if WRAPPED.contains(&symbol) {
+    Some(AssetCategory::Wrapped)
+  } else if PEGGED.contains(&symbol) {
+    Some(AssetCategory::Pegged)
+  } else if EXCHANGE_TOKENS.contains(&symbol) {
+    Some(AssetCategory::ExchangeToken)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_classify() {
+    let tags = |t: &[&str]| t.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    assert_eq!(classify("XYZ", &tags(&["Stablecoin", "defi"])), Some(AssetCategory::Stablecoin));
+    assert_eq!(classify("XYZ", &tags(&["tokenized-btc"])), Some(AssetCategory::Wrapped));
+    assert_eq!(classify("XYZ", &tags(&["tokenized-gold"])), Some(AssetCategory::Pegged));
+    assert_eq!(classify("XYZ", &tags(&["centralized-exchange"])), Some(AssetCategory::ExchangeToken));
+    // Tags win over the bundled classification.
+    assert_eq!(classify("BNB", &tags(&["stablecoin"])), Some(AssetCategory::Stablecoin));
+    // Unknown tags fall back to the bundled classification.
+    assert_eq!(classify("usdc", &tags(&["defi"])), Some(AssetCategory::Stablecoin));
+    assert_eq!(classify("WBTC", &[]), Some(AssetCategory::Wrapped));
+    assert_eq!(classify("PAXG", &[]), Some(AssetCategory::Pegged));
+    assert_eq!(classify("BNB", &[]), Some(AssetCategory::ExchangeToken));
+    assert_eq!(classify("BTC", &tags(&["mineable", "pow"])), None);
+  }
+}
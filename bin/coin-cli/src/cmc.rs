@@ -1,6 +1,8 @@
 use reqwest::{get};
 use std::collections::{HashMap,HashSet};
 use std::time::SystemTime;
+use crate::errors::*;
+use crate::price_store::PriceStore;
 
 const CMC_BASE_URL: &str = "https://pro-api.coinmarketcap.com";
 const CM_BASE_URL: &str = "https://coinmetrics.io/api";
@@ -36,6 +38,8 @@ pub struct CMCListing {
   pub max_supply: Option<f64>,
   pub last_updated: Option<String>,
   pub date_added: Option<String>,
+  #[serde(default)]
+  pub tags: Vec<String>,
   pub quote: HashMap<String, CMCQuote>,
 }
 #[derive(Deserialize)]
@@ -67,6 +71,7 @@ impl CMCListingResponse {
       max_supply: None,
       last_updated: None,
       date_added: None,
+      tags: vec![],
       quote: quote_map,
     });
     self
@@ -90,21 +95,37 @@ pub struct CMCHistoricalQuotesResponse {
 }
 pub struct CMCClient {
   pub key: String,
+  store: Option<PriceStore>,
 }
 impl CMCClient {
   pub fn new(key: String) -> Self {
     CMCClient {
-      key: key
+      key: key,
+      store: None,
     }
   }
+
+  /**
+   * Record every USD listing fetched by this client in the store.
+   */
+  pub fn with_store(mut self, store: PriceStore) -> Self {
+    self.store = Some(store);
+    self
+  }
+
+  pub fn store(&self) -> Option<&PriceStore> {
+    self.store.as_ref()
+  }
   pub fn latest_listings(&self, limit: u16) -> CMCListingResponse {
-    let url: &str = &format!(
-      "{}/v1/cryptocurrency/listings/latest?limit={}&CMC_PRO_API_KEY={}",
-      CMC_BASE_URL,
-      limit,
-      self.key
-    );
-    let body: CMCListingResponse = match get(url) {
+    self.latest_listings_in(limit, "USD")
+  }
+
+  /**
+   * Fetch the latest listings with quotes converted into the given currency.
+   * CoinMarketCap accepts both fiat and crypto symbols for the conversion.
+   */
+  pub fn latest_listings_in(&self, limit: u16, convert: &str) -> CMCListingResponse {
+    let body: CMCListingResponse = match get(&self.listings_url(limit, convert)[..]) {
       Ok(mut data) => match data.json() {
         Ok(o) => o,
         Err(e) => {
@@ -123,10 +144,42 @@ impl CMCClient {
         }
       },
     };
+    self.record(body, convert)
+  }
+
+  /**
+   * Like `latest_listings_in` but a failed request or an error from CoinMarketCap is
+   * returned rather than an empty response or a panic.
+   */
+  pub fn fetch_listings_in(&self, limit: u16, convert: &str) -> Result<CMCListingResponse> {
+    let mut response = get(&self.listings_url(limit, convert)[..])?;
+    let body: CMCListingResponse = response.json()?;
+    if body.status.error_code != 0 {
+      bail!("CoinMarketCap error {}: {}", body.status.error_code, body.status.error_message.unwrap_or_default());
+    }
+    Ok(self.record(body, convert))
+  }
+
+  fn listings_url(&self, limit: u16, convert: &str) -> String {
+    format!(
+      "{}/v1/cryptocurrency/listings/latest?limit={}&convert={}&CMC_PRO_API_KEY={}",
+      CMC_BASE_URL,
+      limit,
+      convert.to_uppercase(),
+      self.key
+    )
+  }
+
+  fn record(&self, body: CMCListingResponse, convert: &str) -> CMCListingResponse {
+    if let (Some(store), true) = (&self.store, convert.to_uppercase() == "USD") {
+      if let Err(e) = store.record_listings(&body) {
+        println!("Could not record listings in the price store: {}", e);
+      }
+    }
     body.fill_usd()
   }
 
-  pub fn historic_quotes(&self, symbol: &str, count: u64, _interval: &str) -> CMCHistoricalQuotesResponse {
+  pub fn historic_quotes(&self, symbol: &str, count: u64, _interval: &str) -> Result<CMCHistoricalQuotesResponse> {
     let current_epoch = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
         Ok(n) => n.as_secs(), // println!("1970-01-01 00:00:00 UTC was {} seconds ago!", ),
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
@@ -143,20 +196,9 @@ impl CMCClient {
       beginning_of_period,
       beginning_of_today
     );
-    let body: CMCHistoricalQuotesResponse = match get(url) {
-      Ok(mut data) => {
-        match data.json() {
-          Ok(o) => o,
-          Err(e) => {
-            println!("{}",e);
-            println!("{:?}", data);
-            panic!(e);
-          },
-        }
-      },
-      Err(e) => panic!(e),
-    };
-    body
+    let mut response = get(url)?;
+    let body: CMCHistoricalQuotesResponse = response.json()?;
+    Ok(body)
   }
 
   pub fn supported_assets(&self) -> HashSet<String> {
@@ -88,6 +88,13 @@ impl ExchangeOps for CoinbaseClient {
     bail!("Unimplemented")
   }
 
+  /**
+   * Return the 24 hour volume of every ticker in its quote currency.
+   */
+  fn volumes_24h(&self) -> Result<HashMap<String, f64>> {
+    bail!("Unimplemented")
+  }
+
   /**
    * Buy one currency using some other base currency. You specify how much of the base
    * currency you would like to sell. This method will look up the current spread and
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use chrono::prelude::*;
+use crate::classification::AssetCategory;
+use crate::cmc::CMCListing;
+
+const SECONDS_IN_DAY: i64 = 60 * 60 * 24;
+
+/**
+ * Rules an asset must pass before it can become an index constituent.
+ * Every rule is optional and configured in the [index] table of ~/.coin.toml.
+ */
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct IndexConfig {
+  // Minimum 24 hour trading volume in USD across all markets as reported by CoinMarketCap.
+  pub min_volume_24h: Option<f64>,
+  // Minimum 24 hour volume on the trading exchange, in the base currency, of the pair we would trade.
+  pub min_exchange_volume: Option<f64>,
+  // Minimum number of markets the asset trades in.
+  pub min_market_pairs: Option<u64>,
+  // Minimum number of days since the asset was listed on CoinMarketCap.
+  pub min_age_days: Option<u64>,
+  // Category exclusions. Everything but exchange tokens is excluded unless turned off.
+  pub exclude_stablecoins: Option<bool>,
+  pub exclude_wrapped: Option<bool>,
+  pub exclude_exchange_tokens: Option<bool>,
+  pub exclude_pegged: Option<bool>,
+}
+
+impl IndexConfig {
+  pub fn excludes(&self, category: AssetCategory) -> bool {
+    match category {
+      AssetCategory::Stablecoin => self.exclude_stablecoins.unwrap_or(true),
+      AssetCategory::Wrapped => self.exclude_wrapped.unwrap_or(true),
+      AssetCategory::ExchangeToken => self.exclude_exchange_tokens.unwrap_or(false),
+      AssetCategory::Pegged => self.exclude_pegged.unwrap_or(true),
+    }
+  }
+}
+
+/**
+ * An asset that was left out of the index and why.
+ */
+#[derive(Debug, Clone)]
+pub struct Exclusion {
+  pub symbol: String,
+  pub reason: String,
+}
+
+/**
+ * Applies the configured index rules to CoinMarketCap listings.
+ */
+pub struct ConstituentFilter<'a> {
+  config: &'a IndexConfig,
+  base_currency: String,
+  // Ticker symbol (e.g. ETHBTC) to 24 hour quote volume on the trading exchange.
+  exchange_volumes: HashMap<String, f64>,
+  now: DateTime<Utc>,
+}
+
+impl<'a> ConstituentFilter<'a> {
+  pub fn new(config: &'a IndexConfig, base_currency: &str, exchange_volumes: HashMap<String, f64>) -> Self {
+    ConstituentFilter {
+      config,
+      base_currency: base_currency.to_uppercase(),
+      exchange_volumes,
+      now: Utc::now(),
+    }
+  }
+
+  /**
+   * Returns the reason a listing should be excluded from the index or None if it qualifies.
+   */
+  pub fn exclusion_reason(&self, listing: &CMCListing) -> Option<String> {
+    let volume_24h = listing.quote.get("USD").map(|q| q.volume_24h).unwrap_or(0.0);
+    if let Some(min) = self.config.min_volume_24h {
+      if volume_24h < min {
+        return Some(format!("24h volume ${:.0} is below ${:.0}", volume_24h, min));
+      }
+    }
+    if let Some(min) = self.config.min_market_pairs {
+      if listing.num_market_pairs < min {
+        return Some(format!("{} market pairs is below {}", listing.num_market_pairs, min));
+      }
+    }
+    if let Some(min) = self.config.min_age_days {
+      let added = listing.date_added.as_ref().and_then(|d| DateTime::parse_from_rfc3339(d).ok());
+      match added {
+        Some(added) => {
+          let age_days = (self.now.timestamp() - added.timestamp()) / SECONDS_IN_DAY;
+          if age_days < min as i64 {
+            return Some(format!("listed {} days ago which is less than {}", age_days, min));
+          }
+        },
+        None => return Some("listing date is unknown".to_string()),
+      }
+    }
+    // The base currency is never traded against itself so it has no pair volume.
+    if let (Some(min), false) = (self.config.min_exchange_volume, listing.symbol == self.base_currency) {
+      let ticker = format!("{}{}", listing.symbol.to_uppercase(), self.base_currency);
+      let volume = self.exchange_volumes.get(&ticker).cloned().unwrap_or(0.0);
+      if volume < min {
+        return Some(format!("{} 24h volume {:.2} {} is below {}", ticker, volume, self.base_currency, min));
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+
+  fn listing(symbol: &str, volume_24h: f64, num_market_pairs: u64, age_days: Option<i64>) -> CMCListing {
+    let mut listing = json!({
+      "id": 1,
+      "name": symbol,
+      "symbol": symbol,
+      "slug": symbol,
+      "cmc_rank": 1,
+      "num_market_pairs": num_market_pairs,
+      "quote": {"USD": {
+        "price": 1.0,
+        "volume_24h": volume_24h,
+        "percent_change_1h": 0.0,
+        "percent_change_24h": 0.0,
+        "percent_change_7d": 0.0,
+        "market_cap": 1000000.0,
+      }},
+    });
+    if let Some(days) = age_days {
+      listing["date_added"] = json!((Utc::now() - chrono::Duration::days(days)).to_rfc3339());
+    }
+    serde_json::from_value(listing).unwrap()
+  }
+
+  #[test]
+  fn test_exclusion_reason() {
+    let config = IndexConfig {
+      min_volume_24h: Some(1000.0),
+      min_exchange_volume: Some(10.0),
+      min_market_pairs: Some(5),
+      min_age_days: Some(30),
+      ..Default::default()
+    };
+    let volumes = vec![("ETHBTC".to_string(), 50.0), ("LINKBTC".to_string(), 5.0)].into_iter().collect();
+    let filter = ConstituentFilter::new(&config, "btc", volumes);
+    assert_eq!(filter.exclusion_reason(&listing("ETH", 5000.0, 10, Some(100))), None);
+    // The base currency has no pair with itself.
+    assert_eq!(filter.exclusion_reason(&listing("BTC", 5000.0, 10, Some(100))), None);
+    assert_eq!(filter.exclusion_reason(&listing("ETH", 500.0, 10, Some(100))).unwrap(), "24h volume $500 is below $1000");
+    assert_eq!(filter.exclusion_reason(&listing("ETH", 5000.0, 2, Some(100))).unwrap(), "2 market pairs is below 5");
+    assert_eq!(filter.exclusion_reason(&listing("ETH", 5000.0, 10, Some(10))).unwrap(), "listed 10 days ago which is less than 30");
+    assert_eq!(filter.exclusion_reason(&listing("ETH", 5000.0, 10, None)).unwrap(), "listing date is unknown");
+    assert_eq!(filter.exclusion_reason(&listing("LINK", 5000.0, 10, Some(100))).unwrap(), "LINKBTC 24h volume 5.00 BTC is below 10");
+    assert!(filter.exclusion_reason(&listing("DOT", 5000.0, 10, Some(100))).is_some());
+
+    // Without rules everything qualifies.
+    let config = IndexConfig::default();
+    let filter = ConstituentFilter::new(&config, "BTC", HashMap::new());
+    assert_eq!(filter.exclusion_reason(&listing("DOT", 0.0, 0, None)), None);
+  }
+
+  #[test]
+  fn test_excludes() {
+    let config = IndexConfig::default();
+    assert!(config.excludes(AssetCategory::Stablecoin));
+    assert!(config.excludes(AssetCategory::Wrapped));
+    assert!(config.excludes(AssetCategory::Pegged));
+    assert!(!config.excludes(AssetCategory::ExchangeToken));
+    let config = IndexConfig { exclude_stablecoins: Some(false), exclude_exchange_tokens: Some(true), ..Default::default() };
+    assert!(!config.excludes(AssetCategory::Stablecoin));
+    assert!(config.excludes(AssetCategory::ExchangeToken));
+  }
+}
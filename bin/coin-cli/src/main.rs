@@ -2,35 +2,59 @@
 #![recursion_limit = "1024"]
 
 use dirs::home_dir;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
+use std::path::PathBuf;
 
 use chrono::prelude::*;
 use clap::clap_app;
 
 use coin_sdk::sdk::SDK;
 use coin_sdk::model::{CoinConfig, AccountConfig, CMCConfig, Asset};
+use coin_sdk::transfers::TransferReport;
+use coin_sdk::accounting::{AssetPosition, PortfolioReport};
+use coin_sdk::tax::TaxReport;
+use coin_sdk::import::ImportFormat;
+use coin_sdk::performance::{compare_benchmark, equal_weight, BenchmarkComparison, DailyValue, PeriodReturns};
+use coin_sdk::prices::Resolution;
+use coin_sdk::alerts::{state_path, Alert, AlertRule, AlertState, MarketView, Quote};
+use coin_sdk::ledger::is_fiat;
+use coin_sdk::drift::{DriftDecision, DriftPolicy, DriftReport};
+use basis::basis::{Lot, LotMethod, Term};
 
 mod airtable;
+mod alerts;
 mod binance;
+mod chart;
+mod classification;
 mod cmc;
 mod coinbase;
 mod coinbasepro;
+mod constituents;
 mod market_cap_balancer;
 mod model;
 mod persist;
+mod price_store;
+mod schedule;
+mod sinks;
+mod watch;
 
-use crate::airtable::{AirtableClient, AirtableConfig};
+use crate::airtable::AirtableConfig;
+use crate::alerts::{deliver, AlertChannel, AlertsConfig, CommandChannel, LogChannel, SmtpChannel, WebhookChannel};
 use crate::binance::BinanceClient;
 use crate::cmc::{CMCClient, CMCListing, CMCListingResponse};
 use crate::coinbase::CoinbaseClient;
 use crate::coinbasepro::CoinbaseProClient;
+use crate::classification::classify;
+use crate::constituents::{ConstituentFilter, Exclusion, IndexConfig};
 use crate::market_cap_balancer::MarketCapBalancer;
-use crate::model::{Account, ExchangeOps, Portfolio, PortfolioBalance, Price};
+use crate::chart::{braille, resample, sparkline};
+use crate::persist::{RebalanceRun, SnapshotStore, StoredSnapshot};
+use crate::price_store::{PriceStore, StoredQuote};
+use crate::watch::{Job, WatchConfig, Watcher};
+use crate::sinks::{write_snapshot, AirtableSink, CsvSink, JsonLinesSink, SinksConfig, Snapshot, SnapshotSink, SqliteSink};
+use crate::model::{format_time, Account, ExchangeOps, Portfolio, PortfolioBalance, Price, ReportingCurrency, DATE_FORMAT, MINUTE_FORMAT, RFC3339_FORMAT};
 
 #[macro_use]
 extern crate serde_derive;
@@ -54,11 +78,13 @@ pub mod errors {
         links {
             Coinbase(::coinbase::errors::Error, coinbase::errors::ErrorKind);
             CoinSDK(::coin_sdk::errors::Error, coin_sdk::errors::ErrorKind);
+            Airtable(::airtable::errors::Error, airtable::errors::ErrorKind);
         }
         foreign_links {
             Reqwest(::reqwest::Error);
             ParseError(::std::num::ParseFloatError);
             TomlDeError(::toml::de::Error);
+            Sqlite(::rusqlite::Error);
         }
     }
 }
@@ -136,21 +162,6 @@ fn run() -> Result<()> {
         }
         None => HashSet::new(),
     };
-    let default_airtable_config = AirtableConfig {
-        key: "".to_string(),
-        app: "".to_string(),
-        table: "".to_string(),
-        column_map: None,
-    };
-    let airtable_config = match &config.airtable {
-        Some(atc) => &atc,
-        None => &default_airtable_config,
-    };
-    let airtable = if (&airtable_config.key).len() > 0 && (&airtable_config.app).len() > 0 {
-        Some(AirtableClient::new(&airtable_config))
-    } else {
-        None
-    };
     let account_clients = get_account_clients(&config.account)?;
     if account_clients.len() == 0 {
         bail!(
@@ -159,37 +170,55 @@ fn run() -> Result<()> {
         );
     }
     let binance_read_client = account_clients.first().unwrap();
-    let cmc = CMCClient::new(cmc_key);
+    let cmc = match open_price_store(&config) {
+        Ok(store) => CMCClient::new(cmc_key).with_store(store),
+        Err(e) => {
+            // Only price history needs the store, every other command works without it.
+            println!("Could not open the price store, prices will not be recorded\n{:?}", e);
+            CMCClient::new(cmc_key)
+        }
+    };
     let coin_config: CoinConfig = get_coin_config()?;
     let sdk = get_sdk(coin_config)?;
     if let Some(_matches) = matches.subcommand_matches("list_assets") {
         let prices = cmc.latest_listings(100);
-        let assets = if let Some(accounts_to_list) = _matches.values_of("account") {
-            let _accounts_to_list: Vec<String> = accounts_to_list.map(|al| al.to_string()).collect::<Vec<String>>();
-            sdk.list_assets(Some(_accounts_to_list))?
-        } else {
-            sdk.list_assets(None)?
-        };
+        let currency = reporting_currency(&cmc, &prices, _matches.value_of("currency"), &config.currency)?;
+        let accounts_to_list = _matches
+            .values_of("account")
+            .map(|names| names.map(|n| n.to_string()).collect::<Vec<String>>());
+        let assets = sdk.list_assets(accounts_to_list.clone())?;
         let accounts = accounts(assets);
-        match make_portfolio(&accounts, &prices) {
-            Ok(acct) => {
-                print_portfolio(&acct);
-                Ok(())
+        let acct = make_portfolio(&accounts, &prices, &currency)?;
+        if _matches.is_present("basis") || _matches.is_present("lots") {
+            let method = lot_method(_matches.value_of("method"), &config.tax)?;
+            let mut books = sdk.books(method, holding_period(&config.tax))?;
+            let current_prices = usd_prices(&prices);
+            let report = books.report(&current_prices);
+            print_portfolio(&acct, Some(&report.by_asset(accounts_to_list.as_ref().map(|a| &a[..]))));
+            if _matches.is_present("lots") {
+                let lots: Vec<(String, String, Lot)> = books
+                    .open_lots()?
+                    .into_iter()
+                    .filter(|(account, _, _)| accounts_to_list.as_ref().map(|a| a.contains(account)).unwrap_or(true))
+                    .collect();
+                print_lots(&lots, &current_prices, books.holding_period());
             }
-            Err(e) => Err(e),
+            for issue in &report.issues {
+                println!("{} {}", "Skipped".yellow(), issue);
+            }
+        } else {
+            print_portfolio(&acct, None);
         }
+        Ok(())
     } else if let Some(_matches) = matches.subcommand_matches("save") {
         let prices = cmc.latest_listings(100);
+        let currency = reporting_currency(&cmc, &prices, _matches.value_of("currency"), &config.currency)?;
+        let sinks = snapshot_sinks(&config);
+        let now = Utc::now().timestamp() as u64;
         for account_client in &sdk.accounts {
             match account_client.list_assets() {
-                Ok(balances) => match make_portfolio(&accounts(balances), &prices) {
-                    Ok(account) => {
-                        if let Some(a_t) = &airtable {
-                            save_account(&a_t, &account, (*account_client).name());
-                        } else {
-                            println!("Could not find airtable credentials in ~/.coin.yaml");
-                        }
-                    }
+                Ok(balances) => match make_portfolio(&accounts(balances), &prices, &currency) {
+                    Ok(account) => save_snapshot(&sinks, account_client.name(), &account, now, None),
                     Err(e) => bail!(e),
                 },
                 Err(e) => bail!(e),
@@ -197,14 +226,8 @@ fn run() -> Result<()> {
         }
         let assets = sdk.list_assets(None)?;
         let accounts = accounts(assets);
-        match make_portfolio(&accounts, &prices) {
-            Ok(account) => {
-                if let Some(a_t) = &airtable {
-                    save_account(&a_t, &account, "ALL");
-                } else {
-                    println!("Could not find airtable credentials in ~/.coin.yaml");
-                }
-            }
+        match make_portfolio(&accounts, &prices, &currency) {
+            Ok(account) => save_snapshot(&sinks, "ALL", &account, now, None),
             Err(e) => bail!(e),
         }
         Ok(())
@@ -213,11 +236,15 @@ fn run() -> Result<()> {
             .value_of("base")
             .unwrap_or("BTC")
             .to_ascii_uppercase();
+        let index_config = config.index.clone().unwrap_or_default();
+        let listings = cmc.latest_listings(100);
         let tradable_symbols = get_tradeable_symbols(
             &base_currency,
             &blacklisted_symbols,
+            &index_config,
             binance_read_client,
             &cmc,
+            &listings.data,
         );
         println!("Tradable symbols: {:?}", tradable_symbols);
         Ok(())
@@ -240,6 +267,13 @@ fn run() -> Result<()> {
             Err(e) => Err(e),
         }
     } else if let Some(_matches) = matches.subcommand_matches("balance") {
+        if _matches.is_present("confirm_drift") {
+            match open_snapshot_store(&config)?.confirm_drift()? {
+                Some(id) => println!("Confirmed dry run {}. Drift may now rebalance and place trades.", id),
+                None => println!("Drift has not triggered a dry run yet, there is nothing to confirm."),
+            }
+            return Ok(());
+        }
         let base_currency = _matches.value_of("base").unwrap_or("BTC").to_uppercase();
         if base_currency != "BTC" {
             bail!("BTC is currently the only supported base currency for balance operations.")
@@ -250,14 +284,79 @@ fn run() -> Result<()> {
         let lookback_i: u64 = lookback.parse().unwrap();
         let factor = _matches.value_of("factor").unwrap_or("0.3");
         let factor_i: f64 = factor.parse().unwrap();
-        let is_mock = _matches.is_present("mock");
+        let mut is_mock = _matches.is_present("mock");
+        let index_config = config.index.clone().unwrap_or_default();
+        // Exchange volumes are only fetched when a rule needs them.
+        let exchange_volumes = if index_config.min_exchange_volume.is_some() {
+            binance_read_client.volumes_24h()?
+        } else {
+            HashMap::new()
+        };
+        let cmc_prices = cmc.latest_listings(100);
         // Find all pairs that trade with the base pai
         let tradable_symbols = get_tradeable_symbols(
             &base_currency,
             &blacklisted_symbols,
+            &index_config,
             binance_read_client,
             &cmc,
+            &cmc_prices.data,
         )?;
+        let currency = reporting_currency(&cmc, &cmc_prices, None, &config.currency)?;
+        let constituent_filter = ConstituentFilter::new(&index_config, &base_currency, exchange_volumes);
+        let balanced_portfolio = balance_by_market_cap(
+            &cmc,
+            &cmc_prices.data,
+            index_size_i,
+            lookback_i,
+            factor_i,
+            &tradable_symbols,
+            &constituent_filter,
+        );
+        print_asset_allocations(&balanced_portfolio);
+        let store = open_snapshot_store(&config)?;
+        let mut trigger = "manual";
+        let mut drift: Option<DriftReport> = None;
+        if _matches.is_present("drift") {
+            let policy = drift_policy(&config);
+            let (decision, report) = check_drift(&policy, &store, &account_clients, &cmc_prices, &currency, &balanced_portfolio)?;
+            match decision {
+                DriftDecision::Hold => {
+                    println!("Every account is within the drift limits. Not rebalancing.");
+                    return Ok(());
+                }
+                DriftDecision::CoolingDown(until) => {
+                    println!("Not rebalancing, drift can trigger the next rebalance after {}.", format_time(until, MINUTE_FORMAT));
+                    return Ok(());
+                }
+                DriftDecision::DryRun(n) => {
+                    println!("Dry run {} of {}. No trades are placed.", n, policy.dry_runs);
+                    is_mock = true;
+                }
+                DriftDecision::Unconfirmed => {
+                    println!("Dry run. No trades are placed until a dry run is confirmed with coin balance --confirm-drift.");
+                    is_mock = true;
+                }
+                DriftDecision::Rebalance => println!("Rebalancing."),
+            }
+            trigger = "drift";
+            drift = report;
+        }
+        let sinks = snapshot_sinks(&config);
+        let run_id = store.start_run(&RebalanceRun {
+            timestamp: Utc::now().timestamp() as u64,
+            base: base_currency.to_owned(),
+            index_size: index_size_i,
+            lookback: lookback_i,
+            factor: factor_i,
+            mock: is_mock,
+            trigger: trigger.to_string(),
+            max_drift: drift.as_ref().and_then(|d| d.max_drift()).map(|a| a.drift.abs()),
+            tracking_error: drift.as_ref().map(|d| d.tracking_error),
+        })?;
+        if let Err(e) = store.record_allocations(run_id, &balanced_portfolio) {
+            println!("Could not save the allocations locally\n{:?}", e);
+        }
         // First exit the market to the base currency.
         for trading_client in &account_clients {
             if !trading_client.can_trade() {
@@ -265,6 +364,9 @@ fn run() -> Result<()> {
             }
             if !is_mock {
                 if let Ok(orders) = trading_client.exit_market(base_currency.to_owned()) {
+                    if let Err(e) = store.record_orders(trading_client.name(), "exit", &orders, Some(run_id)) {
+                        println!("Could not save orders locally\n{:?}", e);
+                    }
                     let order_ids_str = orders
                         .iter()
                         .map(|o| o.id.to_string())
@@ -275,16 +377,6 @@ fn run() -> Result<()> {
                     );
                 }
             }
-            let cmc_prices = cmc.latest_listings(100);
-            let balanced_portfolio = balance_by_market_cap(
-                &cmc,
-                &cmc_prices.data,
-                index_size_i,
-                lookback_i,
-                factor_i,
-                &tradable_symbols,
-            );
-            print_asset_allocations(&balanced_portfolio);
             // Calculating total value
             if !is_mock {
                 match trading_client.enter_market(base_currency.to_owned(), &balanced_portfolio) {
@@ -293,20 +385,26 @@ fn run() -> Result<()> {
                             .iter()
                             .map(|o| o.id.to_string())
                             .collect::<Vec<String>>();
-                        if let Some(a_t) = &airtable {
-                            if let Ok(balances) = trading_client.all_accounts() {
-                                // let account = make_portfolio(&balances, cmc_prices);
-                                if let Ok(account) = make_portfolio(&balances, &cmc_prices) {
-                                    save_account(&a_t, &account, trading_client.name())
-                                }
-                            } else {
-                                println!("Error saving to airtable");
+                        if let Err(e) = store.record_orders(trading_client.name(), "enter", &vec_of_orders, Some(run_id)) {
+                            println!("Could not save orders locally\n{:?}", e);
+                        }
+                        if let Ok(balances) = trading_client.all_accounts() {
+                            if let Ok(account) = make_portfolio(&balances, &cmc_prices, &currency) {
+                                let now = Utc::now().timestamp() as u64;
+                                save_snapshot(&sinks, trading_client.name(), &account, now, Some(run_id));
                             }
+                        } else {
+                            println!("Could not fetch balances of {} to save", trading_client.name().red());
                         }
                         println!(
                             "Successfully entered new positions with order_ids: [{}]",
                             order_ids_str.join(", ").blue()
                         );
+                        // Pull the fills into the ledger so the trades count towards cost basis and gains.
+                        match sdk.sync_ledger(Some(vec![trading_client.name().to_string()])) {
+                            Ok(synced) => print_ledger_sync(&synced),
+                            Err(e) => println!("Could not sync the ledger of {}\n{:?}", trading_client.name().red(), e),
+                        }
                     }
                     Err(e) => println!(
                         "Failed to enter market for account {}\n{:?}",
@@ -354,34 +452,275 @@ fn run() -> Result<()> {
         }
         Ok(())
     } else if let Some(_matches) = matches.subcommand_matches("watch") {
-        let args: Vec<String> = env::args().collect();
-        println!("{:?}", _matches);
-        if let Some(_) = _matches.subcommand_matches("start") {
-            println!("Trying to start daemon process");
-            let mut child = Command::new(&args[0])
-                .args(&["watch", "daemon"])
-                .spawn()
-                .expect("Child process failed to start.");
-            println!("child pid: {}", child.id());
-        } else if let Some(_) = _matches.subcommand_matches("kill") {
-            println!("Would try to kill process.");
-        } else if let Some(_) = _matches.subcommand_matches("daemon") {
-            println!("In daemon");
-            loop {
-                thread::sleep(Duration::new(5, 0));
-                println!("This is an incredibly simple daemon!");
+        let watcher = watcher(&config)?;
+        let exe = env::current_exe().chain_err(|| "Could not find the coin executable")?;
+        if _matches.subcommand_matches("start").is_some() {
+            let pid = watcher.start(&exe)?;
+            println!("Started the watcher with pid {}. It logs to {}", pid, watcher.log_path().display());
+        } else if _matches.subcommand_matches("stop").is_some() {
+            match watcher.stop()? {
+                Some(pid) => println!("Stopped the watcher with pid {}", pid),
+                None => println!("The watcher is not running"),
             }
+        } else if _matches.subcommand_matches("daemon").is_some() {
+            watcher.run(&exe)?;
         } else {
-            println!("Processing args {:?}", args);
+            print_watch_status(&watcher);
+        }
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("alerts") {
+        let default_config = AlertsConfig::default();
+        let alerts_config = config.alerts.as_ref().unwrap_or(&default_config);
+        let rules = alerts_config.rules.clone().unwrap_or_default();
+        let path = state_path(&coin_dir()?);
+        let mut state = AlertState::load(&path)?;
+        if _matches.subcommand_matches("check").is_some() {
+            let market = market_view(&cmc, &account_clients, &rules);
+            let rearm = alerts_config.rearm_minutes.unwrap_or(60) * 60;
+            let alerts = state.evaluate(&rules, &market, rearm, Utc::now().timestamp() as u64);
+            let channels = alert_channels(alerts_config);
+            for alert in &alerts {
+                let mut delivered = false;
+                for (channel, result) in deliver(&channels, alert) {
+                    match result {
+                        Ok(()) => delivered = true,
+                        Err(e) => println!("Could not deliver the alert {} to {}\n{:?}", alert.key, channel.red(), e),
+                    }
+                }
+                // Alerts no channel took are tried again at the next check.
+                if delivered {
+                    state.delivered(alert);
+                }
+            }
+            state.save(&path)?;
+            if alerts.is_empty() {
+                println!("No new alerts.");
+            }
+        } else if _matches.subcommand_matches("test").is_some() {
+            let alert = Alert {
+                key: "test".to_string(),
+                rule: "Test".to_string(),
+                message: "This is a test alert from coin.".to_string(),
+                timestamp: Utc::now().timestamp() as u64,
+            };
+            let channels = alert_channels(alerts_config);
+            if channels.is_empty() {
+                println!("No alert channels are configured in ~/.coin.toml");
+            }
+            for (channel, result) in deliver(&channels, &alert) {
+                match result {
+                    Ok(()) => println!("Sent a test alert to {}.", channel.green()),
+                    Err(e) => println!("Could not send a test alert to {}\n{:?}", channel.red(), e),
+                }
+            }
+        } else {
+            print_alerts(&rules, &state);
         }
         Ok(())
     } else if let Some(_matches) = matches.subcommand_matches("cost") {
-        let cost = sdk.total_costs()?;
-        println!("Portfolio Cost: {:?}", cost);
+        let prices = cmc.latest_listings(100);
+        let currency = reporting_currency(&cmc, &prices, _matches.value_of("currency"), &config.currency)?;
+        let method = lot_method(_matches.value_of("method"), &config.tax)?;
+        let report = sdk.report(method, holding_period(&config.tax), &usd_prices(&prices))?;
+        let cost = coin_sdk::model::Amount { amount: report.total(None).invested, currency: "USD".to_string() };
+        println!("Portfolio Cost: {:?}", convert_amount(cost, &currency));
+        print_accounting_report(&report, &currency);
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("prices") {
+        if let Some(history_matches) = _matches.subcommand_matches("history") {
+            let symbol = history_matches.value_of("symbol").unwrap().to_uppercase();
+            let days: u64 = match history_matches.value_of("days").unwrap_or("30").parse() {
+                Ok(d) => d,
+                Err(_) => bail!("--days must be a whole number"),
+            };
+            let since = (Utc::now().timestamp() as u64).saturating_sub(days * 60 * 60 * 24);
+            let quotes = match cmc.store() {
+                Some(store) => store.history(&symbol, since)?,
+                None => vec![],
+            };
+            print_price_history(&symbol, &quotes);
+        } else if let Some(at_matches) = _matches.subcommand_matches("at") {
+            let symbol = at_matches.value_of("symbol").unwrap().to_uppercase();
+            let date = at_matches.value_of("date").unwrap();
+            let timestamp = parse_timestamp(date)?;
+            let price = sdk.prices.price_at(&symbol, timestamp)?;
+            println!("{} was ${:.5} at {}", symbol, price, format_time(timestamp, MINUTE_FORMAT));
+        } else {
+            println!("{}", _matches.usage());
+        }
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("ledger") {
+        if let Some(sync_matches) = _matches.subcommand_matches("sync") {
+            let accounts = sync_matches
+                .values_of("account")
+                .map(|names| names.map(|n| n.to_string()).collect::<Vec<String>>());
+            let synced = sdk.sync_ledger(accounts)?;
+            print_ledger_sync(&synced);
+        } else if let Some(import_matches) = _matches.subcommand_matches("import") {
+            let format = match import_matches.value_of("format") {
+                Some(name) => Some(ImportFormat::from_name(name)?),
+                None => None,
+            };
+            let path = PathBuf::from(import_matches.value_of("file").unwrap());
+            let account = import_matches.value_of("account").unwrap();
+            let (import, added) = sdk.import_ledger(&path, account, format)?;
+            println!(
+                "Read {} entries from a {} export. Added {}, {} were already in the ledger and {} rows were skipped.",
+                import.entries.len(),
+                import.format.name(),
+                added,
+                import.entries.len() - added,
+                import.skipped
+            );
+        } else if let Some(transfer_matches) = _matches.subcommand_matches("transfers") {
+            if let Some(confirm_matches) = transfer_matches.subcommand_matches("confirm") {
+                let from = confirm_matches.value_of("from").unwrap();
+                let withdrawal = confirm_matches.value_of("withdrawal").unwrap();
+                let to = confirm_matches.value_of("to").unwrap();
+                let deposit = confirm_matches.value_of("deposit").unwrap();
+                sdk.confirm_transfer((from, withdrawal), (to, deposit))?;
+                println!("Confirmed {} {} -> {} {}", from, withdrawal, to, deposit);
+            } else if let Some(reject_matches) = transfer_matches.subcommand_matches("reject") {
+                let account = reject_matches.value_of("account").unwrap();
+                let withdrawal = reject_matches.value_of("withdrawal").unwrap();
+                sdk.reject_transfer(account, withdrawal)?;
+                println!("{} {} will not be matched to a deposit", account, withdrawal);
+            } else {
+                let window_hours: u64 = match transfer_matches.value_of("window").unwrap_or("72").parse() {
+                    Ok(h) => h,
+                    Err(_) => bail!("--window must be a whole number of hours"),
+                };
+                let report = sdk.transfers(window_hours * 60 * 60)?;
+                print_transfers(&report);
+            }
+        } else {
+            println!("{}", _matches.usage());
+        }
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("history") {
+        let account = _matches.value_of("account").unwrap_or("ALL");
+        let since = match _matches.value_of("since") {
+            Some(date) => parse_timestamp(date)?,
+            None => 0,
+        };
+        let until = match _matches.value_of("until") {
+            Some(date) => parse_timestamp(date)?,
+            None => std::i64::MAX as u64,
+        };
+        let width: usize = match _matches.value_of("width").unwrap_or("60").parse() {
+            Ok(w) if w > 0 => w,
+            _ => bail!("--width must be a positive whole number"),
+        };
+        let chart = _matches.value_of("chart").unwrap_or("braille");
+        if chart != "braille" && chart != "sparkline" {
+            bail!("Unknown chart {}. Use braille or sparkline.", chart);
+        }
+        let store = open_snapshot_store(&config)?;
+        let snapshots = store.snapshots(account, since, until)?;
+        if snapshots.is_empty() {
+            bail!(
+                "No snapshots of {} in that range. coin save and coin balance record them. Accounts with snapshots: [{}]",
+                account,
+                store.accounts()?.join(", ")
+            );
+        }
+        print_history(account, &snapshots, width, chart);
+        print_weights(&snapshots);
+        if let Some(output) = _matches.value_of("output") {
+            let path = PathBuf::from(output);
+            let file = fs::File::create(&path).chain_err(|| format!("Could not create {}", path.display()))?;
+            write_history(file, &snapshots).chain_err(|| format!("Could not write {}", path.display()))?;
+            println!("Wrote {} snapshots to {}", snapshots.len(), path.display());
+        }
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("performance") {
+        let now = Utc::now().timestamp() as u64;
+        if _matches.is_present("offline") {
+            sdk.prices.go_offline();
+        }
+        let mut history = sdk.performance(now)?;
+        // Saved snapshots are what the accounts were worth, they win over values rebuilt from the ledger.
+        if config.sinks.as_ref().and_then(|s| s.sqlite).unwrap_or(true) {
+            let accounts: Vec<String> = history.accounts.keys().cloned().collect();
+            match open_snapshot_store(&config).and_then(|store| snapshot_values(&store, &accounts, now)) {
+                Ok(snapshots) => history.use_snapshots(&snapshots),
+                Err(e) => println!("Could not read the saved snapshots, values are rebuilt from the ledger\n{:?}", e),
+            }
+        }
+        let mut rows = vec![];
+        match _matches.values_of("account") {
+            Some(names) => {
+                for name in names {
+                    match history.accounts.get(name) {
+                        Some(values) => rows.push((name.to_string(), PeriodReturns::from_history(values, now))),
+                        None => bail!("{} has no ledger entries. Run coin ledger sync first.", name),
+                    }
+                }
+            }
+            None => {
+                rows.push(("Portfolio".to_string(), PeriodReturns::from_history(&history.portfolio, now)));
+                for (name, values) in &history.accounts {
+                    rows.push((name.to_string(), PeriodReturns::from_history(values, now)));
+                }
+            }
+        }
+        print_performance(&rows);
+        if _matches.is_present("benchmark") {
+            let top: u64 = match _matches.value_of("top").unwrap_or("10").parse() {
+                Ok(n) => n,
+                Err(_) => bail!("--top must be a whole number"),
+            };
+            let since = match _matches.value_of("since") {
+                Some(date) => parse_timestamp(date)?,
+                None => 0,
+            };
+            // Compare the first row, the portfolio or the first account asked for.
+            let (name, values) = match _matches.values_of("account").and_then(|mut names| names.next()) {
+                Some(name) => (name.to_string(), &history.accounts[name]),
+                None => ("Portfolio".to_string(), &history.portfolio),
+            };
+            let comparisons = benchmark(&sdk, cmc.store(), values, since, top)?;
+            print_benchmarks(&name, &comparisons);
+        }
+        for issue in &history.issues {
+            println!("{} {}", "Skipped".yellow(), issue);
+        }
+        Ok(())
+    } else if let Some(_matches) = matches.subcommand_matches("tax") {
+        if let Some(report_matches) = _matches.subcommand_matches("report") {
+            let year: i32 = match report_matches.value_of("year").unwrap().parse() {
+                Ok(y) => y,
+                Err(_) => bail!("--year must be a year like 2025"),
+            };
+            let method = lot_method(report_matches.value_of("method"), &config.tax)?;
+            let report = sdk.tax_report(year, method, holding_period(&config.tax))?;
+            let output = match report_matches.value_of("output") {
+                Some(path) => PathBuf::from(path),
+                None => PathBuf::from(format!("form-8949-{}.csv", year)),
+            };
+            let file = fs::File::create(&output).chain_err(|| format!("Could not create {}", output.display()))?;
+            report.write_form_8949(file)?;
+            print_tax_report(&report);
+            println!("Wrote {} lines to {}", report.lines.len(), output.display());
+        } else {
+            println!("{}", _matches.usage());
+        }
         Ok(())
     } else if let Some(_matches) = matches.subcommand_matches("debug") {
-        let gains = sdk.total_gains()?;
-        println!("Total Gains: {:?}", gains);
+        let prices = cmc.latest_listings(100);
+        let currency = reporting_currency(&cmc, &prices, _matches.value_of("currency"), &config.currency)?;
+        let method = lot_method(_matches.value_of("method"), &config.tax)?;
+        let report = sdk.report(method, holding_period(&config.tax), &usd_prices(&prices))?;
+        let total = report.total(None);
+        let gains = coin_sdk::model::Amount {
+            amount: total.realized_gain() + total.unrealized_gain().unwrap_or(0.0),
+            currency: "USD".to_string(),
+        };
+        println!("Total Gains: {:?}", convert_amount(gains, &currency));
+        if total.unpriced_cost > 0.0 {
+            println!("Unrealized gains leave out {:.2} USD of cost in assets without a current price.", total.unpriced_cost);
+        }
+        print_accounting_report(&report, &currency);
         Ok(())
     } else {
         bail!("Unknown command")
@@ -394,6 +733,140 @@ struct Config {
     pub account: Vec<AccountConfig>,
     pub cmc: CMCConfig,
     pub airtable: Option<AirtableConfig>,
+    // Fiat or crypto symbol to report values in. Defaults to USD.
+    pub currency: Option<String>,
+    pub store: Option<StoreConfig>,
+    pub index: Option<IndexConfig>,
+    pub tax: Option<TaxConfig>,
+    pub sinks: Option<SinksConfig>,
+    pub watch: Option<WatchConfig>,
+    pub drift: Option<DriftConfig>,
+    pub alerts: Option<AlertsConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct TaxConfig {
+    // How lots are picked when an asset is sold: fifo, lifo, hifo or specific. Defaults to fifo.
+    pub lot_method: Option<String>,
+    // Lots held longer than this many days are long term. Defaults to 365.
+    pub holding_period_days: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct DriftConfig {
+    // Rebalance when an asset's weight is this many percentage points off its target. Defaults to 5.
+    pub threshold: Option<f64>,
+    // Or when the tracking error of all weights, in percentage points, is above this. Defaults to 10.
+    pub max_tracking_error: Option<f64>,
+    // Hours after a rebalance before drift may trigger another. Defaults to 24.
+    pub cooldown_hours: Option<u64>,
+    // The first this many rebalances drift triggers only preview their trades. Defaults to 3, at least 1.
+    pub dry_runs: Option<u64>,
+}
+
+fn drift_policy(config: &Config) -> DriftPolicy {
+    let drift = config.drift.as_ref();
+    DriftPolicy {
+        threshold: drift.and_then(|d| d.threshold).unwrap_or(5.0) / 100.0,
+        max_tracking_error: drift.and_then(|d| d.max_tracking_error).unwrap_or(10.0) / 100.0,
+        cooldown: drift.and_then(|d| d.cooldown_hours).unwrap_or(24) * 60 * 60,
+        dry_runs: drift.and_then(|d| d.dry_runs).unwrap_or(3).max(1),
+    }
+}
+
+fn lot_method(arg: Option<&str>, configured: &Option<TaxConfig>) -> Result<LotMethod> {
+    let configured = configured.as_ref().and_then(|t| t.lot_method.as_ref()).map(|m| &m[..]);
+    match arg.or(configured) {
+        Some(name) => LotMethod::from_name(name).map_err(|e| Error::from(e.to_string())),
+        None => Ok(LotMethod::Fifo),
+    }
+}
+
+fn holding_period(configured: &Option<TaxConfig>) -> Option<u64> {
+    configured.as_ref().and_then(|t| t.holding_period_days).map(|days| days * 60 * 60 * 24)
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct StoreConfig {
+    // Path of the SQLite database listings are recorded in. Defaults to ~/.coin/prices.db.
+    pub prices_path: Option<String>,
+    // Path of the SQLite database snapshots, orders and rebalance runs are kept in. Defaults to ~/.coin/coin.db.
+    pub path: Option<String>,
+}
+
+fn open_price_store(config: &Config) -> Result<PriceStore> {
+    let configured = config.store.as_ref().and_then(|s| s.prices_path.as_ref());
+    let path = match configured {
+        Some(p) => PathBuf::from(p),
+        None => coin_dir()?.join("prices.db"),
+    };
+    PriceStore::open(&path)
+}
+
+/**
+ * Every sink a snapshot is written to: the local store unless turned off, airtable when it has
+ * credentials and the configured files.
+ */
+fn snapshot_sinks(config: &Config) -> Vec<Box<dyn SnapshotSink + '_>> {
+    let sinks_config = config.sinks.as_ref();
+    let mut sinks: Vec<Box<dyn SnapshotSink>> = vec![];
+    if sinks_config.and_then(|s| s.sqlite).unwrap_or(true) {
+        sinks.push(Box::new(SqliteSink::new(open_snapshot_store(config))));
+    }
+    if let Some(airtable) = &config.airtable {
+        if !airtable.key.is_empty() && !airtable.app.is_empty() {
+            sinks.push(Box::new(AirtableSink::new(airtable)));
+        }
+    }
+    if let Some(csv) = sinks_config.and_then(|s| s.csv.as_ref()) {
+        sinks.push(Box::new(CsvSink::new(csv)));
+    }
+    if let Some(jsonl) = sinks_config.and_then(|s| s.jsonl.as_ref()) {
+        sinks.push(Box::new(JsonLinesSink::new(jsonl)));
+    }
+    sinks
+}
+
+/**
+ * The value of the crypto assets in every saved snapshot of the accounts up to `until`, which is
+ * what performance counts.
+ */
+fn snapshot_values(store: &SnapshotStore, accounts: &[String], until: u64) -> Result<HashMap<String, Vec<(u64, f64)>>> {
+    let mut values = HashMap::new();
+    for account in accounts {
+        let snapshots = store.snapshots(account, 0, until + 1)?;
+        values.insert(account.to_string(), snapshots.iter().map(|s| {
+            (s.timestamp, s.balances.iter().filter(|b| !is_fiat(&b.symbol)).map(|b| b.value_usd).sum())
+        }).collect());
+    }
+    Ok(values)
+}
+
+fn watcher(config: &Config) -> Result<Watcher> {
+    let watch_config = config.watch.as_ref();
+    let pid_path = match watch_config.and_then(|w| w.pid_path.as_ref()) {
+        Some(p) => PathBuf::from(p),
+        None => coin_dir()?.join("watch.pid"),
+    };
+    let log_path = match watch_config.and_then(|w| w.log_path.as_ref()) {
+        Some(p) => PathBuf::from(p),
+        None => coin_dir()?.join("watch.log"),
+    };
+    let mut jobs = vec![];
+    let configured = watch_config.and_then(|w| w.jobs.as_ref()).map(|j| &j[..]).unwrap_or(&[]);
+    for (i, job) in configured.iter().enumerate() {
+        jobs.push(Job::from_config(job).chain_err(|| format!("Invalid watch job {}", i + 1))?);
+    }
+    Ok(Watcher::new(pid_path, log_path, jobs))
+}
+
+fn open_snapshot_store(config: &Config) -> Result<SnapshotStore> {
+    let configured = config.store.as_ref().and_then(|s| s.path.as_ref());
+    let path = match configured {
+        Some(p) => PathBuf::from(p),
+        None => coin_dir()?.join("coin.db"),
+    };
+    SnapshotStore::open(&path)
 }
 
 fn get_config() -> Result<Config> {
@@ -436,10 +909,42 @@ fn get_coin_config() -> Result<CoinConfig> {
     Ok(CoinConfig {
         blacklist: full_config.blacklist,
         account: full_config.account,
-        cmc: full_config.cmc
+        cmc: full_config.cmc,
+        data_dir: Some(coin_dir()?.to_string_lossy().to_string()),
     })
 }
 
+/**
+ * The directory coin keeps local state in, ~/.coin by default.
+ */
+fn coin_dir() -> Result<PathBuf> {
+    match home_dir() {
+        Some(p) => {
+            let dir = p.join(".coin");
+            fs::create_dir_all(&dir).chain_err(|| format!("Could not create {:?}", dir))?;
+            Ok(dir)
+        }
+        None => bail!("Could not find your home directory"),
+    }
+}
+
+/**
+ * Parses a date (2019-06-01), date and time (2019-06-01 12:00) or RFC 3339
+ * timestamp as UTC and returns seconds since the unix epoch.
+ */
+fn parse_timestamp(s: &str) -> Result<u64> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.timestamp() as u64);
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M") {
+        return Ok(Utc.from_utc_datetime(&dt).timestamp() as u64);
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(d) => Ok(Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap()).timestamp() as u64),
+        Err(_) => bail!("Invalid date {}. Use YYYY-MM-DD, 'YYYY-MM-DD HH:MM' or RFC 3339.", s),
+    }
+}
+
 fn get_account_clients(configs: &Vec<AccountConfig>) -> Result<Vec<Box<ExchangeOps>>> {
     let mut vec_of_clients: Vec<Box<ExchangeOps>> = Vec::new();
     for config in configs {
@@ -485,8 +990,10 @@ fn get_account_clients(configs: &Vec<AccountConfig>) -> Result<Vec<Box<ExchangeO
 fn get_tradeable_symbols(
     base_currency: &str,
     blacklist: &HashSet<String>,
+    index_config: &IndexConfig,
     account: &Box<ExchangeOps>,
     cmc: &CMCClient,
+    listings: &[CMCListing],
 ) -> Result<HashSet<String>> {
     match (*account).all_prices() {
         Ok(prices) => {
@@ -516,38 +1023,161 @@ fn get_tradeable_symbols(
                 .intersection(&coins_with_data)
                 .map(|s| s.to_string())
                 .collect();
+            // Drop stablecoins, wrapped tokens and the like using CoinMarketCap tags where we have them.
+            let tags: HashMap<String, &Vec<String>> = listings
+                .iter()
+                .map(|l| (l.symbol.to_uppercase(), &l.tags))
+                .collect();
+            let no_tags = vec![];
+            let mut exclusions = vec![];
+            let tradable_symbols: HashSet<String> = tradable_symbols
+                .into_iter()
+                .filter(|symbol| {
+                    let symbol_tags = tags.get(symbol).cloned().unwrap_or(&no_tags);
+                    match classify(symbol, symbol_tags) {
+                        Some(category) if symbol != base_currency && index_config.excludes(category) => {
+                            exclusions.push(Exclusion {
+                                symbol: symbol.to_string(),
+                                reason: format!("{} excluded by category", category.name()),
+                            });
+                            false
+                        }
+                        _ => true,
+                    }
+                })
+                .collect();
+            print_exclusions(&exclusions);
             Ok(tradable_symbols)
         }
         Err(e) => Err(e),
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct AccountRecord {
-    total_usd: f64,
-    total_btc: f64,
-    timestamp: String,
-    details: String,
-    name: String,
-}
-fn save_account(airtable: &AirtableClient, account: &Portfolio, name: &str) {
-    let now = Utc::now();
-    let account_str = serde_json::to_string_pretty(&account.balances).unwrap();
-    let value = AccountRecord {
-        total_usd: account.total_usd,
-        total_btc: account.total_btc,
-        name: name.to_owned(),
-        timestamp: now.to_string(),
-        details: account_str,
-    };
-    let value_json = serde_json::to_value(value).unwrap();
-    match airtable.create_record(value_json) {
-        Ok(_) => println!("Successfully saved portfolio to airtable."),
-        Err(e) => println!(
-            "Error saving portfolio to airtable: ${}",
-            e.status().unwrap()
-        ),
-    };
+/**
+ * Compares the weights of every trading account to the target and decides whether drift calls for a
+ * rebalance. Returns the decision and the report of the account furthest off its target.
+ */
+fn check_drift(
+    policy: &DriftPolicy,
+    store: &SnapshotStore,
+    account_clients: &Vec<Box<dyn ExchangeOps>>,
+    prices: &CMCListingResponse,
+    currency: &ReportingCurrency,
+    target: &HashMap<String, f64>,
+) -> Result<(DriftDecision, Option<DriftReport>)> {
+    let mut drifted = false;
+    let mut worst: Option<DriftReport> = None;
+    for trading_client in account_clients.iter().filter(|c| c.can_trade()) {
+        let balances = trading_client.all_accounts()
+            .chain_err(|| format!("Could not fetch the balances of {}", trading_client.name()))?;
+        let portfolio = make_portfolio(&balances, prices, currency)?;
+        let weights: HashMap<String, f64> = portfolio.balances.iter()
+            .map(|b| (b.symbol.to_string(), b.value_usd))
+            .collect();
+        let report = DriftReport::new(&weights, target);
+        print_drift(trading_client.name(), &report, policy.threshold);
+        if let Some(reason) = policy.breach(&report) {
+            println!("{} drifted: {}", trading_client.name().yellow(), reason);
+            drifted = true;
+        }
+        if worst.as_ref().map(|w| w.tracking_error < report.tracking_error).unwrap_or(true) {
+            worst = Some(report);
+        }
+    }
+    let now = Utc::now().timestamp() as u64;
+    let decision = policy.decide(drifted, store.last_rebalance()?, store.drift_dry_runs()?, store.drift_confirmed()?, now);
+    Ok((decision, worst))
+}
+
+fn alert_channels(config: &AlertsConfig) -> Vec<Box<dyn AlertChannel + '_>> {
+    let mut channels: Vec<Box<dyn AlertChannel + '_>> = vec![];
+    if config.log.unwrap_or(true) {
+        channels.push(Box::new(LogChannel));
+    }
+    if let Some(command) = &config.command {
+        channels.push(Box::new(CommandChannel::new(command)));
+    }
+    if let Some(smtp) = &config.smtp {
+        channels.push(Box::new(SmtpChannel::new(smtp)));
+    }
+    if let Some(webhook) = &config.webhook {
+        channels.push(Box::new(WebhookChannel::new(webhook)));
+    }
+    channels
+}
+
+/**
+ * Fetches what the alert rules need: listings deep enough for every top-N rule and, when a rule
+ * needs them, the assets held and the value of every account together.
+ */
+fn market_view(cmc: &CMCClient, account_clients: &Vec<Box<dyn ExchangeOps>>, rules: &[AlertRule]) -> MarketView {
+    let deepest = rules.iter().filter_map(|r| match r {
+        AlertRule::Top { rank, .. } => Some(*rank + 1),
+        _ => None,
+    }).max().unwrap_or(0);
+    let listings = cmc.latest_listings(deepest.max(100).min(5000) as u16);
+    let mut quotes = HashMap::new();
+    for listing in &listings.data {
+        if let Some(quote) = listing.quote.get("USD") {
+            // Several assets may share a symbol, the largest one is meant.
+            quotes.entry(listing.symbol.to_uppercase()).or_insert(Quote {
+                price: quote.price,
+                change_24h: quote.percent_change_24h,
+                rank: listing.cmc_rank,
+            });
+        }
+    }
+    let needs_portfolio = rules.iter().any(|r| match r {
+        AlertRule::Drawdown { .. } => true,
+        AlertRule::Change24h { symbol, .. } | AlertRule::Top { symbol, .. } => symbol.is_none(),
+        AlertRule::Price { .. } => false,
+    });
+    let mut held = BTreeSet::new();
+    let mut portfolio_value = Some(0.0);
+    if needs_portfolio {
+        for client in account_clients {
+            let portfolio = client.all_accounts()
+                .and_then(|balances| make_portfolio(&balances, &listings, &ReportingCurrency::usd()));
+            match portfolio {
+                Ok(p) => {
+                    // Cash is never listed, it would always be outside the top.
+                    held.extend(p.balances.iter()
+                        .filter(|b| b.quantity > 0.0 && !is_fiat(&b.symbol))
+                        .map(|b| b.symbol.to_uppercase()));
+                    portfolio_value = portfolio_value.map(|v| v + p.total_usd);
+                }
+                Err(e) => {
+                    // A partial total would look like a drawdown.
+                    println!("Could not fetch the balances of {}\n{:?}", client.name().red(), e);
+                    portfolio_value = None;
+                }
+            }
+        }
+    } else {
+        portfolio_value = None;
+    }
+    MarketView { quotes, depth: listings.data.len() as u64, held: held.into_iter().collect(), portfolio_value }
+}
+
+/**
+ * Writes a snapshot to every sink and reports which ones failed.
+ */
+fn save_snapshot(sinks: &[Box<dyn SnapshotSink + '_>], name: &str, portfolio: &Portfolio, timestamp: u64, run_id: Option<i64>) {
+    if sinks.is_empty() {
+        println!("No snapshot sinks are configured in ~/.coin.toml");
+        return;
+    }
+    let snapshot = Snapshot { account: name, timestamp, portfolio, run_id };
+    let mut saved = vec![];
+    for (sink, result) in write_snapshot(sinks, &snapshot) {
+        match result {
+            Ok(()) => saved.push(sink),
+            Err(e) => println!("Could not save the snapshot of {} to {}\n{:?}", name, sink.red(), e),
+        }
+    }
+    if !saved.is_empty() {
+        println!("Saved the snapshot of {} to {}.", name, saved.join(", "));
+    }
 }
 
 fn balance_by_market_cap(
@@ -557,8 +1187,10 @@ fn balance_by_market_cap(
     lookback: u64,
     smoothing_factor: f64,
     tradable_assets: &HashSet<String>,
+    constituent_filter: &ConstituentFilter,
 ) -> HashMap<String, f64> {
     let mut market_caps = HashMap::new();
+    let mut exclusions = vec![];
     let mut seen_assets = 0;
     let mut table = Table::new();
     table.add_row(row!["Symbol", "Count", "Historical Market Caps"]);
@@ -570,8 +1202,41 @@ fn balance_by_market_cap(
         if !tradable_assets.contains(&l_symbol) {
             continue;
         }
-        let historical_quotes = cmc.historic_quotes(&price.symbol, lookback, "daily");
-        if historical_quotes.result.len() == 0 {
+        if let Some(reason) = constituent_filter.exclusion_reason(price) {
+            exclusions.push(Exclusion { symbol: l_symbol, reason });
+            continue;
+        }
+        let remote_market_caps = match cmc.historic_quotes(&price.symbol, lookback, "daily") {
+            Ok(historical_quotes) => historical_quotes
+                .result
+                .iter()
+                .map(|h_quote| {
+                    let price = h_quote.1;
+                    price
+                })
+                .collect::<Vec<f64>>(),
+            Err(e) => {
+                println!("Could not fetch market caps for {}: {}", &price.symbol, e);
+                vec![]
+            }
+        };
+        // Fall back to our own listing history when the remote provider has nothing.
+        let historical_market_caps = if remote_market_caps.len() > 0 {
+            remote_market_caps
+        } else {
+            match cmc.store().map(|s| s.daily_market_caps(&price.symbol, lookback)) {
+                Some(Ok(caps)) if caps.len() > 0 => {
+                    println!(
+                        "Using {} days of locally stored market caps for {}",
+                        caps.len(),
+                        &price.symbol
+                    );
+                    caps
+                }
+                _ => vec![],
+            }
+        };
+        if historical_market_caps.len() == 0 {
             println!(
                 "Could not find market cap information for {}",
                 &price.symbol
@@ -581,14 +1246,6 @@ fn balance_by_market_cap(
         // Slow rate to 5 reqs a second
         // let throttle_length = time::Duration::from_millis(200);
         // thread::sleep(throttle_length);
-        let historical_market_caps = historical_quotes
-            .result
-            .iter()
-            .map(|h_quote| {
-                let price = h_quote.1;
-                price
-            })
-            .collect::<Vec<f64>>();
         let symbol = &price.symbol[..];
         let values_as_string = historical_market_caps
             .iter()
@@ -603,9 +1260,22 @@ fn balance_by_market_cap(
     let balancer = MarketCapBalancer::new(market_caps, smoothing_factor);
     let allotments = balancer.balance_by_market_cap();
     table.printstd();
+    print_exclusions(&exclusions);
     allotments
 }
 
+fn print_exclusions(exclusions: &Vec<Exclusion>) {
+    if exclusions.len() == 0 {
+        return;
+    }
+    let mut table = Table::new();
+    table.add_row(row!["Excluded", "Reason"]);
+    for exclusion in exclusions {
+        table.add_row(row![exclusion.symbol, exclusion.reason]);
+    }
+    table.printstd();
+}
+
 fn print_prices(prices: Vec<Price>) {
     let mut table = Table::new();
     table.add_row(row!["Symbol", "Price"]);
@@ -615,6 +1285,46 @@ fn print_prices(prices: Vec<Price>) {
     table.printstd();
 }
 
+fn print_alerts(rules: &[AlertRule], state: &AlertState) {
+    if rules.is_empty() {
+        println!("No alert rules are configured. Add [[alerts.rules]] to ~/.coin.toml.");
+        return;
+    }
+    let mut table = Table::new();
+    table.add_row(row!["Rule", "Alerting", "Last Alert"]);
+    for rule in rules {
+        let prefix = rule.describe();
+        let belongs = |key: &str| key == prefix || key.starts_with(&format!("{}:", prefix));
+        let alerting = state.active.iter().filter(|k| belongs(k)).count();
+        let last = state.fired.iter().filter(|(k, _)| belongs(k)).map(|(_, t)| *t).max();
+        table.add_row(row![
+            prefix,
+            if alerting > 0 { alerting.to_string().red() } else { "no".normal() },
+            last.map(|t| format_time(t, MINUTE_FORMAT)).unwrap_or_else(|| "never".to_string())
+        ]);
+    }
+    table.printstd();
+    if let Some(peak) = state.peak {
+        println!("Portfolio peak: ${:.2}", peak);
+    }
+}
+
+fn print_drift(name: &str, report: &DriftReport, threshold: f64) {
+    println!("{}: tracking error {:.2}%", name, report.tracking_error * 100.0);
+    let mut table = Table::new();
+    table.add_row(row!["Symbol", "Current", "Target", "Drift"]);
+    for asset in &report.assets {
+        let drift = format!("{:+.2}", asset.drift * 100.0);
+        table.add_row(row![
+            asset.symbol,
+            format!("{:.2}", asset.current * 100.0),
+            format!("{:.2}", asset.target * 100.0),
+            if asset.drift.abs() > threshold { drift.red() } else { drift.normal() }
+        ]);
+    }
+    table.printstd();
+}
+
 fn print_asset_allocations(allotments: &HashMap<String, f64>) {
     let mut table = Table::new();
     table.add_row(row!["Symbol", "Percentage"]);
@@ -624,6 +1334,26 @@ fn print_asset_allocations(allotments: &HashMap<String, f64>) {
     table.printstd();
 }
 
+fn print_price_history(symbol: &str, quotes: &Vec<StoredQuote>) {
+    if quotes.len() == 0 {
+        println!("No stored prices for {}. Prices are recorded whenever coin fetches listings.", symbol);
+        return;
+    }
+    let mut table = Table::new();
+    table.add_row(row!["Time", "Rank", "Price (USD)", "Volume 24h (USD)", "Market Cap (USD)", "Change (24h)"]);
+    for quote in quotes {
+        table.add_row(row![
+            format_time(quote.timestamp, MINUTE_FORMAT),
+            quote.cmc_rank,
+            format!("${:.5}", quote.price),
+            format!("${:.0}", quote.volume_24h),
+            format!("${:.0}", quote.market_cap),
+            format!("{:.2}", quote.percent_change_24h)
+        ]);
+    }
+    table.printstd();
+}
+
 fn print_cmc_listings(listings: &CMCListingResponse) {
     let mut table = Table::new();
     table.add_row(row!["Symbol", "Price"]);
@@ -654,6 +1384,10 @@ fn matches() -> clap::ArgMatches<'static> {
             (version: "1.0")
             (@arg verbose: -v --verbose "Print test information verbosely")
             (@arg account: -a --account +takes_value +multiple "List details for one or more accounts.")
+            (@arg currency: --currency +takes_value "Report values in this fiat or crypto currency. Overrides ~/.coin.toml.")
+            (@arg basis: -b --basis "Show the cost basis, average price and unrealized gain of each asset.")
+            (@arg lots: -l --lots "List the open lots of each asset with their age.")
+            (@arg method: --method +takes_value "Lot selection method: fifo, lifo, hifo or specific. Overrides ~/.coin.toml.")
         )
         (@subcommand cmc =>
             (about: "List current prices from CoinMarketCap")
@@ -664,8 +1398,9 @@ fn matches() -> clap::ArgMatches<'static> {
             (version: "1.0")
         )
         (@subcommand save =>
-            (about: "Save your current account information to airtable")
+            (about: "Save a snapshot of your portfolio to every configured sink")
             (version: "1.0")
+            (@arg currency: --currency +takes_value "Report values in this fiat or crypto currency. Overrides ~/.coin.toml.")
         )
         (@subcommand symbols =>
             (about: "List the symbols that can trade with a given currency")
@@ -688,14 +1423,120 @@ fn matches() -> clap::ArgMatches<'static> {
             (@arg lookback: -l --lookback +takes_value "Specifies how many periods to lookback when calculating the moving average. Defaults to 20.")
             (@arg factor: -f --factor +takes_value "Specifies the smoothing factor for the moving average calculation. Defaults to 0.3.")
             (@arg mock: -m --mock "Preview the balance event but do not execute any trades.")
+            (@arg drift: --drift "Only rebalance when the portfolio drifted beyond the limits in the [drift] table of ~/.coin.toml.")
+            (@arg confirm_drift: --("confirm-drift") "Approve the last dry run drift triggered so drift rebalances place trades.")
         )
         (@subcommand cost =>
             (about: "Compute cost basis")
             (version: "1.0")
+            (@arg currency: --currency +takes_value "Report values in this fiat or crypto currency. Overrides ~/.coin.toml.")
+            (@arg method: --method +takes_value "Lot selection method: fifo, lifo, hifo or specific. Overrides ~/.coin.toml.")
+        )
+        (@subcommand prices =>
+            (about: "Look up historical prices")
+            (version: "1.0")
+            (@subcommand history =>
+                (about: "Print prices, volumes and market caps recorded in the local price store")
+                (@arg symbol: +required "The symbol of the asset e.g. BTC")
+                (@arg days: -d --days +takes_value "How many days of history to print. Defaults to 30.")
+            )
+            (@subcommand at =>
+                (about: "Print the USD price of an asset at a date and time")
+                (@arg symbol: +required "The symbol of the asset e.g. BTC")
+                (@arg date: +required "A date (2019-06-01), date and time (2019-06-01 12:00) or RFC 3339 timestamp in UTC")
+            )
+        )
+        (@subcommand ledger =>
+            (about: "Manage the local ledger of account transactions")
+            (version: "1.0")
+            (@subcommand sync =>
+                (about: "Pull the full transaction history of your accounts into ~/.coin/ledger.json")
+                (@arg account: -a --account +takes_value +multiple "Only sync these accounts.")
+            )
+            (@subcommand import =>
+                (about: "Import a CSV export of your transaction history into ~/.coin/ledger.json")
+                (@arg file: +required "The CSV file")
+                (@arg account: -a --account +takes_value +required "The account the transactions belong to. Need not be configured.")
+                (@arg format: -f --format +takes_value "binance-trades, binance-deposits, binance-withdrawals, coinbase, coinbasepro-fills, coinbasepro-account or generic. Detected from the header by default.")
+            )
+            (@subcommand transfers =>
+                (about: "Match withdrawals and deposits between your accounts")
+                (@arg window: -w --window +takes_value "Hours a deposit may arrive after its withdrawal. Defaults to 72.")
+                (@subcommand confirm =>
+                    (about: "Confirm a withdrawal and deposit are the same transfer")
+                    (@arg from: +required "The account of the withdrawal")
+                    (@arg withdrawal: +required "The ledger id of the withdrawal")
+                    (@arg to: +required "The account of the deposit")
+                    (@arg deposit: +required "The ledger id of the deposit")
+                )
+                (@subcommand reject =>
+                    (about: "Never match a withdrawal to a deposit")
+                    (@arg account: +required "The account of the withdrawal")
+                    (@arg withdrawal: +required "The ledger id of the withdrawal")
+                )
+            )
+        )
+        (@subcommand watch =>
+            (about: "Run snapshot, rebalance and sync jobs on a schedule in the background")
+            (version: "1.0")
+            (@subcommand start =>
+                (about: "Start the watcher in the background")
+            )
+            (@subcommand stop =>
+                (about: "Stop the running watcher")
+                (alias: "kill")
+            )
+            (@subcommand status =>
+                (about: "Whether the watcher is running and when each job runs next")
+            )
+            (@subcommand daemon =>
+                (about: "Run the watcher in the foreground. watch start runs this in the background.")
+            )
+        )
+        (@subcommand alerts =>
+            (about: "Price and portfolio alerts configured in ~/.coin.toml. Lists the rules by default.")
+            (version: "1.0")
+            (@subcommand check =>
+                (about: "Evaluate the rules and deliver new alerts. An alerts job of the watcher runs this.")
+            )
+            (@subcommand test =>
+                (about: "Send a test alert to every channel")
+            )
+        )
+        (@subcommand history =>
+            (about: "Charts the saved snapshots of an account and how its asset weights changed")
+            (version: "1.0")
+            (@arg account: -a --account +takes_value "The account to show. Defaults to ALL, the whole portfolio.")
+            (@arg since: --since +takes_value "Only snapshots from this date (YYYY-MM-DD).")
+            (@arg until: --until +takes_value "Only snapshots before this date (YYYY-MM-DD).")
+            (@arg chart: -c --chart +takes_value "braille (default) or sparkline.")
+            (@arg width: -w --width +takes_value "Width of the charts in characters. Defaults to 60.")
+            (@arg output: -o --output +takes_value "Also write the history with each asset's weight to this CSV file.")
+        )
+        (@subcommand performance =>
+            (about: "Time and money weighted returns of the portfolio and each account, net of deposits and withdrawals")
+            (version: "1.0")
+            (@arg account: -a --account +takes_value +multiple "Only show these accounts.")
+            (@arg benchmark: -b --benchmark "Compare against holding BTC, ETH and an equal weight basket of the top assets.")
+            (@arg top: --top +takes_value "How many assets by market cap are in the equal weight basket. Defaults to 10.")
+            (@arg since: --since +takes_value "Compare from this date (YYYY-MM-DD) rather than the first ledger entry.")
+            (@arg offline: --offline "Only use saved snapshots, stored prices and cached closes.")
+        )
+        (@subcommand tax =>
+            (about: "Tax reporting")
+            (version: "1.0")
+            (@subcommand report =>
+                (about: "Write a Form 8949 CSV and print the Schedule D and per-asset totals of a tax year")
+                (@arg year: -y --year +takes_value +required "The tax year e.g. 2025")
+                (@arg method: --method +takes_value "Lot selection method: fifo, lifo, hifo or specific. Overrides ~/.coin.toml.")
+                (@arg output: -o --output +takes_value "Where to write the CSV. Defaults to form-8949-<year>.csv.")
+            )
         )
         (@subcommand debug =>
             (about: "Debug")
             (version: "1.0")
+            (@arg currency: --currency +takes_value "Report values in this fiat or crypto currency. Overrides ~/.coin.toml.")
+            (@arg method: --method +takes_value "Lot selection method: fifo, lifo, hifo or specific. Overrides ~/.coin.toml.")
         )
     ).get_matches();
     matches
@@ -714,7 +1555,7 @@ fn asset(acct: Asset) -> Account {
         locked: acct.locked
     }
 }
-fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse) -> Result<Portfolio> {
+fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse, currency: &ReportingCurrency) -> Result<Portfolio> {
     let price_map = cmc_listings_as_map(prices);
     let price_btc = match price_map.get("BTC") {
         Some(price) => match price.quote.get("USD") {
@@ -725,6 +1566,7 @@ fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse) -> Resul
     };
     let mut total_usd = 0.0;
     let mut total_btc = 0.0;
+    let mut total_reporting = 0.0;
     let mut acct_balances: Vec<PortfolioBalance> = Vec::new();
     let flat_accounts = summarize_accounts(accounts);
     flat_accounts.iter().for_each(|item| {
@@ -758,6 +1600,8 @@ fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse) -> Resul
             None => 0.0,
         };
         total_btc = total_btc + total_value_btc;
+        let total_value_reporting = currency.from_usd(total_value);
+        total_reporting = total_reporting + total_value_reporting;
         if item.total() > 0.0 && total_value > 1.0 {
             // total_value = (total_value * USD_FORMAT_MULTIPLIER).round()/USD_FORMAT_MULTIPLIER;
             // total_value_btc = (total_value_btc * BTC_FORMAT_MULTIPLIER).round()/BTC_FORMAT_MULTIPLIER;
@@ -766,6 +1610,7 @@ fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse) -> Resul
                 quantity: item.total(),
                 value_usd: total_value,
                 value_btc: total_value_btc,
+                value: total_value_reporting,
                 change_7d: increase_7d,
                 change_24h: increase_24h,
             });
@@ -784,9 +1629,36 @@ fn make_portfolio(accounts: &Vec<Account>, prices: &CMCListingResponse) -> Resul
         balances: acct_balances,
         total_btc: total_btc,
         total_usd: total_usd,
+        currency: currency.symbol.to_owned(),
+        total_value: total_reporting,
     })
 }
 
+/**
+ * Resolve the reporting currency from the command line or config, USD unless either names one.
+ */
+fn reporting_currency(
+    cmc: &CMCClient,
+    prices: &CMCListingResponse,
+    arg: Option<&str>,
+    configured: &Option<String>,
+) -> Result<ReportingCurrency> {
+    match arg.or_else(|| configured.as_ref().map(|c| &c[..])) {
+        Some(symbol) => ReportingCurrency::resolve(cmc, prices, symbol),
+        None => Ok(ReportingCurrency::usd()),
+    }
+}
+
+fn convert_amount(amount: coin_sdk::model::Amount, currency: &ReportingCurrency) -> coin_sdk::model::Amount {
+    if amount.currency != "USD" {
+        return amount;
+    }
+    coin_sdk::model::Amount {
+        amount: currency.from_usd(amount.amount),
+        currency: currency.symbol.to_owned(),
+    }
+}
+
 fn summarize_accounts(accounts: &Vec<Account>) -> Vec<Account> {
     let mut asset_map: HashMap<String, Account> = HashMap::new();
     for account in accounts {
@@ -809,16 +1681,26 @@ fn summarize_accounts(accounts: &Vec<Account>) -> Vec<Account> {
     balances
 }
 
-fn print_portfolio(account: &Portfolio) {
+fn print_portfolio(account: &Portfolio, basis: Option<&BTreeMap<String, AssetPosition>>) {
+    let show_reporting = account.has_extra_currency();
     let mut table = Table::new();
-    table.add_row(row!(
+    let mut header = row!(
         "Symbol",
         "Quantity",
         "Value (USD)",
         "Value (BTC)",
         "Change (7d)",
         "Change (14d)"
-    ));
+    );
+    if show_reporting {
+        header.insert_cell(4, cell!(format!("Value ({})", account.currency)));
+    }
+    if basis.is_some() {
+        for title in &["Cost Basis (USD)", "Avg Price (USD)", "Unrealized (USD)", "Unrealized %"] {
+            header.add_cell(cell!(title));
+        }
+    }
+    table.add_row(header);
     account.balances.iter().for_each(|item| {
         let increase_7d = if item.change_7d > 0.0 {
             item.change_7d.to_string().green()
@@ -831,18 +1713,545 @@ fn print_portfolio(account: &Portfolio) {
             item.change_24h.to_string().red()
         };
         if item.value_btc > 0.0 && item.value_usd > 1.0 {
-            table.add_row(row![
+            let mut r = row![
                 item.symbol,
                 item.quantity.to_string().yellow(),
                 item.usd().to_string().blue(),
                 item.btc().to_string().cyan(),
                 increase_7d,
                 increase_24h
-            ]);
+            ];
+            if show_reporting {
+                r.insert_cell(4, cell!(item.value_in(&account.currency).to_string().magenta()));
+            }
+            if let Some(basis) = basis {
+                match basis.get(&item.symbol) {
+                    Some(position) if position.quantity > 0.0 => {
+                        let unrealized = ledger_value(item, position) - position.cost_basis;
+                        r.add_cell(cell!(format!("{:.2}", position.cost_basis)));
+                        r.add_cell(cell!(format!("{:.4}", position.average_cost().unwrap_or(0.0))));
+                        r.add_cell(cell!(colored_gain(format!("{:.2}", unrealized), unrealized)));
+                        r.add_cell(match percent(unrealized, position.cost_basis) {
+                            Some(p) => cell!(colored_gain(format!("{:.2}%", p), p)),
+                            None => cell!("-"),
+                        });
+                    }
+                    _ => {
+                        for _ in 0..4 {
+                            r.add_cell(cell!("-"));
+                        }
+                    }
+                }
+            }
+            table.add_row(r);
         }
     });
-    table.add_row(row!["", "", account.usd(), account.btc(), "", ""]);
+    let mut totals = row!["", "", account.usd(), account.btc(), "", ""];
+    if show_reporting {
+        totals.insert_cell(4, cell!(account.value()));
+    }
+    if let Some(basis) = basis {
+        let held: Vec<&PortfolioBalance> = account
+            .balances
+            .iter()
+            .filter(|b| basis.get(&b.symbol).map(|p| p.quantity > 0.0).unwrap_or(false))
+            .collect();
+        let cost: f64 = held.iter().map(|b| basis[&b.symbol].cost_basis).sum();
+        let unrealized = held.iter().map(|b| ledger_value(b, &basis[&b.symbol])).sum::<f64>() - cost;
+        totals.add_cell(cell!(format!("{:.2}", cost)));
+        totals.add_cell(cell!(""));
+        totals.add_cell(cell!(colored_gain(format!("{:.2}", unrealized), unrealized)));
+        totals.add_cell(match percent(unrealized, cost) {
+            Some(p) => cell!(colored_gain(format!("{:.2}%", p), p)),
+            None => cell!(""),
+        });
+    }
+    table.add_row(totals);
+    table.printstd();
+}
+
+/**
+ * The current value of the quantity the ledger holds, which the cost basis is for. The quantity
+ * held on the exchange may differ until the ledger is synced.
+ */
+fn ledger_value(item: &PortfolioBalance, position: &AssetPosition) -> f64 {
+    if item.quantity > 0.0 {
+        position.quantity * item.value_usd / item.quantity
+    } else {
+        0.0
+    }
+}
+
+fn colored_gain(text: String, gain: f64) -> ColoredString {
+    if gain < 0.0 {
+        text.red()
+    } else {
+        text.green()
+    }
+}
+
+fn percent(gain: f64, cost: f64) -> Option<f64> {
+    if cost > 0.0 {
+        Some(gain / cost * 100.0)
+    } else {
+        None
+    }
+}
+
+fn print_lots(lots: &Vec<(String, String, Lot)>, current_prices: &HashMap<String, f64>, holding_period: u64) {
+    let now = Utc::now().timestamp() as u64;
+    let mut table = Table::new();
+    table.add_row(row![
+        "Account", "Asset", "Acquired", "Age (days)", "Term", "Quantity", "Price (USD)", "Cost (USD)", "Value (USD)", "Unrealized (USD)"
+    ]);
+    for (account, asset, lot) in lots {
+        let cost = lot.quantity * lot.value;
+        let value = current_prices.get(asset).map(|p| p * lot.quantity);
+        table.add_row(row![
+            account,
+            asset,
+            format_time(lot.acquired, MINUTE_FORMAT),
+            now.saturating_sub(lot.acquired) / (60 * 60 * 24),
+            Term::of(lot.acquired, now, holding_period).name(),
+            lot.quantity,
+            format!("{:.4}", lot.value),
+            format!("{:.2}", cost),
+            value.map(|v| format!("{:.2}", v)).unwrap_or_else(|| "-".to_string()),
+            match value {
+                Some(v) => colored_gain(format!("{:.2}", v - cost), v - cost),
+                None => "-".normal(),
+            }
+        ]);
+    }
+    table.printstd();
+}
+
+fn print_ledger_sync(synced: &Vec<(String, usize, usize)>) {
+    let mut table = Table::new();
+    table.add_row(row!["Account", "Fetched", "New"]);
+    for (account, fetched, added) in synced {
+        table.add_row(row![account, fetched, added.to_string().green()]);
+    }
+    table.printstd();
+}
+
+fn print_transfers(report: &TransferReport) {
+    let mut table = Table::new();
+    table.add_row(row!["From", "To", "Asset", "Sent", "Received", "Sent At", "Received At", "Confirmed"]);
+    for m in &report.matched {
+        table.add_row(row![
+            m.from_account,
+            m.to_account,
+            m.asset,
+            m.sent,
+            m.received,
+            format_time(m.sent_at, MINUTE_FORMAT),
+            format_time(m.received_at, MINUTE_FORMAT),
+            if m.confirmed { "yes".green() } else { "no".normal() }
+        ]);
+    }
     table.printstd();
+    for ambiguous in &report.ambiguous {
+        let w = &ambiguous.withdrawal;
+        let sent = w.sent.as_ref().map(|a| format!("{} {}", a.amount, a.currency)).unwrap_or_default();
+        println!(
+            "{} {} from {} at {} could be any of:",
+            w.id.yellow(),
+            sent,
+            w.account,
+            format_time(w.timestamp, MINUTE_FORMAT)
+        );
+        for d in &ambiguous.candidates {
+            let received = d.received.as_ref().map(|a| format!("{} {}", a.amount, a.currency)).unwrap_or_default();
+            println!("    {} {} into {} at {}", d.id, received, d.account, format_time(d.timestamp, MINUTE_FORMAT));
+        }
+    }
+    if !report.ambiguous.is_empty() {
+        println!("Resolve these with `coin ledger transfers confirm <from> <withdrawal> <to> <deposit>` or `coin ledger transfers reject <account> <withdrawal>`.");
+    }
+}
+
+fn usd_prices(listings: &CMCListingResponse) -> HashMap<String, f64> {
+    listings
+        .data
+        .iter()
+        .filter_map(|l| l.quote.get("USD").map(|q| (l.symbol.to_owned(), q.price)))
+        .collect()
+}
+
+fn print_accounting_report(report: &PortfolioReport, currency: &ReportingCurrency) {
+    let money = |usd: f64| format!("{:.2}", currency.from_usd(usd));
+    let maybe_money = |usd: Option<f64>| usd.map(|v| money(v)).unwrap_or_else(|| "-".to_string());
+    let colored_money = |usd: Option<f64>| match usd {
+        Some(v) if v < 0.0 => money(v).red(),
+        Some(v) => money(v).green(),
+        None => "-".normal(),
+    };
+    let mut table = Table::new();
+    table.add_row(row![
+        "Account", "Asset", "Quantity", "Avg Cost", "Cost Basis", "Invested", "Market Value", "Realized", "Unrealized"
+    ]);
+    for account in report.accounts() {
+        for p in report.positions.iter().filter(|p| p.account == account) {
+            table.add_row(row![
+                p.account,
+                p.asset,
+                p.quantity,
+                maybe_money(p.average_cost()),
+                money(p.cost_basis),
+                money(p.invested),
+                maybe_money(p.market_value),
+                colored_money(Some(p.realized_gain())),
+                colored_money(p.unrealized_gain())
+            ]);
+        }
+        let total = report.total(Some(&account));
+        table.add_row(row![
+            total.account.bold(),
+            "",
+            "",
+            "",
+            money(total.cost_basis),
+            money(total.invested),
+            maybe_money(total.market_value),
+            colored_money(Some(total.realized_gain())),
+            colored_money(total.unrealized_gain())
+        ]);
+    }
+    let total = report.total(None);
+    table.add_row(row![
+        total.account.bold(),
+        "",
+        "",
+        "",
+        money(total.cost_basis),
+        money(total.invested),
+        maybe_money(total.market_value),
+        colored_money(Some(total.realized_gain())),
+        colored_money(total.unrealized_gain())
+    ]);
+    println!("Values in {}", currency.symbol);
+    table.printstd();
+    if total.unpriced_cost > 0.0 {
+        println!("Totals leave out {} of cost in assets without a current price.", money(total.unpriced_cost));
+    }
+    for issue in &report.issues {
+        println!("{} {}", "Skipped".yellow(), issue);
+    }
+}
+
+fn print_watch_status(watcher: &Watcher) {
+    match watcher.running() {
+        Some(pid) => println!("The watcher is {} with pid {}", "running".green(), pid),
+        None => println!("The watcher is {}", "not running".red()),
+    }
+    println!("Log: {}", watcher.log_path().display());
+    if watcher.jobs().is_empty() {
+        println!("No jobs are configured. Add [[watch.jobs]] to ~/.coin.toml.");
+        return;
+    }
+    let now = Local::now().naive_local();
+    let mut table = Table::new();
+    table.add_row(row!["Job", "Schedule", "Command", "Next Run"]);
+    for job in watcher.jobs() {
+        let next = match job.schedule.next_after(&now) {
+            Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+            None => "never".to_string(),
+        };
+        table.add_row(row![
+            job.kind.name(),
+            job.schedule.expression,
+            format!("coin {}", job.command_line().join(" ")),
+            next
+        ]);
+    }
+    table.printstd();
+}
+
+/**
+ * Total value over time in USD and BTC. Braille charts are eight rows tall.
+ */
+fn print_history(account: &str, snapshots: &[StoredSnapshot], width: usize, chart: &str) {
+    let first = &snapshots[0];
+    let last = &snapshots[snapshots.len() - 1];
+    println!(
+        "{} snapshots of {} from {} to {}",
+        snapshots.len(),
+        account.bold(),
+        format_time(first.timestamp, MINUTE_FORMAT),
+        format_time(last.timestamp, MINUTE_FORMAT)
+    );
+    let series: Vec<(&str, usize, Vec<(u64, f64)>)> = vec![
+        ("USD", 2, snapshots.iter().map(|s| (s.timestamp, s.total_usd)).collect()),
+        ("BTC", 8, snapshots.iter().map(|s| (s.timestamp, s.total_btc)).collect()),
+    ];
+    for (currency, decimals, points) in &series {
+        let start = points[0].1;
+        let end = points[points.len() - 1].1;
+        let change = match percent(end - start, start) {
+            Some(p) => colored_gain(format!("{:+.2}%", p), p),
+            None => "-".normal(),
+        };
+        println!(
+            "\nValue ({}) {:.*} -> {:.*} {}",
+            currency, *decimals, start, *decimals, end, change
+        );
+        if chart == "sparkline" {
+            println!("{}", sparkline(&resample(points, width)));
+            continue;
+        }
+        let values = resample(points, width * 2);
+        let max = values.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+        let min = values.iter().cloned().fold(std::f64::INFINITY, f64::min);
+        let lines = braille(&values, 8);
+        let last_line = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let label = if i == 0 {
+                format!("{:.*}", *decimals, max)
+            } else if i == last_line {
+                format!("{:.*}", *decimals, min)
+            } else {
+                "".to_string()
+            };
+            println!("{:>14} {}", label, line);
+        }
+    }
+}
+
+/**
+ * Each asset's share of the account at up to six snapshots spread over the range, largest
+ * holdings today first, and a sparkline of the share at every snapshot.
+ */
+fn print_weights(snapshots: &[StoredSnapshot]) {
+    let columns = snapshots.len().min(6);
+    let picked: Vec<&StoredSnapshot> = (0..columns)
+        .map(|i| &snapshots[if columns > 1 { i * (snapshots.len() - 1) / (columns - 1) } else { 0 }])
+        .collect();
+    let mut symbols: Vec<String> = vec![];
+    for snapshot in snapshots.iter().rev() {
+        for balance in &snapshot.balances {
+            if !symbols.contains(&balance.symbol) {
+                symbols.push(balance.symbol.to_string());
+            }
+        }
+    }
+    let mut table = Table::new();
+    let mut header = row!["Asset"];
+    for snapshot in &picked {
+        header.add_cell(cell!(format_time(snapshot.timestamp, DATE_FORMAT)));
+    }
+    header.add_cell(cell!("Trend"));
+    table.add_row(header);
+    for symbol in &symbols {
+        let mut row = row![symbol];
+        for snapshot in &picked {
+            row.add_cell(cell!(format!("{:.2}%", weight(snapshot, symbol) * 100.0)));
+        }
+        let trend: Vec<(u64, f64)> = snapshots.iter().map(|s| (s.timestamp, weight(s, symbol))).collect();
+        row.add_cell(cell!(sparkline(&resample(&trend, 20))));
+        table.add_row(row);
+    }
+    println!("\nAsset weights");
+    table.printstd();
+}
+
+fn weight(snapshot: &StoredSnapshot, symbol: &str) -> f64 {
+    if snapshot.total_usd <= 0.0 {
+        return 0.0;
+    }
+    snapshot.balances.iter()
+        .filter(|b| b.symbol == symbol)
+        .map(|b| b.value_usd)
+        .sum::<f64>() / snapshot.total_usd
+}
+
+/**
+ * One row per snapshot with its totals followed by the weight of every asset held in the range.
+ */
+fn write_history<W: std::io::Write>(writer: W, snapshots: &[StoredSnapshot]) -> Result<()> {
+    let symbols: BTreeSet<String> = snapshots.iter()
+        .flat_map(|s| s.balances.iter().map(|b| b.symbol.to_string()))
+        .collect();
+    let mut csv = csv::Writer::from_writer(writer);
+    let mut header: Vec<String> = ["timestamp", "account", "total_usd", "total_btc", "currency", "total_value"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+    header.extend(symbols.iter().cloned());
+    csv.write_record(&header).chain_err(|| "Could not write the header")?;
+    for snapshot in snapshots {
+        let mut record = vec![
+            format_time(snapshot.timestamp, RFC3339_FORMAT),
+            snapshot.account.to_string(),
+            snapshot.total_usd.to_string(),
+            snapshot.total_btc.to_string(),
+            snapshot.currency.to_string(),
+            snapshot.total_value.to_string(),
+        ];
+        record.extend(symbols.iter().map(|symbol| weight(snapshot, symbol).to_string()));
+        csv.write_record(&record).chain_err(|| "Could not write a snapshot")?;
+    }
+    csv.flush().chain_err(|| "Could not write the history")?;
+    Ok(())
+}
+
+fn print_performance(rows: &Vec<(String, PeriodReturns)>) {
+    let percent = |r: Option<f64>| match r {
+        Some(r) => colored_gain(format!("{:.2}%", r * 100.0), r),
+        None => "-".normal(),
+    };
+    let mut table = Table::new();
+    table.add_row(row![
+        "Name", "Value (USD)", "Net Deposits (USD)", "MTD", "QTD", "YTD", "Since Inception", "IRR (annualized)"
+    ]);
+    for (name, returns) in rows {
+        table.add_row(row![
+            name,
+            format!("{:.2}", returns.value),
+            format!("{:.2}", returns.net_flows),
+            percent(returns.mtd),
+            percent(returns.qtd),
+            percent(returns.ytd),
+            percent(returns.inception),
+            percent(returns.irr)
+        ]);
+    }
+    println!("Time weighted returns except for IRR. Values come from saved snapshots or are rebuilt from the ledger and daily closes.");
+    table.printstd();
+}
+
+/**
+ * Compares the history against BTC, ETH and an equal weight basket of the top assets by
+ * market cap when the comparison starts. Prices only come from local data, a benchmark
+ * without local prices for the whole period is skipped.
+ */
+fn benchmark(
+    sdk: &SDK,
+    store: Option<&PriceStore>,
+    history: &[DailyValue],
+    since: u64,
+    top: u64,
+) -> Result<Vec<BenchmarkComparison>> {
+    let (start, end) = match (history.iter().find(|v| v.day >= since), history.last()) {
+        (Some(first), Some(last)) => (first.day, last.day),
+        _ => bail!("There is no history to compare since {}", format_time(since, MINUTE_FORMAT)),
+    };
+    // One more day so the base value before the window is priced too.
+    let start = start.saturating_sub(60 * 60 * 24);
+    sdk.prices.go_offline();
+    let mut comparisons = vec![];
+    for symbol in &["BTC", "ETH"] {
+        match benchmark_levels(sdk, store, symbol, start, end) {
+            Ok(levels) => comparisons.push(compare_benchmark(&format!("Hold {}", symbol), history, &levels, since)),
+            Err(e) => println!("{} holding {}: {}", "Skipped".yellow(), symbol, e),
+        }
+    }
+    let members = match store {
+        Some(store) => store.top_symbols(start, top)?,
+        None => vec![],
+    };
+    if members.is_empty() {
+        println!("{}", "No stored listings to pick the top assets from. Skipping the equal weight basket.".yellow());
+    } else {
+        let mut levels = vec![];
+        for symbol in &members {
+            match benchmark_levels(sdk, store, symbol, start, end) {
+                Ok(l) => levels.push(l),
+                Err(e) => println!("{} {} from the equal weight basket: {}", "Skipped".yellow(), symbol, e),
+            }
+        }
+        let basket = equal_weight(&levels);
+        comparisons.push(compare_benchmark(&format!("Equal weight top {}", members.len()), history, &basket, since));
+    }
+    Ok(comparisons)
+}
+
+/**
+ * Daily closes of the symbol from the cached closes and the local price store, which wins on days
+ * both have. Nothing is fetched. Fails unless the closes reach from the start to the end.
+ */
+fn benchmark_levels(sdk: &SDK, store: Option<&PriceStore>, symbol: &str, start: u64, end: u64) -> Result<BTreeMap<u64, f64>> {
+    let day = 60 * 60 * 24;
+    let mut levels: BTreeMap<u64, f64> = sdk.prices.closes(symbol, start, end + day - 1, Resolution::Daily)?
+        .into_iter()
+        .map(|p| (p.timestamp - p.timestamp % day, p.price))
+        .collect();
+    if let Some(store) = store {
+        levels.extend(store.daily_closes(symbol, start)?.into_iter().filter(|(d, _)| *d <= end));
+    }
+    match (levels.keys().next(), levels.keys().next_back()) {
+        (Some(first), _) if *first > start + day => {
+            bail!("the local prices only start on {}", format_time(*first, MINUTE_FORMAT))
+        }
+        (_, Some(last)) if *last + day < end => {
+            bail!("the local prices stop on {}", format_time(*last, MINUTE_FORMAT))
+        }
+        (None, _) => bail!("there are no local prices for {}", symbol),
+        _ => Ok(levels),
+    }
+}
+
+fn print_benchmarks(name: &str, comparisons: &Vec<BenchmarkComparison>) {
+    let percent = |r: Option<f64>| match r {
+        Some(r) => format!("{:.2}%", r * 100.0),
+        None => "-".to_string(),
+    };
+    let mut table = Table::new();
+    table.add_row(row![
+        "Benchmark", "Return", name, "Excess Return", "Tracking Error", "Value If Held (USD)"
+    ]);
+    for c in comparisons {
+        table.add_row(row![
+            c.name,
+            percent(c.benchmark_return),
+            percent(c.portfolio_return),
+            match c.excess_return {
+                Some(r) => colored_gain(format!("{:.2}%", r * 100.0), r),
+                None => "-".normal(),
+            },
+            percent(c.tracking_error),
+            c.value_if_held.map(|v| format!("{:.2}", v)).unwrap_or_else(|| "-".to_string())
+        ]);
+    }
+    table.printstd();
+}
+
+fn print_tax_report(report: &TaxReport) {
+    let schedule_d = report.schedule_d();
+    let mut table = Table::new();
+    table.add_row(row!["Schedule D", "Proceeds", "Cost Basis", "Gain or Loss"]);
+    table.add_row(row![
+        "Short term",
+        format!("{:.2}", schedule_d.short_term_proceeds),
+        format!("{:.2}", schedule_d.short_term_cost),
+        format!("{:.2}", schedule_d.short_term_gain())
+    ]);
+    table.add_row(row![
+        "Long term",
+        format!("{:.2}", schedule_d.long_term_proceeds),
+        format!("{:.2}", schedule_d.long_term_cost),
+        format!("{:.2}", schedule_d.long_term_gain())
+    ]);
+    println!("Tax year {} using {} (USD)", report.year, report.method.name());
+    table.printstd();
+
+    let mut table = Table::new();
+    table.add_row(row!["Asset", "Proceeds", "Cost Basis", "Short Term", "Long Term", "Total"]);
+    for (asset, summary) in report.by_asset() {
+        let total = summary.short_term_gain() + summary.long_term_gain();
+        table.add_row(row![
+            asset,
+            format!("{:.2}", summary.short_term_proceeds + summary.long_term_proceeds),
+            format!("{:.2}", summary.short_term_cost + summary.long_term_cost),
+            format!("{:.2}", summary.short_term_gain()),
+            format!("{:.2}", summary.long_term_gain()),
+            if total < 0.0 { format!("{:.2}", total).red() } else { format!("{:.2}", total).green() }
+        ]);
+    }
+    table.printstd();
+    for issue in &report.issues {
+        println!("{} {}", "Skipped".yellow(), issue);
+    }
 }
 
 fn cmc_listings_as_map<'a>(listing: &'a CMCListingResponse) -> HashMap<String, &'a CMCListing> {
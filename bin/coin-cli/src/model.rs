@@ -1,10 +1,18 @@
+use chrono::prelude::*;
+use crate::cmc::{CMCClient, CMCListingResponse};
 use crate::errors::*;
+use coin_sdk::ledger::is_fiat;
 use std::collections::HashMap;
 
 // A single BTC can be divided 100 million times.
 // Therefore the smalest unit, called a satoshi, is equal to 0.00000001 BTC
 const BTC_FORMAT_MULTIPLIER: f64 = 100000000.0;
 const USD_FORMAT_MULTIPLIER: f64 = 100.0;
+// Formats for format_time.
+pub const MINUTE_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+// The same as chrono's to_rfc3339 in UTC.
+pub const RFC3339_FORMAT: &str = "%Y-%m-%dT%H:%M:%S+00:00";
 
 pub trait ExchangeOps {
   fn name(&self) -> &str;
@@ -13,6 +21,7 @@ pub trait ExchangeOps {
   fn get_account(&self, symbol: String) -> Result<Account>;
   fn all_prices(&self) -> Result<Vec<Price>>;
   fn get_price(&self, symbol: &str) -> Result<f64>;
+  fn volumes_24h(&self) -> Result<HashMap<String, f64>>;
   fn market_buy(&self, buy_into: String, buy_with: String, quantity_to_sell: f64) -> Result<Order>;
   fn market_sell(
     &self,
@@ -95,6 +104,9 @@ pub struct Portfolio {
   pub balances: Vec<PortfolioBalance>,
   pub total_usd: f64,
   pub total_btc: f64,
+  // The reporting currency and the total value expressed in it.
+  pub currency: String,
+  pub total_value: f64,
 }
 impl Portfolio {
   pub fn usd(&self) -> f64 {
@@ -103,6 +115,16 @@ impl Portfolio {
   pub fn btc(&self) -> f64 {
     (self.total_btc * BTC_FORMAT_MULTIPLIER).round() / BTC_FORMAT_MULTIPLIER
   }
+  pub fn value(&self) -> f64 {
+    round_currency(self.total_value, &self.currency)
+  }
+  /**
+   * USD and BTC are always reported so the reporting currency only adds
+   * information when it is something else.
+   */
+  pub fn has_extra_currency(&self) -> bool {
+    self.currency != "USD" && self.currency != "BTC"
+  }
 }
 
 /**
@@ -114,6 +136,8 @@ pub struct PortfolioBalance {
   pub quantity: f64,
   pub value_usd: f64,
   pub value_btc: f64,
+  // The value in the portfolio's reporting currency.
+  pub value: f64,
   pub change_7d: f64,
   pub change_24h: f64,
 }
@@ -124,4 +148,123 @@ impl PortfolioBalance {
   pub fn btc(&self) -> f64 {
     (self.value_btc * BTC_FORMAT_MULTIPLIER).round() / BTC_FORMAT_MULTIPLIER
   }
+  pub fn value_in(&self, currency: &str) -> f64 {
+    round_currency(self.value, currency)
+  }
+}
+
+/**
+ * The currency portfolio values are reported in alongside USD and BTC.
+ * The rate is the number of units of the currency that one USD buys.
+ */
+#[derive(Debug, Clone)]
+pub struct ReportingCurrency {
+  pub symbol: String,
+  pub usd_rate: f64,
+}
+impl ReportingCurrency {
+  pub fn usd() -> Self {
+    ReportingCurrency {
+      symbol: "USD".to_string(),
+      usd_rate: 1.0,
+    }
+  }
+  /**
+   * Looks up how many units of the currency one USD buys. USD needs no quotes and crypto rates
+   * come straight from the USD listings. Only fiat rates take another request, they are derived
+   * from BTC quoted in both USD and the target currency.
+   */
+  pub fn resolve(cmc: &CMCClient, listings: &CMCListingResponse, symbol: &str) -> Result<Self> {
+    let symbol = symbol.to_uppercase();
+    if symbol == "USD" {
+      return Ok(ReportingCurrency::usd());
+    }
+    let usd_price = |symbol: &str| {
+      listings.data.iter()
+        .find(|l| l.symbol == symbol)
+        .and_then(|l| l.quote.get("USD"))
+        .map(|q| q.price)
+    };
+    if let Some(price) = usd_price(&symbol).filter(|p| *p > 0.0) {
+      return Ok(ReportingCurrency { symbol, usd_rate: 1.0 / price });
+    }
+    let btc_usd = match usd_price("BTC") {
+      Some(p) => p,
+      None => bail!("Could not find BTC price"),
+    };
+    let converted = cmc.fetch_listings_in(1, &symbol)
+      .chain_err(|| format!("Could not fetch {} quotes from CoinMarketCap", symbol))?;
+    let btc_converted = converted.data.iter()
+      .find(|l| l.symbol == "BTC")
+      .and_then(|l| l.quote.get(&symbol))
+      .map(|q| q.price);
+    match btc_converted {
+      Some(p) if btc_usd > 0.0 => Ok(ReportingCurrency { symbol, usd_rate: p / btc_usd }),
+      _ => bail!("Could not find a {} quote on CoinMarketCap", symbol),
+    }
+  }
+
+  pub fn from_usd(&self, value_usd: f64) -> f64 {
+    value_usd * self.usd_rate
+  }
+}
+
+/**
+ * Fiat currencies are rounded to cents. Everything else is treated as a crypto asset and
+ * rounded to satoshis.
+ */
+pub fn round_currency(value: f64, currency: &str) -> f64 {
+  let multiplier = if is_fiat(currency) {
+    USD_FORMAT_MULTIPLIER
+  } else {
+    BTC_FORMAT_MULTIPLIER
+  };
+  (value * multiplier).round() / multiplier
+}
+
+/**
+ * Formats a unix timestamp in UTC, or gives back the number when it is out of range.
+ */
+pub fn format_time(timestamp: u64, format: &str) -> String {
+  match Utc.timestamp_opt(timestamp as i64, 0).single() {
+    Some(t) => t.format(format).to_string(),
+    None => timestamp.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+
+  #[test]
+  fn test_resolve_reporting_currency() {
+    // The client has no key, none of these may need a request.
+    let cmc = CMCClient::new(String::new());
+    let quote = |price: f64| json!({"USD": {
+      "price": price,
+      "volume_24h": 0.0,
+      "percent_change_1h": 0.0,
+      "percent_change_24h": 0.0,
+      "percent_change_7d": 0.0,
+      "market_cap": 0.0,
+    }});
+    let listing = |symbol: &str, price: f64| json!({
+      "id": 1, "name": symbol, "symbol": symbol, "slug": symbol, "cmc_rank": 1, "num_market_pairs": 1, "quote": quote(price),
+    });
+    let status = json!({"error_code": 0, "elapsed": 0, "credit_count": 1});
+    let listings: CMCListingResponse = serde_json::from_value(json!({
+      "data": [listing("BTC", 40000.0), listing("ETH", 2000.0)],
+      "status": status.clone(),
+    })).unwrap();
+    let empty: CMCListingResponse = serde_json::from_value(json!({"data": [], "status": status})).unwrap();
+
+    let usd = ReportingCurrency::resolve(&cmc, &empty, "usd").unwrap();
+    assert_eq!((&usd.symbol[..], usd.usd_rate), ("USD", 1.0));
+    let eth = ReportingCurrency::resolve(&cmc, &listings, "eth").unwrap();
+    assert_eq!((&eth.symbol[..], eth.usd_rate), ("ETH", 0.0005));
+    // Fiat needs the BTC listing before it asks for a conversion.
+    let error = ReportingCurrency::resolve(&cmc, &empty, "EUR").err().unwrap();
+    assert_eq!(error.to_string(), "Could not find BTC price");
+  }
 }
@@ -1,16 +1,476 @@
-// #[macro_use]
-// extern crate diesel;
-// extern crate dotenv;
-
-// use diesel::prelude::*;
-// use dotenv::dotenv;
-// use std::env;
-
-// pub fn establish_connection() -> PgConnection {
-//     dotenv().ok();
-
-//     let database_url = env::var("DATABASE_URL")
-//         .expect("DATABASE_URL must be set");
-//     PgConnection::establish(&database_url)
-//         .expect(&format!("Error connecting to {}", database_url))
-// }
\ No newline at end of file
+use std::collections::HashMap;
+use std::path::Path;
+use rusqlite::{params, Connection, NO_PARAMS};
+use crate::errors::*;
+use crate::model::{Order, Portfolio, PortfolioBalance};
+
+/**
+ * Schema changes in the order they were made. A database is at the version of the last migration
+ * applied to it, kept in `PRAGMA user_version`. Never edit a released migration, add a new one.
+ */
+const MIGRATIONS: [&str; 4] = [
+  "CREATE TABLE rebalance_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    base TEXT NOT NULL,
+    index_size INTEGER NOT NULL,
+    lookback INTEGER NOT NULL,
+    factor REAL NOT NULL,
+    mock INTEGER NOT NULL
+  );
+  CREATE TABLE rebalance_allocations (
+    run_id INTEGER NOT NULL REFERENCES rebalance_runs (id),
+    symbol TEXT NOT NULL,
+    weight REAL NOT NULL,
+    PRIMARY KEY (run_id, symbol)
+  );
+  CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    total_usd REAL NOT NULL,
+    total_btc REAL NOT NULL,
+    currency TEXT NOT NULL,
+    total_value REAL NOT NULL,
+    run_id INTEGER REFERENCES rebalance_runs (id)
+  );
+  CREATE INDEX snapshots_account_timestamp ON snapshots (account, timestamp);
+  CREATE TABLE snapshot_balances (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots (id),
+    symbol TEXT NOT NULL,
+    quantity REAL NOT NULL,
+    value_usd REAL NOT NULL,
+    value_btc REAL NOT NULL,
+    value REAL NOT NULL,
+    change_7d REAL NOT NULL,
+    change_24h REAL NOT NULL,
+    PRIMARY KEY (snapshot_id, symbol)
+  );
+  CREATE TABLE orders (
+    account TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    symbol TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    side TEXT NOT NULL,
+    run_id INTEGER REFERENCES rebalance_runs (id),
+    PRIMARY KEY (account, symbol, id)
+  );",
+  "ALTER TABLE rebalance_runs ADD COLUMN trigger TEXT NOT NULL DEFAULT 'manual';
+  ALTER TABLE rebalance_runs ADD COLUMN max_drift REAL;
+  ALTER TABLE rebalance_runs ADD COLUMN tracking_error REAL;",
+  "ALTER TABLE rebalance_runs ADD COLUMN confirmed INTEGER NOT NULL DEFAULT 0;",
+  "ALTER TABLE orders RENAME COLUMN side TO phase;",
+];
+
+/**
+ * The parameters of a `coin balance` run and the weights it picked.
+ */
+#[derive(Debug, Clone)]
+pub struct RebalanceRun {
+  pub timestamp: u64,
+  pub base: String,
+  pub index_size: u64,
+  pub lookback: u64,
+  pub factor: f64,
+  pub mock: bool,
+  // What started the run: manual, or drift when the watcher found the portfolio off its targets.
+  pub trigger: String,
+  // The largest drift of an asset and the tracking error that triggered a drift run.
+  pub max_drift: Option<f64>,
+  pub tracking_error: Option<f64>,
+}
+
+/**
+ * A saved snapshot of an account and its balances.
+ */
+#[derive(Debug)]
+pub struct StoredSnapshot {
+  pub id: i64,
+  pub account: String,
+  pub timestamp: u64,
+  pub total_usd: f64,
+  pub total_btc: f64,
+  pub currency: String,
+  pub total_value: f64,
+  pub balances: Vec<PortfolioBalance>,
+}
+
+/**
+ * The SnapshotStore keeps a local history of the portfolio in SQLite: a snapshot of every account
+ * each time it is saved or balanced, the orders placed and the rebalance runs that placed them.
+ */
+pub struct SnapshotStore {
+  conn: Connection,
+}
+
+impl SnapshotStore {
+  pub fn open(path: &Path) -> Result<Self> {
+    let conn = Connection::open(path).chain_err(|| format!("Could not open snapshot store {:?}", path))?;
+    let store = SnapshotStore { conn };
+    store.migrate().chain_err(|| format!("Could not migrate snapshot store {:?}", path))?;
+    Ok(store)
+  }
+
+  /**
+   * The schema version of the database.
+   */
+  pub fn version(&self) -> Result<usize> {
+    let version: i64 = self.conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    Ok(version as usize)
+  }
+
+  /**
+   * Applies every migration the database has not seen yet, each in its own transaction.
+   * Returns the number of migrations applied.
+   */
+  fn migrate(&self) -> Result<usize> {
+    let current = self.version()?;
+    let mut applied = 0;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+      self.conn.execute_batch(&format!(
+        "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+        migration,
+        i + 1
+      )).map_err(|e| {
+        let _ = self.conn.execute_batch("ROLLBACK;");
+        e
+      })?;
+      applied += 1;
+    }
+    Ok(applied)
+  }
+
+  /**
+   * Saves the totals and balances of an account. Snapshots taken after a rebalance are linked to its run.
+   * Returns the id of the snapshot.
+   */
+  pub fn record_snapshot(&self, account: &str, portfolio: &Portfolio, timestamp: u64, run_id: Option<i64>) -> Result<i64> {
+    self.conn.execute_batch("BEGIN;")?;
+    let id = self.insert_snapshot(account, portfolio, timestamp, run_id).map_err(|e| {
+      let _ = self.conn.execute_batch("ROLLBACK;");
+      e
+    })?;
+    self.conn.execute_batch("COMMIT;")?;
+    Ok(id)
+  }
+
+  fn insert_snapshot(&self, account: &str, portfolio: &Portfolio, timestamp: u64, run_id: Option<i64>) -> Result<i64> {
+    self.conn.execute(
+      "INSERT INTO snapshots (account, timestamp, total_usd, total_btc, currency, total_value, run_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+      params![
+        account,
+        timestamp as i64,
+        portfolio.total_usd,
+        portfolio.total_btc,
+        portfolio.currency,
+        portfolio.total_value,
+        run_id
+      ],
+    )?;
+    let id = self.conn.last_insert_rowid();
+    for balance in &portfolio.balances {
+      self.conn.execute(
+        "INSERT OR REPLACE INTO snapshot_balances
+          (snapshot_id, symbol, quantity, value_usd, value_btc, value, change_7d, change_24h)
+          VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+          id,
+          balance.symbol,
+          balance.quantity,
+          balance.value_usd,
+          balance.value_btc,
+          balance.value,
+          balance.change_7d,
+          balance.change_24h
+        ],
+      )?;
+    }
+    Ok(id)
+  }
+
+  /**
+   * Saves the parameters of a rebalance run. Returns the id orders and snapshots are linked with.
+   */
+  pub fn start_run(&self, run: &RebalanceRun) -> Result<i64> {
+    self.conn.execute(
+      "INSERT INTO rebalance_runs (timestamp, base, index_size, lookback, factor, mock, trigger, max_drift, tracking_error)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+      params![
+        run.timestamp as i64,
+        run.base,
+        run.index_size as i64,
+        run.lookback as i64,
+        run.factor,
+        run.mock,
+        run.trigger,
+        run.max_drift,
+        run.tracking_error
+      ],
+    )?;
+    Ok(self.conn.last_insert_rowid())
+  }
+
+  /**
+   * When the portfolio was last rebalanced: the last run that placed orders or was triggered by
+   * drift, dry runs included. Manual previews do not count.
+   */
+  pub fn last_rebalance(&self) -> Result<Option<u64>> {
+    let last: Option<i64> = self.conn.query_row(
+      "SELECT MAX(timestamp) FROM rebalance_runs WHERE mock = 0 OR trigger = 'drift'",
+      NO_PARAMS,
+      |row| row.get(0),
+    )?;
+    Ok(last.map(|t| t as u64))
+  }
+
+  /**
+   * How many dry runs drift has triggered.
+   */
+  pub fn drift_dry_runs(&self) -> Result<u64> {
+    let count: i64 = self.conn.query_row(
+      "SELECT COUNT(*) FROM rebalance_runs WHERE mock = 1 AND trigger = 'drift'",
+      NO_PARAMS,
+      |row| row.get(0),
+    )?;
+    Ok(count as u64)
+  }
+
+  /**
+   * Marks the last dry run drift triggered as confirmed, which lets drift place trades.
+   * Returns the id of the run, or None when drift has not triggered a dry run.
+   */
+  pub fn confirm_drift(&self) -> Result<Option<i64>> {
+    let id: Option<i64> = self.conn.query_row(
+      "SELECT MAX(id) FROM rebalance_runs WHERE mock = 1 AND trigger = 'drift'",
+      NO_PARAMS,
+      |row| row.get(0),
+    )?;
+    if let Some(id) = id {
+      self.conn.execute("UPDATE rebalance_runs SET confirmed = 1 WHERE id = ?1", params![id])?;
+    }
+    Ok(id)
+  }
+
+  /**
+   * Whether a dry run drift triggered was confirmed.
+   */
+  pub fn drift_confirmed(&self) -> Result<bool> {
+    let count: i64 = self.conn.query_row(
+      "SELECT COUNT(*) FROM rebalance_runs WHERE mock = 1 AND trigger = 'drift' AND confirmed = 1",
+      NO_PARAMS,
+      |row| row.get(0),
+    )?;
+    Ok(count > 0)
+  }
+
+  /**
+   * Saves the target weights a run picked.
+   */
+  pub fn record_allocations(&self, run_id: i64, allocations: &HashMap<String, f64>) -> Result<()> {
+    for (symbol, weight) in allocations {
+      self.conn.execute(
+        "INSERT OR REPLACE INTO rebalance_allocations (run_id, symbol, weight) VALUES (?1, ?2, ?3)",
+        params![run_id, symbol, weight],
+      )?;
+    }
+    Ok(())
+  }
+
+  /**
+   * Saves orders placed in an account. `phase` is "exit" or "enter" for orders placed by a rebalance.
+   * Returns the number of new orders.
+   */
+  pub fn record_orders(&self, account: &str, phase: &str, orders: &[Order], run_id: Option<i64>) -> Result<usize> {
+    let mut written = 0;
+    for order in orders {
+      written += self.conn.execute(
+        "INSERT OR IGNORE INTO orders (account, id, symbol, timestamp, phase, run_id)
+          VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![account, order.id as i64, order.symbol, order.timestamp as i64, phase, run_id],
+      )?;
+    }
+    Ok(written)
+  }
+
+  /**
+   * The snapshots of an account taken from `since` up to but not including `until`, oldest first.
+   */
+  pub fn snapshots(&self, account: &str, since: u64, until: u64) -> Result<Vec<StoredSnapshot>> {
+    let mut stmt = self.conn.prepare(
+      "SELECT id, account, timestamp, total_usd, total_btc, currency, total_value
+        FROM snapshots WHERE account = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp ASC, id ASC",
+    )?;
+    let rows = stmt.query_map(params![account, since as i64, until as i64], |row| {
+      Ok(StoredSnapshot {
+        id: row.get(0)?,
+        account: row.get(1)?,
+        timestamp: row.get::<_, i64>(2)? as u64,
+        total_usd: row.get(3)?,
+        total_btc: row.get(4)?,
+        currency: row.get(5)?,
+        total_value: row.get(6)?,
+        balances: vec![],
+      })
+    })?;
+    let mut snapshots = vec![];
+    for snapshot in rows {
+      let mut snapshot = snapshot?;
+      snapshot.balances = self.balances(snapshot.id)?;
+      snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+  }
+
+  fn balances(&self, snapshot_id: i64) -> Result<Vec<PortfolioBalance>> {
+    let mut stmt = self.conn.prepare(
+      "SELECT symbol, quantity, value_usd, value_btc, value, change_7d, change_24h
+        FROM snapshot_balances WHERE snapshot_id = ?1 ORDER BY value_usd DESC",
+    )?;
+    let rows = stmt.query_map(params![snapshot_id], |row| {
+      Ok(PortfolioBalance {
+        symbol: row.get(0)?,
+        quantity: row.get(1)?,
+        value_usd: row.get(2)?,
+        value_btc: row.get(3)?,
+        value: row.get(4)?,
+        change_7d: row.get(5)?,
+        change_24h: row.get(6)?,
+      })
+    })?;
+    let mut balances = vec![];
+    for balance in rows {
+      balances.push(balance?);
+    }
+    Ok(balances)
+  }
+
+  /**
+   * Every account with at least one snapshot.
+   */
+  pub fn accounts(&self) -> Result<Vec<String>> {
+    let mut stmt = self.conn.prepare("SELECT DISTINCT account FROM snapshots ORDER BY account")?;
+    let rows = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+    let mut accounts = vec![];
+    for account in rows {
+      accounts.push(account?);
+    }
+    Ok(accounts)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use crate::model::{Order, Portfolio, PortfolioBalance};
+  use super::*;
+
+  fn portfolio(total_usd: f64) -> Portfolio {
+    let balance = |symbol: &str, value_usd: f64| PortfolioBalance {
+      symbol: symbol.to_string(),
+      quantity: 1.0,
+      value_usd,
+      value_btc: value_usd / 10000.0,
+      value: value_usd,
+      change_7d: 0.0,
+      change_24h: 0.0,
+    };
+    Portfolio {
+      balances: vec![balance("ETH", total_usd / 4.0), balance("BTC", total_usd * 3.0 / 4.0)],
+      total_usd,
+      total_btc: total_usd / 10000.0,
+      currency: "USD".to_string(),
+      total_value: total_usd,
+    }
+  }
+
+  fn run(timestamp: u64, mock: bool, trigger: &str) -> RebalanceRun {
+    RebalanceRun {
+      timestamp,
+      base: "BTC".to_string(),
+      index_size: 10,
+      lookback: 20,
+      factor: 0.3,
+      mock,
+      trigger: trigger.to_string(),
+      max_drift: None,
+      tracking_error: None,
+    }
+  }
+
+  #[test]
+  fn test_migrations() {
+    let path = env::temp_dir().join(format!("coin-persist-{}.db", std::process::id()));
+    let _ = fs::remove_file(&path);
+    // A database written by the first release.
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch(&format!("{} PRAGMA user_version = 1;", MIGRATIONS[0])).unwrap();
+    conn.execute_batch(
+      "INSERT INTO rebalance_runs (timestamp, base, index_size, lookback, factor, mock) VALUES (100, 'BTC', 10, 20, 0.3, 0);
+      INSERT INTO orders (account, id, symbol, timestamp, side, run_id) VALUES ('binance', 1, 'ETHBTC', 100, 'exit', 1);",
+    ).unwrap();
+    drop(conn);
+
+    let store = SnapshotStore::open(&path).unwrap();
+    assert_eq!(store.version().unwrap(), MIGRATIONS.len());
+    assert_eq!(store.last_rebalance().unwrap(), Some(100));
+    let phase: String = store.conn.query_row("SELECT phase FROM orders WHERE id = 1", NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(phase, "exit");
+    let trigger: String = store.conn.query_row("SELECT trigger FROM rebalance_runs", NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(trigger, "manual");
+    drop(store);
+    // Opening it again applies nothing.
+    assert_eq!(SnapshotStore::open(&path).unwrap().migrate().unwrap(), 0);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_record_snapshot() {
+    let store = SnapshotStore::open(Path::new(":memory:")).unwrap();
+    let run_id = store.start_run(&run(150, false, "manual")).unwrap();
+    store.record_snapshot("binance", &portfolio(1000.0), 100, None).unwrap();
+    store.record_snapshot("binance", &portfolio(2000.0), 200, Some(run_id)).unwrap();
+    store.record_snapshot("coinbase", &portfolio(500.0), 200, None).unwrap();
+
+    let snapshots = store.snapshots("binance", 0, 300).unwrap();
+    assert_eq!(snapshots.iter().map(|s| s.total_usd).collect::<Vec<f64>>(), vec![1000.0, 2000.0]);
+    // Balances come back largest first.
+    let symbols: Vec<&str> = snapshots[1].balances.iter().map(|b| &b.symbol[..]).collect();
+    assert_eq!(symbols, vec!["BTC", "ETH"]);
+    assert_eq!(snapshots[1].balances[0].value_usd, 1500.0);
+    // The window includes its start and excludes its end.
+    assert_eq!(store.snapshots("binance", 100, 200).unwrap().len(), 1);
+    assert_eq!(store.accounts().unwrap(), vec!["binance", "coinbase"]);
+  }
+
+  #[test]
+  fn test_rebalance_runs() {
+    let store = SnapshotStore::open(Path::new(":memory:")).unwrap();
+    assert_eq!(store.last_rebalance().unwrap(), None);
+    assert_eq!(store.confirm_drift().unwrap(), None);
+    // Manual previews do not count as rebalances.
+    store.start_run(&run(100, true, "manual")).unwrap();
+    assert_eq!(store.last_rebalance().unwrap(), None);
+    store.start_run(&run(200, true, "drift")).unwrap();
+    let dry_run = store.start_run(&run(300, true, "drift")).unwrap();
+    assert_eq!(store.last_rebalance().unwrap(), Some(300));
+    assert_eq!(store.drift_dry_runs().unwrap(), 2);
+    assert!(!store.drift_confirmed().unwrap());
+    assert_eq!(store.confirm_drift().unwrap(), Some(dry_run));
+    assert!(store.drift_confirmed().unwrap());
+
+    let run_id = store.start_run(&run(400, false, "drift")).unwrap();
+    let allocations = vec![("BTC".to_string(), 0.75), ("ETH".to_string(), 0.25)].into_iter().collect();
+    store.record_allocations(run_id, &allocations).unwrap();
+    let order = |id: u64| Order { symbol: "ETHBTC".to_string(), id, timestamp: 400 };
+    assert_eq!(store.record_orders("binance", "exit", &[order(1)], Some(run_id)).unwrap(), 1);
+    // Orders already saved are skipped.
+    assert_eq!(store.record_orders("binance", "enter", &[order(1), order(2)], Some(run_id)).unwrap(), 1);
+    let phases: Vec<String> = store.conn.prepare("SELECT phase FROM orders ORDER BY id").unwrap()
+      .query_map(NO_PARAMS, |row| row.get(0)).unwrap()
+      .map(|p| p.unwrap())
+      .collect();
+    assert_eq!(phases, vec!["exit", "enter"]);
+  }
+}
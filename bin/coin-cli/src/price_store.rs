@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use chrono::prelude::*;
+use rusqlite::{params, Connection, NO_PARAMS};
+use crate::cmc::CMCListingResponse;
+use crate::errors::*;
+
+const SECONDS_IN_DAY: u64 = 60 * 60 * 24;
+
+/**
+ * A single asset's quote from a stored listings snapshot.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredQuote {
+  pub timestamp: u64,
+  pub symbol: String,
+  pub cmc_rank: u64,
+  pub price: f64,
+  pub volume_24h: f64,
+  pub market_cap: f64,
+  pub percent_change_24h: f64,
+}
+
+/**
+ * The PriceStore keeps every CoinMarketCap listing we fetch in a local SQLite
+ * database so we build up our own history of prices, volumes and market caps.
+ */
+pub struct PriceStore {
+  conn: Connection,
+}
+
+impl PriceStore {
+  pub fn open(path: &Path) -> Result<Self> {
+    let conn = Connection::open(path).chain_err(|| format!("Could not open price store {:?}", path))?;
+    conn.execute(
+      "CREATE TABLE IF NOT EXISTS listings (
+        timestamp INTEGER NOT NULL,
+        symbol TEXT NOT NULL,
+        cmc_rank INTEGER NOT NULL,
+        price REAL NOT NULL,
+        volume_24h REAL NOT NULL,
+        market_cap REAL NOT NULL,
+        percent_change_24h REAL NOT NULL,
+        PRIMARY KEY (symbol, timestamp)
+      )",
+      NO_PARAMS,
+    )?;
+    Ok(PriceStore { conn })
+  }
+
+  /**
+   * Saves the USD quote of every listing in the response. Returns the number of rows written.
+   */
+  pub fn record_listings(&self, listings: &CMCListingResponse) -> Result<usize> {
+    let timestamp = listings.status.timestamp.as_ref()
+      .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+      .map(|t| t.timestamp() as u64)
+      .unwrap_or_else(|| Utc::now().timestamp() as u64);
+    let mut written = 0;
+    for listing in &listings.data {
+      // Skip the placeholder USD listing added by fill_usd.
+      if listing.symbol == "USD" {
+        continue;
+      }
+      if let Some(quote) = listing.quote.get("USD") {
+        written += self.conn.execute(
+          "INSERT OR REPLACE INTO listings
+            (timestamp, symbol, cmc_rank, price, volume_24h, market_cap, percent_change_24h)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+          params![
+            timestamp as i64,
+            listing.symbol,
+            listing.cmc_rank as i64,
+            quote.price,
+            quote.volume_24h,
+            quote.market_cap,
+            quote.percent_change_24h
+          ],
+        )?;
+      }
+    }
+    Ok(written)
+  }
+
+  /**
+   * Every stored quote for a symbol since the given timestamp, oldest first.
+   */
+  pub fn history(&self, symbol: &str, since: u64) -> Result<Vec<StoredQuote>> {
+    let mut stmt = self.conn.prepare(
+      "SELECT timestamp, symbol, cmc_rank, price, volume_24h, market_cap, percent_change_24h
+        FROM listings WHERE symbol = ?1 AND timestamp >= ?2 ORDER BY timestamp ASC",
+    )?;
+    let rows = stmt.query_map(params![symbol.to_uppercase(), since as i64], |row| {
+      Ok(StoredQuote {
+        timestamp: row.get::<_, i64>(0)? as u64,
+        symbol: row.get(1)?,
+        cmc_rank: row.get::<_, i64>(2)? as u64,
+        price: row.get(3)?,
+        volume_24h: row.get(4)?,
+        market_cap: row.get(5)?,
+        percent_change_24h: row.get(6)?,
+      })
+    })?;
+    let mut quotes = vec![];
+    for quote in rows {
+      quotes.push(quote?);
+    }
+    Ok(quotes)
+  }
+
+  /**
+   * The last stored price of each UTC day since the timestamp keyed by the start of the day.
+   */
+  pub fn daily_closes(&self, symbol: &str, since: u64) -> Result<BTreeMap<u64, f64>> {
+    let mut closes = BTreeMap::new();
+    for quote in self.history(symbol, since)? {
+      closes.insert(quote.timestamp - quote.timestamp % SECONDS_IN_DAY, quote.price);
+    }
+    Ok(closes)
+  }
+
+  /**
+   * The symbols ranked 1 to n in the first snapshot at or after the timestamp,
+   * or the last snapshot before it when there is none.
+   */
+  pub fn top_symbols(&self, timestamp: u64, n: u64) -> Result<Vec<String>> {
+    let snapshot: Option<i64> = self.conn.query_row(
+      "SELECT COALESCE(
+        (SELECT MIN(timestamp) FROM listings WHERE timestamp >= ?1),
+        (SELECT MAX(timestamp) FROM listings WHERE timestamp < ?1))",
+      params![timestamp as i64],
+      |row| row.get(0),
+    )?;
+    let snapshot = match snapshot {
+      Some(s) => s,
+      None => return Ok(vec![]),
+    };
+    let mut stmt = self.conn.prepare(
+      "SELECT symbol FROM listings WHERE timestamp = ?1 ORDER BY cmc_rank ASC LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(params![snapshot, n as i64], |row| row.get(0))?;
+    let mut symbols = vec![];
+    for symbol in rows {
+      symbols.push(symbol?);
+    }
+    Ok(symbols)
+  }
+
+  /**
+   * The last stored market cap of each UTC day over the lookback window, oldest first.
+   * Days without a snapshot are skipped.
+   */
+  pub fn daily_market_caps(&self, symbol: &str, lookback_days: u64) -> Result<Vec<f64>> {
+    let now = Utc::now().timestamp() as u64;
+    let beginning_of_today = now - now % SECONDS_IN_DAY;
+    let since = beginning_of_today - SECONDS_IN_DAY * lookback_days;
+    let mut caps: Vec<(u64, f64)> = vec![];
+    for quote in self.history(symbol, since)? {
+      let day = quote.timestamp - quote.timestamp % SECONDS_IN_DAY;
+      match caps.last_mut() {
+        Some(last) if last.0 == day => last.1 = quote.market_cap,
+        _ => caps.push((day, quote.market_cap)),
+      }
+    }
+    Ok(caps.into_iter().map(|(_, cap)| cap).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+
+  // A listings response with the symbol, rank and price of each listing.
+  fn listings(timestamp: &str, quotes: &[(&str, u64, f64)]) -> CMCListingResponse {
+    let data: Vec<_> = quotes.iter().map(|(symbol, rank, price)| json!({
+      "id": rank,
+      "name": symbol,
+      "symbol": symbol,
+      "slug": symbol,
+      "cmc_rank": rank,
+      "num_market_pairs": 10,
+      "quote": {"USD": {
+        "price": price,
+        "volume_24h": 1000.0,
+        "percent_change_1h": 0.0,
+        "percent_change_24h": 0.0,
+        "percent_change_7d": 0.0,
+        "market_cap": price * 100.0,
+      }},
+    })).collect();
+    let status = json!({"timestamp": timestamp, "error_code": 0, "elapsed": 0, "credit_count": 1});
+    serde_json::from_value(json!({"data": data, "status": status})).unwrap()
+  }
+
+  #[test]
+  fn test_record_listings() {
+    let store = PriceStore::open(Path::new(":memory:")).unwrap();
+    let first = listings("2021-01-01T12:00:00Z", &[("BTC", 1, 30000.0), ("ETH", 2, 700.0), ("USD", 99999, 1.0)]);
+    assert_eq!(store.record_listings(&first).unwrap(), 2);
+    store.record_listings(&listings("2021-01-01T18:00:00Z", &[("BTC", 1, 31000.0), ("ETH", 2, 750.0)])).unwrap();
+    store.record_listings(&listings("2021-01-02T12:00:00Z", &[("ETH", 1, 800.0), ("BTC", 2, 32000.0)])).unwrap();
+
+    let history = store.history("btc", 0).unwrap();
+    assert_eq!(history.iter().map(|q| q.price).collect::<Vec<f64>>(), vec![30000.0, 31000.0, 32000.0]);
+    assert_eq!(history[2].cmc_rank, 2);
+    // The last price of each day is its close.
+    let closes: Vec<(u64, f64)> = store.daily_closes("BTC", 0).unwrap().into_iter().collect();
+    assert_eq!(closes, vec![(1609459200, 31000.0), (1609545600, 32000.0)]);
+
+    assert_eq!(store.top_symbols(0, 1).unwrap(), vec!["BTC"]);
+    assert_eq!(store.top_symbols(1609545600, 5).unwrap(), vec!["ETH", "BTC"]);
+    // After the last snapshot the last one counts.
+    assert_eq!(store.top_symbols(1700000000, 1).unwrap(), vec!["ETH"]);
+  }
+
+  #[test]
+  fn test_daily_market_caps() {
+    let store = PriceStore::open(Path::new(":memory:")).unwrap();
+    let now = Utc::now().timestamp();
+    let today = now - now % SECONDS_IN_DAY as i64;
+    let at = |timestamp: i64| Utc.timestamp_opt(timestamp, 0).single().unwrap().to_rfc3339();
+    store.record_listings(&listings(&at(today - 3 * SECONDS_IN_DAY as i64), &[("BTC", 1, 10.0)])).unwrap();
+    store.record_listings(&listings(&at(today - SECONDS_IN_DAY as i64), &[("BTC", 1, 20.0)])).unwrap();
+    store.record_listings(&listings(&at(today), &[("BTC", 1, 30.0)])).unwrap();
+    store.record_listings(&listings(&at(today + 1), &[("BTC", 1, 40.0)])).unwrap();
+    // Two days back, the missing day is skipped and today's last snapshot counts.
+    assert_eq!(store.daily_market_caps("BTC", 2).unwrap(), vec![2000.0, 4000.0]);
+  }
+}
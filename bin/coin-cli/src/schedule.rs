@@ -0,0 +1,179 @@
+use chrono::{Datelike, Duration, NaiveDateTime, Timelike};
+use crate::errors::*;
+
+// How far ahead next_after looks before giving up on a schedule, e.g. one that only matches Feb 30.
+const MAX_DAYS_AHEAD: i64 = 366 * 5;
+
+/**
+ * A cron schedule: minute, hour, day of month, month and day of week, e.g. `30 2 * * 1-5`.
+ * Fields take `*`, numbers, ranges `a-b`, steps over either written with a slash such as `a-b/n`
+ * and lists of those separated by commas. Sunday is 0 or 7. Like cron, when both the day of month
+ * and the day of week are restricted a time matches if either does. `@hourly`, `@daily`,
+ * `@weekly`, `@monthly` and `@yearly` are accepted as well.
+ */
+#[derive(Debug, Clone)]
+pub struct Schedule {
+  pub expression: String,
+  minutes: Vec<bool>,
+  hours: Vec<bool>,
+  days: Vec<bool>,
+  months: Vec<bool>,
+  weekdays: Vec<bool>,
+  days_restricted: bool,
+  weekdays_restricted: bool,
+}
+
+impl Schedule {
+  pub fn parse(expression: &str) -> Result<Self> {
+    let expanded = match expression.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      "@yearly" | "@annually" => "0 0 1 1 *",
+      other => other,
+    };
+    let fields: Vec<&str> = expanded.split_whitespace().collect();
+    if fields.len() != 5 {
+      bail!("Invalid schedule '{}'. Use five fields: minute hour day-of-month month day-of-week.", expression);
+    }
+    let field = |i: usize, min: usize, max: usize| {
+      parse_field(fields[i], min, max).chain_err(|| format!("Invalid schedule '{}'", expression))
+    };
+    let mut weekdays = field(4, 0, 7)?;
+    // 7 is another name for Sunday.
+    if weekdays[7] {
+      weekdays[0] = true;
+    }
+    weekdays.truncate(7);
+    Ok(Schedule {
+      expression: expression.trim().to_string(),
+      minutes: field(0, 0, 59)?,
+      hours: field(1, 0, 23)?,
+      days: field(2, 1, 31)?,
+      months: field(3, 1, 12)?,
+      weekdays,
+      days_restricted: fields[2] != "*",
+      weekdays_restricted: fields[4] != "*",
+    })
+  }
+
+  /**
+   * Whether the schedule fires at the minute of the given time.
+   */
+  pub fn matches(&self, time: &NaiveDateTime) -> bool {
+    self.minutes[time.minute() as usize] && self.hours[time.hour() as usize] && self.matches_day(time)
+  }
+
+  /**
+   * The first minute after `time` the schedule fires at.
+   */
+  pub fn next_after(&self, time: &NaiveDateTime) -> Option<NaiveDateTime> {
+    let start = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+    for day in 0..MAX_DAYS_AHEAD {
+      let date = start.date() + Duration::days(day);
+      let midnight = date.and_hms_opt(0, 0, 0)?;
+      if !self.matches_day(&midnight) {
+        continue;
+      }
+      for hour in (0..24).filter(|h| self.hours[*h as usize]) {
+        for minute in (0..60).filter(|m| self.minutes[*m as usize]) {
+          let candidate = date.and_hms_opt(hour, minute, 0)?;
+          if candidate >= start {
+            return Some(candidate);
+          }
+        }
+      }
+    }
+    None
+  }
+
+  fn matches_day(&self, time: &NaiveDateTime) -> bool {
+    if !self.months[time.month() as usize] {
+      return false;
+    }
+    let day = self.days[time.day() as usize];
+    let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+    match (self.days_restricted, self.weekdays_restricted) {
+      (true, true) => day || weekday,
+      (true, false) => day,
+      (false, true) => weekday,
+      (false, false) => true,
+    }
+  }
+}
+
+// The values a field allows, indexed from 0 up to max.
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>> {
+  let mut allowed = vec![false; max + 1];
+  for part in field.split(',') {
+    let (range, step) = match part.find('/') {
+      Some(i) => {
+        let step: usize = part[i + 1..].parse().chain_err(|| format!("Invalid step in '{}'", part))?;
+        if step == 0 {
+          bail!("Invalid step in '{}'", part);
+        }
+        (&part[..i], step)
+      }
+      None => (part, 1),
+    };
+    let (from, to) = if range == "*" {
+      (min, max)
+    } else if let Some(i) = range.find('-') {
+      (parse_value(&range[..i], min, max)?, parse_value(&range[i + 1..], min, max)?)
+    } else {
+      let value = parse_value(range, min, max)?;
+      // `5/15` means every 15 starting at 5.
+      (value, if step > 1 { max } else { value })
+    };
+    if from > to {
+      bail!("Invalid range '{}'", range);
+    }
+    for value in (from..=to).step_by(step) {
+      allowed[value] = true;
+    }
+  }
+  Ok(allowed)
+}
+
+fn parse_value(value: &str, min: usize, max: usize) -> Result<usize> {
+  match value.parse::<usize>() {
+    Ok(v) if v >= min && v <= max => Ok(v),
+    _ => bail!("'{}' is not between {} and {}", value, min, max),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::NaiveDate;
+  use super::*;
+
+  #[test]
+  fn test_schedule() {
+    let at = |y: i32, mo: u32, d: u32, h: u32, mi: u32| NaiveDate::from_ymd_opt(y, mo, d).and_then(|d| d.and_hms_opt(h, mi, 0)).unwrap();
+    // 2019-06-03 is a Monday.
+    let weekdays = Schedule::parse("30 9-17/4 * * 1-5").unwrap();
+    assert!(weekdays.matches(&at(2019, 6, 3, 13, 30)));
+    assert!(!weekdays.matches(&at(2019, 6, 3, 11, 30)));
+    assert!(!weekdays.matches(&at(2019, 6, 8, 13, 30)));
+    assert_eq!(weekdays.next_after(&at(2019, 6, 3, 17, 30)), Some(at(2019, 6, 4, 9, 30)));
+    assert_eq!(weekdays.next_after(&at(2019, 6, 7, 17, 45)), Some(at(2019, 6, 10, 9, 30)));
+
+    let every = Schedule::parse("*/15 * * * *").unwrap();
+    assert_eq!(every.next_after(&at(2019, 6, 3, 23, 50)), Some(at(2019, 6, 4, 0, 0)));
+    assert_eq!(every.next_after(&at(2019, 6, 3, 10, 0)), Some(at(2019, 6, 3, 10, 15)));
+
+    // Sunday can be 0 or 7, and a restricted day of month or week matches either.
+    let sundays = Schedule::parse("0 0 1 * 7").unwrap();
+    assert!(sundays.matches(&at(2019, 6, 9, 0, 0)));
+    assert!(sundays.matches(&at(2019, 7, 1, 0, 0)));
+    assert!(!sundays.matches(&at(2019, 7, 2, 0, 0)));
+
+    assert_eq!(Schedule::parse("@daily").unwrap().next_after(&at(2019, 6, 3, 0, 0)), Some(at(2019, 6, 4, 0, 0)));
+    assert_eq!(Schedule::parse("0 0 30 2 *").unwrap().next_after(&at(2019, 6, 3, 0, 0)), None);
+    assert!(Schedule::parse("* * * *").is_err());
+    assert!(Schedule::parse("60 * * * *").is_err());
+    assert!(Schedule::parse("*/0 * * * *").is_err());
+    assert!(Schedule::parse("5-1 * * * *").is_err());
+  }
+}
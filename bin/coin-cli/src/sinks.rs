@@ -0,0 +1,363 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use serde_json::{json, Value};
+use crate::airtable::{AirtableClient, AirtableConfig, ColumnMap, DetailRecord};
+use crate::errors::*;
+use crate::model::{format_time, Portfolio, RFC3339_FORMAT};
+use crate::persist::SnapshotStore;
+
+/**
+ * Where snapshots are written besides airtable, configured in the [sinks] table of ~/.coin.toml.
+ */
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct SinksConfig {
+  // Record snapshots in the local SQLite store. Defaults to true.
+  pub sqlite: Option<bool>,
+  pub csv: Option<FileSinkConfig>,
+  pub jsonl: Option<FileSinkConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FileSinkConfig {
+  pub path: String,
+  // Columns default to the field names: name, timestamp, total_usd, total_btc, currency, total_value and details.
+  pub column_map: Option<ColumnMap>,
+}
+
+/**
+ * A portfolio snapshot as handed to every sink.
+ */
+pub struct Snapshot<'a> {
+  pub account: &'a str,
+  pub timestamp: u64,
+  pub portfolio: &'a Portfolio,
+  // The rebalance run the snapshot was taken after, if any.
+  pub run_id: Option<i64>,
+}
+
+impl<'a> Snapshot<'a> {
+  /**
+   * The fields of the snapshot in column order, keyed by their default column names.
+   */
+  fn fields(&self) -> Result<Vec<(&'static str, Value)>> {
+    let p = self.portfolio;
+    Ok(vec![
+      ("name", json!(self.account)),
+      ("timestamp", json!(format_time(self.timestamp, RFC3339_FORMAT))),
+      ("total_usd", json!(p.total_usd)),
+      ("total_btc", json!(p.total_btc)),
+      ("currency", json!(p.currency)),
+      ("total_value", json!(p.total_value)),
+      ("details", serde_json::to_value(&p.balances).chain_err(|| "Could not serialize balances")?),
+    ])
+  }
+}
+
+/**
+ * Somewhere portfolio snapshots are kept.
+ */
+pub trait SnapshotSink {
+  fn name(&self) -> &str;
+  fn write(&self, snapshot: &Snapshot) -> Result<()>;
+}
+
+/**
+ * Writes the snapshot to every sink. A sink that fails does not stop the others from being written,
+ * the outcome of each is returned with the sink's name.
+ */
+pub fn write_snapshot(sinks: &[Box<dyn SnapshotSink + '_>], snapshot: &Snapshot) -> Vec<(String, Result<()>)> {
+  sinks.iter().map(|sink| (sink.name().to_string(), sink.write(snapshot))).collect()
+}
+
+/**
+ * Records snapshots in the local SQLite store. A store that could not be opened fails every write
+ * with the reason, so the other sinks are still written.
+ */
+pub struct SqliteSink {
+  store: Result<SnapshotStore>,
+}
+impl SqliteSink {
+  pub fn new(store: Result<SnapshotStore>) -> Self {
+    SqliteSink { store }
+  }
+}
+impl SnapshotSink for SqliteSink {
+  fn name(&self) -> &str {
+    "sqlite"
+  }
+  fn write(&self, snapshot: &Snapshot) -> Result<()> {
+    match &self.store {
+      Ok(store) => {
+        store.record_snapshot(snapshot.account, snapshot.portfolio, snapshot.timestamp, snapshot.run_id)?;
+        Ok(())
+      }
+      Err(e) => bail!("{}", e.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(": ")),
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize)]
+struct AccountRecord {
+  total_usd: f64,
+  total_btc: f64,
+  timestamp: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  details: Option<String>,
+  name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  total_value: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  currency: Option<String>,
+}
+
+/**
+ * Creates a row in an airtable table. Columns are renamed by the [airtable.column_map]. With a
+ * detail table every asset also gets a row there, linked to the snapshot's row.
+ */
+pub struct AirtableSink<'a> {
+  client: AirtableClient<'a>,
+}
+impl<'a> AirtableSink<'a> {
+  pub fn new(config: &'a AirtableConfig) -> Self {
+    AirtableSink { client: AirtableClient::new(config) }
+  }
+}
+impl<'a> SnapshotSink for AirtableSink<'a> {
+  fn name(&self) -> &str {
+    "airtable"
+  }
+  fn write(&self, snapshot: &Snapshot) -> Result<()> {
+    let portfolio = snapshot.portfolio;
+    // Only send the reporting currency columns when they carry something new.
+    let (total_value, currency) = if portfolio.has_extra_currency() {
+      (Some(portfolio.total_value), Some(portfolio.currency.to_owned()))
+    } else {
+      (None, None)
+    };
+    // Airtable has always been sent chrono's default format.
+    let timestamp = format_time(snapshot.timestamp, "%Y-%m-%d %H:%M:%S UTC");
+    let details = if self.client.has_details() {
+      None
+    } else {
+      Some(serde_json::to_string_pretty(&portfolio.balances).chain_err(|| "Could not serialize balances")?)
+    };
+    let record = AccountRecord {
+      total_usd: portfolio.total_usd,
+      total_btc: portfolio.total_btc,
+      name: snapshot.account.to_owned(),
+      timestamp,
+      details,
+      total_value,
+      currency,
+    };
+    let value = serde_json::to_value(record).chain_err(|| "Could not serialize the airtable record")?;
+    let id = self.client.create_record(value)?;
+    if self.client.has_details() {
+      let rows: Vec<DetailRecord> = portfolio.balances.iter().map(|b| DetailRecord {
+        symbol: b.symbol.to_string(),
+        quantity: b.quantity,
+        value_usd: b.value_usd,
+        value_btc: b.value_btc,
+        weight: if portfolio.total_usd > 0.0 { b.value_usd / portfolio.total_usd } else { 0.0 },
+      }).collect();
+      self.client.create_details(&id, &rows)
+        .chain_err(|| format!("Saved the snapshot as {} but not the rows of its assets", id))?;
+    }
+    Ok(())
+  }
+}
+
+/**
+ * Appends a row per snapshot to a CSV file. The header is written when the file is created and must
+ * match the configured columns afterwards, so a changed column map never mixes layouts in one file.
+ */
+pub struct CsvSink<'a> {
+  config: &'a FileSinkConfig,
+}
+impl<'a> CsvSink<'a> {
+  pub fn new(config: &'a FileSinkConfig) -> Self {
+    CsvSink { config }
+  }
+}
+impl<'a> SnapshotSink for CsvSink<'a> {
+  fn name(&self) -> &str {
+    "csv"
+  }
+  fn write(&self, snapshot: &Snapshot) -> Result<()> {
+    let path = PathBuf::from(&self.config.path);
+    let fields = snapshot.fields()?;
+    let header: Vec<String> = fields.iter().map(|(f, _)| column(&self.config.column_map, f)).collect();
+    let is_new = fs::metadata(&path).map(|m| m.len() == 0).unwrap_or(true);
+    if !is_new {
+      let mut reader = csv::Reader::from_path(&path).chain_err(|| format!("Could not read {}", path.display()))?;
+      let existing: Vec<String> = reader.headers()
+        .chain_err(|| format!("Could not read the header of {}", path.display()))?
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+      if existing != header {
+        bail!("{} has the columns {:?} but the column map gives {:?}", path.display(), existing, header);
+      }
+    }
+    let file = OpenOptions::new().create(true).append(true).open(&path)
+      .chain_err(|| format!("Could not open {}", path.display()))?;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_new {
+      writer.write_record(&header).chain_err(|| format!("Could not write {}", path.display()))?;
+    }
+    let row: Vec<String> = fields.iter().map(|(_, v)| cell(v)).collect();
+    writer.write_record(&row).chain_err(|| format!("Could not write {}", path.display()))?;
+    writer.flush().chain_err(|| format!("Could not write {}", path.display()))?;
+    Ok(())
+  }
+}
+
+/**
+ * Appends a JSON object per snapshot to a JSON Lines file. Balances are kept as an array.
+ */
+pub struct JsonLinesSink<'a> {
+  config: &'a FileSinkConfig,
+}
+impl<'a> JsonLinesSink<'a> {
+  pub fn new(config: &'a FileSinkConfig) -> Self {
+    JsonLinesSink { config }
+  }
+}
+impl<'a> SnapshotSink for JsonLinesSink<'a> {
+  fn name(&self) -> &str {
+    "jsonl"
+  }
+  fn write(&self, snapshot: &Snapshot) -> Result<()> {
+    let path = PathBuf::from(&self.config.path);
+    let mut object = serde_json::Map::new();
+    for (field, value) in snapshot.fields()? {
+      object.insert(column(&self.config.column_map, field), value);
+    }
+    let line = serde_json::to_string(&object).chain_err(|| "Could not serialize the snapshot")?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)
+      .chain_err(|| format!("Could not open {}", path.display()))?;
+    writeln!(file, "{}", line).chain_err(|| format!("Could not write {}", path.display()))?;
+    Ok(())
+  }
+}
+
+fn column(column_map: &Option<ColumnMap>, field: &str) -> String {
+  column_map.as_ref()
+    .and_then(|m| m.column(field))
+    .unwrap_or(field)
+    .to_string()
+}
+
+fn cell(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.to_string(),
+    Value::Null => "".to_string(),
+    other => other.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::path::Path;
+  use crate::model::PortfolioBalance;
+  use super::*;
+
+  fn portfolio() -> Portfolio {
+    Portfolio {
+      balances: vec![PortfolioBalance {
+        symbol: "BTC".to_string(),
+        quantity: 0.5,
+        value_usd: 5000.0,
+        value_btc: 0.5,
+        value: 4500.0,
+        change_7d: 1.0,
+        change_24h: -2.0,
+      }],
+      total_usd: 5000.0,
+      total_btc: 0.5,
+      currency: "EUR".to_string(),
+      total_value: 4500.0,
+    }
+  }
+
+  fn file_sink(dir: &Path, file: &str, column_map: &str) -> FileSinkConfig {
+    FileSinkConfig {
+      path: dir.join(file).to_string_lossy().to_string(),
+      column_map: Some(toml::from_str(column_map).unwrap()),
+    }
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("coin-sinks-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn test_csv_sink() {
+    let dir = temp_dir("csv");
+    let portfolio = portfolio();
+    let snapshot = Snapshot { account: "binance", timestamp: 1561939200, portfolio: &portfolio, run_id: None };
+    let config = file_sink(&dir, "snapshots.csv", "name = 'Account'\ntotal_usd = 'USD'");
+    let sink = CsvSink::new(&config);
+    sink.write(&snapshot).unwrap();
+    sink.write(&snapshot).unwrap();
+    let mut reader = csv::Reader::from_path(&config.path).unwrap();
+    let header: Vec<String> = reader.headers().unwrap().iter().map(|h| h.to_string()).collect();
+    assert_eq!(header, vec!["Account", "timestamp", "USD", "total_btc", "currency", "total_value", "details"]);
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(&rows[0][0], "binance");
+    assert_eq!(&rows[0][1], "2019-07-01T00:00:00+00:00");
+    assert_eq!(&rows[0][2], "5000.0");
+    assert_eq!(&rows[0][4], "EUR");
+    let details: Value = serde_json::from_str(&rows[0][6]).unwrap();
+    assert_eq!(details[0]["symbol"], "BTC");
+
+    // A different column map would mix layouts, the file is left as it is.
+    let renamed = file_sink(&dir, "snapshots.csv", "name = 'Name'");
+    let error = CsvSink::new(&renamed).write(&snapshot).unwrap_err();
+    assert!(error.to_string().contains("has the columns"), "{}", error);
+    assert_eq!(csv::Reader::from_path(&config.path).unwrap().records().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_jsonl_sink() {
+    let dir = temp_dir("jsonl");
+    let portfolio = portfolio();
+    let snapshot = Snapshot { account: "binance", timestamp: 1561939200, portfolio: &portfolio, run_id: None };
+    let config = file_sink(&dir, "snapshots.jsonl", "total_value = 'Value (EUR)'\ndetails = 'Balances'");
+    JsonLinesSink::new(&config).write(&snapshot).unwrap();
+    JsonLinesSink::new(&config).write(&snapshot).unwrap();
+    let contents = fs::read_to_string(&config.path).unwrap();
+    let lines: Vec<Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["name"], "binance");
+    assert_eq!(lines[0]["Value (EUR)"], 4500.0);
+    assert_eq!(lines[0]["Balances"][0]["quantity"], 0.5);
+    assert!(lines[0].get("total_value").is_none());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_sqlite_sink_unopened() {
+    let dir = temp_dir("sqlite");
+    let portfolio = portfolio();
+    let snapshot = Snapshot { account: "binance", timestamp: 1561939200, portfolio: &portfolio, run_id: None };
+    let jsonl = FileSinkConfig { path: dir.join("snapshots.jsonl").to_string_lossy().to_string(), column_map: None };
+    let sinks: Vec<Box<dyn SnapshotSink>> = vec![
+      Box::new(SqliteSink::new(SnapshotStore::open(&dir.join("missing").join("coin.db")))),
+      Box::new(JsonLinesSink::new(&jsonl)),
+    ];
+    let results = write_snapshot(&sinks, &snapshot);
+    let error = results[0].1.as_ref().unwrap_err();
+    assert!(error.to_string().starts_with("Could not open snapshot store"), "{}", error);
+    assert!(results[1].1.is_ok());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
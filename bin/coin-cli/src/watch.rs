@@ -0,0 +1,334 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::prelude::*;
+use crate::errors::*;
+use crate::schedule::Schedule;
+
+// How long `watch stop` waits for the daemon to exit.
+const STOP_TIMEOUT_SECS: u64 = 10;
+
+/**
+ * The background watcher, configured in the [watch] table of ~/.coin.toml.
+ */
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct WatchConfig {
+  // Defaults to ~/.coin/watch.pid.
+  pub pid_path: Option<String>,
+  // Defaults to ~/.coin/watch.log.
+  pub log_path: Option<String>,
+  pub jobs: Option<Vec<JobConfig>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JobConfig {
+  // snapshot, rebalance, drift, sync or alerts.
+  pub kind: String,
+  // A cron expression in local time, e.g. '0 * * * *' for every hour.
+  pub schedule: String,
+  // Extra arguments for the command the job runs, e.g. ['--size', '10'] for a rebalance.
+  pub args: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobKind {
+  Snapshot,
+  Rebalance,
+  // Rebalances only when the portfolio drifted from its targets.
+  Drift,
+  Sync,
+  // Checks the alert rules.
+  Alerts,
+}
+
+impl JobKind {
+  pub fn from_name(name: &str) -> Result<Self> {
+    match name.to_lowercase().as_str() {
+      "snapshot" => Ok(JobKind::Snapshot),
+      "rebalance" => Ok(JobKind::Rebalance),
+      "drift" => Ok(JobKind::Drift),
+      "sync" => Ok(JobKind::Sync),
+      "alerts" => Ok(JobKind::Alerts),
+      _ => bail!("Unknown job {}. Use snapshot, rebalance, drift, sync or alerts.", name),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      JobKind::Snapshot => "snapshot",
+      JobKind::Rebalance => "rebalance",
+      JobKind::Drift => "drift",
+      JobKind::Sync => "sync",
+      JobKind::Alerts => "alerts",
+    }
+  }
+
+  // The coin command a job of this kind runs.
+  fn command(&self) -> &'static [&'static str] {
+    match self {
+      JobKind::Snapshot => &["save"],
+      JobKind::Rebalance => &["balance"],
+      JobKind::Drift => &["balance", "--drift"],
+      JobKind::Sync => &["ledger", "sync"],
+      JobKind::Alerts => &["alerts", "check"],
+    }
+  }
+}
+
+pub struct Job {
+  pub kind: JobKind,
+  pub schedule: Schedule,
+  pub args: Vec<String>,
+}
+
+impl Job {
+  pub fn from_config(config: &JobConfig) -> Result<Self> {
+    Ok(Job {
+      kind: JobKind::from_name(&config.kind)?,
+      schedule: Schedule::parse(&config.schedule)?,
+      args: config.args.clone().unwrap_or_default(),
+    })
+  }
+
+  /**
+   * The arguments coin is run with.
+   */
+  pub fn command_line(&self) -> Vec<String> {
+    let mut args: Vec<String> = self.kind.command().iter().map(|a| a.to_string()).collect();
+    args.extend(self.args.iter().cloned());
+    args
+  }
+}
+
+/**
+ * Runs jobs on their schedules in a background process. Each job runs coin itself with the job's
+ * command, one at a time, and its output goes to the log along with the watcher's own messages.
+ */
+pub struct Watcher {
+  pid_path: PathBuf,
+  log_path: PathBuf,
+  jobs: Vec<Job>,
+}
+
+impl Watcher {
+  pub fn new(pid_path: PathBuf, log_path: PathBuf, jobs: Vec<Job>) -> Self {
+    Watcher { pid_path, log_path, jobs }
+  }
+
+  pub fn jobs(&self) -> &[Job] {
+    &self.jobs
+  }
+
+  pub fn log_path(&self) -> &Path {
+    &self.log_path
+  }
+
+  /**
+   * The pid of the running daemon. A pidfile left behind by a daemon that died is ignored, also
+   * when another process has taken its pid since.
+   */
+  pub fn running(&self) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(&self.pid_path).ok()?.trim().parse().ok()?;
+    if is_alive(pid) && is_daemon(pid) {
+      Some(pid)
+    } else {
+      None
+    }
+  }
+
+  /**
+   * Starts `coin watch daemon` in the background with its output going to the log. The daemon
+   * runs in a session of its own so it outlives the terminal that started it.
+   * Returns the pid of the daemon.
+   */
+  pub fn start(&self, exe: &Path) -> Result<u32> {
+    if let Some(pid) = self.running() {
+      bail!("The watcher is already running with pid {}", pid);
+    }
+    let log = self.open_log()?;
+    let mut command = Command::new(exe);
+    command
+      .args(&["watch", "daemon"])
+      .stdin(Stdio::null())
+      .stdout(log.try_clone().chain_err(|| "Could not open the log")?)
+      .stderr(log);
+    unsafe {
+      command.pre_exec(|| {
+        if libc::setsid() == -1 {
+          return Err(io::Error::last_os_error());
+        }
+        Ok(())
+      });
+    }
+    let mut child = command.spawn().chain_err(|| format!("Could not start {}", exe.display()))?;
+    // Give the daemon a moment to fail on a bad config before reporting it started.
+    thread::sleep(Duration::from_millis(500));
+    if let Ok(Some(status)) = child.try_wait() {
+      bail!("The watcher exited with {}. See {}", status, self.log_path.display());
+    }
+    // The daemon writes it too, this way status sees it straight away.
+    fs::write(&self.pid_path, child.id().to_string())
+      .chain_err(|| format!("Could not write {}", self.pid_path.display()))?;
+    Ok(child.id())
+  }
+
+  /**
+   * Stops the daemon. Returns its pid, or None when it was not running.
+   */
+  pub fn stop(&self) -> Result<Option<u32>> {
+    let pid = match self.running() {
+      Some(pid) => pid,
+      None => {
+        let _ = fs::remove_file(&self.pid_path);
+        return Ok(None);
+      }
+    };
+    let status = Command::new("kill").arg(pid.to_string()).status()
+      .chain_err(|| format!("Could not stop pid {}", pid))?;
+    if !status.success() {
+      bail!("Could not stop pid {}", pid);
+    }
+    let started = Instant::now();
+    while is_alive(pid) && is_daemon(pid) {
+      if started.elapsed() > Duration::from_secs(STOP_TIMEOUT_SECS) {
+        bail!("The watcher with pid {} did not stop within {} seconds", pid, STOP_TIMEOUT_SECS);
+      }
+      thread::sleep(Duration::from_millis(100));
+    }
+    let _ = fs::remove_file(&self.pid_path);
+    self.log(&format!("Stopped pid {}", pid));
+    Ok(Some(pid))
+  }
+
+  /**
+   * Runs every job whose schedule matches the current minute, forever. Writes the pidfile first so
+   * `watch status` and `watch stop` can find the process.
+   */
+  pub fn run(&self, exe: &Path) -> Result<()> {
+    let pid = std::process::id();
+    if let Some(running) = self.running() {
+      if running != pid {
+        bail!("The watcher is already running with pid {}", running);
+      }
+    }
+    fs::write(&self.pid_path, pid.to_string())
+      .chain_err(|| format!("Could not write {}", self.pid_path.display()))?;
+    self.log(&format!("Started pid {} with {} jobs", pid, self.jobs.len()));
+    let now = Local::now().naive_local();
+    for job in &self.jobs {
+      if let Some(next) = job.schedule.next_after(&now) {
+        self.log(&format!("Next {} at {}", job.kind.name(), next.format("%Y-%m-%d %H:%M")));
+      }
+    }
+    let mut last_minute: Option<NaiveDateTime> = None;
+    loop {
+      let now = Local::now().naive_local();
+      let minute = now.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(now);
+      if last_minute != Some(minute) {
+        last_minute = Some(minute);
+        for job in self.jobs.iter().filter(|j| j.schedule.matches(&minute)) {
+          self.run_job(exe, job);
+        }
+      }
+      // Wake up at the start of the next minute.
+      let second = Local::now().second() as u64;
+      thread::sleep(Duration::from_secs(60u64.saturating_sub(second).max(1)));
+    }
+  }
+
+  fn run_job(&self, exe: &Path, job: &Job) {
+    let args = job.command_line();
+    self.log(&format!("Running {}: coin {}", job.kind.name(), args.join(" ")));
+    let started = Instant::now();
+    let status = self.open_log().and_then(|log| {
+      let stderr = log.try_clone().chain_err(|| "Could not open the log")?;
+      Command::new(exe)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(stderr)
+        .status()
+        .chain_err(|| format!("Could not run {}", exe.display()))
+    });
+    match status {
+      Ok(s) if s.success() => self.log(&format!("Finished {} in {}s", job.kind.name(), started.elapsed().as_secs())),
+      Ok(s) => self.log(&format!("{} failed with {}", job.kind.name(), s)),
+      Err(e) => self.log(&format!("{} failed: {}", job.kind.name(), e)),
+    }
+  }
+
+  fn open_log(&self) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(&self.log_path)
+      .chain_err(|| format!("Could not open {}", self.log_path.display()))
+  }
+
+  /**
+   * Appends a timestamped line to the log. Logging never fails the watcher.
+   */
+  pub fn log(&self, message: &str) {
+    if let Ok(mut log) = self.open_log() {
+      let _ = writeln!(log, "[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+    }
+  }
+}
+
+// Signal 0 only checks the process exists and may be signalled.
+fn is_alive(pid: u32) -> bool {
+  Command::new("kill")
+    .args(&["-0", &pid.to_string()])
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .map(|s| s.success())
+    .unwrap_or(false)
+}
+
+// Whether the process runs `coin watch daemon`, going by its arguments.
+fn is_daemon(pid: u32) -> bool {
+  let args: Vec<String> = match fs::read(format!("/proc/{}/cmdline", pid)) {
+    Ok(cmdline) => cmdline.split(|b| *b == 0).map(|a| String::from_utf8_lossy(a).to_string()).collect(),
+    // Without procfs, e.g. on macOS, ps has the command line.
+    Err(_) => match Command::new("ps").args(&["-p", &pid.to_string(), "-o", "command="]).output() {
+      Ok(output) if output.status.success() => {
+        String::from_utf8_lossy(&output.stdout).split_whitespace().map(|a| a.to_string()).collect()
+      }
+      _ => return false,
+    },
+  };
+  args.windows(2).any(|w| w[0] == "watch" && w[1] == "daemon")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::process::Command;
+  use super::*;
+
+  #[test]
+  fn test_running() {
+    let dir = env::temp_dir().join(format!("coin-watch-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let watcher = Watcher::new(dir.join("watch.pid"), dir.join("watch.log"), vec![]);
+    assert_eq!(watcher.running(), None);
+    // A live process that took the pid of a dead daemon.
+    fs::write(dir.join("watch.pid"), std::process::id().to_string()).unwrap();
+    assert_eq!(watcher.running(), None);
+    assert_eq!(watcher.stop().unwrap(), None);
+    assert!(!dir.join("watch.pid").exists());
+
+    // The extra arguments make the command line look like the daemon's. The second command keeps
+    // the shell from replacing itself with sleep.
+    let mut daemon = Command::new("sh").args(&["-c", "sleep 30; true", "watch", "daemon"]).spawn().unwrap();
+    fs::write(dir.join("watch.pid"), daemon.id().to_string()).unwrap();
+    assert_eq!(watcher.running(), Some(daemon.id()));
+    assert_eq!(watcher.stop().unwrap(), Some(daemon.id()));
+    daemon.wait().unwrap();
+    assert_eq!(watcher.running(), None);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -1,46 +1,311 @@
 use reqwest;
+use reqwest::{Method, StatusCode};
+use std::cell::Cell;
+use std::rc::Rc;
 use std::collections::{HashMap};
-use serde_json::{Value, Map};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value, Map};
 use crate::errors::*;
 
 const AIRTABLE_BASE_URL: &str = "https://api.airtable.com/v0";
+// Airtable allows 5 requests per second per base.
+const MIN_REQUEST_INTERVAL_MS: u64 = 200;
+// Airtable asks clients to wait 30 seconds after going over the limit.
+const RATE_LIMIT_BACKOFF_SECS: u64 = 30;
+const MAX_RETRIES: u32 = 3;
+// The most records a single create or update request accepts.
+pub const BATCH_SIZE: usize = 10;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AirtableConfig {
     pub key: String,
     pub app: String,
     pub table: String,
 }
 
-pub struct AirtableClient<'a> {
-  config: &'a AirtableConfig,
+/**
+ * A row of an airtable table.
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Record {
+  pub id: String,
+  #[serde(default)]
+  pub fields: Map<String, Value>,
+  #[serde(rename = "createdTime", default)]
+  pub created_time: Option<String>,
+}
+
+/**
+ * Which records to list. Every option maps to the list records parameter of the same name.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct ListQuery {
+  pub filter_by_formula: Option<String>,
+  pub view: Option<String>,
+  pub fields: Vec<String>,
+  pub page_size: Option<u64>,
+  pub max_records: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RecordPage {
+  records: Vec<Record>,
+  offset: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RecordBatch {
+  records: Vec<Record>,
+}
+
+pub struct AirtableClient {
+  config: AirtableConfig,
   client: reqwest::Client,
+  base_url: String,
+  min_interval: Duration,
+  backoff: Duration,
+  retries: u32,
+  // Shared by every client of the base, Airtable counts requests per base.
+  last_request: Rc<Cell<Option<Instant>>>,
 }
-impl<'a> AirtableClient<'a> {
-  pub fn new(config: &'a AirtableConfig) -> Self {
+impl AirtableClient {
+  pub fn new(config: &AirtableConfig) -> Self {
     let client = reqwest::Client::new();
     AirtableClient {
-      config: config,
-      client: client,
+      config: config.clone(),
+      client,
+      base_url: AIRTABLE_BASE_URL.to_string(),
+      min_interval: Duration::from_millis(MIN_REQUEST_INTERVAL_MS),
+      backoff: Duration::from_secs(RATE_LIMIT_BACKOFF_SECS),
+      retries: MAX_RETRIES,
+      last_request: Rc::new(Cell::new(None)),
+    }
+  }
+
+  /**
+   * A client for another table of the same base. Requests of both count towards one rate limit.
+   */
+  pub fn for_table(&self, table: &str) -> Self {
+    AirtableClient {
+      config: AirtableConfig { table: table.to_string(), ..self.config.clone() },
+      client: self.client.clone(),
+      base_url: self.base_url.clone(),
+      min_interval: self.min_interval,
+      backoff: self.backoff,
+      retries: self.retries,
+      last_request: self.last_request.clone(),
     }
   }
 
+  /**
+   * Sends requests somewhere other than api.airtable.com, e.g. a proxy or a mock server.
+   */
+  pub fn with_base_url(mut self, base_url: &str) -> Self {
+    self.base_url = base_url.trim_end_matches('/').to_string();
+    self
+  }
+
+  /**
+   * How often a rate limited request is retried and how long to wait before the first retry.
+   * The wait doubles after every attempt.
+   */
+  pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+    self.retries = retries;
+    self.backoff = backoff;
+    self
+  }
+
+  /**
+   * The least time between two requests. Defaults to 200ms, Airtable's limit of 5 per second.
+   */
+  pub fn with_min_interval(mut self, interval: Duration) -> Self {
+    self.min_interval = interval;
+    self
+  }
+
   pub fn create_record(&self, record: Value) -> Result<String> {
-    let url = format!("{}/{}/{}", AIRTABLE_BASE_URL, self.config.app, escape_spaces(self.config.table.to_string()));
     let mut data = HashMap::new();
     data.insert("fields", record);
-    // TODO: Handle error gracefully.
-    let mut response = self.client.post(&url)
-      .header("Content-Type", "application/json")
-      .header("Authorization", format!("Bearer {}", self.config.key))
-      .json(&data)
-      .send()
-      .chain_err(|| "Unable to put value into airtable.")?;
-    let text = response.text().chain_err(|| "Serialize airtable response as text")?;
-    Ok(text)
+    let response = self.send(Method::POST, &self.table_url(), &[], Some(&json!(data)))?;
+    Ok(response.to_string())
+  }
+
+  /**
+   * Creates the records in batches of ten. Returns the created records in the order given.
+   */
+  pub fn create_records(&self, records: Vec<Value>) -> Result<Vec<Record>> {
+    let mut created = vec![];
+    for chunk in records.chunks(BATCH_SIZE) {
+      let batch: Vec<Value> = chunk.iter().map(|fields| json!({ "fields": fields })).collect();
+      let response = self.send(Method::POST, &self.table_url(), &[], Some(&json!({ "records": batch })))?;
+      let batch: RecordBatch = serde_json::from_value(response)?;
+      created.extend(batch.records);
+    }
+    Ok(created)
+  }
+
+  /**
+   * Every record matching the query, following pagination to the last page.
+   */
+  pub fn list_records(&self, query: &ListQuery) -> Result<Vec<Record>> {
+    let mut params: Vec<(String, String)> = vec![];
+    if let Some(formula) = &query.filter_by_formula {
+      params.push(("filterByFormula".to_string(), formula.to_string()));
+    }
+    if let Some(view) = &query.view {
+      params.push(("view".to_string(), view.to_string()));
+    }
+    for field in &query.fields {
+      params.push(("fields[]".to_string(), field.to_string()));
+    }
+    if let Some(size) = query.page_size {
+      params.push(("pageSize".to_string(), size.to_string()));
+    }
+    if let Some(max) = query.max_records {
+      params.push(("maxRecords".to_string(), max.to_string()));
+    }
+    let mut records = vec![];
+    let mut offset: Option<String> = None;
+    loop {
+      let mut page_params = params.clone();
+      if let Some(o) = &offset {
+        page_params.push(("offset".to_string(), o.to_string()));
+      }
+      let response = self.send(Method::GET, &self.table_url(), &page_params, None)?;
+      let page: RecordPage = serde_json::from_value(response)?;
+      records.extend(page.records);
+      match page.offset {
+        Some(o) => offset = Some(o),
+        None => return Ok(records),
+      }
+    }
+  }
+
+  /**
+   * Sets the given fields of a record, leaving the others as they are.
+   */
+  pub fn update_record(&self, id: &str, fields: Value) -> Result<Record> {
+    let url = format!("{}/{}", self.table_url(), id);
+    let response = self.send(Method::PATCH, &url, &[], Some(&json!({ "fields": fields })))?;
+    Ok(serde_json::from_value(response)?)
+  }
+
+  /**
+   * Updates the record whose `key` column holds the same value as `fields`, or creates one when
+   * there is none. Fails when more than one record has that value.
+   */
+  pub fn upsert_record(&self, key: &str, fields: Value) -> Result<Record> {
+    let value = match fields.get(key) {
+      Some(v) => v,
+      None => bail!("The fields to upsert have no value for the key column {}", key),
+    };
+    let query = ListQuery {
+      filter_by_formula: Some(format!("{{{}}} = {}", key, formula_value(value))),
+      max_records: Some(2),
+      ..ListQuery::default()
+    };
+    let existing = self.list_records(&query)?;
+    match existing.len() {
+      0 => {
+        let response = self.send(Method::POST, &self.table_url(), &[], Some(&json!({ "fields": fields })))?;
+        Ok(serde_json::from_value(response)?)
+      }
+      1 => self.update_record(&existing[0].id, fields),
+      _ => bail!("More than one record has {} = {}", key, value),
+    }
+  }
+
+  fn table_url(&self) -> String {
+    format!("{}/{}/{}", self.base_url, self.config.app, escape_spaces(self.config.table.to_string()))
+  }
+
+  /**
+   * Sends a request once the rate limit allows it and returns the JSON body. Rate limited requests
+   * are retried with a growing wait. Server errors are only retried for reads, a failed write may
+   * still have gone through.
+   */
+  fn send(&self, method: Method, url: &str, query: &[(String, String)], body: Option<&Value>) -> Result<Value> {
+    let mut attempt = 0;
+    loop {
+      self.throttle();
+      let mut request = self.client.request(method.clone(), url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", self.config.key));
+      if !query.is_empty() {
+        request = request.query(query);
+      }
+      if let Some(b) = body {
+        request = request.json(b);
+      }
+      let mut response = request.send().chain_err(|| "Unable to reach airtable.")?;
+      let status = response.status();
+      let text = response.text().chain_err(|| "Serialize airtable response as text")?;
+      if status.is_success() {
+        return Ok(serde_json::from_str(&text)?);
+      }
+      let retryable = status == StatusCode::TOO_MANY_REQUESTS
+        || (status.is_server_error() && method == Method::GET);
+      if retryable && attempt < self.retries {
+        thread::sleep(self.backoff * 2u32.pow(attempt));
+        attempt += 1;
+        continue;
+      }
+      if status == StatusCode::TOO_MANY_REQUESTS {
+        bail!(ErrorKind::RateLimited(attempt + 1));
+      }
+      return Err(api_error(status.as_u16(), &text));
+    }
+  }
+
+  // Waits until at least min_interval has passed since the last request.
+  fn throttle(&self) {
+    if let Some(last) = self.last_request.get() {
+      let elapsed = last.elapsed();
+      if elapsed < self.min_interval {
+        thread::sleep(self.min_interval - elapsed);
+      }
+    }
+    self.last_request.set(Some(Instant::now()));
+  }
+}
+
+/**
+ * Turns an airtable error body into an error. Airtable sends either
+ * `{"error": {"type": "...", "message": "..."}}` or just `{"error": "NOT_FOUND"}`.
+ */
+fn api_error(status: u16, body: &str) -> Error {
+  let parsed: Option<Value> = serde_json::from_str(body).ok();
+  let error = parsed.as_ref().and_then(|v| v.get("error"));
+  let (error_type, msg) = match error {
+    Some(Value::String(t)) => (t.to_string(), t.to_string()),
+    Some(e) => (
+      e.get("type").and_then(|t| t.as_str()).unwrap_or("UNKNOWN").to_string(),
+      e.get("message").and_then(|m| m.as_str()).unwrap_or(body).to_string(),
+    ),
+    None => ("UNKNOWN".to_string(), body.to_string()),
+  };
+  match status {
+    401 | 403 => ErrorKind::Unauthorized(msg).into(),
+    404 => ErrorKind::NotFound(msg).into(),
+    400 | 422 => ErrorKind::InvalidRequest(error_type, msg).into(),
+    _ => ErrorKind::AirtableError(status, error_type, msg).into(),
+  }
+}
+
+/**
+ * A JSON value written as a formula literal, e.g. for filterByFormula.
+ */
+pub fn formula_value(value: &Value) -> String {
+  match value {
+    Value::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+    Value::Bool(true) => "TRUE()".to_string(),
+    Value::Bool(false) => "FALSE()".to_string(),
+    Value::Null => "BLANK()".to_string(),
+    other => other.to_string(),
   }
 }
 
 fn escape_spaces(s: String) -> String {
   s.split(' ').collect::<Vec<&str>>().join("%20")
-}
\ No newline at end of file
+}
@@ -2,4 +2,32 @@ error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
     }
+
+    errors {
+        Unauthorized(msg: String) {
+            description("Unauthorized")
+            display("Airtable rejected the API key: '{}'", msg)
+        }
+        NotFound(msg: String) {
+            description("NotFound")
+            display("Airtable could not find the base, table or record: '{}'", msg)
+        }
+        InvalidRequest(error_type: String, msg: String) {
+            description("InvalidRequest")
+            display("Airtable rejected the request ({}): '{}'", error_type, msg)
+        }
+        RateLimited(attempts: u32) {
+            description("RateLimited")
+            display("Airtable rate limit still exceeded after {} attempts", attempts)
+        }
+        AirtableError(status: u16, error_type: String, msg: String) {
+            description("AirtableError")
+            display("AirtableError({} {}): '{}'", status, error_type, msg)
+        }
+    }
+
+    foreign_links {
+        ReqError(::reqwest::Error);
+        SerdeJsonError(::serde_json::error::Error);
+    }
 }
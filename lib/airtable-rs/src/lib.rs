@@ -9,8 +9,247 @@ pub mod errors;
 
 #[cfg(test)]
 mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use serde_json::{json, Value};
+    use crate::client::{AirtableClient, AirtableConfig, ListQuery};
+    use crate::errors::ErrorKind;
+
+    /**
+     * A request as the mock server saw it.
+     */
+    #[derive(Debug)]
+    struct Seen {
+        method: String,
+        path: String,
+        body: Value,
+        at: Instant,
+    }
+
+    /**
+     * Serves the given responses, one connection each, and hands back every request it received.
+     */
+    fn mock_server(responses: Vec<(u16, Value)>) -> (String, thread::JoinHandle<Vec<Seen>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut seen = vec![];
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lower = header.to_lowercase();
+                    if let Some(length) = lower.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                let mut parts = request_line.split_whitespace();
+                seen.push(Seen {
+                    method: parts.next().unwrap().to_string(),
+                    path: parts.next().unwrap().to_string(),
+                    body: serde_json::from_slice(&request_body).unwrap_or(Value::Null),
+                    at: Instant::now(),
+                });
+                let body = body.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                ).unwrap();
+            }
+            seen
+        });
+        (url, handle)
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn config() -> AirtableConfig {
+        AirtableConfig {
+            key: "key".to_string(),
+            app: "app".to_string(),
+            table: "Coin History".to_string(),
+        }
+    }
+
+    fn record(id: &str, name: &str) -> Value {
+        json!({ "id": id, "fields": { "Name": name }, "createdTime": "2019-06-01T00:00:00.000Z" })
+    }
+
+    #[test]
+    fn test_list_records() {
+        let (url, server) = mock_server(vec![
+            (200, json!({ "records": [record("rec1", "a"), record("rec2", "b")], "offset": "page2" })),
+            (200, json!({ "records": [record("rec3", "c")] })),
+        ]);
+        let config = config();
+        let client = AirtableClient::new(&config).with_base_url(&url).with_min_interval(Duration::from_millis(0));
+        let query = ListQuery {
+            filter_by_formula: Some("{Name} = 'a'".to_string()),
+            page_size: Some(2),
+            ..ListQuery::default()
+        };
+        let records = client.list_records(&query).unwrap();
+        let ids: Vec<&str> = records.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["rec1", "rec2", "rec3"]);
+        assert_eq!(records[2].fields["Name"], json!("c"));
+        let seen = server.join().unwrap();
+        assert_eq!(seen[0].method, "GET");
+        assert!(seen[0].path.starts_with("/app/Coin%20History?"));
+        assert!(seen[0].path.contains("filterByFormula=%7BName%7D+%3D+%27a%27"));
+        assert!(seen[0].path.contains("pageSize=2"));
+        assert!(!seen[0].path.contains("offset"));
+        assert!(seen[1].path.contains("offset=page2"));
+    }
+
+    #[test]
+    fn test_update_and_upsert() {
+        let (url, server) = mock_server(vec![
+            (200, record("rec1", "a")),
+            // The upsert of an existing key updates it.
+            (200, json!({ "records": [record("rec1", "a")] })),
+            (200, record("rec1", "a")),
+            // The upsert of a new key creates it.
+            (200, json!({ "records": [] })),
+            (200, record("rec2", "it's")),
+        ]);
+        let config = config();
+        let client = AirtableClient::new(&config).with_base_url(&url).with_min_interval(Duration::from_millis(0));
+        let updated = client.update_record("rec1", json!({ "Value (USD)": 10.0 })).unwrap();
+        assert_eq!(updated.id, "rec1");
+        assert_eq!(client.upsert_record("Name", json!({ "Name": "a", "Value (USD)": 20.0 })).unwrap().id, "rec1");
+        assert_eq!(client.upsert_record("Name", json!({ "Name": "it's", "Value (USD)": 5.0 })).unwrap().id, "rec2");
+        assert!(client.upsert_record("Missing", json!({ "Name": "a" })).is_err());
+        let seen = server.join().unwrap();
+        let methods: Vec<&str> = seen.iter().map(|s| s.method.as_str()).collect();
+        assert_eq!(methods, vec!["PATCH", "GET", "PATCH", "GET", "POST"]);
+        assert_eq!(seen[0].path, "/app/Coin%20History/rec1");
+        assert_eq!(seen[0].body, json!({ "fields": { "Value (USD)": 10.0 } }));
+        assert_eq!(seen[2].body["fields"]["Value (USD)"], json!(20.0));
+        // Quotes in the key are escaped in the formula.
+        assert!(seen[3].path.contains("filterByFormula=%7BName%7D+%3D+%27it%5C%27s%27"));
+        assert_eq!(seen[4].body, json!({ "fields": { "Name": "it's", "Value (USD)": 5.0 } }));
+    }
+
+    #[test]
+    fn test_create_records_in_batches() {
+        let batch = |from: usize, to: usize| {
+            let records: Vec<Value> = (from..to).map(|i| record(&format!("rec{}", i), &i.to_string())).collect();
+            (200, json!({ "records": records }))
+        };
+        let (url, server) = mock_server(vec![batch(0, 10), batch(10, 20), batch(20, 23)]);
+        let config = config();
+        let client = AirtableClient::new(&config).with_base_url(&url).with_min_interval(Duration::from_millis(0));
+        let records: Vec<Value> = (0..23).map(|i| json!({ "Name": i.to_string() })).collect();
+        let created = client.create_records(records).unwrap();
+        assert_eq!(created.len(), 23);
+        assert_eq!(created[22].id, "rec22");
+        let seen = server.join().unwrap();
+        let sizes: Vec<usize> = seen.iter().map(|s| s.body["records"].as_array().unwrap().len()).collect();
+        assert_eq!(sizes, vec![10, 10, 3]);
+        assert_eq!(seen[1].body["records"][0], json!({ "fields": { "Name": "10" } }));
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let limited = json!({ "error": { "type": "RATE_LIMIT_REACHED", "message": "Slow down" } });
+        let (url, server) = mock_server(vec![
+            (200, record("rec1", "a")),
+            (429, limited.clone()),
+            (200, record("rec1", "a")),
+            (429, limited.clone()),
+            (429, limited.clone()),
+        ]);
+        let config = config();
+        let client = AirtableClient::new(&config)
+            .with_base_url(&url)
+            .with_min_interval(Duration::from_millis(50))
+            .with_retries(1, Duration::from_millis(10));
+        client.update_record("rec1", json!({})).unwrap();
+        // The second request waits for the interval and the retry goes through.
+        assert_eq!(client.update_record("rec1", json!({})).unwrap().id, "rec1");
+        match client.update_record("rec1", json!({})) {
+            Err(e) => match e.kind() {
+                ErrorKind::RateLimited(attempts) => assert_eq!(*attempts, 2),
+                other => panic!("Expected RateLimited, got {:?}", other),
+            },
+            Ok(_) => panic!("Expected the rate limit to be exceeded"),
+        }
+        let seen = server.join().unwrap();
+        for pair in seen.windows(2) {
+            assert!(pair[1].at.duration_since(pair[0].at) >= Duration::from_millis(45));
+        }
+    }
+
+    #[test]
+    fn test_tables_share_rate_limit() {
+        let (url, server) = mock_server(vec![
+            (200, json!({ "records": [record("rec1", "a")] })),
+            (200, json!({ "records": [record("rec2", "BTC")] })),
+        ]);
+        let config = config();
+        let snapshots = AirtableClient::new(&config).with_base_url(&url).with_min_interval(Duration::from_millis(50));
+        let assets = snapshots.for_table("Assets");
+        snapshots.create_records(vec![json!({ "Name": "a" })]).unwrap();
+        assets.create_records(vec![json!({ "Symbol": "BTC", "Snapshot": ["rec1"] })]).unwrap();
+        let seen = server.join().unwrap();
+        assert_eq!(seen[0].path, "/app/Coin%20History");
+        assert_eq!(seen[1].path, "/app/Assets");
+        assert!(seen[1].at.duration_since(seen[0].at) >= Duration::from_millis(45));
+    }
+
+    #[test]
+    fn test_errors() {
+        let (url, server) = mock_server(vec![
+            (422, json!({ "error": { "type": "INVALID_VALUE_FOR_COLUMN", "message": "Field Value cannot accept \"x\"" } })),
+            (404, json!({ "error": "NOT_FOUND" })),
+            (401, json!({ "error": { "type": "AUTHENTICATION_REQUIRED", "message": "Authentication required" } })),
+            (500, json!({ "error": { "type": "SERVER_ERROR", "message": "Oops" } })),
+        ]);
+        let config = config();
+        let client = AirtableClient::new(&config)
+            .with_base_url(&url)
+            .with_min_interval(Duration::from_millis(0))
+            .with_retries(0, Duration::from_millis(0));
+        match client.create_record(json!({ "Value": "x" })).unwrap_err().kind() {
+            ErrorKind::InvalidRequest(error_type, msg) => {
+                assert_eq!(error_type, "INVALID_VALUE_FOR_COLUMN");
+                assert_eq!(msg, "Field Value cannot accept \"x\"");
+            }
+            other => panic!("Expected InvalidRequest, got {:?}", other),
+        }
+        match client.update_record("missing", json!({})).unwrap_err().kind() {
+            ErrorKind::NotFound(msg) => assert_eq!(msg, "NOT_FOUND"),
+            other => panic!("Expected NotFound, got {:?}", other),
+        }
+        match client.list_records(&ListQuery::default()).unwrap_err().kind() {
+            ErrorKind::Unauthorized(msg) => assert_eq!(msg, "Authentication required"),
+            other => panic!("Expected Unauthorized, got {:?}", other),
+        }
+        match client.list_records(&ListQuery::default()).unwrap_err().kind() {
+            ErrorKind::AirtableError(status, error_type, _) => {
+                assert_eq!(*status, 500);
+                assert_eq!(error_type, "SERVER_ERROR");
+            }
+            other => panic!("Expected AirtableError, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
 }
@@ -1,33 +1,250 @@
-use std::collections::VecDeque;
+use std::cmp::Ordering;
 use crate::errors::*;
 
-pub struct Amount {
+// Quantities smaller than this are treated as fully consumed to absorb floating point dust.
+const DUST: f64 = 1e-9;
+
+/**
+ * Assets held for longer than a year are long-term by default.
+ */
+pub const DEFAULT_HOLDING_PERIOD: u64 = 365 * 24 * 60 * 60;
+
+/**
+ * Whether a realized gain was short-term or long-term.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Term {
+  Short,
+  Long,
+}
+impl Term {
+  /**
+   * A lot is long-term when it is disposed of more than the holding period after it was acquired.
+   */
+  pub fn of(acquired: u64, disposed: u64, holding_period: u64) -> Term {
+    if disposed.saturating_sub(acquired) > holding_period {
+      Term::Long
+    } else {
+      Term::Short
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      Term::Short => "short-term",
+      Term::Long => "long-term",
+    }
+  }
+}
+
+/**
+ * A lot is a quantity of an asset acquired at the same time for the same per unit value.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lot {
+  pub id: u64,
+  pub acquired: u64,
+  pub quantity: f64,
+  pub value: f64,
+}
+
+/**
+ * A taxable disposal of an asset. `lot_ids` names the lots to sell when using specific identification.
+ * `fee` is the total value of fees paid on the disposal and is subtracted from the proceeds.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disposal {
+  pub id: u64,
+  pub timestamp: u64,
   pub quantity: f64,
   pub value: f64,
+  pub fee: f64,
+  pub lot_ids: Vec<u64>,
+}
+
+/**
+ * The part of a disposal that was matched against a single lot.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Realization {
+  pub disposal_id: u64,
+  pub lot_id: u64,
+  pub acquired: u64,
+  pub disposed: u64,
+  pub quantity: f64,
+  pub cost: f64,
+  pub proceeds: f64,
+  pub term: Term,
+}
+impl Realization {
+  pub fn gain(&self) -> f64 {
+    self.proceeds - self.cost
+  }
+}
+
+/**
+ * Realized totals split by holding period.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TermSummary {
+  pub short_term_proceeds: f64,
+  pub short_term_cost: f64,
+  pub long_term_proceeds: f64,
+  pub long_term_cost: f64,
+}
+impl TermSummary {
+  pub fn from_realizations(realizations: &[Realization]) -> Self {
+    let mut summary = TermSummary::default();
+    for r in realizations {
+      match r.term {
+        Term::Short => {
+          summary.short_term_proceeds += r.proceeds;
+          summary.short_term_cost += r.cost;
+        },
+        Term::Long => {
+          summary.long_term_proceeds += r.proceeds;
+          summary.long_term_cost += r.cost;
+        },
+      }
+    }
+    summary
+  }
+
+  pub fn short_term_gain(&self) -> f64 {
+    self.short_term_proceeds - self.short_term_cost
+  }
+
+  pub fn long_term_gain(&self) -> f64 {
+    self.long_term_proceeds - self.long_term_cost
+  }
+}
+
+/**
+ * Decides which lots are consumed first by a disposal or transfer.
+ * Specific identification uses the lots named by the disposal and FIFO for anything left over.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LotMethod {
+  Fifo,
+  Lifo,
+  Hifo,
+  Specific,
 }
+impl LotMethod {
+  pub fn all() -> Vec<LotMethod> {
+    vec![LotMethod::Fifo, LotMethod::Lifo, LotMethod::Hifo, LotMethod::Specific]
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      LotMethod::Fifo => "fifo",
+      LotMethod::Lifo => "lifo",
+      LotMethod::Hifo => "hifo",
+      LotMethod::Specific => "specific",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Result<LotMethod> {
+    match &name.to_lowercase()[..] {
+      "fifo" => Ok(LotMethod::Fifo),
+      "lifo" => Ok(LotMethod::Lifo),
+      "hifo" => Ok(LotMethod::Hifo),
+      "specific" => Ok(LotMethod::Specific),
+      _ => Err(Error::from_kind(ErrorKind::BasisError(format!("Unknown lot selection method {}", name)))),
+    }
+  }
+}
+
+enum Event {
+  Acquire(Lot),
+  Dispose(Disposal),
+  // The method the lots were selected with, the lots and how much of each was moved.
+  // The lots already left for another account so they can not be selected again.
+  Transfer(LotMethod, Vec<(u64, f64)>),
+}
+
+struct Entry {
+  timestamp: u64,
+  seq: u64,
+  event: Event,
+}
+
 pub struct BasisImpl {
-  costs: VecDeque<Amount>,
-  gains: VecDeque<Amount>,
+  entries: Vec<Entry>,
+  next_id: u64,
+  method: LotMethod,
+  holding_period: u64,
 }
 
 pub trait Basis {
   fn new() -> Self;
 
+  /**
+   * Sets the lot selection method used by calc_cost_basis, calc_capital_gain and transfers.
+   */
+  fn set_method(&mut self, method: LotMethod) -> &Self;
+
+  fn method(&self) -> LotMethod;
+
+  /**
+   * Sets the number of seconds an asset must be held before its gains are long-term.
+   */
+  fn set_holding_period(&mut self, seconds: u64) -> &Self;
+
+  fn holding_period(&self) -> u64;
+
   /**
    * Add a single cost basis entry.
    */
   fn add_cost(&mut self, quantity: f64, value: f64) -> &Self;
 
+  /**
+   * Adds a lot acquired at the timestamp and returns its id.
+   */
+  fn acquire(&mut self, timestamp: u64, quantity: f64, value: f64) -> u64;
+
+  /**
+   * Adds a lot acquired at the timestamp where the fee, a total value, is added to the lot's cost.
+   */
+  fn acquire_with_fee(&mut self, timestamp: u64, quantity: f64, value: f64, fee: f64) -> u64;
+
+  /**
+   * Adds a lot transferred in from another account keeping its original acquisition date.
+   * The lot can only be disposed of after it was received.
+   */
+  fn receive_lot(&mut self, timestamp: u64, lot: &Lot) -> u64;
+
   /**
    * Removes cost basis entries starting at the oldest to transfer to another account.
    */
-  fn transfer_basis(&mut self, quantity: f64) -> Result<Vec<Amount>>;
+  fn transfer_basis(&mut self, quantity: f64) -> Result<Vec<Lot>>;
+
+  /**
+   * Removes lots held at the timestamp, selected with the current method, to transfer to another account.
+   * The basis can only be replayed with a method that selects lots the same way afterwards.
+   */
+  fn transfer_basis_at(&mut self, timestamp: u64, quantity: f64) -> Result<Vec<Lot>>;
 
   /**
    * Registers a taxable event.
    */
   fn realize_gain(&mut self, quantity: f64, value: f64) -> &Self;
 
+  /**
+   * Registers a taxable event at the timestamp and returns its id.
+   */
+  fn dispose(&mut self, timestamp: u64, quantity: f64, value: f64) -> u64;
+
+  /**
+   * Registers a taxable event that sells the named lots when using specific identification.
+   */
+  fn dispose_lots(&mut self, timestamp: u64, quantity: f64, value: f64, lot_ids: Vec<u64>) -> u64;
+
+  /**
+   * Registers a taxable event where the fee, a total value, is subtracted from the proceeds.
+   */
+  fn dispose_with_fee(&mut self, timestamp: u64, quantity: f64, value: f64, fee: f64, lot_ids: Vec<u64>) -> u64;
+
   /**
    * Returns the value of the cost basis for any realized returns.
    * Unrealized returns are not included in the cost basis.
@@ -38,76 +255,260 @@ pub trait Basis {
    * Returns the value of the total capital gains for the realized returns.
    */
   fn calc_capital_gain(&mut self) -> Result<f64>;
+
+  /**
+   * Returns the cost basis of realized returns when lots are selected with the method.
+   */
+  fn calc_cost_basis_with(&self, method: LotMethod) -> Result<f64>;
+
+  /**
+   * Returns the capital gains of realized returns when lots are selected with the method.
+   */
+  fn calc_capital_gain_with(&self, method: LotMethod) -> Result<f64>;
+
+  /**
+   * Returns every disposal matched against the lots it consumed.
+   * A disposal that consumed several lots has one row per lot.
+   */
+  fn realizations(&self, method: LotMethod) -> Result<Vec<Realization>>;
+
+  /**
+   * Returns the realized proceeds, cost and gains split into short-term and long-term.
+   */
+  fn calc_gains_by_term(&self, method: LotMethod) -> Result<TermSummary>;
+
+  /**
+   * Returns the lots that are still held after every disposal and transfer.
+   */
+  fn open_lots(&self, method: LotMethod) -> Result<Vec<Lot>>;
+}
+
+impl BasisImpl {
+  fn push(&mut self, timestamp: u64, event: Event) {
+    let seq = self.entries.len() as u64;
+    self.entries.push(Entry { timestamp, seq, event });
+  }
+
+  fn next_id(&mut self) -> u64 {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  /**
+   * Replays every entry up to and including the given timestamp in order and returns the open lots
+   * and the realizations.
+   */
+  fn replay(&self, method: LotMethod, until: Option<u64>) -> Result<(Vec<Lot>, Vec<Realization>)> {
+    let mut entries: Vec<&Entry> = self.entries.iter()
+      .filter(|e| until.map(|u| e.timestamp <= u).unwrap_or(true))
+      .collect();
+    entries.sort_by_key(|e| (e.timestamp, e.seq));
+    let mut held: Vec<Lot> = vec![];
+    let mut realizations = vec![];
+    for entry in entries {
+      match &entry.event {
+        Event::Acquire(lot) if lot.quantity > DUST => held.push(lot.clone()),
+        Event::Acquire(_) => (),
+        Event::Dispose(disposal) => {
+          let named: &[u64] = if method == LotMethod::Specific { &disposal.lot_ids } else { &[] };
+          let taken = take(&mut held, method, disposal.quantity, named)
+            .chain_err(|| ErrorKind::BasisError("There is not enough cost basis to support gains.".to_string()))?;
+          for lot in taken {
+            // The lot's share of the fee. Nothing is taken for an empty disposal so it has no share.
+            let fee = if disposal.quantity > 0.0 { disposal.fee * lot.quantity / disposal.quantity } else { 0.0 };
+            realizations.push(Realization {
+              disposal_id: disposal.id,
+              lot_id: lot.id,
+              acquired: lot.acquired,
+              disposed: disposal.timestamp,
+              quantity: lot.quantity,
+              cost: lot.quantity * lot.value,
+              proceeds: lot.quantity * disposal.value - fee,
+              term: Term::of(lot.acquired, disposal.timestamp, self.holding_period),
+            });
+          }
+        },
+        Event::Transfer(selected_with, named) => {
+          if lot_order(*selected_with) != lot_order(method) {
+            return Err(Error::from_kind(ErrorKind::BasisError(format!(
+              "Lots were transferred using {} and can not be replayed using {}.",
+              selected_with.name(),
+              method.name()
+            ))));
+          }
+          for (lot_id, quantity) in named {
+            take(&mut held, method, *quantity, &[*lot_id])
+              .chain_err(|| ErrorKind::BasisError("There is not enough cost basis to support transfer.".to_string()))?;
+          }
+        },
+      }
+    }
+    Ok((held, realizations))
+  }
+}
+
+/**
+ * Removes the quantity from the held lots, taking the named lots first and then
+ * following the order given by the method.
+ */
+fn take(held: &mut Vec<Lot>, method: LotMethod, quantity: f64, lot_ids: &[u64]) -> Result<Vec<Lot>> {
+  let mut order: Vec<usize> = (0..held.len()).collect();
+  order.sort_by(|a, b| compare_lots(&held[*a], &held[*b], method));
+  if !lot_ids.is_empty() {
+    // Named lots go first in the order they were named.
+    let named: Vec<usize> = lot_ids.iter()
+      .filter_map(|id| held.iter().position(|l| l.id == *id))
+      .collect();
+    order.retain(|i| !named.contains(i));
+    order = named.into_iter().chain(order).collect();
+  }
+  let mut remaining = quantity;
+  let mut taken = vec![];
+  for i in order {
+    if remaining <= DUST {
+      break;
+    }
+    let lot = &mut held[i];
+    let amount = lot.quantity.min(remaining);
+    if amount <= 0.0 {
+      continue;
+    }
+    lot.quantity -= amount;
+    remaining -= amount;
+    taken.push(Lot { id: lot.id, acquired: lot.acquired, quantity: amount, value: lot.value });
+  }
+  held.retain(|l| l.quantity > DUST);
+  if remaining > DUST {
+    return Err(Error::from_kind(ErrorKind::BasisError(format!("Missing basis for {} units.", remaining))));
+  }
+  Ok(taken)
+}
+
+/**
+ * The method that decides the order lots are taken in when none are named.
+ */
+fn lot_order(method: LotMethod) -> LotMethod {
+  match method {
+    LotMethod::Specific => LotMethod::Fifo,
+    other => other,
+  }
+}
+
+fn compare_lots(a: &Lot, b: &Lot, method: LotMethod) -> Ordering {
+  let oldest_first = (a.acquired, a.id).cmp(&(b.acquired, b.id));
+  match method {
+    LotMethod::Fifo | LotMethod::Specific => oldest_first,
+    LotMethod::Lifo => oldest_first.reverse(),
+    LotMethod::Hifo => b.value.partial_cmp(&a.value).unwrap_or(Ordering::Equal).then(oldest_first),
+  }
 }
 
 impl Basis for BasisImpl {
   fn new() -> Self {
     BasisImpl {
-      costs: VecDeque::new(),
-      gains: VecDeque::new(),
+      entries: Vec::new(),
+      next_id: 0,
+      method: LotMethod::Fifo,
+      holding_period: DEFAULT_HOLDING_PERIOD,
     }
   }
 
+  fn set_method(&mut self, method: LotMethod) -> &Self {
+    self.method = method;
+    self
+  }
+
+  fn method(&self) -> LotMethod {
+    self.method
+  }
+
+  fn set_holding_period(&mut self, seconds: u64) -> &Self {
+    self.holding_period = seconds;
+    self
+  }
+
+  fn holding_period(&self) -> u64 {
+    self.holding_period
+  }
+
   fn add_cost(&mut self, quantity: f64, value: f64) -> &Self {
-    self.costs.push_back(Amount { quantity, value });
+    self.acquire(0, quantity, value);
     self
   }
 
-  fn transfer_basis(&mut self, quantity: f64) -> Result<Vec<Amount>> {
-    let mut remaining_quantity = quantity;
-    let mut transferring = Vec::new();
-    while (remaining_quantity > 0.0) {
-      if let Some(oldest_quantity) = self.costs.pop_front() {
-        if oldest_quantity.quantity > remaining_quantity {
-          self.costs.push_front(Amount { quantity: oldest_quantity.quantity - remaining_quantity, value: oldest_quantity.value });
-          transferring.push(Amount { quantity: remaining_quantity, value: oldest_quantity.value });
-          remaining_quantity = 0.0;
-        } else {
-          remaining_quantity -= oldest_quantity.quantity;
-          transferring.push(Amount { quantity: oldest_quantity.quantity, value: oldest_quantity.value });
-        }
-      } else {
-        return Err(Error::from_kind(ErrorKind::BasisError("There is not enough cost basis to support transfer.".to_string())));
-      }
-    }
+  fn acquire(&mut self, timestamp: u64, quantity: f64, value: f64) -> u64 {
+    self.acquire_with_fee(timestamp, quantity, value, 0.0)
+  }
+
+  fn acquire_with_fee(&mut self, timestamp: u64, quantity: f64, value: f64, fee: f64) -> u64 {
+    let id = self.next_id();
+    let value = if quantity > 0.0 { value + fee / quantity } else { value };
+    self.push(timestamp, Event::Acquire(Lot { id, acquired: timestamp, quantity, value }));
+    id
+  }
+
+  fn receive_lot(&mut self, timestamp: u64, lot: &Lot) -> u64 {
+    let id = self.next_id();
+    self.push(timestamp, Event::Acquire(Lot { id, acquired: lot.acquired, quantity: lot.quantity, value: lot.value }));
+    id
+  }
+
+  fn transfer_basis(&mut self, quantity: f64) -> Result<Vec<Lot>> {
+    self.transfer_basis_at(0, quantity)
+  }
+
+  fn transfer_basis_at(&mut self, timestamp: u64, quantity: f64) -> Result<Vec<Lot>> {
+    let (mut held, _) = self.replay(self.method, Some(timestamp))?;
+    let transferring = take(&mut held, self.method, quantity, &[])
+      .chain_err(|| ErrorKind::BasisError("There is not enough cost basis to support transfer.".to_string()))?;
+    self.push(timestamp, Event::Transfer(self.method, transferring.iter().map(|l| (l.id, l.quantity)).collect()));
     Ok(transferring)
   }
 
   fn realize_gain(&mut self, quantity: f64, value: f64) -> &Self {
-    self.gains.push_back(Amount { quantity, value });
+    self.dispose(0, quantity, value);
     self
   }
 
+  fn dispose(&mut self, timestamp: u64, quantity: f64, value: f64) -> u64 {
+    self.dispose_lots(timestamp, quantity, value, vec![])
+  }
+
+  fn dispose_lots(&mut self, timestamp: u64, quantity: f64, value: f64, lot_ids: Vec<u64>) -> u64 {
+    self.dispose_with_fee(timestamp, quantity, value, 0.0, lot_ids)
+  }
+
+  fn dispose_with_fee(&mut self, timestamp: u64, quantity: f64, value: f64, fee: f64, lot_ids: Vec<u64>) -> u64 {
+    let id = self.next_id();
+    self.push(timestamp, Event::Dispose(Disposal { id, timestamp, quantity, value, fee, lot_ids }));
+    id
+  }
+
   fn calc_cost_basis(&mut self) -> Result<f64> {
-    let mut cost_basis = 0.0;
-    let mut cost_index = 0;
-    let mut cost_index_consumed = 0.0;
-    for amt in &self.gains {
-      let mut remaining_gain_quantity = amt.quantity;
-      while remaining_gain_quantity > 0.0 {
-        if let Some(oldest_cost) = self.costs.get(cost_index) {
-          let remaining_cost_quantity = oldest_cost.quantity - cost_index_consumed;
-          if remaining_cost_quantity > remaining_gain_quantity {
-            cost_basis += remaining_gain_quantity * oldest_cost.value;
-            cost_index_consumed += remaining_gain_quantity;
-            remaining_gain_quantity = 0.0;
-          } else {
-            cost_basis += remaining_cost_quantity * oldest_cost.value;
-            remaining_gain_quantity -= remaining_cost_quantity;
-            cost_index += 1;
-            cost_index_consumed = 0.0;
-          }
-        } else {
-          return Err(Error::from_kind(ErrorKind::BasisError("There is not enough cost basis to support gains.".to_string())));
-        }
-      }
-    }
-    Ok(cost_basis)
+    self.calc_cost_basis_with(self.method)
   }
 
   fn calc_capital_gain(&mut self) -> Result<f64> {
-    let cost_basis = self.calc_cost_basis()?;
-    let total_gains = self.gains.iter().fold(0f64, |acc, gain| acc + gain.quantity * gain.value);
-    Ok(total_gains - cost_basis)
+    self.calc_capital_gain_with(self.method)
+  }
+
+  fn calc_cost_basis_with(&self, method: LotMethod) -> Result<f64> {
+    Ok(self.realizations(method)?.iter().fold(0f64, |acc, r| acc + r.cost))
   }
-}
\ No newline at end of file
+
+  fn calc_capital_gain_with(&self, method: LotMethod) -> Result<f64> {
+    Ok(self.realizations(method)?.iter().fold(0f64, |acc, r| acc + r.gain()))
+  }
+
+  fn realizations(&self, method: LotMethod) -> Result<Vec<Realization>> {
+    Ok(self.replay(method, None)?.1)
+  }
+
+  fn calc_gains_by_term(&self, method: LotMethod) -> Result<TermSummary> {
+    Ok(TermSummary::from_realizations(&self.realizations(method)?))
+  }
+
+  fn open_lots(&self, method: LotMethod) -> Result<Vec<Lot>> {
+    Ok(self.replay(method, None)?.0)
+  }
+}
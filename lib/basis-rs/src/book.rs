@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashSet};
+use crate::basis::{Basis, BasisImpl, Lot, LotMethod, Realization, TermSummary};
+use crate::errors::*;
+
+/**
+ * A fee paid in some asset. `value` is the value of one unit of the asset at the time.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fee {
+  pub asset: String,
+  pub quantity: f64,
+  pub value: f64,
+}
+impl Fee {
+  pub fn new(asset: &str, quantity: f64, value: f64) -> Self {
+    Fee { asset: asset.to_uppercase(), quantity, value }
+  }
+
+  pub fn total(&self) -> f64 {
+    self.quantity * self.value
+  }
+}
+
+/**
+ * An exchange of `sold_quantity` of one asset for `bought_quantity` of another, worth
+ * `value` in total.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+  pub timestamp: u64,
+  pub sold: String,
+  pub sold_quantity: f64,
+  pub bought: String,
+  pub bought_quantity: f64,
+  pub value: f64,
+  pub fee: Option<Fee>,
+}
+impl Trade {
+  pub fn new(timestamp: u64, sold: &str, sold_quantity: f64, bought: &str, bought_quantity: f64, value: f64) -> Self {
+    Trade {
+      timestamp,
+      sold: sold.to_uppercase(),
+      sold_quantity,
+      bought: bought.to_uppercase(),
+      bought_quantity,
+      value,
+      fee: None,
+    }
+  }
+
+  pub fn with_fee(mut self, fee: Option<Fee>) -> Self {
+    self.fee = fee;
+    self
+  }
+}
+
+/**
+ * Tracks the cost basis of every asset in an account.
+ *
+ * Acquisition fees are added to the cost of the lot and disposal fees are subtracted
+ * from the proceeds. When the fee is paid in the traded asset the fee is taken out of
+ * the quantity received or added to the quantity disposed. When it is paid in a third
+ * asset, like BNB on Binance, spending the fee is itself a disposal of that asset.
+ * Fees paid in a cash currency are never disposals.
+ */
+pub struct BasisBook {
+  assets: BTreeMap<String, BasisImpl>,
+  cash: HashSet<String>,
+  method: LotMethod,
+  holding_period: Option<u64>,
+}
+
+impl Default for BasisBook {
+  fn default() -> Self {
+    BasisBook::new()
+  }
+}
+
+impl BasisBook {
+  pub fn new() -> Self {
+    let mut cash = HashSet::new();
+    cash.insert("USD".to_string());
+    BasisBook {
+      assets: BTreeMap::new(),
+      cash,
+      method: LotMethod::Fifo,
+      holding_period: None,
+    }
+  }
+
+  /**
+   * Marks a currency as cash. Only USD is cash by default.
+   */
+  pub fn add_cash(&mut self, currency: &str) -> &mut Self {
+    self.cash.insert(currency.to_uppercase());
+    self
+  }
+
+  pub fn is_cash(&self, asset: &str) -> bool {
+    self.cash.contains(&asset.to_uppercase())
+  }
+
+  pub fn set_method(&mut self, method: LotMethod) -> &mut Self {
+    self.method = method;
+    for basis in self.assets.values_mut() {
+      basis.set_method(method);
+    }
+    self
+  }
+
+  pub fn set_holding_period(&mut self, seconds: u64) -> &mut Self {
+    self.holding_period = Some(seconds);
+    for basis in self.assets.values_mut() {
+      basis.set_holding_period(seconds);
+    }
+    self
+  }
+
+  /**
+   * The cost basis of a single asset, created on first use.
+   */
+  pub fn asset(&mut self, asset: &str) -> &mut BasisImpl {
+    let method = self.method;
+    let holding_period = self.holding_period;
+    self.assets.entry(asset.to_uppercase()).or_insert_with(|| {
+      let mut basis: BasisImpl = Basis::new();
+      basis.set_method(method);
+      if let Some(seconds) = holding_period {
+        basis.set_holding_period(seconds);
+      }
+      basis
+    })
+  }
+
+  pub fn assets(&self) -> Vec<String> {
+    self.assets.keys().cloned().collect()
+  }
+
+  /**
+   * Records the acquisition of quantity units of the asset worth value each.
+   */
+  pub fn acquire(&mut self, asset: &str, timestamp: u64, quantity: f64, value: f64, fee: Option<&Fee>) -> u64 {
+    let asset = asset.to_uppercase();
+    match fee {
+      Some(fee) if fee.asset == asset => {
+        // The fee was withheld from what we received so the remaining units carry the whole cost.
+        let received = quantity - fee.quantity;
+        if received <= 0.0 {
+          // The fee took everything, there are no units to carry the cost.
+          return self.asset(&asset).acquire(timestamp, 0.0, value);
+        }
+        self.asset(&asset).acquire_with_fee(timestamp, received, value * quantity / received, 0.0)
+      },
+      Some(fee) => {
+        self.spend_fee(fee, timestamp);
+        self.asset(&asset).acquire_with_fee(timestamp, quantity, value, fee.total())
+      },
+      None => self.asset(&asset).acquire(timestamp, quantity, value),
+    }
+  }
+
+  /**
+   * Records the disposal of quantity units of the asset worth value each.
+   */
+  pub fn dispose(&mut self, asset: &str, timestamp: u64, quantity: f64, value: f64, fee: Option<&Fee>) -> u64 {
+    let asset = asset.to_uppercase();
+    match fee {
+      Some(fee) if fee.asset == asset => {
+        // The fee units leave the account too but bring in no proceeds.
+        let disposed = quantity + fee.quantity;
+        self.asset(&asset).dispose_with_fee(timestamp, disposed, value, fee.total(), vec![])
+      },
+      Some(fee) => {
+        self.spend_fee(fee, timestamp);
+        self.asset(&asset).dispose_with_fee(timestamp, quantity, value, fee.total(), vec![])
+      },
+      None => self.asset(&asset).dispose(timestamp, quantity, value),
+    }
+  }
+
+  /**
+   * Records an exchange of one asset for another worth `value` in total, like a fill on an
+   * alt/BTC market. The exchange is a disposal of the sold asset and an acquisition of the
+   * bought asset, both at the fair market value of the exchange. A fee paid in the bought
+   * asset is withheld from what was received, any other fee comes out of the proceeds.
+   * Returns the ids of the disposal and the lot, unless that side is cash. Either side
+   * without a quantity has no value per unit and is an error.
+   */
+  pub fn trade(&mut self, trade: &Trade) -> Result<(Option<u64>, Option<u64>)> {
+    if trade.sold_quantity <= 0.0 || trade.bought_quantity <= 0.0 {
+      return Err(Error::from_kind(ErrorKind::BasisError(format!(
+        "Can not trade {} {} for {} {}.",
+        trade.sold_quantity, trade.sold, trade.bought_quantity, trade.bought
+      ))));
+    }
+    let fee = trade.fee.as_ref();
+    let bought_fee = fee.filter(|f| f.asset == trade.bought);
+    let sold_fee = fee.filter(|f| f.asset != trade.bought);
+    let disposal = if self.is_cash(&trade.sold) {
+      None
+    } else {
+      Some(self.dispose(&trade.sold, trade.timestamp, trade.sold_quantity, trade.value / trade.sold_quantity, sold_fee))
+    };
+    let lot = if self.is_cash(&trade.bought) {
+      None
+    } else if disposal.is_none() {
+      // Bought with cash, so the fee is part of the cost like any other purchase.
+      Some(self.acquire(&trade.bought, trade.timestamp, trade.bought_quantity, trade.value / trade.bought_quantity, fee))
+    } else {
+      Some(self.acquire(&trade.bought, trade.timestamp, trade.bought_quantity, trade.value / trade.bought_quantity, bought_fee))
+    };
+    Ok((disposal, lot))
+  }
+
+  /**
+   * Paying a fee in a non-cash asset disposes of the fee at its market value.
+   */
+  fn spend_fee(&mut self, fee: &Fee, timestamp: u64) {
+    if !self.is_cash(&fee.asset) && fee.quantity > 0.0 {
+      self.asset(&fee.asset).dispose(timestamp, fee.quantity, fee.value);
+    }
+  }
+
+  /**
+   * Every realization grouped by asset.
+   */
+  pub fn realizations(&self) -> Result<BTreeMap<String, Vec<Realization>>> {
+    let mut realizations = BTreeMap::new();
+    for (asset, basis) in &self.assets {
+      let rows = basis.realizations(self.method).chain_err(|| format!("Could not compute the basis of {}", asset))?;
+      realizations.insert(asset.to_string(), rows);
+    }
+    Ok(realizations)
+  }
+
+  /**
+   * The lots still held grouped by asset.
+   */
+  pub fn open_lots(&self) -> Result<BTreeMap<String, Vec<Lot>>> {
+    let mut lots = BTreeMap::new();
+    for (asset, basis) in &self.assets {
+      let open = basis.open_lots(self.method).chain_err(|| format!("Could not compute the basis of {}", asset))?;
+      lots.insert(asset.to_string(), open);
+    }
+    Ok(lots)
+  }
+
+  /**
+   * Realized totals across every asset split by holding period.
+   */
+  pub fn summary(&self) -> Result<TermSummary> {
+    let all: Vec<Realization> = self.realizations()?.into_values().flatten().collect();
+    Ok(TermSummary::from_realizations(&all))
+  }
+}
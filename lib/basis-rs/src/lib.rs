@@ -3,10 +3,12 @@ extern crate error_chain;
 
 pub mod errors;
 pub mod basis;
+pub mod book;
 
 #[cfg(test)]
 mod tests {
-    use crate::basis::{Basis,BasisImpl};
+    use crate::basis::{Basis,BasisImpl,LotMethod,Term};
+    use crate::book::{BasisBook,Fee,Trade};
     use crate::errors::*;
 
     #[test]
@@ -25,6 +27,7 @@ mod tests {
         Ok(())
     }
 
+    #[test]
     fn test_transfer_balance() -> Result<()> {
         let mut account1: BasisImpl = Basis::new();
         let mut account2: BasisImpl = Basis::new();
@@ -41,6 +44,143 @@ mod tests {
         account1.realize_gain(100.0, 15.0);
         assert_eq!(account1.calc_cost_basis()?, 1250.0);
         assert_eq!(account1.calc_capital_gain()?, 250.0);
+        // The transfer took the oldest lot, so replaying it with HIFO would take other lots.
+        assert_eq!(account1.calc_cost_basis_with(LotMethod::Specific)?, 1250.0);
+        assert!(account1.calc_cost_basis_with(LotMethod::Hifo).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lot_methods() -> Result<()> {
+        let mut basis: BasisImpl = Basis::new();
+        basis.acquire(1, 10.0, 100.0);
+        let middle = basis.acquire(2, 10.0, 300.0);
+        basis.acquire(3, 10.0, 200.0);
+        basis.dispose_lots(4, 15.0, 400.0, vec![middle]);
+        assert_eq!(basis.calc_cost_basis_with(LotMethod::Fifo)?, 2500.0);
+        assert_eq!(basis.calc_cost_basis_with(LotMethod::Lifo)?, 3500.0);
+        assert_eq!(basis.calc_cost_basis_with(LotMethod::Hifo)?, 4000.0);
+        assert_eq!(basis.calc_cost_basis_with(LotMethod::Specific)?, 3500.0);
+        assert_eq!(basis.calc_capital_gain_with(LotMethod::Hifo)?, 2000.0);
+        basis.set_method(LotMethod::Lifo);
+        assert_eq!(basis.calc_capital_gain()?, 2500.0);
+        let open = basis.open_lots(LotMethod::Lifo)?;
+        assert_eq!(open.len(), 2);
+        assert_eq!(open.iter().map(|l| l.quantity).sum::<f64>(), 15.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lots_are_only_sold_once_held() -> Result<()> {
+        let mut basis: BasisImpl = Basis::new();
+        basis.acquire(1, 10.0, 100.0);
+        basis.dispose(2, 5.0, 150.0);
+        basis.acquire(3, 10.0, 500.0);
+        // The second lot was bought after the sale so even HIFO has to use the first lot.
+        assert_eq!(basis.calc_cost_basis_with(LotMethod::Hifo)?, 500.0);
+        basis.dispose(0, 1.0, 150.0);
+        assert!(basis.calc_cost_basis_with(LotMethod::Fifo).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gains_by_term() -> Result<()> {
+        let day = 24 * 60 * 60;
+        let mut basis: BasisImpl = Basis::new();
+        basis.acquire(0, 10.0, 100.0);
+        basis.acquire(300 * day, 10.0, 200.0);
+        basis.dispose(400 * day, 15.0, 300.0);
+        let realizations = basis.realizations(LotMethod::Fifo)?;
+        assert_eq!(realizations.len(), 2);
+        assert_eq!(realizations[0].term, Term::Long);
+        assert_eq!(realizations[1].term, Term::Short);
+        let summary = basis.calc_gains_by_term(LotMethod::Fifo)?;
+        assert_eq!(summary.long_term_gain(), 2000.0);
+        assert_eq!(summary.short_term_gain(), 500.0);
+        basis.set_holding_period(30 * day);
+        let summary = basis.calc_gains_by_term(LotMethod::Fifo)?;
+        assert_eq!(summary.long_term_gain(), 2500.0);
+        assert_eq!(summary.short_term_gain(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fees() -> Result<()> {
+        let mut basis: BasisImpl = Basis::new();
+        basis.acquire_with_fee(1, 10.0, 100.0, 10.0);
+        basis.dispose_with_fee(2, 10.0, 150.0, 20.0, vec![]);
+        assert_eq!(basis.calc_cost_basis()?, 1010.0);
+        assert_eq!(basis.calc_capital_gain()?, 470.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fees_in_other_assets() -> Result<()> {
+        let mut book = BasisBook::new();
+        book.acquire("BNB", 1, 10.0, 10.0, Some(&Fee::new("USD", 1.0, 1.0)));
+        // 0.001 ETH is withheld from the purchase.
+        book.acquire("ETH", 2, 1.001, 200.0, Some(&Fee::new("ETH", 0.001, 200.0)));
+        // Paying 1 BNB worth $15 in fees disposes of it.
+        book.dispose("ETH", 3, 1.0, 300.0, Some(&Fee::new("BNB", 1.0, 15.0)));
+        let realizations = book.realizations()?;
+        let bnb = &realizations["BNB"];
+        assert_eq!(bnb.len(), 1);
+        assert!((bnb[0].cost - 10.1).abs() < 1e-9);
+        assert!((bnb[0].proceeds - 15.0).abs() < 1e-9);
+        let eth = &realizations["ETH"];
+        assert!((eth[0].cost - 200.2).abs() < 1e-9);
+        assert!((eth[0].proceeds - 285.0).abs() < 1e-9);
+        let open = book.open_lots()?;
+        assert!((open["BNB"][0].quantity - 9.0).abs() < 1e-9);
+        assert!(open["ETH"].is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_quantities() -> Result<()> {
+        let mut book = BasisBook::new();
+        // The whole purchase is withheld as a fee.
+        book.acquire("ETH", 1, 0.001, 200.0, Some(&Fee::new("ETH", 0.001, 200.0)));
+        assert!(book.open_lots()?["ETH"].is_empty());
+
+        let mut basis: BasisImpl = Basis::new();
+        basis.acquire(1, 1.0, 100.0);
+        basis.dispose_with_fee(2, 0.0, 150.0, 5.0, vec![]);
+        basis.dispose(3, 1.0, 150.0);
+        let realizations = basis.realizations(LotMethod::Fifo)?;
+        assert_eq!(realizations.len(), 1);
+        assert!(realizations.iter().all(|r| r.proceeds.is_finite()));
+        assert_eq!(basis.calc_capital_gain()?, 50.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trades() -> Result<()> {
+        let mut book = BasisBook::new();
+        book.acquire("BTC", 1, 1.0, 10000.0, None);
+        // 0.5 BTC worth $7,500 buys 100 ETH. The BTC gain is realized and the ETH costs $75 each.
+        let (disposal, lot) = book.trade(&Trade::new(2, "BTC", 0.5, "ETH", 100.0, 7500.0))?;
+        assert!(disposal.is_some() && lot.is_some());
+        // 1 ETH of the next 10 bought is withheld as a fee.
+        book.trade(&Trade::new(3, "BTC", 0.1, "ETH", 10.0, 1000.0).with_fee(Some(Fee::new("ETH", 1.0, 100.0))))?;
+        let realizations = book.realizations()?;
+        let btc = &realizations["BTC"];
+        assert_eq!(btc.len(), 2);
+        assert!((btc[0].gain() - 2500.0).abs() < 1e-9);
+        assert!((btc[1].gain() - 0.0).abs() < 1e-9);
+        let open = book.open_lots()?;
+        assert!((open["BTC"][0].quantity - 0.4).abs() < 1e-9);
+        assert!((open["ETH"][0].value - 75.0).abs() < 1e-9);
+        assert!((open["ETH"][1].quantity - 9.0).abs() < 1e-9);
+        assert!((open["ETH"][1].quantity * open["ETH"][1].value - 1000.0).abs() < 1e-9);
+        // Selling for cash is an ordinary disposal.
+        let (disposal, lot) = book.trade(&Trade::new(4, "ETH", 10.0, "USD", 1000.0, 1000.0))?;
+        assert!(disposal.is_some() && lot.is_none());
+        assert!((book.realizations()?["ETH"][0].gain() - 250.0).abs() < 1e-9);
+        // Nothing received has no price per unit.
+        assert!(book.trade(&Trade::new(5, "ETH", 1.0, "BTC", 0.0, 100.0)).is_err());
+        assert!(book.trade(&Trade::new(5, "USD", 0.0, "BTC", 1.0, 100.0)).is_err());
+        assert_eq!(book.open_lots()?["ETH"].len(), 2);
         Ok(())
     }
 }
@@ -2,6 +2,7 @@ use reqwest::{get};
 use std::collections::{HashMap,HashSet};
 use std::time::SystemTime;
 use crate::model::*;
+use crate::errors::*;
 
 const CMC_BASE_URL: &str = "https://pro-api.coinmarketcap.com";
 const CM_BASE_URL: &str = "https://coinmetrics.io/api";
@@ -77,6 +78,48 @@ impl CMCClient {
     body
   }
 
+  /**
+   * Daily closing USD prices from coinmetrics between two unix timestamps.
+   */
+  pub fn historic_prices(&self, symbol: &str, start: u64, end: u64) -> Result<CMCHistoricalQuotesResponse> {
+    let url: &str = &format!(
+      "{}/v1/get_asset_data_for_time_range/{}/price(usd)/{}/{}",
+      CM_BASE_URL,
+      symbol.to_lowercase(),
+      start,
+      end
+    );
+    let mut response = get(url)?;
+    let body: CMCHistoricalQuotesResponse = response.json()?;
+    Ok(body)
+  }
+
+  /**
+   * Quotes from CoinMarketCap between two unix timestamps at an interval such as
+   * "hourly" or "daily". This endpoint requires a plan with historical data access.
+   */
+  pub fn historical_quotes(&self, symbol: &str, start: u64, end: u64, interval: &str) -> Result<CMCHistoricalQuotes> {
+    let url: &str = &format!(
+      "{}/v1/cryptocurrency/quotes/historical?symbol={}&time_start={}&time_end={}&interval={}&CMC_PRO_API_KEY={}",
+      CMC_BASE_URL,
+      symbol.to_uppercase(),
+      start,
+      end,
+      interval,
+      self.key
+    );
+    let mut response = get(url)?;
+    if !response.status().is_success() {
+      let status = response.status();
+      bail!(ErrorKind::CMCError(status.as_u16() as u64, format!("Historical quotes for {} are unavailable", symbol)));
+    }
+    let body: CMCHistoricalQuotesListing = response.json()?;
+    if body.status.error_code != 0 {
+      bail!(ErrorKind::CMCError(body.status.error_code, body.status.error_message.unwrap_or_default()));
+    }
+    Ok(body.data)
+  }
+
   pub fn supported_assets(&self) -> HashSet<String> {
     let url: &str = &format!(
       "{}/v1/get_supported_assets",
@@ -2,4 +2,15 @@ error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
     }
+
+    errors {
+        CMCError(code: u64, msg: String) {
+            description("CMCError")
+            display("CMCError({}): '{}'", code, msg)
+        }
+    }
+
+    foreign_links {
+        ReqError(::reqwest::Error);
+    }
 }
@@ -68,9 +68,16 @@ impl CMCListingResponse {
   }
 }
 #[derive(Deserialize)]
+pub struct CMCHistoricalPrice {
+  pub price: f64,
+  pub volume_24h: Option<f64>,
+  pub market_cap: Option<f64>,
+  pub timestamp: Option<String>,
+}
+#[derive(Deserialize)]
 pub struct CMCHistoricalQuote {
   pub timestamp: String,
-  pub quote: HashMap<String, CMCQuote>,
+  pub quote: HashMap<String, CMCHistoricalPrice>,
 }
 #[derive(Deserialize)]
 pub struct CMCHistoricalQuotes {
@@ -79,6 +86,11 @@ pub struct CMCHistoricalQuotes {
   pub symbol: String,
   pub quotes: Vec<CMCHistoricalQuote>,
 }
+#[derive(Deserialize)]
+pub struct CMCHistoricalQuotesListing {
+  pub data: CMCHistoricalQuotes,
+  pub status: CMCStatus,
+}
 #[derive(Deserialize,Debug)]
 pub struct CMCHistoricalQuotesResponse {
   pub result: Vec<(u64, f64)>,
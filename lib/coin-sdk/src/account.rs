@@ -1,5 +1,6 @@
 use crate::errors::*;
-use crate::model::{AccountConfig, Asset, Amount};
+use crate::ledger::LedgerEntry;
+use crate::model::{AccountConfig, Asset};
 
 /**
  * The account is the bridge abstraction.
@@ -15,11 +16,8 @@ pub trait Account {
 
   fn list_assets(&self) -> Result<Vec<Asset>>;
 
-  fn total_costs(&self) -> Result<Amount>;
-
-  fn total_gains(&self) -> Result<Amount>;
-
-  fn cost_basis(&self) -> Result<()>;
-
-  fn capital_gains(&self) -> Result<()>;
+  /**
+   * Returns the full transaction history of the account as ledger entries.
+   */
+  fn ledger(&self) -> Result<Vec<LedgerEntry>>;
 }
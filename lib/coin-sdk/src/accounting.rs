@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, HashMap};
+use basis::basis::{Basis, Lot, LotMethod, Realization, DEFAULT_HOLDING_PERIOD};
+use basis::book::{BasisBook, Fee, Trade};
+use crate::errors::*;
+use crate::ledger::{is_fiat, EntryKind, Ledger, LedgerEntry, FIAT_CURRENCIES};
+use crate::model::Amount;
+use crate::prices::HistoricalPrices;
+use crate::transfers::{carry_basis, TransferMatch};
+
+// Assets whose price is used to value a trade before the other side's.
+const PRICE_REFERENCES: [&str; 8] = ["USD", "USDT", "USDC", "BUSD", "DAI", "BTC", "ETH", "BNB"];
+
+/**
+ * What an account paid for, sold and still holds of a single asset, in USD.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AssetPosition {
+  pub account: String,
+  pub asset: String,
+  pub quantity: f64,
+  // The cost of the lots still held. Sums of positions only count positions with a market value
+  // here, so the unrealized gain compares like with like.
+  pub cost_basis: f64,
+  // In sums of positions, the cost of the lots held of assets without a current price.
+  pub unpriced_cost: f64,
+  // The cost of everything bought or received from outside, excluding transfers in from other
+  // accounts and what was acquired by trading another asset.
+  pub invested: f64,
+  pub proceeds: f64,
+  pub realized_cost: f64,
+  pub income: f64,
+  pub market_value: Option<f64>,
+}
+impl AssetPosition {
+  pub fn realized_gain(&self) -> f64 {
+    self.proceeds - self.realized_cost
+  }
+
+  pub fn unrealized_gain(&self) -> Option<f64> {
+    self.market_value.map(|v| v - self.cost_basis)
+  }
+
+  /**
+   * The average price paid per unit of the open lots.
+   */
+  pub fn average_cost(&self) -> Option<f64> {
+    if self.quantity > 0.0 { Some((self.cost_basis + self.unpriced_cost) / self.quantity) } else { None }
+  }
+
+  // Adds a position to a sum of positions.
+  fn add(&mut self, p: &AssetPosition) {
+    self.invested += p.invested;
+    self.proceeds += p.proceeds;
+    self.realized_cost += p.realized_cost;
+    self.income += p.income;
+    self.unpriced_cost += p.unpriced_cost;
+    match p.market_value {
+      Some(value) => {
+        self.cost_basis += p.cost_basis;
+        self.market_value = Some(self.market_value.unwrap_or(0.0) + value);
+      }
+      None => self.unpriced_cost += p.cost_basis,
+    }
+  }
+}
+
+/**
+ * Cost basis and gains of every account built from the ledger.
+ * `issues` lists entries that could not be accounted for, like a deposit without a price.
+ */
+#[derive(Debug, Default)]
+pub struct PortfolioReport {
+  pub positions: Vec<AssetPosition>,
+  pub issues: Vec<String>,
+}
+impl PortfolioReport {
+  pub fn accounts(&self) -> Vec<String> {
+    let mut accounts: Vec<String> = self.positions.iter().map(|p| p.account.to_string()).collect();
+    accounts.dedup();
+    accounts
+  }
+
+  /**
+   * Sums the positions of an account, or of every account, into a single position. The cost of
+   * positions without a market value goes to `unpriced_cost` rather than `cost_basis`.
+   */
+  pub fn total(&self, account: Option<&str>) -> AssetPosition {
+    let mut total = AssetPosition::default();
+    total.account = account.unwrap_or("Total").to_string();
+    for p in self.positions.iter().filter(|p| account.map(|a| a == p.account).unwrap_or(true)) {
+      total.add(p);
+    }
+    total
+  }
+
+  /**
+   * Sums the positions of each asset across the named accounts, or every account.
+   */
+  pub fn by_asset(&self, accounts: Option<&[String]>) -> BTreeMap<String, AssetPosition> {
+    let mut totals: BTreeMap<String, AssetPosition> = BTreeMap::new();
+    for p in self.positions.iter().filter(|p| accounts.map(|a| a.contains(&p.account)).unwrap_or(true)) {
+      let total = totals.entry(p.asset.to_string()).or_insert_with(|| AssetPosition {
+        account: "Total".to_string(),
+        asset: p.asset.to_string(),
+        ..Default::default()
+      });
+      total.quantity += p.quantity;
+      total.add(p);
+    }
+    totals
+  }
+}
+
+/**
+ * One basis book per account built by replaying the ledger in order.
+ */
+pub struct Books {
+  pub method: LotMethod,
+  holding_period: Option<u64>,
+  pub accounts: BTreeMap<String, BasisBook>,
+  invested: HashMap<(String, String), f64>,
+  income: HashMap<(String, String), f64>,
+  pub issues: Vec<String>,
+}
+
+impl Books {
+  /**
+   * Replays every ledger entry in order. Matched transfers move lots between accounts,
+   * deposits from outside and income are acquired at market value and withdrawals to
+   * outside remove lots without realizing a gain. Trades between two crypto assets dispose
+   * of one and acquire the other at fair market value.
+   */
+  pub fn build(ledger: &Ledger, transfers: &[TransferMatch], prices: &HistoricalPrices, method: LotMethod, holding_period: Option<u64>) -> Self {
+    let mut books = Books {
+      method,
+      holding_period,
+      accounts: BTreeMap::new(),
+      invested: HashMap::new(),
+      income: HashMap::new(),
+      issues: vec![],
+    };
+    let withdrawals: HashMap<(String, String), &TransferMatch> = transfers.iter().map(|t| (t.withdrawal_key(), t)).collect();
+    let deposits: HashMap<(String, String), &TransferMatch> = transfers.iter().map(|t| (t.deposit_key(), t)).collect();
+    let mut entries: Vec<&LedgerEntry> = ledger.entries.iter().collect();
+    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    for entry in entries {
+      books.book(&entry.account);
+      let result = if let Some(transfer) = withdrawals.get(&entry.key()) {
+        books.transfer(transfer, prices)
+      } else if deposits.contains_key(&entry.key()) {
+        // The lots were moved when the withdrawal was replayed.
+        Ok(())
+      } else {
+        books.record(entry, prices)
+      };
+      if let Err(e) = result {
+        books.issues.push(format!("{} {}: {}", entry.account, entry.id, e));
+      }
+    }
+    books
+  }
+
+  fn book(&mut self, account: &str) -> &mut BasisBook {
+    let method = self.method;
+    let holding_period = self.holding_period;
+    self.accounts.entry(account.to_string()).or_insert_with(|| {
+      let mut book = BasisBook::new();
+      for fiat in FIAT_CURRENCIES.iter() {
+        book.add_cash(fiat);
+      }
+      book.set_method(method);
+      if let Some(seconds) = holding_period {
+        book.set_holding_period(seconds);
+      }
+      book
+    })
+  }
+
+  fn transfer(&mut self, transfer: &TransferMatch, prices: &HistoricalPrices) -> Result<()> {
+    if is_fiat(&transfer.asset) {
+      return Ok(());
+    }
+    self.book(&transfer.to_account);
+    let mut from = self.accounts.remove(&transfer.from_account).unwrap_or_else(BasisBook::new);
+    let moved = {
+      let to = self.accounts.get_mut(&transfer.to_account).unwrap();
+      carry_basis(&mut from, to, transfer)
+    };
+    self.accounts.insert(transfer.from_account.to_string(), from);
+    if let Err(e) = moved {
+      // Without the sending side's history the best we can do is start over at market value.
+      let price = prices.price_at(&transfer.asset, transfer.received_at)?;
+      self.acquire(&transfer.to_account, &transfer.asset, transfer.received_at, transfer.received, price, None);
+      bail!("{}. Received at market value instead.", e);
+    }
+    Ok(())
+  }
+
+  fn record(&mut self, entry: &LedgerEntry, prices: &HistoricalPrices) -> Result<()> {
+    let account = &entry.account;
+    match entry.kind {
+      EntryKind::Buy => {
+        let received = expect_quantity(&entry.received, entry)?;
+        let value = self.usd_value(entry, prices)?;
+        let fee = self.fee(entry, prices)?;
+        self.acquire(account, &received.currency, entry.timestamp, received.amount, value / received.amount, fee.as_ref());
+      },
+      EntryKind::Sell => {
+        let sent = expect_quantity(&entry.sent, entry)?;
+        let value = self.usd_value(entry, prices)?;
+        let fee = self.fee(entry, prices)?;
+        self.book(account).dispose(&sent.currency, entry.timestamp, sent.amount, value / sent.amount, fee.as_ref());
+      },
+      EntryKind::Trade => {
+        let sent = expect_quantity(&entry.sent, entry)?;
+        let received = expect_quantity(&entry.received, entry)?;
+        let value = self.trade_value(entry, sent, received, prices)?;
+        // A fee in either traded asset is worth what the trade implies.
+        let fee = match &entry.fee {
+          Some(fee) if fee.amount > 0.0 && fee.currency == sent.currency => Some(Fee::new(&fee.currency, fee.amount, value / sent.amount)),
+          Some(fee) if fee.amount > 0.0 && fee.currency == received.currency => Some(Fee::new(&fee.currency, fee.amount, value / received.amount)),
+          _ => self.fee(entry, prices)?,
+        };
+        let trade = Trade::new(entry.timestamp, &sent.currency, sent.amount, &received.currency, received.amount, value).with_fee(fee);
+        self.book(account).trade(&trade)?;
+      },
+      EntryKind::Deposit | EntryKind::Transfer | EntryKind::Income if entry.received.is_some() => {
+        let received = expect(&entry.received, entry)?;
+        if is_fiat(&received.currency) {
+          return Ok(());
+        }
+        let price = prices.price_at(&received.currency, entry.timestamp)?;
+        self.acquire(account, &received.currency, entry.timestamp, received.amount, price, None);
+        if entry.kind == EntryKind::Income {
+          *self.income.entry((account.to_string(), received.currency.to_uppercase())).or_insert(0.0) += received.amount * price;
+        }
+      },
+      EntryKind::Withdrawal | EntryKind::Transfer => {
+        let sent = expect(&entry.sent, entry)?;
+        if is_fiat(&sent.currency) {
+          return Ok(());
+        }
+        // Funds leaving for somewhere we do not track take their basis with them.
+        let quantity = sent.amount + entry.fee.as_ref().filter(|f| f.currency == sent.currency).map(|f| f.amount).unwrap_or(0.0);
+        self.book(account).asset(&sent.currency).transfer_basis_at(entry.timestamp, quantity)?;
+      },
+      EntryKind::Fee => {
+        let sent = expect(&entry.sent, entry)?;
+        if is_fiat(&sent.currency) {
+          return Ok(());
+        }
+        let price = prices.price_at(&sent.currency, entry.timestamp)?;
+        self.book(account).dispose(&sent.currency, entry.timestamp, sent.amount, price, None);
+      },
+      EntryKind::Deposit | EntryKind::Income => bail!("{} has nothing received", entry.kind.name()),
+    }
+    Ok(())
+  }
+
+  fn acquire(&mut self, account: &str, asset: &str, timestamp: u64, quantity: f64, price: f64, fee: Option<&Fee>) {
+    let fee_total = fee.map(|f| f.total()).unwrap_or(0.0);
+    *self.invested.entry((account.to_string(), asset.to_uppercase())).or_insert(0.0) += quantity * price + fee_total;
+    self.book(account).acquire(asset, timestamp, quantity, price, fee);
+  }
+
+  /**
+   * The USD value of an entry, as reported by the provider or from the fiat side of the entry.
+   */
+  fn usd_value(&self, entry: &LedgerEntry, prices: &HistoricalPrices) -> Result<f64> {
+    if let Some(value) = entry.usd_value {
+      return Ok(value);
+    }
+    let fiat = match (&entry.sent, &entry.received) {
+      (Some(s), _) if is_fiat(&s.currency) => s,
+      (_, Some(r)) if is_fiat(&r.currency) => r,
+      _ => bail!("{} has no fiat value", entry.kind.name()),
+    };
+    Ok(fiat.amount * usd_price(&fiat.currency, entry.timestamp, prices)?)
+  }
+
+  /**
+   * The fair market value of a trade in USD. Whichever side has a price is used, preferring
+   * the assets that are most liquid and most likely to have price history.
+   */
+  fn trade_value(&self, entry: &LedgerEntry, sent: &Amount, received: &Amount, prices: &HistoricalPrices) -> Result<f64> {
+    if let Some(value) = entry.usd_value {
+      return Ok(value);
+    }
+    let mut sides = vec![sent, received];
+    sides.sort_by_key(|a| PRICE_REFERENCES.iter().position(|r| *r == a.currency.to_uppercase()).unwrap_or(PRICE_REFERENCES.len()));
+    let mut errors = vec![];
+    for side in sides {
+      match usd_price(&side.currency, entry.timestamp, prices) {
+        Ok(price) => return Ok(side.amount * price),
+        Err(e) => errors.push(e.to_string()),
+      }
+    }
+    bail!("Could not value the trade: {}", errors.join(", "))
+  }
+
+  fn fee(&self, entry: &LedgerEntry, prices: &HistoricalPrices) -> Result<Option<Fee>> {
+    match &entry.fee {
+      Some(fee) if fee.amount > 0.0 => {
+        let price = usd_price(&fee.currency, entry.timestamp, prices)?;
+        Ok(Some(Fee::new(&fee.currency, fee.amount, price)))
+      },
+      _ => Ok(None),
+    }
+  }
+
+  /**
+   * The position of every asset in every account. Prices are current USD prices by symbol
+   * and are used to value the open lots.
+   */
+  pub fn report(&mut self, current_prices: &HashMap<String, f64>) -> PortfolioReport {
+    let mut report = PortfolioReport::default();
+    report.issues = self.issues.clone();
+    let method = self.method;
+    for (account, book) in self.accounts.iter_mut() {
+      for asset in book.assets() {
+        let basis = book.asset(&asset);
+        let rows = basis.realizations(method).and_then(|r| Ok((r, basis.open_lots(method)?)));
+        let (realizations, lots) = match rows {
+          Ok(rows) => rows,
+          Err(e) => {
+            report.issues.push(format!("{} {}: {}", account, asset, e));
+            continue;
+          },
+        };
+        let key = (account.to_string(), asset.to_string());
+        let quantity: f64 = lots.iter().map(|l| l.quantity).sum();
+        report.positions.push(AssetPosition {
+          account: account.to_string(),
+          asset: asset.to_string(),
+          quantity,
+          cost_basis: lots.iter().map(|l| l.quantity * l.value).sum(),
+          unpriced_cost: 0.0,
+          invested: self.invested.get(&key).cloned().unwrap_or(0.0),
+          proceeds: realizations.iter().map(|r| r.proceeds).sum(),
+          realized_cost: realizations.iter().map(|r| r.cost).sum(),
+          income: self.income.get(&key).cloned().unwrap_or(0.0),
+          market_value: current_prices.get(&asset).map(|p| p * quantity),
+        });
+      }
+    }
+    report
+  }
+
+  /**
+   * Every lot still held in every account keyed by account and asset.
+   */
+  pub fn open_lots(&self) -> Result<Vec<(String, String, Lot)>> {
+    let mut rows = vec![];
+    for (account, book) in &self.accounts {
+      let lots = book.open_lots().chain_err(|| format!("Could not compute the lots of {}", account))?;
+      for (asset, open) in lots {
+        rows.extend(open.into_iter().map(|l| (account.to_string(), asset.to_string(), l)));
+      }
+    }
+    Ok(rows)
+  }
+
+  pub fn holding_period(&self) -> u64 {
+    self.holding_period.unwrap_or(DEFAULT_HOLDING_PERIOD)
+  }
+
+  /**
+   * Every realization of every account keyed by account and asset.
+   */
+  pub fn realizations(&self) -> Result<Vec<(String, String, Realization)>> {
+    let mut rows = vec![];
+    for (account, book) in &self.accounts {
+      let realizations = book.realizations().chain_err(|| format!("Could not compute the gains of {}", account))?;
+      for (asset, realized) in realizations {
+        rows.extend(realized.into_iter().map(|r| (account.to_string(), asset.to_string(), r)));
+      }
+    }
+    Ok(rows)
+  }
+}
+
+fn expect<'a>(amount: &'a Option<Amount>, entry: &LedgerEntry) -> Result<&'a Amount> {
+  match amount {
+    Some(a) => Ok(a),
+    None => bail!("{} {} is missing an amount", entry.kind.name(), entry.id),
+  }
+}
+
+/**
+ * Like `expect` but the amount must be positive, since the price per unit is divided by it.
+ */
+fn expect_quantity<'a>(amount: &'a Option<Amount>, entry: &LedgerEntry) -> Result<&'a Amount> {
+  let a = expect(amount, entry)?;
+  if a.amount <= 0.0 {
+    bail!("{} {} has no {} to price", entry.kind.name(), entry.id, a.currency);
+  }
+  Ok(a)
+}
+
+/**
+ * The USD price of a currency. Crypto price history has no exchange rates between fiat
+ * currencies so fiat other than USD can not be priced and must come with a USD value.
+ */
+fn usd_price(currency: &str, timestamp: u64, prices: &HistoricalPrices) -> Result<f64> {
+  if !is_fiat(currency) {
+    return prices.price_at(currency, timestamp);
+  }
+  if currency.to_uppercase() != "USD" {
+    bail!("There is no USD exchange rate for {}. Import the entry with its USD value instead", currency.to_uppercase());
+  }
+  Ok(1.0)
+}
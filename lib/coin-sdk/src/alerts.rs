@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::errors::*;
+
+/**
+ * A condition to alert on, configured as [[alerts.rules]] in ~/.coin.toml with its kind. Rules
+ * that take an optional symbol apply to every asset held when it is left out.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertRule {
+  // The USD price is above or below a level.
+  Price { symbol: String, above: Option<f64>, below: Option<f64> },
+  // The price moved more than this many percent in 24 hours, either way.
+  #[serde(rename = "change_24h")]
+  Change24h { symbol: Option<String>, percent: f64 },
+  // The portfolio's value is this many percent below the highest value seen.
+  Drawdown { percent: f64 },
+  // The asset is not among the top `rank` assets by market cap.
+  Top { symbol: Option<String>, rank: u64 },
+}
+
+/**
+ * The price of an asset in USD, its change over 24 hours in percent and its market cap rank.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quote {
+  pub price: f64,
+  pub change_24h: f64,
+  pub rank: u64,
+}
+
+/**
+ * What the rules are evaluated against.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MarketView {
+  pub quotes: HashMap<String, Quote>,
+  // How many of the largest assets were fetched. Assets missing from the quotes rank below that.
+  pub depth: u64,
+  // The symbols held in any account.
+  pub held: Vec<String>,
+  // The value of every account together in USD, when it could be fetched.
+  pub portfolio_value: Option<f64>,
+}
+
+/**
+ * An alert to deliver. The key tells conditions apart, e.g. the same 24h rule for two assets.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Alert {
+  pub key: String,
+  pub rule: String,
+  pub message: String,
+  pub timestamp: u64,
+}
+
+impl AlertRule {
+  pub fn describe(&self) -> String {
+    match self {
+      AlertRule::Price { symbol, above, below } => {
+        let mut levels = vec![];
+        if let Some(a) = above {
+          levels.push(format!("above ${}", a));
+        }
+        if let Some(b) = below {
+          levels.push(format!("below ${}", b));
+        }
+        format!("{} {}", symbol, levels.join(" or "))
+      }
+      AlertRule::Change24h { symbol, percent } => {
+        format!("{} moves {}% in 24h", symbol.as_ref().map(|s| &s[..]).unwrap_or("Any asset held"), percent)
+      }
+      AlertRule::Drawdown { percent } => format!("Portfolio {}% below its peak", percent),
+      AlertRule::Top { symbol, rank } => {
+        format!("{} leaves the top {}", symbol.as_ref().map(|s| &s[..]).unwrap_or("Any asset held"), rank)
+      }
+    }
+  }
+
+  /**
+   * The conditions of the rule that hold now, as keys and messages. `peak` is the highest portfolio
+   * value seen, including the current one.
+   */
+  pub fn evaluate(&self, market: &MarketView, peak: Option<f64>) -> Vec<(String, String)> {
+    let rule = self.describe();
+    let symbols = |symbol: &Option<String>| match symbol {
+      Some(s) => vec![s.to_uppercase()],
+      None => market.held.iter().map(|s| s.to_uppercase()).collect(),
+    };
+    let mut triggered = vec![];
+    match self {
+      AlertRule::Price { symbol, above, below } => {
+        if let Some(quote) = market.quotes.get(&symbol.to_uppercase()) {
+          if let Some(level) = above.filter(|l| quote.price > *l) {
+            triggered.push((format!("{}:above", rule), format!("{} is at ${:.2}, above ${}", symbol, quote.price, level)));
+          }
+          if let Some(level) = below.filter(|l| quote.price < *l) {
+            triggered.push((format!("{}:below", rule), format!("{} is at ${:.2}, below ${}", symbol, quote.price, level)));
+          }
+        }
+      }
+      AlertRule::Change24h { symbol, percent } => {
+        for s in symbols(symbol) {
+          if let Some(quote) = market.quotes.get(&s).filter(|q| q.change_24h.abs() > *percent) {
+            triggered.push((format!("{}:{}", rule, s), format!("{} moved {:+.2}% in 24h to ${:.2}", s, quote.change_24h, quote.price)));
+          }
+        }
+      }
+      AlertRule::Drawdown { percent } => {
+        if let (Some(value), Some(peak)) = (market.portfolio_value, peak) {
+          let drawdown = if peak > 0.0 { (peak - value) / peak * 100.0 } else { 0.0 };
+          if drawdown > *percent {
+            triggered.push((rule, format!("The portfolio is worth ${:.2}, {:.2}% below its peak of ${:.2}", value, drawdown, peak)));
+          }
+        }
+      }
+      AlertRule::Top { symbol, rank } => {
+        for s in symbols(symbol) {
+          // A missing asset is only known to be outside the top when enough assets were fetched.
+          let position = match market.quotes.get(&s).map(|q| q.rank) {
+            Some(r) if r > *rank => format!("ranks {}", r),
+            None if market.depth >= *rank => format!("ranks below {}", market.depth),
+            _ => continue,
+          };
+          triggered.push((format!("{}:{}", rule, s), format!("{} {}, outside the top {}", s, position, rank)));
+        }
+      }
+    }
+    triggered
+  }
+}
+
+/**
+ * What the watcher remembers between evaluations, kept in ~/.coin/alerts.json.
+ */
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AlertState {
+  // The highest portfolio value seen, for drawdowns.
+  pub peak: Option<f64>,
+  // The conditions that held at the last evaluation and were delivered.
+  pub active: BTreeSet<String>,
+  // When each condition last alerted.
+  pub fired: BTreeMap<String, u64>,
+}
+
+impl AlertState {
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(AlertState::default());
+    }
+    let contents = fs::read_to_string(path)?;
+    let state = serde_json::from_str(&contents).chain_err(|| format!("Could not read alert state {:?}", path))?;
+    Ok(state)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /**
+   * Evaluates the rules and returns the alerts to deliver. A condition alerts when it starts to
+   * hold and not again while it keeps holding. Once it alerted it stays quiet for `rearm` seconds
+   * even if it clears and holds again, then alerts if it still holds. Only alerts passed to
+   * `delivered` count as alerted, the others are returned again at the next evaluation.
+   */
+  pub fn evaluate(&mut self, rules: &[AlertRule], market: &MarketView, rearm: u64, now: u64) -> Vec<Alert> {
+    if let Some(value) = market.portfolio_value {
+      self.peak = Some(self.peak.map(|p| p.max(value)).unwrap_or(value));
+    }
+    let mut alerts = vec![];
+    let mut active = BTreeSet::new();
+    for rule in rules {
+      for (key, message) in rule.evaluate(market, self.peak) {
+        if self.active.contains(&key) {
+          active.insert(key);
+          continue;
+        }
+        let armed = self.fired.get(&key).map(|fired| now >= fired + rearm).unwrap_or(true);
+        if armed {
+          alerts.push(Alert { key, rule: rule.describe(), message, timestamp: now });
+        }
+      }
+    }
+    self.active = active;
+    alerts
+  }
+
+  /**
+   * Records that an alert reached at least one channel.
+   */
+  pub fn delivered(&mut self, alert: &Alert) {
+    self.fired.insert(alert.key.to_string(), alert.timestamp);
+    self.active.insert(alert.key.to_string());
+  }
+}
+
+pub fn state_path(data_dir: &Path) -> PathBuf {
+  data_dir.join("alerts.json")
+}
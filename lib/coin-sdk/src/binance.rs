@@ -1,20 +1,26 @@
-use std::collections::{HashMap};
-use std::fs::File;
-use std::io::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 use binance::api::*;
 use binance::account::{Account as AccountImpl};
-use binance::market::{Market};
-use binance::model::{Prices};
+use binance::model::{TradeHistory};
 use binance::wapi::{Wapi};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
 use crate::model::{AccountConfig, Asset, Amount};
 use crate::errors::*;
 use crate::account::Account;
+use crate::ledger::{EntryKind, LedgerEntry};
+
+const API_URL: &str = "https://api.binance.com";
+// The most trades binance returns for one request.
+const TRADES_PER_REQUEST: usize = 1000;
 
 pub struct BinanceAccount {
   config: AccountConfig,
   account: AccountImpl,
-  market: Market,
   wapi: Wapi,
+  http: reqwest::Client,
   rounding: HashMap<String, i32>,
 }
 
@@ -23,13 +29,12 @@ impl BinanceAccount {
     match (&config.key, &config.secret) {
       (Some(k), Some(s)) => {
         let account = Binance::new(Some(k.to_string()), Some(s.to_string()));
-        let market: Market = Binance::new(None, None);
         let wapi: Wapi = Binance::new(Some(k.to_string()), Some(s.to_string()));
         Ok(BinanceAccount {
           config,
           account,
-          market,
           wapi,
+          http: reqwest::Client::new(),
           rounding: make_rounding_rules(),
         })
       },
@@ -68,26 +73,171 @@ impl Account for BinanceAccount {
     Ok(coerced)
   }
 
-  fn total_costs(&self) -> Result<Amount> {
-    let trades = self.account.trade_history("ETHBTC")?;
-    // let mut file = File::create(format!("{}_ETHBTC_transactions.json", &self.config.name))?;
-    // file.write_all(serde_json::to_string_pretty(&trades)?.as_bytes())?;
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
+  /**
+   * Binance only returns trades for one symbol at a time so trades are fetched for every
+   * pair with an asset that is held, was ever deposited or withdrawn, or was traded on
+   * another pair.
+   */
+  fn ledger(&self) -> Result<Vec<LedgerEntry>> {
+    let mut entries = vec![];
+    let mut assets: HashSet<String> = self.list_assets()?
+      .into_iter()
+      .filter(|a| a.total() > 0.0)
+      .map(|a| a.asset)
+      .collect();
+    let deposits = serde_json::to_value(&self.wapi.get_deposit_history()?)?;
+    for deposit in list(&deposits, &["depositList", "deposit_list"]) {
+      let entry = self.transfer_entry(deposit, EntryKind::Deposit)?;
+      assets.extend(entry.received.iter().map(|a| a.currency.to_string()));
+      entries.push(entry);
+    }
+    let withdrawals = serde_json::to_value(&self.wapi.get_withdraw_history()?)?;
+    for withdrawal in list(&withdrawals, &["withdrawList", "withdraw_list"]) {
+      let entry = self.transfer_entry(withdrawal, EntryKind::Withdrawal)?;
+      assets.extend(entry.sent.iter().map(|a| a.currency.to_string()));
+      entries.push(entry);
+    }
+    let pairs = self.pairs()?;
+    let mut fetched = HashSet::new();
+    let mut touched = HashSet::new();
+    let mut pending: Vec<String> = assets.into_iter().collect();
+    while let Some(asset) = pending.pop() {
+      if !touched.insert(asset.to_string()) {
+        continue;
+      }
+      for (symbol, base, quote) in &pairs {
+        if (base != &asset && quote != &asset) || !fetched.insert(symbol.to_string()) {
+          continue;
+        }
+        let trades = self.trade_history(symbol)?;
+        if !trades.is_empty() {
+          pending.push(base.to_string());
+          pending.push(quote.to_string());
+        }
+        for trade in trades {
+          entries.push(self.trade_entry(symbol, base, quote, &trade)?);
+        }
+      }
+    }
+    Ok(entries)
   }
+}
 
-  fn total_gains(&self) -> Result<Amount> {
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
+impl BinanceAccount {
+  /**
+   * Every symbol ever listed with its base and quote asset.
+   */
+  fn pairs(&self) -> Result<Vec<(String, String, String)>> {
+    let mut response = self.http.get(&format!("{}/api/v3/exchangeInfo", API_URL)).send()?;
+    if !response.status().is_success() {
+      bail!("Error fetching binance symbols: {}", response.status());
+    }
+    let info: Value = response.json()?;
+    Ok(list(&info, &["symbols"]).into_iter()
+      .filter_map(|s| Some((text(s, &["symbol"])?, text(s, &["baseAsset"])?, text(s, &["quoteAsset"])?)))
+      .collect())
   }
 
-  fn cost_basis(&self) -> Result<()> {
-    let deposits = self.wapi.get_deposit_history()?;
-    let mut file = File::create("binance_deposits.json")?;
-    file.write_all(serde_json::to_string_pretty(&deposits)?.as_bytes())?;
-    Ok(())
+  /**
+   * Every trade on the symbol, oldest first. The client only returns the latest trades so
+   * pages are requested here from the first trade id on.
+   */
+  fn trade_history(&self, symbol: &str) -> Result<Vec<TradeHistory>> {
+    let mut trades: Vec<TradeHistory> = vec![];
+    loop {
+      let from_id = trades.last().map(|t| t.id + 1).unwrap_or(0);
+      let query = format!("symbol={}&fromId={}&limit={}", symbol, from_id, TRADES_PER_REQUEST);
+      let page: Vec<TradeHistory> = serde_json::from_value(self.signed_get("/api/v3/myTrades", &query)?)?;
+      let done = page.len() < TRADES_PER_REQUEST;
+      trades.extend(page);
+      if done {
+        return Ok(trades);
+      }
+    }
   }
 
-  fn capital_gains(&self) -> Result<()> {
-    Ok(())
+  fn signed_get(&self, path: &str, query: &str) -> Result<Value> {
+    let (key, secret) = match (&self.config.key, &self.config.secret) {
+      (Some(k), Some(s)) => (k, s),
+      _ => bail!("Binance account {} requires a key and secret.", &self.config.name),
+    };
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Invalid SystemTime.").as_millis();
+    let query = format!("{}&recvWindow=5000&timestamp={}", query, timestamp);
+    let mut mac: Hmac<Sha256> = Hmac::new_varkey(secret.as_bytes()).expect("Hmac::new(key)");
+    mac.input(query.as_bytes());
+    let url = format!("{}{}?{}&signature={}", API_URL, path, query, hex::encode(&mac.result().code()));
+    let mut response = self.http.get(&url).header("X-MBX-APIKEY", &key[..]).send()?;
+    if !response.status().is_success() {
+      bail!("Error fetching {} from binance: {} {}", path, response.status(), response.text().unwrap_or_default());
+    }
+    Ok(response.json()?)
+  }
+
+  fn trade_entry(&self, symbol: &str, base: &str, quote: &str, trade: &TradeHistory) -> Result<LedgerEntry> {
+    let id = format!("binance:trade:{}:{}", symbol, trade.id);
+    let mut entry = LedgerEntry::new(&self.config.name, id, EntryKind::Trade, trade.time / 1000);
+    let base_amount = Amount { amount: trade.qty, currency: base.to_string() };
+    let quote_amount = Amount { amount: trade.qty * trade.price, currency: quote.to_string() };
+    if trade.is_buyer {
+      entry.received = Some(base_amount);
+      entry.sent = Some(quote_amount);
+    } else {
+      entry.sent = Some(base_amount);
+      entry.received = Some(quote_amount);
+    }
+    entry.fee = Some(Amount { amount: trade.commission.parse()?, currency: trade.commission_asset.to_string() });
+    Ok(entry)
+  }
+
+  /**
+   * Reads a deposit or withdrawal from its JSON representation.
+   */
+  fn transfer_entry(&self, item: &Value, kind: EntryKind) -> Result<LedgerEntry> {
+    let asset = text(item, &["asset", "coin"]).unwrap_or_default().to_uppercase();
+    let amount = number(item, &["amount"]).unwrap_or(0.0);
+    let time = number(item, &["insertTime", "insert_time", "applyTime", "apply_time"]).unwrap_or(0.0) as u64 / 1000;
+    let tx_hash = text(item, &["txId", "tx_id"]);
+    let id = match text(item, &["id"]) {
+      Some(id) => format!("binance:{}:{}", kind.name(), id),
+      None => format!("binance:{}:{}:{}:{}", kind.name(), asset, time, tx_hash.as_ref().map(|s| &s[..]).unwrap_or("")),
+    };
+    let mut entry = LedgerEntry::new(&self.config.name, id, kind, time);
+    let quantity = Some(Amount { amount, currency: asset.to_string() });
+    if kind == EntryKind::Deposit {
+      entry.received = quantity;
+    } else {
+      entry.sent = quantity;
+      entry.fee = number(item, &["transactionFee", "transaction_fee"]).map(|fee| Amount { amount: fee, currency: asset.to_string() });
+    }
+    entry.tx_hash = tx_hash;
+    Ok(entry)
+  }
+}
+
+fn field<'a>(item: &'a Value, keys: &[&str]) -> Option<&'a Value> {
+  keys.iter().filter_map(|k| item.get(*k)).next()
+}
+
+fn list<'a>(value: &'a Value, keys: &[&str]) -> Vec<&'a Value> {
+  match field(value, keys) {
+    Some(Value::Array(items)) => items.iter().collect(),
+    _ => vec![],
+  }
+}
+
+fn text(item: &Value, keys: &[&str]) -> Option<String> {
+  match field(item, keys)? {
+    Value::String(s) if !s.is_empty() => Some(s.to_string()),
+    Value::Number(n) => Some(n.to_string()),
+    _ => None,
+  }
+}
+
+fn number(item: &Value, keys: &[&str]) -> Option<f64> {
+  match field(item, keys)? {
+    Value::Number(n) => n.as_f64(),
+    Value::String(s) => s.parse().ok(),
+    _ => None,
   }
 }
 
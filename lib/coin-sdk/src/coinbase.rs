@@ -1,12 +1,11 @@
 use std::collections::{HashMap};
-use std::fs::File;
-use std::io::prelude::*;
 use crate::model::{AccountConfig, Asset, Amount};
 use crate::errors::*;
 use coinbase::account::{Account as AccountImpl};
 use coinbase::api::{Coinbase};
-use coinbase::model::{CoinbaseTransactionType};
+use coinbase::model::{CoinbaseAmount, CoinbaseTransaction, CoinbaseTransactionStatus, CoinbaseTransactionType};
 use crate::account::Account;
+use crate::ledger::{is_fiat, parse_timestamp, EntryKind, LedgerEntry};
 
 pub struct CoinbaseAccount {
   config: AccountConfig,
@@ -53,45 +52,91 @@ impl Account for CoinbaseAccount {
     Ok(accounts)
   }
 
-  fn total_costs(&self) -> Result<Amount> {
+  fn ledger(&self) -> Result<Vec<LedgerEntry>> {
     let accounts = self.client.list_accounts()?;
-    let mut total_cost = 0.0;
+    let mut entries = vec![];
     for account in accounts.data {
-      let transactions = self.client.list_transactions(&account.id)?;
-      let mut file = File::create(format!("coinbase_{}_transactions.json", &account.id))?;
-      file.write_all(serde_json::to_string_pretty(&transactions)?.as_bytes())?;
-      let acct_cost = transactions.data.iter().filter(
-        |transaction| transaction.r#type == CoinbaseTransactionType::Buy
-      ).fold(0.0, |acc, x| acc + x.native_amount.amount.parse::<f64>().unwrap_or(0.0));
-      total_cost += acct_cost;
+      // Buy fees are only reported on the buy resource.
+      let mut buy_fees = HashMap::new();
+      for buy in self.client.list_buys(&account.id)?.data {
+        buy_fees.insert(buy.id.to_string(), to_amount(&buy.fee)?);
+      }
+      for transaction in self.client.list_transactions(&account.id)?.data {
+        if let Some(mut entry) = self.ledger_entry(&transaction)? {
+          entry.fee = transaction.buy.as_ref()
+            .and_then(|b| b.id.as_ref())
+            .and_then(|id| buy_fees.get(id).cloned())
+            .or(entry.fee);
+          entries.push(entry);
+        }
+      }
     }
-    Ok(Amount { amount: total_cost, currency: "USD".to_string() })
+    Ok(entries)
   }
+}
 
-  fn total_gains(&self) -> Result<Amount> {
-    let accounts = self.client.list_accounts()?;
-    let mut total_gains = 0.0;
-    for account in accounts.data {
-      let transactions = self.client.list_transactions(&account.id)?;
-      let acct_cost = transactions.data.iter().filter(
-        |transaction| transaction.r#type == CoinbaseTransactionType::Sell
-      ).fold(0.0, |acc, x| acc + x.native_amount.amount.parse::<f64>().unwrap_or(0.0));
-      total_gains += acct_cost;
+impl CoinbaseAccount {
+  /**
+   * Converts a completed Coinbase transaction into a ledger entry.
+   */
+  fn ledger_entry(&self, transaction: &CoinbaseTransaction) -> Result<Option<LedgerEntry>> {
+    if transaction.status != CoinbaseTransactionStatus::Completed {
+      return Ok(None);
     }
-    Ok(Amount { amount: total_gains, currency: "USD".to_string() })
-  }
-
-  fn cost_basis(&self) -> Result<()> {
-    let accounts = self.client.list_accounts()?;
-    for account in accounts.data {
-      let transactions = self.client.list_transactions(&account.id)?;
-      let mut file = File::create(format!("coinbase_{}_transactions.json", &account.id))?;
-      file.write_all(serde_json::to_string_pretty(&transactions)?.as_bytes())?;
+    let amount = to_amount(&transaction.amount)?;
+    let native = to_amount(&transaction.native_amount)?;
+    let incoming = amount.amount > 0.0;
+    let quantity = Amount { amount: amount.amount.abs(), currency: amount.currency.to_string() };
+    let native = Amount { amount: native.amount.abs(), currency: native.currency.to_string() };
+    let kind = match transaction.r#type {
+      CoinbaseTransactionType::Buy => EntryKind::Buy,
+      CoinbaseTransactionType::Sell => EntryKind::Sell,
+      CoinbaseTransactionType::Trade => EntryKind::Trade,
+      CoinbaseTransactionType::FiatDeposit => EntryKind::Deposit,
+      CoinbaseTransactionType::FiatWithdrawal => EntryKind::Withdrawal,
+      CoinbaseTransactionType::ExchangeDeposit
+      | CoinbaseTransactionType::ExchangeWithdrawal
+      | CoinbaseTransactionType::ProDeposit
+      | CoinbaseTransactionType::ProWithdrawal
+      | CoinbaseTransactionType::VaultWithdrawal
+      | CoinbaseTransactionType::Transfer => EntryKind::Transfer,
+      CoinbaseTransactionType::Send | CoinbaseTransactionType::Request if incoming => EntryKind::Deposit,
+      CoinbaseTransactionType::Send | CoinbaseTransactionType::Request => EntryKind::Withdrawal,
+      CoinbaseTransactionType::Other if incoming => EntryKind::Income,
+      CoinbaseTransactionType::Other => EntryKind::Fee,
+    };
+    let mut entry = LedgerEntry::new(&self.config.name, format!("coinbase:{}", transaction.id), kind, parse_timestamp(&transaction.created_at)?);
+    match kind {
+      // Buys and sells settle against the native currency. Trades are recorded one leg per account.
+      EntryKind::Buy => {
+        entry.received = Some(quantity);
+        entry.sent = Some(native.clone());
+      },
+      EntryKind::Sell => {
+        entry.sent = Some(quantity);
+        entry.received = Some(native.clone());
+      },
+      _ if incoming => entry.received = Some(quantity),
+      _ => entry.sent = Some(quantity),
     }
-    Ok(())
+    if native.currency == "USD" {
+      entry.usd_value = Some(native.amount);
+    }
+    if let Some(network) = &transaction.network {
+      entry.tx_hash = network.hash.clone();
+      if let Some(fee) = &network.transaction_fee {
+        entry.fee = Some(to_amount(fee)?);
+      }
+    }
+    entry.description = transaction.description.clone();
+    // Fiat moving in and out of the account is not a transfer of an asset we track.
+    if kind == EntryKind::Transfer && is_fiat(&amount.currency) {
+      entry.kind = if incoming { EntryKind::Deposit } else { EntryKind::Withdrawal };
+    }
+    Ok(Some(entry))
   }
+}
 
-  fn capital_gains(&self) -> Result<()> {
-    Ok(())
-  }
+fn to_amount(amount: &CoinbaseAmount) -> Result<Amount> {
+  Ok(Amount { amount: amount.amount.parse()?, currency: amount.currency.to_string() })
 }
\ No newline at end of file
@@ -1,12 +1,23 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
 use crate::model::{AccountConfig, Asset, Amount};
 use crate::account::{Account};
 use crate::errors::*;
+use crate::ledger::{is_fiat, EntryKind, LedgerEntry};
 use coinbase_pro_rs::{Private, Sync, MAIN_URL};
+use coinbase_pro_rs::structs::private::{AccountHistory, AccountHistoryDetails, AccountHistoryDetailsTransferType, Fill};
+use coinbase_pro_rs::structs::reqs::OrderSide;
+
+// The most items coinbase pro returns for one request.
+const ITEMS_PER_REQUEST: usize = 100;
 
 pub struct CoinbaseProAccount {
   config: AccountConfig,
   private: Private<Sync>,
+  http: reqwest::Client,
 }
 
 impl CoinbaseProAccount {
@@ -14,6 +25,7 @@ impl CoinbaseProAccount {
     match (&config.passphrase, &config.key, &config.secret) {
       (Some(ps), Some(k), Some(s)) => Ok(CoinbaseProAccount {
         private: Private::new(MAIN_URL, k, s, ps),
+        http: reqwest::Client::new(),
         config,
       }),
       _ => bail!("CoinbasePro accounts requires a key, secret, and passphrase")
@@ -48,19 +60,123 @@ impl Account for CoinbaseProAccount {
     Ok(results)
   }
 
-  fn total_costs(&self) -> Result<Amount> {
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
-  }
-
-  fn total_gains(&self) -> Result<Amount> {
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
+  fn ledger(&self) -> Result<Vec<LedgerEntry>> {
+    let accounts = match self.private.get_accounts() {
+      Ok(accounts) => accounts,
+      Err(e) => bail!("Error fetching coinbase pro accounts {:?}", e),
+    };
+    let mut entries = vec![];
+    let mut products = HashSet::new();
+    for account in accounts {
+      let history: Vec<AccountHistory> = self.get_pages(&format!("/accounts/{}/ledger", account.id))
+        .chain_err(|| format!("Error fetching coinbase pro history for {}", account.currency))?;
+      for item in history {
+        match &item.details {
+          AccountHistoryDetails::Match { product_id, .. } => {
+            products.insert(product_id.to_string());
+          },
+          AccountHistoryDetails::Transfer { transfer_id, transfer_type } => {
+            let kind = match transfer_type {
+              AccountHistoryDetailsTransferType::Deposit => EntryKind::Deposit,
+              AccountHistoryDetailsTransferType::Withdraw => EntryKind::Withdrawal,
+            };
+            let mut entry = LedgerEntry::new(&self.config.name, format!("coinbasepro:transfer:{}", transfer_id), kind, item.created_at.timestamp() as u64);
+            let amount = Some(Amount { amount: item.amount.abs(), currency: account.currency.to_string() });
+            if kind == EntryKind::Deposit {
+              entry.received = amount;
+            } else {
+              entry.sent = amount;
+            }
+            entries.push(entry);
+          },
+          _ => (),
+        }
+      }
+    }
+    // Trades are read from fills which carry the price and fee of each match.
+    for product in products {
+      let fills: Vec<Fill> = self.get_pages(&format!("/fills?product_id={}", product))
+        .chain_err(|| format!("Error fetching coinbase pro fills for {}", product))?;
+      for fill in fills {
+        entries.push(self.fill_entry(&fill)?);
+      }
+    }
+    Ok(entries)
   }
+}
 
-  fn cost_basis(&self) -> Result<()> {
-    Ok(())
+impl CoinbaseProAccount {
+  /**
+   * Every item of a paginated resource. The client only returns the first page so pages are
+   * requested here, each from the cursor coinbase pro returns in the CB-AFTER header.
+   */
+  fn get_pages<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>> {
+    let (key, secret, passphrase) = match (&self.config.key, &self.config.secret, &self.config.passphrase) {
+      (Some(k), Some(s), Some(ps)) => (k, s, ps),
+      _ => bail!("CoinbasePro accounts requires a key, secret, and passphrase"),
+    };
+    let secret = base64::decode(secret).chain_err(|| "The coinbase pro secret is not base64")?;
+    let separator = if path.contains('?') { '&' } else { '?' };
+    let mut items = vec![];
+    let mut after: Option<String> = None;
+    loop {
+      let mut uri = format!("{}{}limit={}", path, separator, ITEMS_PER_REQUEST);
+      if let Some(cursor) = &after {
+        uri.push_str(&format!("&after={}", cursor));
+      }
+      let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Invalid SystemTime.").as_secs().to_string();
+      let mut mac: Hmac<Sha256> = Hmac::new_varkey(&secret).expect("Hmac::new(key)");
+      mac.input(format!("{}GET{}", timestamp, uri).as_bytes());
+      let mut response = self.http.get(&format!("{}{}", MAIN_URL, uri))
+        .header("User-Agent", "coin")
+        .header("CB-ACCESS-KEY", &key[..])
+        .header("CB-ACCESS-SIGN", base64::encode(&mac.result().code()))
+        .header("CB-ACCESS-TIMESTAMP", &timestamp[..])
+        .header("CB-ACCESS-PASSPHRASE", &passphrase[..])
+        .send()?;
+      if !response.status().is_success() {
+        bail!("{} {}", response.status(), response.text().unwrap_or_default());
+      }
+      after = response.headers().get("CB-AFTER").and_then(|h| h.to_str().ok()).map(|h| h.to_string());
+      let page: Vec<T> = response.json()?;
+      let done = page.len() < ITEMS_PER_REQUEST || after.is_none();
+      items.extend(page);
+      if done {
+        return Ok(items);
+      }
+    }
   }
 
-  fn capital_gains(&self) -> Result<()> {
-    Ok(())
+  fn fill_entry(&self, fill: &Fill) -> Result<LedgerEntry> {
+    let pair: Vec<&str> = fill.product_id.split('-').collect();
+    let (base, quote) = match &pair[..] {
+      [base, quote] => (base.to_string(), quote.to_string()),
+      _ => bail!("Unexpected coinbase pro product {}", fill.product_id),
+    };
+    let kind = match (&fill.side, is_fiat(&quote)) {
+      (OrderSide::Buy, true) => EntryKind::Buy,
+      (OrderSide::Sell, true) => EntryKind::Sell,
+      _ => EntryKind::Trade,
+    };
+    let id = format!("coinbasepro:fill:{}:{}", fill.product_id, fill.trade_id);
+    let mut entry = LedgerEntry::new(&self.config.name, id, kind, fill.created_at.timestamp() as u64);
+    let base_amount = Amount { amount: fill.size, currency: base };
+    let quote_amount = Amount { amount: fill.size * fill.price, currency: quote.to_string() };
+    match fill.side {
+      OrderSide::Buy => {
+        entry.received = Some(base_amount);
+        entry.sent = Some(quote_amount);
+      },
+      OrderSide::Sell => {
+        entry.sent = Some(base_amount);
+        entry.received = Some(quote_amount);
+      },
+    }
+    // Fees are always charged in the quote currency.
+    entry.fee = Some(Amount { amount: fill.fee, currency: quote.to_string() });
+    if quote == "USD" {
+      entry.usd_value = Some(fill.size * fill.price);
+    }
+    Ok(entry)
   }
 }
\ No newline at end of file
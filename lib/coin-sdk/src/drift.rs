@@ -0,0 +1,131 @@
+use std::collections::{BTreeSet, HashMap};
+
+/**
+ * How far an asset's share of a portfolio is from its target. Weights are fractions of the
+ * portfolio's value and drift is the current weight less the target.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AssetDrift {
+  pub symbol: String,
+  pub current: f64,
+  pub target: f64,
+  pub drift: f64,
+}
+
+/**
+ * The drift of every asset held or targeted, largest first. The tracking error is the root of the
+ * summed squared drifts, so a few small drifts add up without any one asset crossing a threshold.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DriftReport {
+  pub assets: Vec<AssetDrift>,
+  pub tracking_error: f64,
+}
+
+impl DriftReport {
+  /**
+   * Compares the current weights to the targets. Both are scaled to add up to one first, an asset
+   * missing from either side has a weight of zero there.
+   */
+  pub fn new(current: &HashMap<String, f64>, target: &HashMap<String, f64>) -> Self {
+    let current = normalize(current);
+    let target = normalize(target);
+    let symbols: BTreeSet<&String> = current.keys().chain(target.keys()).collect();
+    let mut assets: Vec<AssetDrift> = symbols.into_iter().map(|symbol| {
+      let c = current.get(symbol).cloned().unwrap_or(0.0);
+      let t = target.get(symbol).cloned().unwrap_or(0.0);
+      AssetDrift { symbol: symbol.to_string(), current: c, target: t, drift: c - t }
+    }).collect();
+    assets.sort_by(|a, b| b.drift.abs().partial_cmp(&a.drift.abs()).unwrap_or(std::cmp::Ordering::Equal));
+    let tracking_error = assets.iter().map(|a| a.drift * a.drift).sum::<f64>().sqrt();
+    DriftReport { assets, tracking_error }
+  }
+
+  /**
+   * The asset furthest from its target.
+   */
+  pub fn max_drift(&self) -> Option<&AssetDrift> {
+    self.assets.first()
+  }
+}
+
+/**
+ * When drift calls for a rebalance. Thresholds are fractions of the portfolio's value, the
+ * cooldown is in seconds.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriftPolicy {
+  // The drift of a single asset that triggers a rebalance.
+  pub threshold: f64,
+  // The tracking error that triggers a rebalance.
+  pub max_tracking_error: f64,
+  // How long after a rebalance drift is ignored.
+  pub cooldown: u64,
+  // How many triggered rebalances only preview their trades before any is placed.
+  pub dry_runs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriftDecision {
+  // Every asset is within the limits.
+  Hold,
+  // Drifted, but the last rebalance was too recent. Holds the time the cooldown ends.
+  CoolingDown(u64),
+  // Drifted, preview the rebalance. Holds which of the policy's dry runs this is, from 1.
+  DryRun(u64),
+  // Drifted after the dry runs, but none of them was confirmed. Preview the rebalance again.
+  Unconfirmed,
+  Rebalance,
+}
+
+impl DriftPolicy {
+  /**
+   * Why the report calls for a rebalance, or None when it is within the limits.
+   */
+  pub fn breach(&self, report: &DriftReport) -> Option<String> {
+    if let Some(asset) = report.max_drift().filter(|a| a.drift.abs() > self.threshold) {
+      return Some(format!(
+        "{} is at {:.2}% against a target of {:.2}%",
+        asset.symbol, asset.current * 100.0, asset.target * 100.0
+      ));
+    }
+    if report.tracking_error > self.max_tracking_error {
+      return Some(format!(
+        "The tracking error is {:.2}% against a limit of {:.2}%",
+        report.tracking_error * 100.0, self.max_tracking_error * 100.0
+      ));
+    }
+    None
+  }
+
+  /**
+   * What to do about drift at `now`, given when the last rebalance ran, how many dry runs drift
+   * has triggered so far and whether one of them was confirmed. Dry runs count towards the cooldown
+   * like any rebalance. Drift only rebalances once the dry runs are done and one was confirmed.
+   */
+  pub fn decide(&self, drifted: bool, last_rebalance: Option<u64>, dry_runs_done: u64, confirmed: bool, now: u64) -> DriftDecision {
+    if !drifted {
+      return DriftDecision::Hold;
+    }
+    if let Some(last) = last_rebalance {
+      if now < last + self.cooldown {
+        return DriftDecision::CoolingDown(last + self.cooldown);
+      }
+    }
+    if dry_runs_done < self.dry_runs {
+      DriftDecision::DryRun(dry_runs_done + 1)
+    } else if !confirmed {
+      DriftDecision::Unconfirmed
+    } else {
+      DriftDecision::Rebalance
+    }
+  }
+}
+
+fn normalize(weights: &HashMap<String, f64>) -> HashMap<String, f64> {
+  let total: f64 = weights.values().filter(|w| **w > 0.0).sum();
+  weights.iter()
+    .filter(|(_, w)| **w > 0.0)
+    .map(|(s, w)| (s.to_string(), w / total))
+    .collect()
+}
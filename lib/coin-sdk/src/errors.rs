@@ -8,16 +8,24 @@ error_chain! {
             description("CoinError")
             display("CoinError: '{}'", msg)
         }
+        NoPriceData(symbol: String, timestamp: u64) {
+            description("NoPriceData")
+            display("No price data for {} at {}", symbol, timestamp)
+        }
     }
 
     links {
         Coinbase(::coinbase::errors::Error, coinbase::errors::ErrorKind);
         Binance(::binance::errors::Error, binance::errors::ErrorKind);
+        CMC(::cmc::errors::Error, cmc::errors::ErrorKind);
+        Basis(::basis::errors::Error, basis::errors::ErrorKind);
     }
 
     foreign_links {
         ParseError(::std::num::ParseFloatError);
         SerdeJsonError(::serde_json::error::Error);
         StdIo(::std::io::Error);
+        Csv(::csv::Error);
+        Reqwest(::reqwest::Error);
     }
 }
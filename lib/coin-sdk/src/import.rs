@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::io::Read;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use crate::errors::*;
+use crate::ledger::{is_fiat, EntryKind, LedgerEntry};
+use crate::model::Amount;
+
+/**
+ * The CSV exports `ledger import` understands.
+ *
+ * `Generic` is our own schema for exchanges without a supported export. Its columns are
+ * `timestamp,kind,received_amount,received_currency,sent_amount,sent_currency,fee_amount,fee_currency,usd_value,tx_hash,id,description`
+ * and only `timestamp` and `kind` are required. `kind` is one of buy, sell, trade, deposit,
+ * withdrawal, transfer, fee or income and timestamps are RFC 3339, `YYYY-MM-DD HH:MM:SS` in UTC
+ * or seconds since the epoch.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+  BinanceTrades,
+  BinanceDeposits,
+  BinanceWithdrawals,
+  Coinbase,
+  CoinbaseProFills,
+  CoinbaseProAccount,
+  Generic,
+}
+impl ImportFormat {
+  pub fn all() -> Vec<ImportFormat> {
+    vec![
+      ImportFormat::BinanceTrades,
+      ImportFormat::BinanceDeposits,
+      ImportFormat::BinanceWithdrawals,
+      ImportFormat::Coinbase,
+      ImportFormat::CoinbaseProFills,
+      ImportFormat::CoinbaseProAccount,
+      ImportFormat::Generic,
+    ]
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      ImportFormat::BinanceTrades => "binance-trades",
+      ImportFormat::BinanceDeposits => "binance-deposits",
+      ImportFormat::BinanceWithdrawals => "binance-withdrawals",
+      ImportFormat::Coinbase => "coinbase",
+      ImportFormat::CoinbaseProFills => "coinbasepro-fills",
+      ImportFormat::CoinbaseProAccount => "coinbasepro-account",
+      ImportFormat::Generic => "generic",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Result<ImportFormat> {
+    match ImportFormat::all().into_iter().find(|f| f.name() == name.to_lowercase()) {
+      Some(format) => Ok(format),
+      None => {
+        let names: Vec<String> = ImportFormat::all().iter().map(|f| f.name().to_string()).collect();
+        bail!("Unknown import format {}. Use one of {}", name, names.join(", "))
+      },
+    }
+  }
+
+  /**
+   * Recognizes an export from its header row. Binance deposit and withdrawal exports share
+   * a header so they can not be told apart.
+   */
+  fn detect(headers: &[String]) -> Option<ImportFormat> {
+    let has = |name: &str| headers.iter().any(|h| h == name);
+    if has("date(utc)") && (has("pair") || has("market")) {
+      Some(ImportFormat::BinanceTrades)
+    } else if has("transaction type") && has("quantity transacted") {
+      Some(ImportFormat::Coinbase)
+    } else if has("trade id") && has("product") && has("size") {
+      Some(ImportFormat::CoinbaseProFills)
+    } else if has("amount/balance unit") && has("transfer id") {
+      Some(ImportFormat::CoinbaseProAccount)
+    } else if has("timestamp") && has("kind") {
+      Some(ImportFormat::Generic)
+    } else {
+      None
+    }
+  }
+}
+
+/**
+ * The entries read from an export and the number of rows that were not imported,
+ * like pending transfers or transaction types that do not move funds.
+ */
+#[derive(Debug)]
+pub struct Import {
+  pub format: ImportFormat,
+  pub entries: Vec<LedgerEntry>,
+  pub skipped: usize,
+}
+
+/**
+ * Reads an exchange CSV export into ledger entries for the account. The format is detected
+ * from the header when it is not given. Lines before the header, like the preamble of
+ * Coinbase reports, are ignored. A row whose id an earlier row of the file already has, like
+ * identical fills in the same second, gets the number of those earlier rows appended.
+ */
+pub fn read_export<R: Read>(mut reader: R, account: &str, format: Option<ImportFormat>) -> Result<Import> {
+  let mut contents = String::new();
+  reader.read_to_string(&mut contents)?;
+  let contents = contents.trim_start_matches('\u{feff}');
+  let lines: Vec<&str> = contents.lines().collect();
+  let mut found = None;
+  for (i, line) in lines.iter().enumerate() {
+    let headers = split_header(line);
+    let detected = match ImportFormat::detect(&headers) {
+      Some(detected) => Some(detected),
+      None if headers.iter().any(|h| h == "txid") && headers.iter().any(|h| h == "coin") => {
+        match format {
+          Some(ImportFormat::BinanceDeposits) | Some(ImportFormat::BinanceWithdrawals) => format,
+          _ => bail!("Binance deposit and withdrawal exports look the same. Use --format binance-deposits or binance-withdrawals"),
+        }
+      },
+      None => None,
+    };
+    if let Some(detected) = detected {
+      found = Some((i, detected));
+      break;
+    }
+  }
+  let (start, detected) = match found {
+    Some(found) => found,
+    None => bail!("Could not find a known CSV header. Use --format to name the export"),
+  };
+  if let Some(format) = format {
+    if format != detected {
+      bail!("The file looks like a {} export, not {}", detected.name(), format.name());
+    }
+  }
+  let body = lines[start..].join("\n");
+  let mut csv = csv::ReaderBuilder::new().flexible(true).from_reader(body.as_bytes());
+  let headers: Vec<String> = csv.headers()?.iter().map(normalize).collect();
+  let mut import = Import { format: detected, entries: vec![], skipped: 0 };
+  // How often each id was read, since identical fills in the same second get the same id.
+  let mut seen: HashMap<String, usize> = HashMap::new();
+  for (line, record) in csv.records().enumerate() {
+    let record = record?;
+    let row = Row { headers: &headers, record: &record };
+    if row.is_empty() {
+      continue;
+    }
+    let entry = read_row(detected, account, &row).chain_err(|| format!("Could not read line {} of the {} export", start + line + 2, detected.name()))?;
+    match entry {
+      Some(mut entry) => {
+        let count = seen.entry(entry.id.to_string()).or_insert(0);
+        if *count > 0 {
+          entry.id = format!("{}:{}", entry.id, count);
+        }
+        *count += 1;
+        entry.imported = true;
+        import.entries.push(entry);
+      },
+      None => import.skipped += 1,
+    }
+  }
+  Ok(import)
+}
+
+fn read_row(format: ImportFormat, account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  match format {
+    ImportFormat::BinanceTrades => binance_trade(account, row),
+    ImportFormat::BinanceDeposits => binance_transfer(account, row, EntryKind::Deposit),
+    ImportFormat::BinanceWithdrawals => binance_transfer(account, row, EntryKind::Withdrawal),
+    ImportFormat::Coinbase => coinbase_transaction(account, row),
+    ImportFormat::CoinbaseProFills => coinbasepro_fill(account, row),
+    ImportFormat::CoinbaseProAccount => coinbasepro_transfer(account, row),
+    ImportFormat::Generic => generic(account, row),
+  }
+}
+
+/**
+ * A row of a CSV export addressed by header name.
+ */
+struct Row<'a> {
+  headers: &'a [String],
+  record: &'a csv::StringRecord,
+}
+impl<'a> Row<'a> {
+  fn get(&self, names: &[&str]) -> Option<&'a str> {
+    names.iter()
+      .filter_map(|name| self.headers.iter().position(|h| h == name))
+      .filter_map(|i| self.record.get(i))
+      .map(|v| v.trim())
+      .find(|v| !v.is_empty())
+  }
+
+  fn require(&self, names: &[&str]) -> Result<&'a str> {
+    match self.get(names) {
+      Some(value) => Ok(value),
+      None => bail!("Missing {}", names[0]),
+    }
+  }
+
+  fn number(&self, names: &[&str]) -> Result<Option<f64>> {
+    match self.get(names) {
+      Some(value) => Ok(Some(parse_number(value)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.record.iter().all(|v| v.trim().is_empty())
+  }
+}
+
+/**
+ * Binance trade history. Newer exports put the asset after each amount, like `0.5BTC`,
+ * while older exports have `Market`, `Type`, `Amount`, `Total` and `Fee Coin` columns.
+ */
+fn binance_trade(account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  let timestamp = parse_time(row.require(&["date(utc)"])?)?;
+  let pair = row.require(&["pair", "market"])?.to_uppercase();
+  let side = row.require(&["side", "type"])?.to_uppercase();
+  let (base, quote, fee) = match (row.get(&["executed"]), row.get(&["fee coin"])) {
+    (Some(executed), _) => (
+      split_amount(executed)?,
+      split_amount(row.require(&["amount"])?)?,
+      split_amount(row.require(&["fee"])?)?,
+    ),
+    (None, Some(fee_coin)) => {
+      let base_asset = pair_base(&pair)?;
+      let quote_asset = pair[base_asset.len()..].to_string();
+      (
+        Amount { amount: parse_number(row.require(&["amount"])?)?, currency: base_asset },
+        Amount { amount: parse_number(row.require(&["total"])?)?, currency: quote_asset },
+        Amount { amount: parse_number(row.require(&["fee"])?)?, currency: fee_coin.to_uppercase() },
+      )
+    },
+    _ => bail!("Missing executed or fee coin"),
+  };
+  let id = format!("binance:import:trade:{}:{}:{}:{}", pair, timestamp, side, base.amount);
+  let mut entry = LedgerEntry::new(account, id, EntryKind::Trade, timestamp);
+  if side == "BUY" {
+    entry.received = Some(base);
+    entry.sent = Some(quote);
+  } else {
+    entry.sent = Some(base);
+    entry.received = Some(quote);
+  }
+  entry.fee = Some(fee);
+  Ok(Some(entry))
+}
+
+/**
+ * Binance deposit or withdrawal history. Ids follow the API's when it does not return an id.
+ */
+fn binance_transfer(account: &str, row: &Row, kind: EntryKind) -> Result<Option<LedgerEntry>> {
+  if let Some(status) = row.get(&["status"]) {
+    if !(status.eq_ignore_ascii_case("completed") || status.eq_ignore_ascii_case("success")) {
+      return Ok(None);
+    }
+  }
+  let timestamp = parse_time(row.require(&["date(utc)", "date"])?)?;
+  let asset = row.require(&["coin", "asset"])?.to_uppercase();
+  let amount = parse_number(row.require(&["amount"])?)?;
+  let tx_hash = row.get(&["txid"]).map(|s| s.to_string());
+  let id = format!("binance:{}:{}:{}:{}", kind.name(), asset, timestamp, tx_hash.as_ref().map(|s| &s[..]).unwrap_or(""));
+  let mut entry = LedgerEntry::new(account, id, kind, timestamp);
+  let quantity = Some(Amount { amount, currency: asset.to_string() });
+  if kind == EntryKind::Deposit {
+    entry.received = quantity;
+  } else {
+    entry.sent = quantity;
+    entry.fee = row.number(&["transactionfee"])?.map(|fee| Amount { amount: fee, currency: asset.to_string() });
+  }
+  entry.tx_hash = tx_hash;
+  Ok(Some(entry))
+}
+
+/**
+ * Coinbase transaction reports. Reports with an ID column use the same ids as the API.
+ */
+fn coinbase_transaction(account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  let timestamp = parse_time(row.require(&["timestamp"])?)?;
+  let transaction_type = row.require(&["transaction type"])?.to_lowercase();
+  let asset = row.require(&["asset"])?.to_uppercase();
+  let quantity = parse_number(row.require(&["quantity transacted"])?)?.abs();
+  let spot = row.get(&["spot price currency", "price currency"]).unwrap_or("USD").to_uppercase();
+  let subtotal = row.number(&["subtotal"])?.map(f64::abs);
+  let fee = row.number(&["fees", "fees and/or spread"])?.map(f64::abs).filter(|f| *f > 0.0);
+  let notes = row.get(&["notes"]).map(|s| s.to_string());
+  let kind = match &transaction_type[..] {
+    "buy" | "advanced trade buy" => EntryKind::Buy,
+    "sell" | "advanced trade sell" => EntryKind::Sell,
+    "convert" => EntryKind::Trade,
+    "send" | "withdrawal" => EntryKind::Withdrawal,
+    "receive" | "deposit" => EntryKind::Deposit,
+    t if t.contains("reward") || t.contains("income") || t.contains("earn") || t.contains("interest") => EntryKind::Income,
+    _ => return Ok(None),
+  };
+  let id = match row.get(&["id"]) {
+    Some(id) => format!("coinbase:{}", id),
+    None => format!("coinbase:import:{}:{}:{}:{}", timestamp, transaction_type.replace(' ', "-"), asset, quantity),
+  };
+  let mut entry = LedgerEntry::new(account, id, kind, timestamp);
+  let amount = Amount { amount: quantity, currency: asset.to_string() };
+  let native = subtotal.map(|s| Amount { amount: s, currency: spot.to_string() });
+  match kind {
+    EntryKind::Buy => {
+      entry.received = Some(amount);
+      entry.sent = native.clone();
+    },
+    EntryKind::Sell => {
+      entry.sent = Some(amount);
+      entry.received = native.clone();
+    },
+    EntryKind::Trade => {
+      entry.sent = Some(amount);
+      entry.received = notes.as_ref().and_then(|n| converted_to(n));
+      if entry.received.is_none() {
+        bail!("Could not read what was received from the notes of a convert");
+      }
+    },
+    EntryKind::Deposit | EntryKind::Income => entry.received = Some(amount),
+    _ => entry.sent = Some(amount),
+  }
+  if spot == "USD" {
+    entry.usd_value = subtotal;
+  }
+  entry.fee = fee.map(|f| Amount { amount: f, currency: spot.to_string() });
+  entry.description = notes;
+  Ok(Some(entry))
+}
+
+/**
+ * Coinbase Pro fills. Ids match the API so fills are never imported twice.
+ */
+fn coinbasepro_fill(account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  let product = row.require(&["product"])?.to_uppercase();
+  let pair: Vec<&str> = product.split('-').collect();
+  let (base, quote) = match &pair[..] {
+    [base, quote] => (base.to_string(), quote.to_string()),
+    _ => bail!("Unexpected coinbase pro product {}", product),
+  };
+  let timestamp = parse_time(row.require(&["created at"])?)?;
+  let side = row.require(&["side"])?.to_uppercase();
+  let size = parse_number(row.require(&["size"])?)?;
+  let price = parse_number(row.require(&["price"])?)?;
+  let fee = row.number(&["fee"])?.unwrap_or(0.0);
+  let kind = if !is_fiat(&quote) {
+    EntryKind::Trade
+  } else if side == "BUY" {
+    EntryKind::Buy
+  } else {
+    EntryKind::Sell
+  };
+  let id = format!("coinbasepro:fill:{}:{}", product, row.require(&["trade id"])?);
+  let mut entry = LedgerEntry::new(account, id, kind, timestamp);
+  let base_amount = Amount { amount: size, currency: base };
+  let quote_amount = Amount { amount: size * price, currency: quote.to_string() };
+  if side == "BUY" {
+    entry.received = Some(base_amount);
+    entry.sent = Some(quote_amount);
+  } else {
+    entry.sent = Some(base_amount);
+    entry.received = Some(quote_amount);
+  }
+  entry.fee = Some(Amount { amount: fee.abs(), currency: quote.to_string() });
+  if quote == "USD" {
+    entry.usd_value = Some(size * price);
+  }
+  Ok(Some(entry))
+}
+
+/**
+ * Coinbase Pro account statements. Like the API only deposits and withdrawals are read,
+ * trades come from the fills export.
+ */
+fn coinbasepro_transfer(account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  let kind = match &row.require(&["type"])?.to_lowercase()[..] {
+    "deposit" => EntryKind::Deposit,
+    "withdrawal" => EntryKind::Withdrawal,
+    _ => return Ok(None),
+  };
+  let timestamp = parse_time(row.require(&["time"])?)?;
+  let id = format!("coinbasepro:transfer:{}", row.require(&["transfer id"])?);
+  let amount = Amount {
+    amount: parse_number(row.require(&["amount"])?)?.abs(),
+    currency: row.require(&["amount/balance unit"])?.to_uppercase(),
+  };
+  let mut entry = LedgerEntry::new(account, id, kind, timestamp);
+  if kind == EntryKind::Deposit {
+    entry.received = Some(amount);
+  } else {
+    entry.sent = Some(amount);
+  }
+  Ok(Some(entry))
+}
+
+fn generic(account: &str, row: &Row) -> Result<Option<LedgerEntry>> {
+  let timestamp = parse_time(row.require(&["timestamp"])?)?;
+  let kind_name = row.require(&["kind"])?.to_lowercase();
+  let kind: EntryKind = match serde_json::from_value(serde_json::Value::String(kind_name.to_string())) {
+    Ok(kind) => kind,
+    Err(_) => bail!("Unknown kind {}", kind_name),
+  };
+  let amount = |amount: &str, currency: &str| -> Result<Option<Amount>> {
+    match (row.number(&[amount])?, row.get(&[currency])) {
+      (Some(amount), Some(currency)) => Ok(Some(Amount { amount: amount.abs(), currency: currency.to_uppercase() })),
+      (None, None) => Ok(None),
+      _ => bail!("{} and {} must be given together", amount, currency),
+    }
+  };
+  let received = amount("received_amount", "received_currency")?;
+  let sent = amount("sent_amount", "sent_currency")?;
+  if received.is_none() && sent.is_none() {
+    bail!("Nothing was received or sent");
+  }
+  let id = match row.get(&["id"]) {
+    Some(id) => format!("import:{}", id),
+    None => {
+      let leg = |a: &Option<Amount>| a.as_ref().map(|a| format!("{}{}", a.amount, a.currency)).unwrap_or_default();
+      format!("import:{}:{}:{}:{}", timestamp, kind_name, leg(&received), leg(&sent))
+    },
+  };
+  let mut entry = LedgerEntry::new(account, id, kind, timestamp);
+  entry.received = received;
+  entry.sent = sent;
+  entry.fee = amount("fee_amount", "fee_currency")?;
+  entry.usd_value = row.number(&["usd_value"])?;
+  entry.tx_hash = row.get(&["tx_hash"]).map(|s| s.to_string());
+  entry.description = row.get(&["description"]).map(|s| s.to_string());
+  Ok(Some(entry))
+}
+
+fn normalize(header: &str) -> String {
+  header.trim().trim_matches('"').to_lowercase()
+}
+
+fn split_header(line: &str) -> Vec<String> {
+  line.split(',').map(normalize).collect()
+}
+
+/**
+ * Parses amounts like `1,234.5`, `$1,234.50` or `-0.1`.
+ */
+fn parse_number(value: &str) -> Result<f64> {
+  let cleaned: String = value.chars().filter(|c| *c != ',' && *c != '$' && !c.is_whitespace()).collect();
+  Ok(cleaned.parse().chain_err(|| format!("Invalid number {}", value))?)
+}
+
+/**
+ * Splits amounts with the asset appended, like `0.5BTC`.
+ */
+fn split_amount(value: &str) -> Result<Amount> {
+  let value = value.trim();
+  let end = value.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ',' || c == '-')).unwrap_or_else(|| value.len());
+  if end == 0 || end == value.len() {
+    bail!("Expected an amount followed by an asset, got {}", value);
+  }
+  Ok(Amount { amount: parse_number(&value[..end])?, currency: value[end..].trim().to_uppercase() })
+}
+
+/**
+ * Older Binance exports only give the market, like `ETHBTC`.
+ */
+fn pair_base(pair: &str) -> Result<String> {
+  const QUOTES: [&str; 8] = ["USDT", "BUSD", "USDC", "TUSD", "BTC", "ETH", "BNB", "USD"];
+  match QUOTES.iter().find(|q| pair.ends_with(*q) && pair.len() > q.len()) {
+    Some(quote) => Ok(pair[..pair.len() - quote.len()].to_string()),
+    None => bail!("Could not split the market {}", pair),
+  }
+}
+
+/**
+ * Reads what a Coinbase convert received from its notes, like `Converted 0.5 ETH to 0.01 BTC`.
+ */
+fn converted_to(notes: &str) -> Option<Amount> {
+  let words: Vec<&str> = notes.split_whitespace().collect();
+  let to = words.iter().rposition(|w| *w == "to")?;
+  let amount = parse_number(words.get(to + 1)?).ok()?;
+  let currency = words.get(to + 2)?.trim_end_matches('.').to_uppercase();
+  Some(Amount { amount, currency })
+}
+
+fn parse_time(value: &str) -> Result<u64> {
+  let value = value.trim();
+  if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+    return Ok(dt.timestamp() as u64);
+  }
+  let naive = value.trim_end_matches(" UTC").trim_end_matches('Z');
+  for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M", "%m/%d/%Y %H:%M:%S"] {
+    if let Ok(dt) = NaiveDateTime::parse_from_str(naive, format) {
+      return Ok(Utc.from_utc_datetime(&dt).timestamp() as u64);
+    }
+  }
+  match value.parse::<u64>() {
+    // Some exports use milliseconds.
+    Ok(t) if t > 100_000_000_000 => Ok(t / 1000),
+    Ok(t) => Ok(t),
+    Err(_) => bail!("Invalid time {}", value),
+  }
+}
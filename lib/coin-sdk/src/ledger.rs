@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::DateTime;
+use crate::errors::*;
+use crate::model::Amount;
+
+// Currencies that are never treated as crypto assets.
+pub const FIAT_CURRENCIES: [&str; 10] = ["USD", "EUR", "GBP", "JPY", "CAD", "AUD", "CHF", "CNY", "KRW", "SGD"];
+
+pub fn is_fiat(currency: &str) -> bool {
+  FIAT_CURRENCIES.contains(&&currency.to_uppercase()[..])
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+  // Crypto bought with fiat.
+  Buy,
+  // Crypto sold for fiat.
+  Sell,
+  // One crypto asset exchanged for another.
+  Trade,
+  // Funds received from outside the configured accounts.
+  Deposit,
+  // Funds sent outside the account.
+  Withdrawal,
+  // Funds moved between accounts of the same provider.
+  Transfer,
+  // A fee charged on its own rather than as part of another entry.
+  Fee,
+  // Staking rewards, interest, airdrops and similar.
+  Income,
+}
+impl EntryKind {
+  pub fn name(&self) -> &str {
+    match self {
+      EntryKind::Buy => "buy",
+      EntryKind::Sell => "sell",
+      EntryKind::Trade => "trade",
+      EntryKind::Deposit => "deposit",
+      EntryKind::Withdrawal => "withdrawal",
+      EntryKind::Transfer => "transfer",
+      EntryKind::Fee => "fee",
+      EntryKind::Income => "income",
+    }
+  }
+}
+
+/**
+ * A single movement of funds in an account normalized across providers.
+ * `received` is what came into the account and `sent` is what left it.
+ * A trade has both, a deposit only `received` and a withdrawal only `sent`.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LedgerEntry {
+  // Unique within the account, derived from the provider's own ids.
+  pub id: String,
+  pub account: String,
+  pub kind: EntryKind,
+  pub timestamp: u64,
+  pub received: Option<Amount>,
+  pub sent: Option<Amount>,
+  pub fee: Option<Amount>,
+  // The value of the entry in USD at the time, when the provider reports it.
+  pub usd_value: Option<f64>,
+  // The on-chain transaction hash of deposits and withdrawals.
+  pub tx_hash: Option<String>,
+  pub description: Option<String>,
+  // True when the entry was read from a CSV export rather than a provider's API.
+  #[serde(default)]
+  pub imported: bool,
+}
+impl LedgerEntry {
+  pub fn new(account: &str, id: String, kind: EntryKind, timestamp: u64) -> Self {
+    LedgerEntry {
+      id,
+      account: account.to_string(),
+      kind,
+      timestamp,
+      received: None,
+      sent: None,
+      fee: None,
+      usd_value: None,
+      tx_hash: None,
+      description: None,
+      imported: false,
+    }
+  }
+
+  pub fn key(&self) -> (String, String) {
+    (self.account.to_string(), self.id.to_string())
+  }
+
+  /**
+   * Whether two entries describe the same movement of funds. CSV exports rarely carry the ids the
+   * APIs return so an imported entry is compared by its transaction hash, or by time and amounts.
+   * Exports and APIs disagree on whether fees are included in fiat amounts, so fiat amounts that
+   * differ by either entry's fee are the same.
+   */
+  pub fn same_movement(&self, other: &LedgerEntry) -> bool {
+    if self.account != other.account {
+      return false;
+    }
+    if let (Some(a), Some(b)) = (&self.tx_hash, &other.tx_hash) {
+      return a == b;
+    }
+    let seconds_apart = if self.timestamp > other.timestamp { self.timestamp - other.timestamp } else { other.timestamp - self.timestamp };
+    seconds_apart <= SAME_MOVEMENT_SECONDS
+      && same_amount(&self.received, &other.received, &[&self.fee, &other.fee])
+      && same_amount(&self.sent, &other.sent, &[&self.fee, &other.fee])
+  }
+}
+
+// Exports round times to the second while APIs report milliseconds.
+const SAME_MOVEMENT_SECONDS: u64 = 1;
+
+fn same_amount(a: &Option<Amount>, b: &Option<Amount>, fees: &[&Option<Amount>]) -> bool {
+  match (a, b) {
+    (Some(a), Some(b)) if a.currency.to_uppercase() == b.currency.to_uppercase() => {
+      let difference = (a.amount - b.amount).abs();
+      if is_fiat(&a.currency) {
+        let cent = 0.01;
+        difference < cent || fees.iter()
+          .filter_map(|f| f.as_ref())
+          .filter(|f| f.currency.to_uppercase() == a.currency.to_uppercase())
+          .any(|f| (difference - f.amount.abs()).abs() < cent)
+      } else {
+        difference <= 1e-6 * a.amount.abs().max(b.amount.abs()).max(1e-8)
+      }
+    },
+    (None, None) => true,
+    _ => false,
+  }
+}
+
+/**
+ * The positions of ledger entries by account and second, and by account and transaction hash,
+ * so a new entry is only compared with the entries that can be the same movement.
+ */
+#[derive(Default)]
+struct Movements {
+  by_time: HashMap<(String, u64), Vec<usize>>,
+  by_hash: HashMap<(String, String), Vec<usize>>,
+}
+impl Movements {
+  fn add(&mut self, position: usize, entry: &LedgerEntry) {
+    self.by_time.entry((entry.account.to_string(), entry.timestamp)).or_insert_with(Vec::new).push(position);
+    if let Some(hash) = &entry.tx_hash {
+      self.by_hash.entry((entry.account.to_string(), hash.to_string())).or_insert_with(Vec::new).push(position);
+    }
+  }
+
+  fn candidates<'a>(&'a self, entry: &'a LedgerEntry) -> impl Iterator<Item = usize> + 'a {
+    let times = entry.timestamp.saturating_sub(SAME_MOVEMENT_SECONDS)..=entry.timestamp + SAME_MOVEMENT_SECONDS;
+    let by_time = times.filter_map(move |t| self.by_time.get(&(entry.account.to_string(), t)));
+    let by_hash = entry.tx_hash.as_ref().and_then(|hash| self.by_hash.get(&(entry.account.to_string(), hash.to_string())));
+    by_time.chain(by_hash).flatten().cloned()
+  }
+}
+
+/**
+ * Every ledger entry synced from the configured accounts, kept as a JSON file.
+ */
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Ledger {
+  pub entries: Vec<LedgerEntry>,
+}
+impl Ledger {
+  /**
+   * Loads the ledger at the path or returns an empty ledger if the file does not exist yet.
+   */
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(Ledger::default());
+    }
+    let contents = fs::read_to_string(path)?;
+    let ledger = serde_json::from_str(&contents).chain_err(|| format!("Could not read ledger {:?}", path))?;
+    Ok(ledger)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /**
+   * Adds entries that are not in the ledger yet and returns how many were added.
+   * Imported entries are also skipped when they are the same movement as a synced entry,
+   * and synced entries when they are the same movement as an imported one. Each entry in
+   * the ledger stands in for at most one skipped entry so identical fills are all kept.
+   */
+  pub fn merge(&mut self, entries: Vec<LedgerEntry>) -> usize {
+    let mut known: HashSet<(String, String)> = self.entries.iter().map(|e| e.key()).collect();
+    let mut movements = Movements::default();
+    for (position, entry) in self.entries.iter().enumerate() {
+      movements.add(position, entry);
+    }
+    let mut matched: HashSet<usize> = HashSet::new();
+    let mut added = 0;
+    for entry in entries {
+      if known.contains(&entry.key()) {
+        continue;
+      }
+      if let Some(position) = self.same_movement(&entry, &movements, &matched) {
+        matched.insert(position);
+        continue;
+      }
+      known.insert(entry.key());
+      movements.add(self.entries.len(), &entry);
+      self.entries.push(entry);
+      added += 1;
+    }
+    self.entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    added
+  }
+
+  /**
+   * The position of an entry from the other source that is the same movement and has not
+   * been matched yet.
+   */
+  fn same_movement(&self, entry: &LedgerEntry, movements: &Movements, matched: &HashSet<usize>) -> Option<usize> {
+    movements.candidates(entry)
+      .filter(|position| !matched.contains(position))
+      .find(|position| {
+        let e = &self.entries[*position];
+        e.imported != entry.imported && e.same_movement(entry)
+      })
+  }
+
+  pub fn for_account(&self, account: &str) -> Vec<&LedgerEntry> {
+    self.entries.iter().filter(|e| e.account == account).collect()
+  }
+}
+
+pub fn parse_timestamp(s: &str) -> Result<u64> {
+  let parsed = DateTime::parse_from_rfc3339(s).chain_err(|| format!("Invalid timestamp {}", s))?;
+  Ok(parsed.timestamp() as u64)
+}
+
+pub fn ledger_path(data_dir: &Path) -> PathBuf {
+  data_dir.join("ledger.json")
+}
@@ -13,12 +13,693 @@ pub mod binance;
 pub mod coinbase;
 pub mod coinbasepro;
 pub mod offline;
+pub mod prices;
+pub mod ledger;
+pub mod import;
+pub mod transfers;
+pub mod accounting;
+pub mod tax;
+pub mod performance;
+pub mod drift;
+pub mod alerts;
 mod client_factory;
 
 #[cfg(test)]
 mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use basis::basis::{Basis, LotMethod};
+    use basis::book::BasisBook;
+    use crate::accounting::Books;
+    use crate::alerts::{AlertRule, AlertState, MarketView, Quote};
+    use crate::drift::{DriftDecision, DriftPolicy, DriftReport};
+    use crate::errors::*;
+    use crate::import::{read_export, ImportFormat};
+    use crate::ledger::{ledger_path, EntryKind, Ledger, LedgerEntry};
+    use crate::model::{Amount, CMCConfig, CoinConfig};
+    use crate::performance::{compare_benchmark, equal_weight, irr, time_weighted_return, DailyValue, PerformanceHistory};
+    use crate::prices::{interpolate, HistoricalPrices, PricePoint, PriceProvider, Resolution};
+    use crate::tax::TaxReport;
+    use crate::sdk::SDK;
+    use crate::transfers::{carry_basis, overrides_path, TransferMatcher, TransferOverrides, DEFAULT_WINDOW};
+
+    fn entry(account: &str, id: &str, kind: EntryKind, timestamp: u64, sent: Option<f64>, received: Option<f64>) -> LedgerEntry {
+        let mut e = LedgerEntry::new(account, id.to_string(), kind, timestamp);
+        e.sent = sent.map(|amount| Amount { amount, currency: "BTC".to_string() });
+        e.received = received.map(|amount| Amount { amount, currency: "BTC".to_string() });
+        e
+    }
+
+    struct NoPrices;
+    impl PriceProvider for NoPrices {
+        fn name(&self) -> &str {
+            "none"
+        }
+
+        fn closes(&self, symbol: &str, start: u64, _end: u64, _resolution: Resolution) -> Result<Vec<PricePoint>> {
+            bail!(ErrorKind::NoPriceData(symbol.to_string(), start))
+        }
+    }
+
+    // A constant USD price for each symbol.
+    struct FixedPrices(Vec<(&'static str, f64)>);
+    impl PriceProvider for FixedPrices {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn closes(&self, symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>> {
+            let price = match self.0.iter().find(|(s, _)| *s == symbol) {
+                Some((_, price)) => *price,
+                None => bail!(ErrorKind::NoPriceData(symbol.to_string(), start)),
+            };
+            let step = resolution.seconds() as usize;
+            Ok((start..=end).step_by(step).map(|timestamp| PricePoint { timestamp, price }).collect())
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_interpolate_prices() {
+        let day = 86400;
+        let points = vec![
+            PricePoint { timestamp: 0, price: 100.0 },
+            PricePoint { timestamp: day, price: 200.0 },
+            PricePoint { timestamp: 4 * day, price: 400.0 },
+        ];
+        assert_eq!(interpolate(&points, day, day), Some(200.0));
+        assert_eq!(interpolate(&points, day / 4, day), Some(125.0));
+        // Closes three days apart are too far to interpolate between.
+        assert_eq!(interpolate(&points, 2 * day, day), Some(200.0));
+        assert_eq!(interpolate(&points, 2 * day + 1, day), None);
+        assert_eq!(interpolate(&points, 5 * day, day), Some(400.0));
+        assert_eq!(interpolate(&points, 6 * day, day), None);
+        assert_eq!(interpolate(&[], 0, day), None);
+    }
+
+    #[test]
+    fn test_match_transfers() -> Result<()> {
+        let hour = 3600;
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![
+            entry("coinbase", "w1", EntryKind::Withdrawal, 0, Some(1.0), None),
+            entry("binance", "d1", EntryKind::Deposit, hour, None, Some(0.999)),
+            // Two identical withdrawals and deposits can not be told apart.
+            entry("coinbase", "w2", EntryKind::Withdrawal, 100 * hour, Some(0.5), None),
+            entry("coinbase", "w3", EntryKind::Withdrawal, 100 * hour, Some(0.5), None),
+            entry("binance", "d2", EntryKind::Deposit, 101 * hour, None, Some(0.5)),
+            entry("binance", "d3", EntryKind::Deposit, 102 * hour, None, Some(0.5)),
+            // Too late to be the same funds.
+            entry("coinbase", "w4", EntryKind::Withdrawal, 200 * hour, Some(2.0), None),
+            entry("binance", "d4", EntryKind::Deposit, 300 * hour, None, Some(2.0)),
+        ]);
+        let matcher = TransferMatcher::new(DEFAULT_WINDOW);
+        let mut overrides = TransferOverrides::default();
+        let report = matcher.match_transfers(&ledger, &overrides)?;
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].deposit, "d1");
+        assert_eq!(report.ambiguous.len(), 2);
+        overrides.confirm(("coinbase".to_string(), "w2".to_string()), ("binance".to_string(), "d3".to_string()));
+        overrides.reject(("coinbase".to_string(), "w1".to_string()));
+        let report = matcher.match_transfers(&ledger, &overrides)?;
+        let pairs: Vec<(&str, &str)> = report.matched.iter().map(|m| (&m.withdrawal[..], &m.deposit[..])).collect();
+        assert_eq!(pairs, vec![("w2", "d3"), ("w3", "d2")]);
+        assert!(report.ambiguous.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfers_shared_ids() -> Result<()> {
+        let hour = 3600;
+        let mut buy = entry("coinbase", "1", EntryKind::Buy, 0, None, Some(1.0));
+        buy.usd_value = Some(1000.0);
+        let mut ledger = Ledger::default();
+        // Each provider numbers its own entries so the withdrawal and deposit share an id.
+        ledger.merge(vec![
+            buy,
+            entry("coinbase", "2", EntryKind::Withdrawal, hour, Some(1.0), None),
+            entry("binance", "2", EntryKind::Deposit, 2 * hour, None, Some(1.0)),
+            entry("kraken", "2", EntryKind::Withdrawal, 3 * hour, Some(0.5), None),
+        ]);
+        let mut overrides = TransferOverrides::default();
+        overrides.reject(("kraken".to_string(), "2".to_string()));
+        let report = TransferMatcher::new(DEFAULT_WINDOW).match_transfers(&ledger, &overrides)?;
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].withdrawal_key(), ("coinbase".to_string(), "2".to_string()));
+        assert_eq!(report.matched[0].deposit_key(), ("binance".to_string(), "2".to_string()));
+        let books = Books::build(&ledger, &report.matched, &HistoricalPrices::new(Box::new(NoPrices), None), LotMethod::Fifo, None);
+        let lots = books.open_lots()?;
+        let opened: Vec<(&str, f64)> = lots.iter().map(|(account, _, lot)| (&account[..], lot.quantity)).collect();
+        assert_eq!(opened, vec![("binance", 1.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_transfer() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("coin-sdk-reject-{}", std::process::id()));
+        let config = CoinConfig {
+            blacklist: None,
+            account: vec![],
+            cmc: CMCConfig { key: String::new() },
+            data_dir: Some(dir.to_string_lossy().to_string()),
+        };
+        let sdk = SDK::new(config)?;
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![
+            entry("coinbase", "w1", EntryKind::Withdrawal, 0, Some(1.0), None),
+            entry("binance", "d1", EntryKind::Deposit, 3600, None, Some(1.0)),
+        ]);
+        ledger.save(&ledger_path(&dir))?;
+        assert!(sdk.reject_transfer("coinbase", "w2").is_err());
+        assert!(sdk.reject_transfer("binance", "w1").is_err());
+        assert!(sdk.reject_transfer("binance", "d1").is_err());
+        sdk.reject_transfer("coinbase", "w1")?;
+        let rejected = TransferOverrides::load(&overrides_path(&dir))?.rejected;
+        assert_eq!(rejected, vec![("coinbase".to_string(), "w1".to_string())]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_carry_basis() -> Result<()> {
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![
+            entry("coinbase", "w1", EntryKind::Withdrawal, 10, Some(1.0), None),
+            entry("binance", "d1", EntryKind::Deposit, 20, None, Some(0.5)),
+        ]);
+        let report = TransferMatcher::new(DEFAULT_WINDOW).match_transfers(&ledger, &TransferOverrides::default())?;
+        assert_eq!(report.matched.len(), 0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![
+            entry("coinbase", "w1", EntryKind::Withdrawal, 10, Some(1.0), None),
+            entry("binance", "d1", EntryKind::Deposit, 20, None, Some(0.99)),
+        ]);
+        let report = TransferMatcher::new(DEFAULT_WINDOW).match_transfers(&ledger, &TransferOverrides::default())?;
+        let mut coinbase = BasisBook::new();
+        let mut binance = BasisBook::new();
+        coinbase.acquire("BTC", 1, 2.0, 1000.0, None);
+        carry_basis(&mut coinbase, &mut binance, &report.matched[0])?;
+        binance.asset("BTC").dispose(30, 0.99, 2000.0);
+        assert!((binance.asset("BTC").calc_cost_basis_with(LotMethod::Fifo)? - 1000.0).abs() < 1e-9);
+        assert_eq!(binance.realizations()?["BTC"][0].acquired, 1);
+        assert_eq!(coinbase.open_lots()?["BTC"][0].quantity, 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tax_report() -> Result<()> {
+        let mut buy_early = entry("coinbase", "b1", EntryKind::Buy, 1704067200, None, Some(1.0));
+        buy_early.usd_value = Some(1000.0);
+        let mut buy_late = entry("coinbase", "b2", EntryKind::Buy, 1740787200, None, Some(1.0));
+        buy_late.usd_value = Some(2000.0);
+        let mut sell = entry("coinbase", "s1", EntryKind::Sell, 1748736000, Some(1.5), None);
+        sell.usd_value = Some(4500.0);
+        let mut sell_next_year = entry("coinbase", "s2", EntryKind::Sell, 1768435200, Some(0.5), None);
+        sell_next_year.usd_value = Some(1000.0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![buy_early, buy_late, sell, sell_next_year]);
+        let prices = HistoricalPrices::new(Box::new(NoPrices), None);
+        let books = Books::build(&ledger, &[], &prices, LotMethod::Fifo, None);
+        assert!(books.issues.is_empty());
+        let report = TaxReport::build(&books, 2025)?;
+        assert_eq!(report.lines.len(), 2);
+        let schedule_d = report.schedule_d();
+        assert!((schedule_d.short_term_gain() - 500.0).abs() < 1e-6);
+        assert!((schedule_d.long_term_gain() - 2000.0).abs() < 1e-6);
+        assert_eq!(report.by_asset().keys().collect::<Vec<&String>>(), vec!["BTC"]);
+        let mut csv = vec![];
+        report.write_form_8949(&mut csv)?;
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], "Description,Date Acquired,Date Sold,Proceeds,Cost Basis,Gain or Loss,Term,Asset,Account");
+        assert_eq!(rows[1], "0.5 BTC,03/01/2025,06/01/2025,1500.00,1000.00,500.00,short-term,BTC,coinbase");
+        assert_eq!(rows[2], "1 BTC,01/01/2024,06/01/2025,3000.00,1000.00,2000.00,long-term,BTC,coinbase");
+        assert_eq!(TaxReport::build(&books, 2026)?.lines.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_exports() -> Result<()> {
+        let coinbase = "You can use this transaction report to inform your likely tax obligations.\n\
+            \n\
+            Transactions\n\
+            ID,Timestamp,Transaction Type,Asset,Quantity Transacted,Price Currency,Price at Transaction,Subtotal,Total (inclusive of fees and/or spread),Fees and/or Spread,Notes\n\
+            abc,2021-01-01 12:00:00 UTC,Buy,BTC,0.1,USD,$30000.00,\"$3,000.00\",$3010.00,$10.00,Bought 0.1 BTC\n\
+            def,2021-01-02T12:00:00Z,Convert,BTC,0.05,USD,$31000.00,$1550.00,$1550.00,$0.00,Converted 0.05 BTC to 1.2 ETH\n\
+            ghi,2021-01-03T12:00:00Z,Learning Reward,GRT,10,USD,$0.50,$5.00,$5.00,$0.00,\n";
+        let import = read_export(coinbase.as_bytes(), "coinbase", None)?;
+        assert_eq!(import.format, ImportFormat::Coinbase);
+        assert_eq!(import.entries.len(), 3);
+        let buy = &import.entries[0];
+        assert_eq!(buy.id, "coinbase:abc");
+        assert_eq!(buy.kind, EntryKind::Buy);
+        assert_eq!(buy.usd_value, Some(3000.0));
+        assert_eq!(buy.fee, Some(Amount { amount: 10.0, currency: "USD".to_string() }));
+        assert_eq!(import.entries[1].received, Some(Amount { amount: 1.2, currency: "ETH".to_string() }));
+        assert_eq!(import.entries[2].kind, EntryKind::Income);
+
+        let transfers = "Date(UTC),Coin,Network,Amount,TransactionFee,Address,TXID,SourceAddress,PaymentID,Status\n\
+            2021-01-01 12:00:00,BTC,BTC,0.5,0.0005,bc1q,0xabc,,,Completed\n\
+            2021-01-02 12:00:00,BTC,BTC,0.1,0.0005,bc1q,0xdef,,,Cancelled\n";
+        assert!(read_export(transfers.as_bytes(), "binance", None).is_err());
+        let import = read_export(transfers.as_bytes(), "binance", Some(ImportFormat::BinanceWithdrawals))?;
+        assert_eq!(import.entries.len(), 1);
+        assert_eq!(import.skipped, 1);
+        assert_eq!(import.entries[0].fee.as_ref().map(|f| f.amount), Some(0.0005));
+
+        // A trade synced from the API is not imported again from the export and vice versa.
+        let mut ledger = Ledger::default();
+        let mut synced = LedgerEntry::new("binance", "binance:trade:ETHBTC:1".to_string(), EntryKind::Trade, 1609502400);
+        synced.received = Some(Amount { amount: 2.0, currency: "ETH".to_string() });
+        synced.sent = Some(Amount { amount: 0.0625, currency: "BTC".to_string() });
+        ledger.merge(vec![synced.clone()]);
+        let trades = "Date(UTC),Pair,Side,Price,Executed,Amount,Fee\n\
+            2021-01-01 12:00:00,ETHBTC,BUY,0.03125,2ETH,0.0625BTC,0.001BNB\n\
+            2021-01-01 13:00:00,ETHBTC,SELL,0.03125,1ETH,0.03125BTC,0.001BNB\n";
+        let import = read_export(trades.as_bytes(), "binance", None)?;
+        assert_eq!(import.format, ImportFormat::BinanceTrades);
+        assert_eq!(ledger.merge(import.entries.clone()), 1);
+        assert_eq!(ledger.merge(import.entries), 0);
+        synced.id = "binance:trade:ETHBTC:2".to_string();
+        synced.timestamp += 3600;
+        synced.sent = Some(Amount { amount: 1.0, currency: "ETH".to_string() });
+        synced.received = Some(Amount { amount: 0.03125, currency: "BTC".to_string() });
+        assert_eq!(ledger.merge(vec![synced]), 0);
+        assert_eq!(ledger.entries.len(), 2);
+
+        // Identical fills in the same second are separate trades.
+        let fills = "Date(UTC),Pair,Side,Price,Executed,Amount,Fee\n\
+            2021-01-02 12:00:00,ETHBTC,BUY,0.03125,1ETH,0.03125BTC,0.001BNB\n\
+            2021-01-02 12:00:00,ETHBTC,BUY,0.03125,1ETH,0.03125BTC,0.001BNB\n";
+        let import = read_export(fills.as_bytes(), "binance", None)?;
+        assert_eq!(import.entries[1].id, format!("{}:1", import.entries[0].id));
+        assert_eq!(ledger.merge(import.entries.clone()), 2);
+        assert_eq!(ledger.merge(import.entries), 0);
+
+        // Fiat amounts are the same movement when they differ by a fee, not otherwise.
+        let mut ledger = Ledger::default();
+        let mut synced = LedgerEntry::new("coinbase", "coinbase:buy:1".to_string(), EntryKind::Buy, 1609502400);
+        synced.received = Some(Amount { amount: 0.1, currency: "BTC".to_string() });
+        synced.sent = Some(Amount { amount: 3010.0, currency: "USD".to_string() });
+        ledger.merge(vec![synced]);
+        let mut imported = LedgerEntry::new("coinbase", "coinbase:abc".to_string(), EntryKind::Buy, 1609502400);
+        imported.received = Some(Amount { amount: 0.1, currency: "BTC".to_string() });
+        imported.sent = Some(Amount { amount: 3000.0, currency: "USD".to_string() });
+        imported.fee = Some(Amount { amount: 10.0, currency: "USD".to_string() });
+        imported.imported = true;
+        let mut other = imported.clone();
+        other.id = "coinbase:def".to_string();
+        other.sent = Some(Amount { amount: 2000.0, currency: "USD".to_string() });
+        assert_eq!(ledger.merge(vec![imported, other]), 1);
+        assert_eq!(ledger.entries[1].id, "coinbase:def");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_identical_fills() {
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![entry("binance", "binance:trade:1", EntryKind::Buy, 1609502400, None, Some(0.5))]);
+        // Two fills of the same size in the same second, only one of which the API returned.
+        let mut first = entry("binance", "import:1", EntryKind::Buy, 1609502400, None, Some(0.5));
+        first.imported = true;
+        let mut second = first.clone();
+        second.id = "import:2".to_string();
+        assert_eq!(ledger.merge(vec![first.clone(), second.clone()]), 1);
+        assert_eq!(ledger.entries.len(), 2);
+        // Importing the same export again adds nothing.
+        assert_eq!(ledger.merge(vec![first, second]), 0);
+    }
+
+    #[test]
+    fn test_crypto_trades() -> Result<()> {
+        let day = 86400;
+        let mut buy = entry("binance", "b1", EntryKind::Buy, day, None, Some(1.0));
+        buy.usd_value = Some(10000.0);
+        // Half a BTC buys 100 LINK when BTC is worth $12,000.
+        let mut trade = entry("binance", "t1", EntryKind::Trade, 2 * day, Some(0.5), None);
+        trade.received = Some(Amount { amount: 100.0, currency: "LINK".to_string() });
+        trade.fee = Some(Amount { amount: 0.1, currency: "LINK".to_string() });
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![buy, trade]);
+        let prices = HistoricalPrices::new(Box::new(FixedPrices(vec![("BTC", 12000.0)])), None);
+        let mut books = Books::build(&ledger, &[], &prices, LotMethod::Fifo, None);
+        assert!(books.issues.is_empty(), "{:?}", books.issues);
+        let report = books.report(&Default::default());
+        let btc = report.positions.iter().find(|p| p.asset == "BTC").unwrap();
+        assert!((btc.realized_gain() - 1000.0).abs() < 1e-6);
+        let link = report.positions.iter().find(|p| p.asset == "LINK").unwrap();
+        assert!((link.quantity - 99.9).abs() < 1e-9);
+        assert!((link.cost_basis - 6000.0).abs() < 1e-6);
+        assert_eq!(link.invested, 0.0);
+        // Without any price the trade can not be valued.
+        let books = Books::build(&ledger, &[], &HistoricalPrices::new(Box::new(NoPrices), None), LotMethod::Fifo, None);
+        assert_eq!(books.issues.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fiat_buys() -> Result<()> {
+        let mut usd = entry("coinbase", "b1", EntryKind::Buy, 0, None, Some(1.0));
+        usd.sent = Some(Amount { amount: 1000.0, currency: "USD".to_string() });
+        usd.fee = Some(Amount { amount: 10.0, currency: "USD".to_string() });
+        let mut eur = entry("coinbase", "b2", EntryKind::Buy, 0, None, Some(1.0));
+        eur.sent = Some(Amount { amount: 900.0, currency: "EUR".to_string() });
+        let mut empty = entry("coinbase", "b3", EntryKind::Buy, 0, None, Some(0.0));
+        empty.usd_value = Some(10.0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![usd, eur, empty]);
+        // EUR must never be priced like a crypto asset.
+        let prices = HistoricalPrices::new(Box::new(FixedPrices(vec![("EUR", 5.0)])), None);
+        let mut books = Books::build(&ledger, &[], &prices, LotMethod::Fifo, None);
+        assert_eq!(books.issues.len(), 2);
+        assert!(books.issues[0].contains("no USD exchange rate for EUR"), "{}", books.issues[0]);
+        assert!(books.issues[1].starts_with("coinbase b3"), "{}", books.issues[1]);
+        let report = books.report(&Default::default());
+        assert!((report.total(None).invested - 1010.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_asset_positions() -> Result<()> {
+        let day = 86400;
+        let mut binance = entry("binance", "b1", EntryKind::Buy, day, None, Some(0.5));
+        binance.usd_value = Some(5000.0);
+        let mut coinbase = entry("coinbase", "c1", EntryKind::Buy, 2 * day, None, Some(0.25));
+        coinbase.usd_value = Some(3000.0);
+        let mut later = entry("coinbase", "c2", EntryKind::Buy, 3 * day, None, Some(0.25));
+        later.usd_value = Some(3500.0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![binance, coinbase, later]);
+        let mut books = Books::build(&ledger, &[], &HistoricalPrices::new(Box::new(NoPrices), None), LotMethod::Fifo, None);
+        let report = books.report(&Default::default());
+        let coinbase = &report.by_asset(Some(&["coinbase".to_string()]))["BTC"];
+        assert!((coinbase.quantity - 0.5).abs() < 1e-9);
+        assert!((coinbase.average_cost().unwrap() - 13000.0).abs() < 1e-6);
+        assert!((report.by_asset(None)["BTC"].quantity - 1.0).abs() < 1e-9);
+        assert!(report.by_asset(Some(&["kraken".to_string()])).is_empty());
+        let lots = books.open_lots()?;
+        let opened: Vec<(&str, u64)> = lots.iter().map(|(account, _, lot)| (&account[..], lot.acquired)).collect();
+        assert_eq!(opened, vec![("binance", day), ("coinbase", 2 * day), ("coinbase", 3 * day)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_portfolio_report() {
+        let amount = |amount: f64, currency: &str| Some(Amount { amount, currency: currency.to_string() });
+        let mut btc = LedgerEntry::new("binance", "b1".to_string(), EntryKind::Buy, 100);
+        btc.sent = amount(10000.0, "USD");
+        btc.received = amount(1.0, "BTC");
+        // Not among the listings current prices come from.
+        let mut xyz = LedgerEntry::new("coinbase", "b2".to_string(), EntryKind::Buy, 200);
+        xyz.sent = amount(1000.0, "USD");
+        xyz.received = amount(100.0, "XYZ");
+        let mut sell = LedgerEntry::new("coinbase", "s1".to_string(), EntryKind::Sell, 300);
+        sell.sent = amount(50.0, "XYZ");
+        sell.received = amount(750.0, "USD");
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![btc, xyz, sell]);
+        let prices = HistoricalPrices::new(Box::new(FixedPrices(vec![("USD", 1.0)])), None);
+        let mut books = Books::build(&ledger, &[], &prices, LotMethod::Fifo, None);
+        assert!(books.issues.is_empty(), "{:?}", books.issues);
+        let current_prices = vec![("BTC".to_string(), 12000.0)].into_iter().collect();
+        let report = books.report(&current_prices);
+
+        let total = report.total(None);
+        assert_eq!(total.cost_basis, 10000.0);
+        assert_eq!(total.unpriced_cost, 500.0);
+        assert_eq!(total.market_value, Some(12000.0));
+        assert_eq!(total.unrealized_gain(), Some(2000.0));
+        assert_eq!(total.realized_gain(), 250.0);
+        assert_eq!(total.invested, 11000.0);
+        let coinbase = report.total(Some("coinbase"));
+        assert_eq!(coinbase.market_value, None);
+        assert_eq!(coinbase.unrealized_gain(), None);
+
+        let assets = report.by_asset(None);
+        assert_eq!(assets["XYZ"].unpriced_cost, 500.0);
+        assert_eq!(assets["XYZ"].average_cost(), Some(10.0));
+        assert_eq!(assets["BTC"].unrealized_gain(), Some(2000.0));
+    }
+
+    #[test]
+    fn test_performance() -> Result<()> {
+        let day = 86400;
+        let history = vec![
+            DailyValue { day: 0, value: 100.0, flow: 100.0 },
+            DailyValue { day, value: 110.0, flow: 0.0 },
+            // Doubling the money invested is not a gain.
+            DailyValue { day: 2 * day, value: 220.0, flow: 110.0 },
+            DailyValue { day: 3 * day, value: 198.0, flow: 0.0 },
+        ];
+        assert!((time_weighted_return(&history, 0).unwrap() - (1.1 * 0.9 - 1.0)).abs() < 1e-9);
+        assert!((time_weighted_return(&history, 2 * day).unwrap() + 0.1).abs() < 1e-9);
+        assert!((irr(&[(0.0, -100.0), (1.0, 110.0)]).unwrap() - 0.1).abs() < 1e-6);
+        assert_eq!(irr(&[(0.0, 100.0), (1.0, 110.0)]), None);
+
+        let mut buy = entry("coinbase", "b1", EntryKind::Buy, 10, None, Some(1.0));
+        buy.usd_value = Some(9000.0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![
+            buy,
+            entry("coinbase", "w1", EntryKind::Withdrawal, day + 10, Some(1.0), None),
+            entry("binance", "d1", EntryKind::Deposit, day + 20, None, Some(1.0)),
+        ]);
+        let transfers = TransferMatcher::new(DEFAULT_WINDOW).match_transfers(&ledger, &TransferOverrides::default())?;
+        let prices = HistoricalPrices::new(Box::new(FixedPrices(vec![("BTC", 10000.0)])), None);
+        let performance = PerformanceHistory::from_ledger(&ledger, &transfers.matched, &prices, 2 * day);
+        assert!(performance.issues.is_empty(), "{:?}", performance.issues);
+        let flows: Vec<f64> = performance.portfolio.iter().map(|v| v.flow).collect();
+        assert_eq!(flows, vec![9000.0, 0.0, 0.0]);
+        assert_eq!(performance.accounts["coinbase"][1].flow, -10000.0);
+        assert_eq!(performance.accounts["binance"][0], DailyValue { day, value: 10000.0, flow: 10000.0 });
+        assert!((time_weighted_return(&performance.portfolio, 0).unwrap() - 1.0 / 9.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_performance_gaps() -> Result<()> {
+        // Prices only from the third day on.
+        struct ListedFrom(u64);
+        impl PriceProvider for ListedFrom {
+            fn name(&self) -> &str {
+                "listed"
+            }
+
+            fn closes(&self, _symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>> {
+                let step = resolution.seconds() as usize;
+                Ok((start.max(self.0)..=end).step_by(step).map(|timestamp| PricePoint { timestamp, price: 100.0 }).collect())
+            }
+        }
+        let day = 86400;
+        let mut buy = entry("coinbase", "b1", EntryKind::Buy, 10, None, Some(1.0));
+        buy.received.as_mut().unwrap().currency = "NEW".to_string();
+        buy.usd_value = Some(50.0);
+        let mut ledger = Ledger::default();
+        ledger.merge(vec![buy]);
+        let prices = HistoricalPrices::new(Box::new(ListedFrom(2 * day)), None);
+        let mut performance = PerformanceHistory::from_ledger(&ledger, &[], &prices, 3 * day);
+        // The first days are neither valued at 0 nor at the later price, the money put in counts on
+        // the first day that is valued.
+        assert_eq!(performance.portfolio, vec![
+            DailyValue { day: 2 * day, value: 100.0, flow: 50.0 },
+            DailyValue { day: 3 * day, value: 100.0, flow: 0.0 },
+        ]);
+        assert_eq!(performance.issues, vec!["No price for NEW on 2 days from 1970-01-01 to 1970-01-02, they are left out"]);
+
+        let snapshots = vec![("coinbase".to_string(), vec![(3 * day + 60, 90.0), (3 * day + 7200, 120.0)])].into_iter().collect();
+        performance.use_snapshots(&snapshots);
+        assert_eq!(performance.accounts["coinbase"][1].value, 120.0);
+        assert_eq!(performance.portfolio[1].value, 120.0);
+        assert_eq!(performance.portfolio[0].value, 100.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_benchmarks() {
+        let day = 86400;
+        let history = vec![
+            DailyValue { day: 0, value: 100.0, flow: 100.0 },
+            DailyValue { day, value: 120.0, flow: 0.0 },
+            DailyValue { day: 2 * day, value: 220.0, flow: 100.0 },
+            DailyValue { day: 3 * day, value: 220.0, flow: 0.0 },
+        ];
+        let btc: std::collections::BTreeMap<u64, f64> = vec![(0, 10.0), (day, 11.0), (2 * day, 11.0), (3 * day, 12.1)].into_iter().collect();
+        let comparison = compare_benchmark("BTC", &history, &btc, 0);
+        assert!((comparison.benchmark_return.unwrap() - 0.21).abs() < 1e-9);
+        assert!((comparison.portfolio_return.unwrap() - 0.2).abs() < 1e-9);
+        assert!((comparison.excess_return.unwrap() + 0.01).abs() < 1e-9);
+        assert!(comparison.tracking_error.unwrap() > 0.0);
+        // 10 BTC bought at 10 and 9.09 at 11 are worth 12.1 each.
+        assert!((comparison.value_if_held.unwrap() - (10.0 + 100.0 / 11.0) * 12.1).abs() < 1e-9);
+
+        // ETH has no close on the third day, its move from the second to the fourth still counts.
+        let eth: std::collections::BTreeMap<u64, f64> = vec![(0, 1.0), (day, 0.9), (3 * day, 0.99)].into_iter().collect();
+        let basket = equal_weight(&[btc, eth]);
+        assert!((basket[&day] - 1.0).abs() < 1e-9);
+        assert!((basket[&(2 * day)] - 1.0).abs() < 1e-9);
+        assert!((basket[&(3 * day)] - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_offline_prices() -> Result<()> {
+        let day = 86400;
+        let prices = HistoricalPrices::new(Box::new(FixedPrices(vec![("BTC", 10.0), ("ETH", 1.0)])), None);
+        assert_eq!(prices.closes("BTC", 0, day, Resolution::Daily)?.len(), 2);
+        prices.go_offline();
+        assert_eq!(prices.closes("BTC", 0, day, Resolution::Daily)?.len(), 2);
+        assert!(prices.closes("ETH", 0, day, Resolution::Daily)?.is_empty());
+        Ok(())
+    }
+
+    // Daily closes of 10 and hourly closes that fail with a status, counting the hourly requests.
+    struct HourlyFails(u64, Rc<Cell<usize>>);
+    impl PriceProvider for HourlyFails {
+        fn name(&self) -> &str {
+            "hourly-fails"
+        }
+
+        fn closes(&self, symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>> {
+            if resolution == Resolution::Hourly {
+                self.1.set(self.1.get() + 1);
+                let kind = cmc::errors::ErrorKind::CMCError(self.0, format!("Historical quotes for {} are unavailable", symbol));
+                return Err(cmc::errors::Error::from_kind(kind).into());
+            }
+            Ok((start..=end).step_by(86400).map(|timestamp| PricePoint { timestamp, price: 10.0 }).collect())
+        }
+    }
+
+    #[test]
+    fn test_hourly_fallback() -> Result<()> {
+        let requests = Rc::new(Cell::new(0));
+        let prices = HistoricalPrices::new(Box::new(HourlyFails(500, requests.clone())), None);
+        assert_eq!(prices.price_at("BTC", 3600)?, 10.0);
+        assert_eq!(prices.price_at("BTC", 7200)?, 10.0);
+        // A server error is retried on the next lookup.
+        assert_eq!(requests.get(), 2);
+        let requests = Rc::new(Cell::new(0));
+        let prices = HistoricalPrices::new(Box::new(HourlyFails(403, requests.clone())), None);
+        assert_eq!(prices.price_at("BTC", 3600)?, 10.0);
+        assert_eq!(prices.price_at("BTC", 7200)?, 10.0);
+        assert_eq!(requests.get(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift() {
+        let weights = |w: &[(&str, f64)]| w.iter().map(|(s, v)| (s.to_string(), *v)).collect::<std::collections::HashMap<String, f64>>();
+        let target = weights(&[("BTC", 0.6), ("ETH", 0.4)]);
+        // Values rather than fractions are scaled, DOGE is held but not targeted.
+        let report = DriftReport::new(&weights(&[("BTC", 700.0), ("ETH", 200.0), ("DOGE", 100.0)]), &target);
+        let drift = report.max_drift().unwrap();
+        assert_eq!(drift.symbol, "ETH");
+        assert!((drift.drift + 0.2).abs() < 1e-9);
+        assert_eq!(report.assets.len(), 3);
+        assert!((report.tracking_error - (0.01f64 + 0.04 + 0.01).sqrt()).abs() < 1e-9);
+
+        let policy = DriftPolicy { threshold: 0.05, max_tracking_error: 0.1, cooldown: 3600, dry_runs: 2 };
+        assert!(policy.breach(&report).unwrap().starts_with("ETH is at 20.00%"));
+        let close = DriftReport::new(&weights(&[("BTC", 0.62), ("ETH", 0.38)]), &target);
+        assert_eq!(policy.breach(&close), None);
+        // Many small drifts add up.
+        let spread = DriftReport::new(
+            &weights(&[("A", 0.29), ("B", 0.29), ("C", 0.21), ("D", 0.21)]),
+            &weights(&[("A", 0.25), ("B", 0.25), ("C", 0.25), ("D", 0.25)]),
+        );
+        let strict = DriftPolicy { max_tracking_error: 0.05, ..policy };
+        assert_eq!(policy.breach(&spread), None);
+        assert!(strict.breach(&spread).unwrap().starts_with("The tracking error is 8.00%"));
+
+        assert_eq!(policy.decide(false, None, 0, false, 10000), DriftDecision::Hold);
+        assert_eq!(policy.decide(true, Some(9000), 0, false, 10000), DriftDecision::CoolingDown(12600));
+        assert_eq!(policy.decide(true, Some(5000), 0, false, 10000), DriftDecision::DryRun(1));
+        assert_eq!(policy.decide(true, None, 1, true, 10000), DriftDecision::DryRun(2));
+        // The dry runs are done, trades wait for one of them to be confirmed.
+        assert_eq!(policy.decide(true, None, 2, false, 10000), DriftDecision::Unconfirmed);
+        assert_eq!(policy.decide(true, None, 2, true, 10000), DriftDecision::Rebalance);
+    }
+
+    #[test]
+    fn test_alerts() {
+        let rules: Vec<AlertRule> = serde_json::from_str(r#"[
+            {"kind": "price", "symbol": "BTC", "above": 10000.0},
+            {"kind": "change_24h", "percent": 10.0},
+            {"kind": "drawdown", "percent": 20.0},
+            {"kind": "top", "symbol": "LINK", "rank": 20}
+        ]"#).unwrap();
+        let quote = |price: f64, change_24h: f64, rank: u64| Quote { price, change_24h, rank };
+        let market = |btc: f64, eth_change: f64, link_rank: u64, value: f64| MarketView {
+            quotes: vec![
+                ("BTC".to_string(), quote(btc, 1.0, 1)),
+                ("ETH".to_string(), quote(200.0, eth_change, 2)),
+                ("LINK".to_string(), quote(3.0, 0.0, link_rank)),
+            ].into_iter().collect(),
+            depth: 100,
+            held: vec!["BTC".to_string(), "ETH".to_string()],
+            portfolio_value: Some(value),
+        };
+        let mut state = AlertState::default();
+        let evaluate = |state: &mut AlertState, market: &MarketView, now: u64| {
+            let alerts = state.evaluate(&rules, market, 3600, now);
+            for alert in &alerts {
+                state.delivered(alert);
+            }
+            alerts
+        };
+        assert!(evaluate(&mut state, &market(9000.0, 5.0, 15, 1000.0), 0).is_empty());
+
+        let alerts = evaluate(&mut state, &market(11000.0, -12.0, 25, 700.0), 60);
+        let messages: Vec<&str> = alerts.iter().map(|a| &a.message[..]).collect();
+        assert_eq!(messages, vec![
+            "BTC is at $11000.00, above $10000",
+            "ETH moved -12.00% in 24h to $200.00",
+            "The portfolio is worth $700.00, 30.00% below its peak of $1000.00",
+            "LINK ranks 25, outside the top 20",
+        ]);
+        assert_eq!(alerts[1].rule, "Any asset held moves 10% in 24h");
+        // Conditions that keep holding do not alert again.
+        assert!(evaluate(&mut state, &market(11000.0, -12.0, 25, 700.0), 120).is_empty());
+        // BTC falls back and crosses again within the re-arm window, then after it.
+        assert_eq!(evaluate(&mut state, &market(9000.0, -12.0, 25, 700.0), 180).len(), 0);
+        assert_eq!(evaluate(&mut state, &market(11000.0, -12.0, 25, 700.0), 240).len(), 0);
+        let alerts = evaluate(&mut state, &market(11000.0, -12.0, 25, 700.0), 3660);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].key, "BTC above $10000:above");
+        assert_eq!(state.peak, Some(1000.0));
+    }
+
+    #[test]
+    fn test_alerts_undelivered() {
+        let rules = vec![AlertRule::Price { symbol: "BTC".to_string(), above: Some(10000.0), below: None }];
+        let mut market = MarketView::default();
+        market.quotes.insert("BTC".to_string(), Quote { price: 11000.0, change_24h: 0.0, rank: 1 });
+        let mut state = AlertState::default();
+        // No channel took the alert, so it is still pending at the next check.
+        assert_eq!(state.evaluate(&rules, &market, 3600, 0).len(), 1);
+        assert!(state.active.is_empty() && state.fired.is_empty());
+        let alerts = state.evaluate(&rules, &market, 3600, 60);
+        assert_eq!(alerts.len(), 1);
+        state.delivered(&alerts[0]);
+        assert_eq!(state.fired.get("BTC above $10000:above"), Some(&60));
+        assert!(state.evaluate(&rules, &market, 3600, 120).is_empty());
+    }
+
+    #[test]
+    fn test_alerts_rank() {
+        let rule = AlertRule::Top { symbol: Some("link".to_string()), rank: 20 };
+        let mut market = MarketView::default();
+        market.quotes.insert("LINK".to_string(), Quote { price: 3.0, change_24h: 0.0, rank: 20 });
+        market.depth = 100;
+        assert!(rule.evaluate(&market, None).is_empty());
+        // Past the listings fetched, the asset ranks somewhere below them.
+        market.quotes.clear();
+        let triggered = rule.evaluate(&market, None);
+        assert_eq!(triggered[0].1, "LINK ranks below 100, outside the top 20");
+        // Too few listings to tell, e.g. when fetching them failed.
+        market.depth = 0;
+        assert!(rule.evaluate(&market, None).is_empty());
+    }
 }
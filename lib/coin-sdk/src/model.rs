@@ -4,6 +4,9 @@ pub struct CoinConfig {
     pub blacklist: Option<Vec<String>>,
     pub account: Vec<AccountConfig>,
     pub cmc: CMCConfig,
+    // Where local state such as cached prices is kept. Nothing is written to disk when unset.
+    #[serde(default)]
+    pub data_dir: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -87,7 +90,7 @@ impl PortfolioBalance {
   }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Amount {
   pub amount: f64,
   pub currency: String
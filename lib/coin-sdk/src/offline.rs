@@ -1,6 +1,7 @@
-use crate::model::{AccountConfig, Asset, Amount};
+use crate::model::{AccountConfig, Asset};
 use crate::errors::*;
 use crate::account::Account;
+use crate::ledger::LedgerEntry;
 
 pub struct OfflineAccount {
   config: AccountConfig,
@@ -34,19 +35,10 @@ impl Account for OfflineAccount {
     }
   }
 
-  fn total_costs(&self) -> Result<Amount> {
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
-  }
-
-  fn total_gains(&self) -> Result<Amount> {
-    Ok(Amount { amount: 0.0, currency: "USD".to_string() })
-  }
-
-  fn cost_basis(&self) -> Result<()> {
-    Ok(())
-  }
-
-  fn capital_gains(&self) -> Result<()> {
-    Ok(())
+  /**
+   * Offline wallets have no history to sync.
+   */
+  fn ledger(&self) -> Result<Vec<LedgerEntry>> {
+    Ok(vec![])
   }
 }
\ No newline at end of file
@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use crate::errors::*;
+use crate::ledger::{is_fiat, EntryKind, Ledger, LedgerEntry};
+use crate::model::Amount;
+use crate::prices::{HistoricalPrices, Resolution};
+use crate::transfers::TransferMatch;
+
+const SECONDS_IN_DAY: u64 = 24 * 60 * 60;
+const SECONDS_IN_YEAR: f64 = 365.25 * 24.0 * 60.0 * 60.0;
+// Quantities this small are left over from rounding and need no price.
+const DUST: f64 = 1e-9;
+
+/**
+ * The value of a portfolio at the end of a day and the money that moved in (positive) or
+ * out (negative) of it during the day, in USD. `day` is the timestamp the UTC day starts at.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct DailyValue {
+  pub day: u64,
+  pub value: f64,
+  pub flow: f64,
+}
+
+/**
+ * Chains the return of every day since `since` so deposits and withdrawals do not count as
+ * performance. Flows are assumed to arrive at the start of the day. Returns None when there
+ * is nothing to measure.
+ */
+pub fn time_weighted_return(history: &[DailyValue], since: u64) -> Option<f64> {
+  let mut previous = history.iter().filter(|v| v.day < since).last().map(|v| v.value).unwrap_or(0.0);
+  let mut growth = 1.0;
+  let mut measured = false;
+  for v in history.iter().filter(|v| v.day >= since) {
+    let invested = previous + v.flow;
+    if invested > 0.0 {
+      growth *= v.value / invested;
+      measured = true;
+    }
+    previous = v.value;
+  }
+  if measured { Some(growth - 1.0) } else { None }
+}
+
+/**
+ * The annualized internal rate of return of the money invested since `since`. The value
+ * held before `since` counts as invested at the start.
+ */
+pub fn money_weighted_return(history: &[DailyValue], since: u64) -> Option<f64> {
+  let window: Vec<&DailyValue> = history.iter().filter(|v| v.day >= since).collect();
+  let last = window.last()?;
+  let mut flows: Vec<(u64, f64)> = vec![];
+  if let Some(base) = history.iter().filter(|v| v.day < since).last() {
+    flows.push((base.day, -base.value));
+  }
+  flows.extend(window.iter().filter(|v| v.flow != 0.0).map(|v| (v.day, -v.flow)));
+  flows.push((last.day, last.value));
+  let start = flows[0].0;
+  let years: Vec<(f64, f64)> = flows.iter().map(|(day, amount)| ((day - start) as f64 / SECONDS_IN_YEAR, *amount)).collect();
+  irr(&years)
+}
+
+/**
+ * Finds the rate that discounts the cash flows, given as years from the first flow and
+ * amount, to zero by bisection.
+ */
+pub fn irr(flows: &[(f64, f64)]) -> Option<f64> {
+  let npv = |rate: f64| -> f64 { flows.iter().map(|(t, amount)| amount / (1.0 + rate).powf(*t)).sum() };
+  let (mut low, mut high) = (-0.9999, 1000.0);
+  let (npv_low, npv_high) = (npv(low), npv(high));
+  if !npv_low.is_finite() || !npv_high.is_finite() || npv_low.signum() == npv_high.signum() {
+    return None;
+  }
+  for _ in 0..200 {
+    let mid = (low + high) / 2.0;
+    if npv(mid).signum() == npv_low.signum() {
+      low = mid;
+    } else {
+      high = mid;
+    }
+  }
+  Some((low + high) / 2.0)
+}
+
+/**
+ * Time-weighted returns to date for the month, quarter, year and since the first entry,
+ * and the annualized money-weighted return since the first entry.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeriodReturns {
+  pub mtd: Option<f64>,
+  pub qtd: Option<f64>,
+  pub ytd: Option<f64>,
+  pub inception: Option<f64>,
+  pub irr: Option<f64>,
+  // The value at the end of the history and everything that was put in less what was taken out.
+  pub value: f64,
+  pub net_flows: f64,
+}
+impl PeriodReturns {
+  pub fn from_history(history: &[DailyValue], now: u64) -> Self {
+    let today = match Utc.timestamp_opt(now as i64, 0).single() {
+      Some(dt) => dt.naive_utc().date(),
+      None => return PeriodReturns::default(),
+    };
+    let start_of = |month: u32| {
+      NaiveDate::from_ymd_opt(today.year(), month, 1)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| Utc.from_utc_datetime(&d).timestamp() as u64)
+        .unwrap_or(0)
+    };
+    PeriodReturns {
+      mtd: time_weighted_return(history, start_of(today.month())),
+      qtd: time_weighted_return(history, start_of((today.month() - 1) / 3 * 3 + 1)),
+      ytd: time_weighted_return(history, start_of(1)),
+      inception: time_weighted_return(history, 0),
+      irr: money_weighted_return(history, 0),
+      value: history.last().map(|v| v.value).unwrap_or(0.0),
+      net_flows: history.iter().map(|v| v.flow).sum(),
+    }
+  }
+}
+
+/**
+ * Daily values of the portfolio and each account.
+ *
+ * Only crypto assets count towards the value. Buying crypto with fiat is money put in and
+ * selling it for fiat is money taken out, as are crypto deposits and withdrawals to
+ * addresses outside the portfolio. Transfers matched between accounts move money between
+ * those accounts but not in or out of the portfolio. Days an account holds an asset without
+ * a price are left out, for the portfolio too, and their flows count on the next day kept.
+ */
+#[derive(Debug, Default)]
+pub struct PerformanceHistory {
+  pub portfolio: Vec<DailyValue>,
+  pub accounts: BTreeMap<String, Vec<DailyValue>>,
+  pub issues: Vec<String>,
+}
+
+impl PerformanceHistory {
+  /**
+   * Rebuilds the value of every account at the end of each day from the ledger and daily closes.
+   */
+  pub fn from_ledger(ledger: &Ledger, transfers: &[TransferMatch], prices: &HistoricalPrices, until: u64) -> Self {
+    let mut history = PerformanceHistory::default();
+    let mut entries: Vec<&LedgerEntry> = ledger.entries.iter().filter(|e| e.timestamp <= until).collect();
+    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    let first_day = match entries.first() {
+      Some(e) => e.timestamp - e.timestamp % SECONDS_IN_DAY,
+      None => return history,
+    };
+    let last_day = until - until % SECONDS_IN_DAY;
+    let internal: HashSet<(String, String)> = transfers.iter().flat_map(|t| vec![t.withdrawal_key(), t.deposit_key()]).collect();
+    let closes = history.daily_closes(&entries, prices, first_day, last_day);
+    let mut holdings: BTreeMap<String, HashMap<String, f64>> = BTreeMap::new();
+    let mut unpriced: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    let mut carried: HashMap<String, f64> = HashMap::new();
+    let mut carried_portfolio = 0.0;
+    let mut next = 0;
+    let mut day = first_day;
+    while day <= last_day {
+      let mut flows: HashMap<String, f64> = HashMap::new();
+      let mut portfolio_flow = 0.0;
+      while next < entries.len() && entries[next].timestamp < day + SECONDS_IN_DAY {
+        let entry = entries[next];
+        next += 1;
+        let held = holdings.entry(entry.account.to_string()).or_insert_with(HashMap::new);
+        apply(held, entry);
+        match flow(entry, &closes, day, prices) {
+          Ok(amount) => {
+            *flows.entry(entry.account.to_string()).or_insert(0.0) += amount;
+            if !internal.contains(&entry.key()) {
+              portfolio_flow += amount;
+            }
+          },
+          Err(e) => history.issues.push(format!("{} {}: {}", entry.account, entry.id, e)),
+        }
+      }
+      let mut total = 0.0;
+      let mut complete = true;
+      for (account, held) in &holdings {
+        let flow = flows.get(account).cloned().unwrap_or(0.0) + carried.remove(account).unwrap_or(0.0);
+        match value_on(held, &closes, day) {
+          Ok(value) => {
+            total += value;
+            history.accounts.entry(account.to_string()).or_insert_with(Vec::new).push(DailyValue { day, value, flow });
+          },
+          Err(missing) => {
+            for asset in missing {
+              unpriced.entry(asset).or_insert_with(BTreeSet::new).insert(day);
+            }
+            carried.insert(account.to_string(), flow);
+            complete = false;
+          },
+        }
+      }
+      carried_portfolio += portfolio_flow;
+      if complete {
+        history.portfolio.push(DailyValue { day, value: total, flow: carried_portfolio });
+        carried_portfolio = 0.0;
+      }
+      day += SECONDS_IN_DAY;
+    }
+    for (asset, days) in unpriced {
+      let (first, last) = (days.iter().next().cloned().unwrap_or(0), days.iter().next_back().cloned().unwrap_or(0));
+      history.issues.push(format!("No price for {} on {} days from {} to {}, they are left out", asset, days.len(), date(first), date(last)));
+    }
+    history
+  }
+
+  /**
+   * Replaces the values rebuilt from the ledger with what the accounts were recorded to be worth
+   * on the days they have a snapshot, then adds up the portfolio again. Snapshots are given as
+   * timestamps and values in USD by account, the last one of a day counts.
+   */
+  pub fn use_snapshots(&mut self, snapshots: &HashMap<String, Vec<(u64, f64)>>) {
+    for (account, values) in self.accounts.iter_mut() {
+      let recorded = match snapshots.get(account) {
+        Some(recorded) => recorded,
+        None => continue,
+      };
+      let mut by_day: BTreeMap<u64, (u64, f64)> = BTreeMap::new();
+      for (timestamp, value) in recorded {
+        let day = timestamp - timestamp % SECONDS_IN_DAY;
+        if by_day.get(&day).map(|(t, _)| t <= timestamp).unwrap_or(true) {
+          by_day.insert(day, (*timestamp, *value));
+        }
+      }
+      for v in values.iter_mut() {
+        if let Some((_, value)) = by_day.get(&v.day) {
+          v.value = *value;
+        }
+      }
+    }
+    let mut totals: HashMap<u64, f64> = HashMap::new();
+    for v in self.accounts.values().flatten() {
+      *totals.entry(v.day).or_insert(0.0) += v.value;
+    }
+    for v in self.portfolio.iter_mut() {
+      v.value = totals.get(&v.day).cloned().unwrap_or(0.0);
+    }
+  }
+
+  fn daily_closes(&mut self, entries: &[&LedgerEntry], prices: &HistoricalPrices, first_day: u64, last_day: u64) -> HashMap<String, BTreeMap<u64, f64>> {
+    let assets: HashSet<String> = entries.iter()
+      .flat_map(|e| vec![&e.received, &e.sent, &e.fee])
+      .filter_map(|a| a.as_ref())
+      .filter(|a| !is_fiat(&a.currency))
+      .map(|a| a.currency.to_uppercase())
+      .collect();
+    let mut closes = HashMap::new();
+    for asset in assets {
+      match prices.closes(&asset, first_day, last_day + SECONDS_IN_DAY - 1, Resolution::Daily) {
+        Ok(points) if !points.is_empty() => {
+          closes.insert(asset, points.into_iter().map(|p| (p.timestamp - p.timestamp % SECONDS_IN_DAY, p.price)).collect());
+        },
+        Ok(_) => self.issues.push(format!("No price history for {}", asset)),
+        Err(e) => self.issues.push(format!("No price history for {}: {}", asset, e)),
+      }
+    }
+    closes
+  }
+}
+
+fn apply(held: &mut HashMap<String, f64>, entry: &LedgerEntry) {
+  let mut change = |amount: &Option<Amount>, sign: f64| {
+    if let Some(a) = amount.as_ref().filter(|a| !is_fiat(&a.currency)) {
+      *held.entry(a.currency.to_uppercase()).or_insert(0.0) += sign * a.amount;
+    }
+  };
+  change(&entry.received, 1.0);
+  change(&entry.sent, -1.0);
+  change(&entry.fee, -1.0);
+}
+
+/**
+ * The money an entry put into the account, negative when it was taken out.
+ */
+fn flow(entry: &LedgerEntry, closes: &HashMap<String, BTreeMap<u64, f64>>, day: u64, prices: &HistoricalPrices) -> Result<f64> {
+  let fiat_value = |amount: &Option<Amount>| -> Result<f64> {
+    match amount {
+      Some(a) if is_fiat(&a.currency) => Ok(a.amount * prices.price_at(&a.currency, entry.timestamp)?),
+      _ => Ok(0.0),
+    }
+  };
+  let crypto_value = |amount: &Option<Amount>| -> Result<f64> {
+    match amount {
+      Some(a) if !is_fiat(&a.currency) => match price_on(closes, &a.currency, day) {
+        Some(price) => Ok(a.amount * price),
+        None => bail!("No price for {} on {}", a.currency, day),
+      },
+      _ => Ok(0.0),
+    }
+  };
+  match entry.kind {
+    EntryKind::Buy => Ok(entry.usd_value.map(Ok).unwrap_or_else(|| fiat_value(&entry.sent))? + fiat_value(&entry.fee)?),
+    EntryKind::Sell => Ok(-(entry.usd_value.map(Ok).unwrap_or_else(|| fiat_value(&entry.received))? - fiat_value(&entry.fee)?)),
+    EntryKind::Deposit | EntryKind::Withdrawal | EntryKind::Transfer => Ok(crypto_value(&entry.received)? - crypto_value(&entry.sent)?),
+    EntryKind::Trade | EntryKind::Fee | EntryKind::Income => Ok(0.0),
+  }
+}
+
+/**
+ * The close of the day, or the last close before it. Later closes are never used, an asset is
+ * not worth what it only fetched after the day.
+ */
+fn price_on(closes: &HashMap<String, BTreeMap<u64, f64>>, asset: &str, day: u64) -> Option<f64> {
+  let series = closes.get(&asset.to_uppercase())?;
+  series.range(..=day).next_back().map(|(_, price)| *price)
+}
+
+/**
+ * The value of the holdings at the close of the day, or the assets held without a price.
+ */
+fn value_on(held: &HashMap<String, f64>, closes: &HashMap<String, BTreeMap<u64, f64>>, day: u64) -> std::result::Result<f64, Vec<String>> {
+  let mut value = 0.0;
+  let mut missing = vec![];
+  for (asset, quantity) in held.iter().filter(|(_, q)| q.abs() > DUST) {
+    match price_on(closes, asset, day) {
+      Some(price) => value += quantity * price,
+      None => missing.push(asset.to_string()),
+    }
+  }
+  if missing.is_empty() { Ok(value) } else { Err(missing) }
+}
+
+fn date(day: u64) -> String {
+  match Utc.timestamp_opt(day as i64, 0).single() {
+    Some(t) => t.format("%Y-%m-%d").to_string(),
+    None => day.to_string(),
+  }
+}
+
+/**
+ * How the portfolio did against holding a benchmark over the same days.
+ * `value_if_held` is what the portfolio would be worth had every deposit and withdrawal
+ * bought or sold the benchmark instead.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkComparison {
+  pub name: String,
+  pub benchmark_return: Option<f64>,
+  pub portfolio_return: Option<f64>,
+  pub excess_return: Option<f64>,
+  pub tracking_error: Option<f64>,
+  pub value_if_held: Option<f64>,
+}
+
+/**
+ * Compares the history against a benchmark given as daily price levels keyed by day.
+ * Days missing from the levels use the last level before them.
+ */
+pub fn compare_benchmark(name: &str, history: &[DailyValue], levels: &BTreeMap<u64, f64>, since: u64) -> BenchmarkComparison {
+  let window: Vec<&DailyValue> = history.iter().filter(|v| v.day >= since).collect();
+  let level = |day: u64| levels.range(..=day).next_back().map(|(_, l)| *l);
+  let base = history.iter().filter(|v| v.day < since).last();
+  let start_level = base.map(|b| b.day).or_else(|| window.first().map(|v| v.day)).and_then(level);
+  let end_level = window.last().and_then(|v| level(v.day));
+  let benchmark_return = match (start_level, end_level) {
+    (Some(start), Some(end)) if start > 0.0 => Some(end / start - 1.0),
+    _ => None,
+  };
+  let portfolio_return = time_weighted_return(history, since);
+  // Daily differences between the portfolio's and the benchmark's returns.
+  let mut differences = vec![];
+  let mut previous = base.map(|b| b.value).unwrap_or(0.0);
+  let mut previous_day = base.map(|b| b.day);
+  for v in &window {
+    let invested = previous + v.flow;
+    let benchmark = match (previous_day.and_then(level), level(v.day)) {
+      (Some(a), Some(b)) if a > 0.0 => Some(b / a - 1.0),
+      _ => None,
+    };
+    if let (true, Some(b)) = (invested > 0.0, benchmark) {
+      differences.push(v.value / invested - 1.0 - b);
+    }
+    previous = v.value;
+    previous_day = Some(v.day);
+  }
+  let tracking_error = if differences.len() > 1 {
+    let mean = differences.iter().sum::<f64>() / differences.len() as f64;
+    let variance = differences.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (differences.len() - 1) as f64;
+    Some(variance.sqrt() * 365f64.sqrt())
+  } else {
+    None
+  };
+  let mut units = 0.0;
+  let mut priced = true;
+  let flows = base.map(|b| (b.day, b.value)).into_iter().chain(window.iter().filter(|v| v.flow != 0.0).map(|v| (v.day, v.flow)));
+  for (day, amount) in flows {
+    match level(day) {
+      Some(l) if l > 0.0 => units += amount / l,
+      _ => priced = false,
+    }
+  }
+  BenchmarkComparison {
+    name: name.to_string(),
+    benchmark_return,
+    portfolio_return,
+    excess_return: match (portfolio_return, benchmark_return) {
+      (Some(p), Some(b)) => Some(p - b),
+      _ => None,
+    },
+    tracking_error,
+    value_if_held: if priced { end_level.map(|l| units * l) } else { None },
+  }
+}
+
+/**
+ * The levels of a basket that holds every member in equal weight, rebalanced daily, starting at 1.
+ * A member counts from the day after its first price. Days it has no price for use its last one, so
+ * a move across missing days counts on the day it is priced again.
+ */
+pub fn equal_weight(members: &[BTreeMap<u64, f64>]) -> BTreeMap<u64, f64> {
+  let mut days: Vec<u64> = members.iter().flat_map(|m| m.keys().cloned()).collect();
+  days.sort();
+  days.dedup();
+  let mut levels = BTreeMap::new();
+  let mut level = 1.0;
+  let mut previous_day = None;
+  for day in days {
+    if let Some(previous) = previous_day {
+      let returns: Vec<f64> = members.iter()
+        .filter_map(|m| match (last_level(m, previous), last_level(m, day)) {
+          (Some(a), Some(b)) if *a > 0.0 => Some(b / a - 1.0),
+          _ => None,
+        })
+        .collect();
+      if !returns.is_empty() {
+        level *= 1.0 + returns.iter().sum::<f64>() / returns.len() as f64;
+      }
+    }
+    levels.insert(day, level);
+    previous_day = Some(day);
+  }
+  levels
+}
+
+fn last_level(levels: &BTreeMap<u64, f64>, day: u64) -> Option<&f64> {
+  levels.range(..=day).next_back().map(|(_, l)| l)
+}
@@ -0,0 +1,292 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use chrono::DateTime;
+use cmc::client::CMCClient;
+use crate::errors::*;
+
+const SECONDS_IN_HOUR: u64 = 60 * 60;
+const SECONDS_IN_DAY: u64 = 24 * SECONDS_IN_HOUR;
+// Daily prices are fetched and cached a month at a time to keep the number of requests down.
+const DAILY_BUCKET_SECONDS: u64 = 30 * SECONDS_IN_DAY;
+// Statuses returned when the API key or its plan has no access to hourly closes.
+const ACCESS_DENIED: [u64; 2] = [401, 403];
+
+/**
+ * The closing price of an asset in USD at a point in time.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PricePoint {
+  pub timestamp: u64,
+  pub price: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+  Hourly,
+  Daily,
+}
+impl Resolution {
+  pub fn seconds(&self) -> u64 {
+    match self {
+      Resolution::Hourly => SECONDS_IN_HOUR,
+      Resolution::Daily => SECONDS_IN_DAY,
+    }
+  }
+
+  fn bucket_seconds(&self) -> u64 {
+    match self {
+      Resolution::Hourly => SECONDS_IN_DAY,
+      Resolution::Daily => DAILY_BUCKET_SECONDS,
+    }
+  }
+}
+
+/**
+ * A source of historical prices.
+ */
+pub trait PriceProvider {
+
+  fn name(&self) -> &str;
+
+  /**
+   * Returns closing USD prices between start and end in ascending order.
+   * Providers return an error when they do not support the resolution.
+   */
+  fn closes(&self, symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>>;
+}
+
+/**
+ * Daily closes come from coinmetrics which is free. Hourly closes come from
+ * CoinMarketCap and need a plan with access to historical quotes.
+ */
+impl PriceProvider for CMCClient {
+
+  fn name(&self) -> &str {
+    "coinmarketcap"
+  }
+
+  fn closes(&self, symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>> {
+    match resolution {
+      Resolution::Daily => {
+        let response = self.historic_prices(symbol, start, end)?;
+        Ok(response.result.iter().map(|(timestamp, price)| PricePoint { timestamp: *timestamp, price: *price }).collect())
+      },
+      Resolution::Hourly => {
+        let response = self.historical_quotes(symbol, start, end, "hourly")?;
+        let mut points = vec![];
+        for quote in response.quotes {
+          if let Some(usd) = quote.quote.get("USD") {
+            let timestamp = DateTime::parse_from_rfc3339(&quote.timestamp)
+              .chain_err(|| format!("Invalid quote timestamp {}", quote.timestamp))?;
+            points.push(PricePoint { timestamp: timestamp.timestamp() as u64, price: usd.price });
+          }
+        }
+        Ok(points)
+      }
+    }
+  }
+}
+
+/**
+ * Price series for one asset keyed by the start of the bucket they were fetched for.
+ */
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CachedSeries {
+  daily: BTreeMap<u64, Vec<PricePoint>>,
+  hourly: BTreeMap<u64, Vec<PricePoint>>,
+}
+impl CachedSeries {
+  fn buckets(&mut self, resolution: Resolution) -> &mut BTreeMap<u64, Vec<PricePoint>> {
+    match resolution {
+      Resolution::Daily => &mut self.daily,
+      Resolution::Hourly => &mut self.hourly,
+    }
+  }
+}
+
+/**
+ * Keeps fetched prices in memory and, when given a directory, on disk as one
+ * JSON file per symbol. Buckets that have not closed yet are never cached.
+ */
+pub struct PriceCache {
+  dir: Option<PathBuf>,
+  series: HashMap<String, CachedSeries>,
+}
+impl PriceCache {
+  pub fn new(dir: Option<PathBuf>) -> Self {
+    PriceCache {
+      dir,
+      series: HashMap::new(),
+    }
+  }
+
+  fn path(&self, symbol: &str) -> Option<PathBuf> {
+    self.dir.as_ref().map(|d| d.join(format!("{}.json", symbol)))
+  }
+
+  fn load(&mut self, symbol: &str) -> &mut CachedSeries {
+    if !self.series.contains_key(symbol) {
+      let from_disk = self.path(symbol)
+        .and_then(|p| fs::read_to_string(p).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default();
+      self.series.insert(symbol.to_string(), from_disk);
+    }
+    self.series.get_mut(symbol).unwrap()
+  }
+
+  pub fn get(&mut self, symbol: &str, resolution: Resolution, bucket: u64) -> Option<Vec<PricePoint>> {
+    self.load(symbol).buckets(resolution).get(&bucket).cloned()
+  }
+
+  pub fn insert(&mut self, symbol: &str, resolution: Resolution, bucket: u64, points: Vec<PricePoint>) -> Result<()> {
+    self.load(symbol).buckets(resolution).insert(bucket, points);
+    if let Some(path) = self.path(symbol) {
+      if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+      }
+      fs::write(path, serde_json::to_string(&self.series[symbol])?)?;
+    }
+    Ok(())
+  }
+}
+
+/**
+ * Looks up the USD price of an asset at any point in the past.
+ *
+ * Hourly closes are used when the provider has them and daily closes otherwise.
+ * A price between two closes is linearly interpolated as long as the closes are
+ * no more than two periods apart. A timestamp before the first or after the last
+ * close uses the nearest close if it is within one period. Anything else is a
+ * `NoPriceData` error rather than a guess.
+ */
+pub struct HistoricalPrices {
+  provider: Box<dyn PriceProvider>,
+  cache: RefCell<PriceCache>,
+  // Set once the provider refuses to return hourly closes so we stop asking.
+  hourly_unavailable: Cell<bool>,
+  // Set when only cached closes may be used.
+  offline: Cell<bool>,
+}
+impl HistoricalPrices {
+  pub fn new(provider: Box<dyn PriceProvider>, cache_dir: Option<PathBuf>) -> Self {
+    HistoricalPrices {
+      provider,
+      cache: RefCell::new(PriceCache::new(cache_dir)),
+      hourly_unavailable: Cell::new(false),
+      offline: Cell::new(false),
+    }
+  }
+
+  /**
+   * Stops asking the provider. Buckets that are not cached have no closes from now on.
+   */
+  pub fn go_offline(&self) {
+    self.offline.set(true);
+  }
+
+  /**
+   * The USD price of the symbol at the timestamp using the best resolution available.
+   * Hourly closes are only given up on for the rest of the run when access to them is
+   * denied. Any other failure falls back to daily closes for this call alone.
+   */
+  pub fn price_at(&self, symbol: &str, timestamp: u64) -> Result<f64> {
+    let symbol = symbol.to_uppercase();
+    if symbol == "USD" {
+      return Ok(1.0);
+    }
+    if !self.hourly_unavailable.get() {
+      match self.close_at(&symbol, timestamp, Resolution::Hourly) {
+        Ok(price) => return Ok(price),
+        Err(ref e) if is_access_denied(e) => self.hourly_unavailable.set(true),
+        Err(_) => (),
+      }
+    }
+    self.close_at(&symbol, timestamp, Resolution::Daily)
+  }
+
+  /**
+   * The USD price of the symbol at the timestamp using closes of a single resolution.
+   */
+  pub fn close_at(&self, symbol: &str, timestamp: u64, resolution: Resolution) -> Result<f64> {
+    let symbol = symbol.to_uppercase();
+    let period = resolution.seconds();
+    let start = timestamp.saturating_sub(2 * period);
+    let end = timestamp + 2 * period;
+    let points = self.closes(&symbol, start, end, resolution)?;
+    match interpolate(&points, timestamp, period) {
+      Some(price) => Ok(price),
+      None => bail!(ErrorKind::NoPriceData(symbol.to_string(), timestamp)),
+    }
+  }
+
+  /**
+   * All closes between start and end, fetched a bucket at a time through the cache.
+   */
+  pub fn closes(&self, symbol: &str, start: u64, end: u64, resolution: Resolution) -> Result<Vec<PricePoint>> {
+    let symbol = symbol.to_uppercase();
+    let size = resolution.bucket_seconds();
+    let now = now();
+    let mut points = vec![];
+    let mut bucket = start - start % size;
+    while bucket <= end {
+      let cached = self.cache.borrow_mut().get(&symbol, resolution, bucket);
+      let bucket_points = match cached {
+        Some(p) => p,
+        None if self.offline.get() => vec![],
+        None => {
+          let fetched = self.provider.closes(&symbol, bucket, bucket + size - 1, resolution)?;
+          if bucket + size <= now {
+            self.cache.borrow_mut().insert(&symbol, resolution, bucket, fetched.clone())?;
+          }
+          fetched
+        }
+      };
+      points.extend(bucket_points.into_iter().filter(|p| p.timestamp >= start && p.timestamp <= end));
+      bucket += size;
+    }
+    points.sort_by_key(|p| p.timestamp);
+    points.dedup_by_key(|p| p.timestamp);
+    Ok(points)
+  }
+}
+
+fn is_access_denied(error: &Error) -> bool {
+  match error.kind() {
+    ErrorKind::CMC(cmc::errors::ErrorKind::CMCError(code, _)) => ACCESS_DENIED.contains(code),
+    _ => false,
+  }
+}
+
+/**
+ * Interpolates the price at a timestamp from closes sorted in ascending order.
+ * See `HistoricalPrices` for the rules.
+ */
+pub fn interpolate(points: &[PricePoint], timestamp: u64, period: u64) -> Option<f64> {
+  let before = points.iter().filter(|p| p.timestamp <= timestamp).last();
+  let after = points.iter().find(|p| p.timestamp >= timestamp);
+  if let (Some(b), Some(a)) = (before, after) {
+    if b.timestamp == a.timestamp {
+      return Some(b.price);
+    }
+    if a.timestamp - b.timestamp <= 2 * period {
+      let weight = (timestamp - b.timestamp) as f64 / (a.timestamp - b.timestamp) as f64;
+      return Some(b.price + (a.price - b.price) * weight);
+    }
+  }
+  let nearest_before = before.filter(|b| timestamp - b.timestamp <= period);
+  let nearest_after = after.filter(|a| a.timestamp - timestamp <= period);
+  match (nearest_before, nearest_after) {
+    (Some(b), Some(a)) if a.timestamp - timestamp < timestamp - b.timestamp => Some(a.price),
+    (Some(b), _) => Some(b.price),
+    (None, Some(a)) => Some(a.price),
+    (None, None) => None,
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
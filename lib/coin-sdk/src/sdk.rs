@@ -1,19 +1,37 @@
 use std::collections::{HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use cmc::client::CMCClient;
 use crate::model::{CoinConfig, Portfolio};
 use crate::errors::*;
 use crate::account::{Account};
 use crate::model::{Asset, Amount};
 use crate::client_factory::ClientFactory;
+use crate::prices::HistoricalPrices;
+use crate::ledger::{ledger_path, Ledger};
+use crate::import::{read_export, Import, ImportFormat};
+use crate::transfers::{is_outgoing, overrides_path, TransferMatcher, DEFAULT_WINDOW, TransferOverrides, TransferReport};
+use crate::accounting::{Books, PortfolioReport};
+use crate::tax::TaxReport;
+use crate::performance::PerformanceHistory;
+use basis::basis::LotMethod;
 
 pub struct SDK {
   pub accounts: Vec<Box<Account>>,
+  pub prices: HistoricalPrices,
+  data_dir: Option<PathBuf>,
 }
 
 impl SDK {
   pub fn new(config: CoinConfig) -> Result<Self> {
+    let data_dir = config.data_dir.as_ref().map(PathBuf::from);
+    let cache_dir = data_dir.as_ref().map(|d| d.join("cache").join("prices"));
+    let prices = HistoricalPrices::new(Box::new(CMCClient::new(config.cmc.key.to_string())), cache_dir);
     let accounts = SDK::get_account_clients(config)?;
     Ok(SDK {
-      accounts
+      accounts,
+      prices,
+      data_dir,
     })
   }
 
@@ -58,49 +76,176 @@ impl SDK {
         .collect::<Vec<Asset>>())
   }
 
+  /**
+   * Replays the ledger into a basis book per account using the lot selection method.
+   * Transfers between accounts that were matched or confirmed carry their basis over.
+   */
+  pub fn books(&self, method: LotMethod, holding_period: Option<u64>) -> Result<Books> {
+    let ledger = self.ledger()?;
+    let transfers = self.transfers(DEFAULT_WINDOW)?;
+    Ok(Books::build(&ledger, &transfers.matched, &self.prices, method, holding_period))
+  }
+
+  /**
+   * Returns the cost basis, realized and unrealized gains of every asset in every account.
+   * Open lots are valued with the current USD prices by symbol. Lots are matched with `method` and
+   * sales of lots held longer than `holding_period` seconds are long term.
+   */
+  pub fn report(&self, method: LotMethod, holding_period: Option<u64>, current_prices: &HashMap<String, f64>) -> Result<PortfolioReport> {
+    Ok(self.books(method, holding_period)?.report(current_prices))
+  }
+
+  /**
+   * Rebuilds the daily value of the portfolio and each account from the ledger up to now.
+   */
+  pub fn performance(&self, now: u64) -> Result<PerformanceHistory> {
+    let ledger = self.ledger()?;
+    let transfers = self.transfers(DEFAULT_WINDOW)?;
+    Ok(PerformanceHistory::from_ledger(&ledger, &transfers.matched, &self.prices, now))
+  }
+
+  /**
+   * Returns every sale closed during the tax year for Form 8949 and Schedule D.
+   * Sales of lots held longer than `holding_period` seconds are long term.
+   */
+  pub fn tax_report(&self, year: i32, method: LotMethod, holding_period: Option<u64>) -> Result<TaxReport> {
+    TaxReport::build(&self.books(method, holding_period)?, year)
+  }
+
   /**
    * Returns the total cost sunk into the portfolio.
    * This will return costs even if no profit has been captured.
    */
-  pub fn total_costs(&self) -> Result<Amount> {
-    let mut total_cost = 0.0;
-    for account in &self.accounts {
-      let total = account.total_costs()?;
-      total_cost += total.amount;
-    }
-    Ok(Amount { amount: total_cost, currency: "USD".to_string() })
+  pub fn total_costs(&self, method: LotMethod, holding_period: Option<u64>) -> Result<Amount> {
+    let report = self.report(method, holding_period, &HashMap::new())?;
+    Ok(Amount { amount: report.total(None).invested, currency: "USD".to_string() })
   }
 
   /**
-   * Returns the total gains for the portfolio.
+   * Returns the total gains for the portfolio, realized and unrealized.
    */
-  pub fn total_gains(&self) -> Result<Amount> {
-    let mut total_gains = 0.0;
-    for account in &self.accounts {
-      let total = account.total_gains()?;
-      total_gains += total.amount;
-    }
-    Ok(Amount { amount: total_gains, currency: "USD".to_string() })
+  pub fn total_gains(&self, method: LotMethod, holding_period: Option<u64>, current_prices: &HashMap<String, f64>) -> Result<Amount> {
+    let total = self.report(method, holding_period, current_prices)?.total(None);
+    let gains = total.realized_gain() + total.unrealized_gain().unwrap_or(0.0);
+    Ok(Amount { amount: gains, currency: "USD".to_string() })
   }
 
   /**
    * Returns the total cost basis for taxable events in the portfolio.
    */
-  pub fn cost_basis(&self) -> Result<()> {
-    for account in &self.accounts {
-      account.cost_basis()?;
-    }
-    Ok(())
+  pub fn cost_basis(&self, method: LotMethod, holding_period: Option<u64>) -> Result<Amount> {
+    let report = self.report(method, holding_period, &HashMap::new())?;
+    Ok(Amount { amount: report.total(None).realized_cost, currency: "USD".to_string() })
   }
 
   /**
    * Returns the total gains - the cost basis.
    */
-  pub fn capital_gains(&self) -> Result<()> {
+  pub fn capital_gains(&self, method: LotMethod, holding_period: Option<u64>) -> Result<Amount> {
+    let report = self.report(method, holding_period, &HashMap::new())?;
+    Ok(Amount { amount: report.total(None).realized_gain(), currency: "USD".to_string() })
+  }
+
+  /**
+   * Returns the ledger of every synced transaction.
+   */
+  pub fn ledger(&self) -> Result<Ledger> {
+    Ledger::load(&self.ledger_path()?)
+  }
+
+  /**
+   * Pulls the full history of the named accounts, or every account, into the ledger.
+   * Returns the number of entries fetched and added for each account.
+   */
+  pub fn sync_ledger(&self, accounts: Option<Vec<String>>) -> Result<Vec<(String, usize, usize)>> {
+    let path = self.ledger_path()?;
+    let mut ledger = Ledger::load(&path)?;
+    let mut synced = vec![];
     for account in &self.accounts {
-      account.capital_gains()?;
+      if let Some(names) = &accounts {
+        if !names.contains(&account.name().to_string()) {
+          continue;
+        }
+      }
+      let entries = account.ledger().chain_err(|| format!("Could not sync the ledger of {}", account.name()))?;
+      let fetched = entries.len();
+      let added = ledger.merge(entries);
+      synced.push((account.name().to_string(), fetched, added));
+    }
+    ledger.save(&path)?;
+    Ok(synced)
+  }
+
+  /**
+   * Reads a CSV export into the ledger of the account and returns what was read and how many
+   * entries were new. Entries already synced from the provider's API are not added again.
+   */
+  pub fn import_ledger(&self, path: &Path, account: &str, format: Option<ImportFormat>) -> Result<(Import, usize)> {
+    let file = File::open(path).chain_err(|| format!("Could not open {:?}", path))?;
+    let import = read_export(file, account, format)?;
+    let ledger_path = self.ledger_path()?;
+    let mut ledger = Ledger::load(&ledger_path)?;
+    let added = ledger.merge(import.entries.clone());
+    ledger.save(&ledger_path)?;
+    Ok((import, added))
+  }
+
+  /**
+   * Pairs withdrawals and deposits between accounts. Deposits more than `window` seconds
+   * after a withdrawal are never matched to it.
+   */
+  pub fn transfers(&self, window: u64) -> Result<TransferReport> {
+    let ledger = self.ledger()?;
+    let overrides = TransferOverrides::load(&self.overrides_path()?)?;
+    TransferMatcher::new(window).match_transfers(&ledger, &overrides)
+  }
+
+  /**
+   * Records that the withdrawal and deposit are the same funds moving between accounts.
+   */
+  pub fn confirm_transfer(&self, withdrawal: (&str, &str), deposit: (&str, &str)) -> Result<()> {
+    let ledger = self.ledger()?;
+    for (account, id) in &[withdrawal, deposit] {
+      if !ledger.entries.iter().any(|e| e.account == *account && e.id == *id) {
+        bail!("{} {} is not in the ledger", account, id);
+      }
+    }
+    let path = self.overrides_path()?;
+    let mut overrides = TransferOverrides::load(&path)?;
+    overrides.confirm(
+      (withdrawal.0.to_string(), withdrawal.1.to_string()),
+      (deposit.0.to_string(), deposit.1.to_string()),
+    );
+    overrides.save(&path)
+  }
+
+  /**
+   * Records that the withdrawal left the configured accounts and must not be matched.
+   */
+  pub fn reject_transfer(&self, account: &str, withdrawal: &str) -> Result<()> {
+    match self.ledger()?.entries.iter().find(|e| e.account == account && e.id == withdrawal) {
+      Some(entry) if is_outgoing(entry) => (),
+      Some(_) => bail!("{} {} is not a withdrawal", account, withdrawal),
+      None => bail!("{} {} is not in the ledger", account, withdrawal),
+    }
+    let path = self.overrides_path()?;
+    let mut overrides = TransferOverrides::load(&path)?;
+    overrides.reject((account.to_string(), withdrawal.to_string()));
+    overrides.save(&path)
+  }
+
+  fn ledger_path(&self) -> Result<PathBuf> {
+    match &self.data_dir {
+      Some(dir) => Ok(ledger_path(dir)),
+      None => bail!("A data directory is required to keep a ledger"),
+    }
+  }
+
+  fn overrides_path(&self) -> Result<PathBuf> {
+    match &self.data_dir {
+      Some(dir) => Ok(overrides_path(dir)),
+      None => bail!("A data directory is required to keep a ledger"),
     }
-    Ok(())
   }
 
   fn get_account_clients(config: CoinConfig) -> Result<Vec<Box<Account>>> {
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use chrono::{Datelike, TimeZone, Utc};
+use basis::basis::{LotMethod, Realization, Term, TermSummary};
+use crate::accounting::Books;
+use crate::errors::*;
+
+const FORM_8949_HEADER: [&str; 9] = [
+  "Description",
+  "Date Acquired",
+  "Date Sold",
+  "Proceeds",
+  "Cost Basis",
+  "Gain or Loss",
+  "Term",
+  "Asset",
+  "Account",
+];
+
+/**
+ * A single line of Form 8949: the part of a sale matched against one lot.
+ */
+#[derive(Debug, Clone)]
+pub struct TaxLine {
+  pub account: String,
+  pub asset: String,
+  pub realization: Realization,
+}
+impl TaxLine {
+  pub fn description(&self) -> String {
+    format!("{} {}", format_quantity(self.realization.quantity), self.asset)
+  }
+}
+
+/**
+ * Every sale closed in a tax year, ready for Form 8949 and Schedule D.
+ * `issues` lists ledger entries that were left out of the books and may need a manual fix.
+ */
+#[derive(Debug)]
+pub struct TaxReport {
+  pub year: i32,
+  pub method: LotMethod,
+  pub lines: Vec<TaxLine>,
+  pub issues: Vec<String>,
+}
+
+impl TaxReport {
+  /**
+   * Collects the realizations of every account that were disposed of during the year, in UTC.
+   */
+  pub fn build(books: &Books, year: i32) -> Result<Self> {
+    let mut lines: Vec<TaxLine> = books.realizations()?
+      .into_iter()
+      .filter(|(_, _, r)| year_of(r.disposed) == Some(year))
+      .map(|(account, asset, realization)| TaxLine { account, asset, realization })
+      .collect();
+    lines.sort_by(|a, b| {
+      a.realization.disposed.cmp(&b.realization.disposed)
+        .then_with(|| a.asset.cmp(&b.asset))
+        .then_with(|| a.realization.acquired.cmp(&b.realization.acquired))
+    });
+    Ok(TaxReport {
+      year,
+      method: books.method,
+      lines,
+      issues: books.issues.clone(),
+    })
+  }
+
+  /**
+   * Totals for Schedule D, split into short and long term.
+   */
+  pub fn schedule_d(&self) -> TermSummary {
+    let realizations: Vec<Realization> = self.lines.iter().map(|l| l.realization.clone()).collect();
+    TermSummary::from_realizations(&realizations)
+  }
+
+  /**
+   * Totals of every asset sold during the year.
+   */
+  pub fn by_asset(&self) -> BTreeMap<String, TermSummary> {
+    let mut grouped: BTreeMap<String, Vec<Realization>> = BTreeMap::new();
+    for line in &self.lines {
+      grouped.entry(line.asset.to_string()).or_insert_with(Vec::new).push(line.realization.clone());
+    }
+    grouped.into_iter().map(|(asset, rows)| (asset, TermSummary::from_realizations(&rows))).collect()
+  }
+
+  /**
+   * Writes the lines as a Form 8949 compatible CSV. Short term lines come first, like on the form,
+   * and amounts are rounded to cents.
+   */
+  pub fn write_form_8949<W: Write>(&self, writer: W) -> Result<()> {
+    let mut csv = csv::Writer::from_writer(writer);
+    csv.write_record(&FORM_8949_HEADER).chain_err(|| "Could not write Form 8949")?;
+    for term in &[Term::Short, Term::Long] {
+      for line in self.lines.iter().filter(|l| l.realization.term == *term) {
+        let r = &line.realization;
+        csv.write_record(&[
+          line.description(),
+          format_date(r.acquired),
+          format_date(r.disposed),
+          format!("{:.2}", r.proceeds),
+          format!("{:.2}", r.cost),
+          format!("{:.2}", r.gain()),
+          r.term.name().to_string(),
+          line.asset.to_string(),
+          line.account.to_string(),
+        ]).chain_err(|| "Could not write Form 8949")?;
+      }
+    }
+    csv.flush()?;
+    Ok(())
+  }
+}
+
+fn year_of(timestamp: u64) -> Option<i32> {
+  Utc.timestamp_opt(timestamp as i64, 0).single().map(|dt| dt.year())
+}
+
+// Form 8949 dates are MM/DD/YYYY.
+fn format_date(timestamp: u64) -> String {
+  match Utc.timestamp_opt(timestamp as i64, 0).single() {
+    Some(dt) => dt.format("%m/%d/%Y").to_string(),
+    None => timestamp.to_string(),
+  }
+}
+
+fn format_quantity(quantity: f64) -> String {
+  let formatted = format!("{:.8}", quantity);
+  formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use basis::basis::{Basis, Lot};
+use basis::book::BasisBook;
+use crate::errors::*;
+use crate::ledger::{EntryKind, Ledger, LedgerEntry};
+
+// Deposits are usually credited within hours but some chains and exchanges take days.
+pub const DEFAULT_WINDOW: u64 = 3 * 24 * 60 * 60;
+// Clocks differ between providers so a deposit may look slightly older than its withdrawal.
+const CLOCK_SKEW: u64 = 10 * 60;
+// The largest share of a withdrawal a network fee may take when the provider does not report the fee.
+const MAX_UNKNOWN_FEE: f64 = 0.02;
+const TOLERANCE: f64 = 1e-8;
+
+/**
+ * A withdrawal from one account paired with the deposit of the same funds into another.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransferMatch {
+  pub withdrawal: String,
+  pub deposit: String,
+  pub from_account: String,
+  pub to_account: String,
+  pub asset: String,
+  pub sent: f64,
+  pub received: f64,
+  pub sent_at: u64,
+  pub received_at: u64,
+  // True when the match was confirmed by the user or the transaction hashes agree.
+  pub confirmed: bool,
+}
+impl TransferMatch {
+  pub fn withdrawal_key(&self) -> (String, String) {
+    (self.from_account.to_string(), self.withdrawal.to_string())
+  }
+
+  pub fn deposit_key(&self) -> (String, String) {
+    (self.to_account.to_string(), self.deposit.to_string())
+  }
+}
+
+/**
+ * A withdrawal that could be one of several deposits, or a deposit claimed by several withdrawals.
+ */
+#[derive(Debug, Clone)]
+pub struct AmbiguousTransfer {
+  pub withdrawal: LedgerEntry,
+  pub candidates: Vec<LedgerEntry>,
+}
+
+#[derive(Debug, Default)]
+pub struct TransferReport {
+  pub matched: Vec<TransferMatch>,
+  pub ambiguous: Vec<AmbiguousTransfer>,
+}
+
+/**
+ * Decisions the user made about transfers, kept next to the ledger.
+ * Entries are identified by account and id since ids are only unique within an account.
+ * `confirmed` pairs withdrawals with deposits and `rejected` lists withdrawals that
+ * must never be matched.
+ */
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TransferOverrides {
+  pub confirmed: Vec<((String, String), (String, String))>,
+  pub rejected: Vec<(String, String)>,
+}
+impl TransferOverrides {
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(TransferOverrides::default());
+    }
+    let contents = fs::read_to_string(path)?;
+    let overrides = serde_json::from_str(&contents).chain_err(|| format!("Could not read transfer overrides {:?}", path))?;
+    Ok(overrides)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  pub fn confirm(&mut self, withdrawal: (String, String), deposit: (String, String)) {
+    self.rejected.retain(|w| *w != withdrawal);
+    self.confirmed.retain(|(w, d)| *w != withdrawal && *d != deposit);
+    self.confirmed.push((withdrawal, deposit));
+  }
+
+  pub fn reject(&mut self, withdrawal: (String, String)) {
+    self.confirmed.retain(|(w, _)| *w != withdrawal);
+    if !self.rejected.contains(&withdrawal) {
+      self.rejected.push(withdrawal);
+    }
+  }
+}
+
+pub fn overrides_path(data_dir: &Path) -> PathBuf {
+  data_dir.join("transfers.json")
+}
+
+/**
+ * Pairs withdrawals and deposits across accounts by asset, amount net of network fees,
+ * time and transaction hash.
+ *
+ * Matching transaction hashes are always a match and different hashes never are.
+ * Otherwise a withdrawal and deposit are matched when they are the only candidates
+ * for each other. Everything else is reported as ambiguous until the user confirms
+ * or rejects it.
+ */
+pub struct TransferMatcher {
+  window: u64,
+}
+
+impl TransferMatcher {
+  pub fn new(window: u64) -> Self {
+    TransferMatcher { window }
+  }
+
+  pub fn match_transfers(&self, ledger: &Ledger, overrides: &TransferOverrides) -> Result<TransferReport> {
+    let by_key: HashMap<(String, String), &LedgerEntry> = ledger.entries.iter().map(|e| (e.key(), e)).collect();
+    let mut report = TransferReport::default();
+    let mut used: HashSet<(String, String)> = HashSet::new();
+    for (withdrawal, deposit) in &overrides.confirmed {
+      match (by_key.get(withdrawal), by_key.get(deposit)) {
+        (Some(w), Some(d)) => {
+          report.matched.push(make_match(w, d, true)?);
+          used.insert(withdrawal.clone());
+          used.insert(deposit.clone());
+        },
+        _ => bail!("Confirmed transfer {} {} -> {} {} is not in the ledger", withdrawal.0, withdrawal.1, deposit.0, deposit.1),
+      }
+    }
+    let rejected: HashSet<&(String, String)> = overrides.rejected.iter().collect();
+    let withdrawals: Vec<&LedgerEntry> = ledger.entries.iter()
+      .filter(|e| is_outgoing(e) && !used.contains(&e.key()) && !rejected.contains(&e.key()))
+      .collect();
+    let deposits: Vec<&LedgerEntry> = ledger.entries.iter()
+      .filter(|e| is_incoming(e) && !used.contains(&e.key()))
+      .collect();
+    let candidates: Vec<(&LedgerEntry, Vec<&LedgerEntry>)> = withdrawals.iter()
+      .map(|w| (*w, deposits.iter().filter(|d| self.could_match(w, d)).cloned().collect()))
+      .collect();
+    // How many withdrawals could have produced each deposit.
+    let mut claims: HashMap<(String, String), usize> = HashMap::new();
+    for (_, ds) in &candidates {
+      for d in ds {
+        *claims.entry(d.key()).or_insert(0) += 1;
+      }
+    }
+    for (withdrawal, ds) in candidates {
+      let same_hash = ds.iter().find(|d| d.tx_hash.is_some() && d.tx_hash == withdrawal.tx_hash);
+      if let Some(deposit) = same_hash {
+        report.matched.push(make_match(withdrawal, deposit, true)?);
+      } else if ds.len() == 1 && claims[&ds[0].key()] == 1 {
+        report.matched.push(make_match(withdrawal, ds[0], false)?);
+      } else if !ds.is_empty() {
+        report.ambiguous.push(AmbiguousTransfer {
+          withdrawal: withdrawal.clone(),
+          candidates: ds.into_iter().cloned().collect(),
+        });
+      }
+    }
+    Ok(report)
+  }
+
+  fn could_match(&self, withdrawal: &LedgerEntry, deposit: &LedgerEntry) -> bool {
+    let (sent, received) = match (&withdrawal.sent, &deposit.received) {
+      (Some(s), Some(r)) => (s, r),
+      _ => return false,
+    };
+    if withdrawal.account == deposit.account || sent.currency.to_uppercase() != received.currency.to_uppercase() {
+      return false;
+    }
+    if let (Some(a), Some(b)) = (&withdrawal.tx_hash, &deposit.tx_hash) {
+      return a == b;
+    }
+    if deposit.timestamp + CLOCK_SKEW < withdrawal.timestamp || deposit.timestamp > withdrawal.timestamp + self.window {
+      return false;
+    }
+    let max_fee = match &withdrawal.fee {
+      Some(fee) if fee.currency.to_uppercase() == sent.currency.to_uppercase() => fee.amount,
+      _ => sent.amount * MAX_UNKNOWN_FEE,
+    };
+    // Some providers report the amount net of the fee and some the gross amount.
+    received.amount <= sent.amount + TOLERANCE && received.amount + max_fee + TOLERANCE >= sent.amount
+  }
+}
+
+/**
+ * Whether the entry sends funds out of its account and can be matched to a deposit.
+ */
+pub fn is_outgoing(entry: &LedgerEntry) -> bool {
+  (entry.kind == EntryKind::Withdrawal || entry.kind == EntryKind::Transfer) && entry.sent.is_some()
+}
+
+fn is_incoming(entry: &LedgerEntry) -> bool {
+  (entry.kind == EntryKind::Deposit || entry.kind == EntryKind::Transfer) && entry.received.is_some()
+}
+
+fn make_match(withdrawal: &LedgerEntry, deposit: &LedgerEntry, confirmed: bool) -> Result<TransferMatch> {
+  match (&withdrawal.sent, &deposit.received) {
+    (Some(sent), Some(received)) => Ok(TransferMatch {
+      withdrawal: withdrawal.id.to_string(),
+      deposit: deposit.id.to_string(),
+      from_account: withdrawal.account.to_string(),
+      to_account: deposit.account.to_string(),
+      asset: sent.currency.to_uppercase(),
+      sent: sent.amount,
+      received: received.amount,
+      sent_at: withdrawal.timestamp,
+      received_at: deposit.timestamp,
+      confirmed,
+    }),
+    _ => bail!("{} -> {} is not a withdrawal and a deposit", withdrawal.id, deposit.id),
+  }
+}
+
+/**
+ * Moves the lots of a matched transfer from one account's basis to another's.
+ * The lots keep their acquisition dates and the network fee is carried over as
+ * part of the cost of what was received.
+ */
+pub fn carry_basis(from: &mut BasisBook, to: &mut BasisBook, transfer: &TransferMatch) -> Result<Vec<Lot>> {
+  let lots = from.asset(&transfer.asset).transfer_basis_at(transfer.sent_at, transfer.sent)
+    .chain_err(|| format!("Could not move the basis of {} {} from {}", transfer.sent, transfer.asset, transfer.from_account))?;
+  let scale = if transfer.sent > 0.0 { transfer.received / transfer.sent } else { 1.0 };
+  let mut received = vec![];
+  for lot in lots {
+    let moved = Lot {
+      id: lot.id,
+      acquired: lot.acquired,
+      quantity: lot.quantity * scale,
+      value: if scale > 0.0 { lot.value / scale } else { lot.value },
+    };
+    to.asset(&transfer.asset).receive_lot(transfer.received_at, &moved);
+    received.push(moved);
+  }
+  Ok(received)
+}
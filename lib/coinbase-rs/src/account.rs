@@ -9,13 +9,27 @@ pub struct Account {
 impl Account {
     pub fn list_accounts(&self) -> Result<CoinbasePaginatedResource<CoinbaseAccount>> {
         let mut results = self.client.get("/v2/accounts?limit=100")?;
-        let coinbase_accounts: CoinbasePaginatedResource<CoinbaseAccount> = results.json()?;
+        let mut coinbase_accounts: CoinbasePaginatedResource<CoinbaseAccount> = results.json()?;
+        while coinbase_accounts.pagination.next_uri.is_some() {
+            let url = coinbase_accounts.pagination.next_uri.as_mut().unwrap().to_string();
+            let mut page_resp = self.client.get(&url)?;
+            let next_page: CoinbasePaginatedResource<CoinbaseAccount> = page_resp.json()?;
+            coinbase_accounts.data.extend(next_page.data);
+            coinbase_accounts.pagination = next_page.pagination;
+        }
         Ok(coinbase_accounts)
     }
 
     pub fn list_buys(&self, acct_id: &str) -> Result<CoinbasePaginatedResource<CoinbaseBuy>> {
         let mut resp = self.client.get(&format!("/v2/accounts/{}/buys?limit=100", acct_id))?;
         let mut buys: CoinbasePaginatedResource<CoinbaseBuy> = resp.json()?;
+        while buys.pagination.next_uri.is_some() {
+            let url = buys.pagination.next_uri.as_mut().unwrap().to_string();
+            let mut page_resp = self.client.get(&url)?;
+            let next_page: CoinbasePaginatedResource<CoinbaseBuy> = page_resp.json()?;
+            buys.data.extend(next_page.data);
+            buys.pagination = next_page.pagination;
+        }
         buys.data.sort_unstable_by(|a, b| a.created_at.cmp(&b.created_at));
         Ok(buys)
     }
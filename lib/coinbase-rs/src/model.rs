@@ -116,7 +116,11 @@ pub struct CoinbaseTransactionDetail {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CoinbaseNetwork {
   status: Option<String>,
-  name: Option<String>
+  name: Option<String>,
+  #[serde(default)]
+  pub hash: Option<String>,
+  #[serde(default)]
+  pub transaction_fee: Option<CoinbaseAmount>
 }
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -133,11 +137,12 @@ pub enum CoinbaseTransactionType {
   VaultWithdrawal,
   ProDeposit,
   ProWithdrawal,
+  Trade,
   #[serde(other)]
   Other,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum CoinbaseTransactionStatus {
   #[serde(rename = "pending")]
   Pending,